@@ -0,0 +1,849 @@
+//! A textual assembly format for compiled programs.
+//!
+//! Every function is a header line, its constants, then one instruction per
+//! line, closed by `.end`:
+//!
+//! ```text
+//! .module 0 main
+//! .global answer
+//! .func main arity 0 module 0 path "main.oni"
+//! .const 0 uint 42
+//!     const 0          @10..12
+//! L1:
+//!     jump L1
+//! .end
+//! ```
+//!
+//! Functions follow the `.module` or `.type` they belong to. Module members are
+//! `.global NAME`, `.init` and `.func NAME`; type members are `.field NAME MODE`,
+//! `.method NAME` and `.ctor`. Names that aren't identifiers are quoted,
+//! jump targets are labels, and `@START..END` gives the source span of an
+//! instruction. Everything after a `;` is a comment.
+
+use std::{collections::HashMap, fmt::Write as _, ops::Range, path::Path, rc::Rc};
+
+use lasso::{Rodeo, Spur};
+
+use crate::{
+    compiler::{find_main, FuncProto, ImportBinding, ModuleProto, Program, TypeProto},
+    error::Error,
+    eval::{ConstValue, Flags, Opcode, RuntimeFunc},
+};
+
+/// Prints a program as assembly.
+pub fn disassemble(program: &Program, interner: &Rodeo) -> String {
+    let mut out = String::new();
+    let name = |spur: &Spur| quote_name(interner.resolve(spur));
+    for module in &program.modules {
+        writeln!(out, ".module {} {}", module.index, name(&module.name)).unwrap();
+        for global in &module.globals {
+            writeln!(out, ".global {}", name(global)).unwrap();
+        }
+        write_func(&mut out, ".init", &module.init, interner);
+        let mut funcs = module.funcs.iter().collect::<Vec<_>>();
+        funcs.sort_by_key(|(name, _)| interner.resolve(name));
+        for (func_name, func) in funcs {
+            let header = format!(".func {}", name(func_name));
+            write_func(&mut out, &header, func, interner);
+        }
+        out.push('\n');
+    }
+    let mut types = program.types.values().collect::<Vec<_>>();
+    types.sort_by_key(|ty| interner.resolve(&ty.name));
+    for ty in types {
+        writeln!(out, ".type {}", name(&ty.name)).unwrap();
+        for (field, flags) in ty.fields.iter() {
+            writeln!(out, ".field {} {}", name(field), flags_name(*flags)).unwrap();
+        }
+        let mut methods = ty.methods.iter().collect::<Vec<_>>();
+        methods.sort_by_key(|(name, _)| interner.resolve(name));
+        for (method, func) in methods {
+            // Only builtin types have native methods
+            if let RuntimeFunc::Virtual(func) = func {
+                let header = format!(".method {}", name(method));
+                write_func(&mut out, &header, func, interner);
+            }
+        }
+        if let Some(ctor) = &ty.ctor {
+            write_func(&mut out, ".ctor", ctor, interner);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn write_func(out: &mut String, header: &str, func: &FuncProto, interner: &Rodeo) {
+    writeln!(
+        out,
+        "{} arity {} module {} path {:?}",
+        header,
+        func.arity,
+        func.module,
+        func.path.to_string_lossy()
+    )
+    .unwrap();
+    for (i, value) in func.consts.iter().enumerate() {
+        let value = match value {
+            ConstValue::Int(i) => format!("int {}", i),
+            ConstValue::Uint(u) => format!("uint {}", u),
+            ConstValue::Float(f) => format!("float {:?}", f),
+            ConstValue::Bool(b) => format!("bool {}", b),
+            ConstValue::Str(s) => format!("str {:?}", interner.resolve(s)),
+        };
+        writeln!(out, ".const {} {}", i, value).unwrap();
+    }
+    let labels = func
+        .code
+        .iter()
+        .filter_map(jump_target)
+        .collect::<std::collections::HashSet<_>>();
+    for (ip, op) in func.code.iter().enumerate() {
+        if labels.contains(&ip) {
+            writeln!(out, "L{}:", ip).unwrap();
+        }
+        let text = instruction(op, interner);
+        match func.spans.get(ip) {
+            Some(span) => writeln!(out, "    {:<32} @{}..{}", text, span.start, span.end),
+            None => writeln!(out, "    {}", text),
+        }
+        .unwrap();
+    }
+    out.push_str(".end\n");
+}
+
+fn jump_target(op: &Opcode) -> Option<usize> {
+    match *op {
+        Opcode::Jump(target)
+        | Opcode::JumpIfFalse(target)
+        | Opcode::IterNext(target)
+        | Opcode::PushHandler(target) => Some(target),
+        _ => None,
+    }
+}
+
+fn flags_name(flags: Flags) -> &'static str {
+    match (
+        flags.contains(Flags::BINDING_MODE_MUT),
+        flags.contains(Flags::ASSIGNED),
+    ) {
+        (true, false) => "mut",
+        (false, false) => "immut",
+        (true, true) => "mut+assigned",
+        (false, true) => "immut+assigned",
+    }
+}
+
+/// Names are printed bare when they're identifiers, and quoted otherwise.
+fn quote_name(name: &str) -> String {
+    let mut chars = name.chars();
+    let ident = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_');
+    if ident {
+        name.to_owned()
+    } else {
+        format!("{:?}", name)
+    }
+}
+
+fn instruction(op: &Opcode, interner: &Rodeo) -> String {
+    let name = |spur: &Spur| quote_name(interner.resolve(spur));
+    let mnemonic = mnemonic(op);
+    match *op {
+        Opcode::Defslot(s, flags) | Opcode::DefGlobal(s, flags) | Opcode::DefField(s, flags) => {
+            format!("{} {} {}", mnemonic, name(&s), flags_name(flags))
+        }
+        Opcode::Import(ImportBinding {
+            name: n,
+            module,
+            item,
+        }) => match item {
+            Some(item) => format!("{} {} {} {}", mnemonic, name(&n), module, name(&item)),
+            None => format!("{} {} {}", mnemonic, name(&n), module),
+        },
+        Opcode::Dropslot(s)
+        | Opcode::Assign(s)
+        | Opcode::Read(s)
+        | Opcode::LoadField(s)
+        | Opcode::StoreField(s)
+        | Opcode::InitField(s) => format!("{} {}", mnemonic, name(&s)),
+        Opcode::Call(s, n) | Opcode::CallMethod(s, n) | Opcode::New(s, n) => {
+            format!("{} {} {}", mnemonic, name(&s), n)
+        }
+        Opcode::CallValue(n)
+        | Opcode::MakeList(n)
+        | Opcode::MakeMap(n)
+        | Opcode::TestList(n)
+        | Opcode::Const(n) => format!("{} {}", mnemonic, n),
+        Opcode::Jump(target)
+        | Opcode::JumpIfFalse(target)
+        | Opcode::IterNext(target)
+        | Opcode::PushHandler(target) => format!("{} L{}", mnemonic, target),
+        Opcode::MakeRange(b) | Opcode::Bool(b) => format!("{} {}", mnemonic, b),
+        _ => mnemonic.to_owned(),
+    }
+}
+
+fn mnemonic(op: &Opcode) -> &'static str {
+    match op {
+        Opcode::Defslot(..) => "defslot",
+        Opcode::DefGlobal(..) => "defglobal",
+        Opcode::Import(_) => "import",
+        Opcode::Dropslot(_) => "dropslot",
+        Opcode::Assign(_) => "assign",
+        Opcode::Call(..) => "call",
+        Opcode::CallValue(_) => "call_value",
+        Opcode::CallMethod(..) => "call_method",
+        Opcode::Return => "return",
+        Opcode::Read(_) => "read",
+        Opcode::LoadField(_) => "load_field",
+        Opcode::StoreField(_) => "store_field",
+        Opcode::DefField(..) => "def_field",
+        Opcode::InitField(_) => "init_field",
+        Opcode::Seal => "seal",
+        Opcode::MakeList(_) => "make_list",
+        Opcode::MakeMap(_) => "make_map",
+        Opcode::LoadIndex => "load_index",
+        Opcode::StoreIndex => "store_index",
+        Opcode::TestList(_) => "test_list",
+        Opcode::TestKey => "test_key",
+        Opcode::LoadKey => "load_key",
+        Opcode::NoMatch => "no_match",
+        Opcode::New(..) => "new",
+        Opcode::Jump(_) => "jump",
+        Opcode::JumpIfFalse(_) => "jump_if_false",
+        Opcode::Iter => "iter",
+        Opcode::IterNext(_) => "iter_next",
+        Opcode::MakeRange(_) => "make_range",
+        Opcode::Add => "add",
+        Opcode::Sub => "sub",
+        Opcode::Eq => "eq",
+        Opcode::Ne => "ne",
+        Opcode::Lt => "lt",
+        Opcode::Le => "le",
+        Opcode::Gt => "gt",
+        Opcode::Ge => "ge",
+        Opcode::PushHandler(_) => "push_handler",
+        Opcode::PopHandler => "pop_handler",
+        Opcode::Raise => "raise",
+        Opcode::EndEnsure => "end_ensure",
+        Opcode::Bool(_) => "bool",
+        Opcode::Const(_) => "const",
+        Opcode::Nil => "nil",
+        Opcode::Dup => "dup",
+        Opcode::Pop => "pop",
+    }
+}
+
+/// Parses assembly back into a program, interning its names into `interner`.
+pub fn assemble(src: &str, interner: &mut Rodeo) -> Result<Program, Error> {
+    let mut asm = Assembler {
+        interner,
+        paths: HashMap::new(),
+        line: 0,
+    };
+    let mut modules: Vec<ModuleProto> = Vec::new();
+    let mut types: HashMap<Spur, TypeProto> = HashMap::new();
+    // The module or type members are added to
+    let mut current_type: Option<Spur> = None;
+    let mut lines = src.lines().enumerate();
+    while let Some((line, text)) = lines.next() {
+        asm.line = line + 1;
+        let tokens = asm.tokenize(text)?;
+        let mut tokens = tokens.iter();
+        let directive = match tokens.next() {
+            Some(Token::Word(word)) => word.as_str(),
+            Some(Token::Str(_)) => return Err(asm.error("expected a directive")),
+            None => continue,
+        };
+        match directive {
+            ".module" => {
+                let index = asm.number(tokens.next())?;
+                let name = asm.name(tokens.next())?;
+                asm.done(tokens)?;
+                let init = FuncProto {
+                    arity: 0,
+                    module: index,
+                    code: Rc::new([]),
+                    consts: Rc::new([]),
+                    path: Rc::from(Path::new("")),
+                    spans: Rc::new([]),
+                };
+                modules.push(ModuleProto {
+                    index,
+                    name,
+                    funcs: HashMap::new(),
+                    globals: Vec::new(),
+                    init,
+                });
+                current_type = None;
+            }
+            ".global" | ".init" | ".func" => {
+                let module = match (current_type, modules.last_mut()) {
+                    (None, Some(module)) => module,
+                    _ => return Err(asm.error("module member outside of a module")),
+                };
+                match directive {
+                    ".global" => {
+                        module.globals.push(asm.name(tokens.next())?);
+                        asm.done(tokens)?;
+                    }
+                    ".init" => module.init = asm.func(tokens, &mut lines)?,
+                    _ => {
+                        let name = asm.name(tokens.next())?;
+                        let func = asm.func(tokens, &mut lines)?;
+                        if module.funcs.insert(name, func).is_some() {
+                            return Err(asm.error("duplicate function"));
+                        }
+                    }
+                }
+            }
+            ".type" => {
+                let name = asm.name(tokens.next())?;
+                asm.done(tokens)?;
+                let ty = TypeProto {
+                    name,
+                    fields: Rc::new([]),
+                    methods: HashMap::new(),
+                    ctor: None,
+                };
+                if types.insert(name, ty).is_some() {
+                    return Err(asm.error("duplicate type"));
+                }
+                current_type = Some(name);
+            }
+            ".field" | ".method" | ".ctor" => {
+                let ty = match current_type.and_then(|ty| types.get_mut(&ty)) {
+                    Some(ty) => ty,
+                    None => return Err(asm.error("type member outside of a type")),
+                };
+                match directive {
+                    ".field" => {
+                        let field = asm.name(tokens.next())?;
+                        let flags = asm.flags(tokens.next())?;
+                        asm.done(tokens)?;
+                        let mut fields = ty.fields.to_vec();
+                        fields.push((field, flags));
+                        ty.fields = fields.into();
+                    }
+                    ".method" => {
+                        let name = asm.name(tokens.next())?;
+                        let func = asm.func(tokens, &mut lines)?;
+                        if ty
+                            .methods
+                            .insert(name, RuntimeFunc::Virtual(func))
+                            .is_some()
+                        {
+                            return Err(asm.error("duplicate method"));
+                        }
+                    }
+                    _ => ty.ctor = Some(asm.func(tokens, &mut lines)?),
+                }
+            }
+            _ => return Err(asm.error(&format!("unknown directive {}", directive))),
+        }
+    }
+
+    let main = find_main(&modules, asm.interner)?;
+    Ok(Program {
+        main,
+        modules,
+        types,
+        warnings: Vec::new(),
+    })
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+}
+
+struct Assembler<'i> {
+    interner: &'i mut Rodeo,
+    paths: HashMap<String, Rc<Path>>,
+    /// The line being assembled, from 1
+    line: usize,
+}
+
+impl<'i> Assembler<'i> {
+    fn error(&self, reason: &str) -> Error {
+        Error::assembler(concat!(file!(), ":", line!()), self.line, reason.to_owned())
+    }
+
+    fn tokenize(&self, line: &str) -> Result<Vec<Token>, Error> {
+        let mut tokens = Vec::new();
+        let mut chars = line.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if c == ';' {
+                break;
+            } else if c == '"' {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => s.push(self.escape(&mut chars)?),
+                        Some(c) => s.push(c),
+                        None => return Err(self.error("unterminated string")),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            } else {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == ';' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+        Ok(tokens)
+    }
+
+    fn escape(&self, chars: &mut impl Iterator<Item = char>) -> Result<char, Error> {
+        Ok(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some(c @ ('\\' | '"' | '\'')) => c,
+            Some('u') => {
+                let hex = chars.skip(1).take_while(|&c| c != '}').collect::<String>();
+                u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| self.error("invalid unicode escape"))?
+            }
+            _ => return Err(self.error("invalid escape")),
+        })
+    }
+
+    fn done<'t>(&self, mut tokens: impl Iterator<Item = &'t Token>) -> Result<(), Error> {
+        match tokens.next() {
+            Some(_) => Err(self.error("unexpected operand")),
+            None => Ok(()),
+        }
+    }
+
+    fn word<'t>(&self, token: Option<&'t Token>) -> Result<&'t str, Error> {
+        match token {
+            Some(Token::Word(word)) => Ok(word),
+            _ => Err(self.error("missing operand")),
+        }
+    }
+
+    fn keyword(&self, token: Option<&Token>, keyword: &str) -> Result<(), Error> {
+        match token {
+            Some(Token::Word(word)) if word == keyword => Ok(()),
+            _ => Err(self.error(&format!("expected {}", keyword))),
+        }
+    }
+
+    fn number(&self, token: Option<&Token>) -> Result<usize, Error> {
+        self.word(token)?
+            .parse()
+            .map_err(|_| self.error("expected a number"))
+    }
+
+    fn name(&mut self, token: Option<&Token>) -> Result<Spur, Error> {
+        match token {
+            Some(Token::Word(name) | Token::Str(name)) => Ok(self.interner.get_or_intern(name)),
+            None => Err(self.error("missing name")),
+        }
+    }
+
+    fn flags(&self, token: Option<&Token>) -> Result<Flags, Error> {
+        Ok(match self.word(token)? {
+            "mut" => Flags::BINDING_MODE_MUT,
+            "immut" => Flags::BINDING_MODE_IMMUT,
+            "mut+assigned" => Flags::BINDING_MODE_MUT | Flags::ASSIGNED,
+            "immut+assigned" => Flags::ASSIGNED,
+            _ => return Err(self.error("expected a binding mode")),
+        })
+    }
+
+    fn bool(&self, token: Option<&Token>) -> Result<bool, Error> {
+        self.word(token)?
+            .parse()
+            .map_err(|_| self.error("expected true or false"))
+    }
+
+    /// Parses the rest of a function header, then its body up to `.end`.
+    fn func<'t, 'l>(
+        &mut self,
+        mut header: impl Iterator<Item = &'t Token>,
+        lines: &mut impl Iterator<Item = (usize, &'l str)>,
+    ) -> Result<FuncProto, Error> {
+        self.keyword(header.next(), "arity")?;
+        let arity = self.number(header.next())?;
+        self.keyword(header.next(), "module")?;
+        let module = self.number(header.next())?;
+        self.keyword(header.next(), "path")?;
+        let path = match header.next() {
+            Some(Token::Str(path)) => path,
+            _ => return Err(self.error("expected a quoted path")),
+        };
+        let path = Rc::clone(
+            self.paths
+                .entry(path.clone())
+                .or_insert_with(|| Rc::from(Path::new(path))),
+        );
+        self.done(header)?;
+
+        let mut consts = Vec::new();
+        let mut code = Vec::new();
+        let mut spans: Vec<Range<usize>> = Vec::new();
+        let mut labels = HashMap::new();
+        // Jumps waiting for their label, with the line they're on
+        let mut pending = Vec::new();
+        loop {
+            let (line, text) = lines.next().ok_or_else(|| self.error("missing .end"))?;
+            self.line = line + 1;
+            let tokens = self.tokenize(text)?;
+            let mut tokens = tokens.iter().peekable();
+            let first = match tokens.next() {
+                Some(Token::Word(word)) => word.as_str(),
+                Some(Token::Str(_)) => return Err(self.error("expected an instruction")),
+                None => continue,
+            };
+            if first == ".end" {
+                self.done(tokens)?;
+                break;
+            }
+            if first == ".const" {
+                if self.number(tokens.next())? != consts.len() {
+                    return Err(self.error("constants must be numbered in order"));
+                }
+                consts.push(self.constant(&mut tokens)?);
+                self.done(tokens)?;
+                continue;
+            }
+            if let Some(label) = first.strip_suffix(':') {
+                if labels.insert(label.to_owned(), code.len()).is_some() {
+                    return Err(self.error("duplicate label"));
+                }
+                self.done(tokens)?;
+                continue;
+            }
+
+            let mut jump = |asm: &Self, token: Option<&Token>| -> Result<usize, Error> {
+                pending.push((code.len(), asm.word(token)?.to_owned(), asm.line));
+                Ok(usize::MAX)
+            };
+            let op = match first {
+                "defslot" => Opcode::Defslot(self.name(tokens.next())?, self.flags(tokens.next())?),
+                "defglobal" => {
+                    Opcode::DefGlobal(self.name(tokens.next())?, self.flags(tokens.next())?)
+                }
+                "import" => {
+                    let name = self.name(tokens.next())?;
+                    let module = self.number(tokens.next())?;
+                    let item = match tokens.peek() {
+                        Some(Token::Word(w)) if w.starts_with('@') => None,
+                        Some(_) => Some(self.name(tokens.next())?),
+                        None => None,
+                    };
+                    Opcode::Import(ImportBinding { name, module, item })
+                }
+                "dropslot" => Opcode::Dropslot(self.name(tokens.next())?),
+                "assign" => Opcode::Assign(self.name(tokens.next())?),
+                "call" => Opcode::Call(self.name(tokens.next())?, self.number(tokens.next())?),
+                "call_value" => Opcode::CallValue(self.number(tokens.next())?),
+                "call_method" => {
+                    Opcode::CallMethod(self.name(tokens.next())?, self.number(tokens.next())?)
+                }
+                "return" => Opcode::Return,
+                "read" => Opcode::Read(self.name(tokens.next())?),
+                "load_field" => Opcode::LoadField(self.name(tokens.next())?),
+                "store_field" => Opcode::StoreField(self.name(tokens.next())?),
+                "def_field" => {
+                    Opcode::DefField(self.name(tokens.next())?, self.flags(tokens.next())?)
+                }
+                "init_field" => Opcode::InitField(self.name(tokens.next())?),
+                "seal" => Opcode::Seal,
+                "make_list" => Opcode::MakeList(self.number(tokens.next())?),
+                "make_map" => Opcode::MakeMap(self.number(tokens.next())?),
+                "load_index" => Opcode::LoadIndex,
+                "store_index" => Opcode::StoreIndex,
+                "test_list" => Opcode::TestList(self.number(tokens.next())?),
+                "test_key" => Opcode::TestKey,
+                "load_key" => Opcode::LoadKey,
+                "no_match" => Opcode::NoMatch,
+                "new" => Opcode::New(self.name(tokens.next())?, self.number(tokens.next())?),
+                "jump" => Opcode::Jump(jump(self, tokens.next())?),
+                "jump_if_false" => Opcode::JumpIfFalse(jump(self, tokens.next())?),
+                "iter" => Opcode::Iter,
+                "iter_next" => Opcode::IterNext(jump(self, tokens.next())?),
+                "make_range" => Opcode::MakeRange(self.bool(tokens.next())?),
+                "add" => Opcode::Add,
+                "sub" => Opcode::Sub,
+                "eq" => Opcode::Eq,
+                "ne" => Opcode::Ne,
+                "lt" => Opcode::Lt,
+                "le" => Opcode::Le,
+                "gt" => Opcode::Gt,
+                "ge" => Opcode::Ge,
+                "push_handler" => Opcode::PushHandler(jump(self, tokens.next())?),
+                "pop_handler" => Opcode::PopHandler,
+                "raise" => Opcode::Raise,
+                "end_ensure" => Opcode::EndEnsure,
+                "bool" => Opcode::Bool(self.bool(tokens.next())?),
+                "const" => Opcode::Const(self.number(tokens.next())?),
+                "nil" => Opcode::Nil,
+                "dup" => Opcode::Dup,
+                "pop" => Opcode::Pop,
+                _ => return Err(self.error(&format!("unknown instruction {}", first))),
+            };
+            let span = match tokens.next() {
+                Some(Token::Word(span)) if span.starts_with('@') => self.span(&span[1..])?,
+                Some(_) => return Err(self.error("unexpected operand")),
+                None => 0..0,
+            };
+            self.done(tokens)?;
+            code.push(op);
+            spans.push(span);
+        }
+
+        for (at, label, line) in pending {
+            let target = match labels.get(&label) {
+                Some(&target) => target,
+                None => {
+                    self.line = line;
+                    return Err(self.error(&format!("undefined label {}", label)));
+                }
+            };
+            code[at] = match code[at] {
+                Opcode::Jump(_) => Opcode::Jump(target),
+                Opcode::JumpIfFalse(_) => Opcode::JumpIfFalse(target),
+                Opcode::IterNext(_) => Opcode::IterNext(target),
+                Opcode::PushHandler(_) => Opcode::PushHandler(target),
+                _ => unreachable!("patching a non-jump instruction"),
+            };
+        }
+        Ok(FuncProto {
+            arity,
+            module,
+            code: code.into(),
+            consts: consts.into(),
+            path,
+            spans: spans.into(),
+        })
+    }
+
+    fn constant<'t>(
+        &mut self,
+        tokens: &mut impl Iterator<Item = &'t Token>,
+    ) -> Result<ConstValue, Error> {
+        let kind = self.word(tokens.next())?;
+        if kind == "str" {
+            return match tokens.next() {
+                Some(Token::Str(s)) => Ok(ConstValue::Str(self.interner.get_or_intern(s))),
+                _ => Err(self.error("expected a quoted string")),
+            };
+        }
+        let value = self.word(tokens.next())?;
+        let invalid = || self.error(&format!("invalid {} constant", kind));
+        Ok(match kind {
+            "int" => ConstValue::Int(value.parse().map_err(|_| invalid())?),
+            "uint" => ConstValue::Uint(value.parse().map_err(|_| invalid())?),
+            "float" => ConstValue::Float(value.parse().map_err(|_| invalid())?),
+            "bool" => ConstValue::Bool(value.parse().map_err(|_| invalid())?),
+            _ => return Err(self.error(&format!("unknown constant type {}", kind))),
+        })
+    }
+
+    fn span(&self, span: &str) -> Result<Range<usize>, Error> {
+        span.split_once("..")
+            .and_then(|(start, end)| Some(start.parse().ok()?..end.parse().ok()?))
+            .ok_or_else(|| self.error("invalid span"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lasso::Rodeo;
+
+    use super::*;
+    use crate::{
+        error::ErrorKind,
+        eval::Vm,
+        testing::{compile, raised},
+    };
+
+    const SCRIPT: &str = r#"
+type Point do
+    x := 0
+    y $= 0
+    defn init(x) do
+        self.x = x
+    end
+    defn norm() do
+        self.x + self.y
+    end
+end
+
+total := 0
+
+defn describe(v) do
+    match v do
+        [a, b] => a + b,
+        {name: n} => n,
+        nil => "nothing",
+        _ => "other\n\"quoted\"",
+    end
+end
+
+defn main() do
+    p := new(3) Point
+    for x in [1, 2, 3] do
+        continue if x == 2
+        total = total + x
+    end
+    i := 0
+    while i < 5 do
+        i = i + 1
+        break if i >= 4
+    end
+    r := try
+        raise describe([i, p.norm()])
+    rescue e do
+        e
+    ensure
+        total = total - 1
+    end
+    raise [r, total, 0..2, {a: 1.5, b: true}]
+end
+"#;
+
+    fn run(src: &str) -> Result<(), Error> {
+        let mut interner = Rodeo::new();
+        let program = assemble(src, &mut interner)?;
+        let mut vm = Vm::new(program, &mut interner)?;
+        vm.eval()
+    }
+
+    /// Wraps the body of `main` in a module with an empty initializer.
+    fn program(consts: &str, code: &str, funcs: &str) -> String {
+        format!(
+            ".module 0 test\n\
+             .init arity 0 module 0 path \"test.oni\"\n    nil\n    return\n.end\n\
+             .func main arity 0 module 0 path \"test.oni\"\n{}{}.end\n{}",
+            consts, code, funcs
+        )
+    }
+
+    #[test]
+    fn disassembly_round_trips() {
+        for opt_level in 0..=2 {
+            let mut interner = Rodeo::new();
+            let program = compile(SCRIPT, &mut interner, opt_level).unwrap();
+            let text = disassemble(&program, &interner);
+            let assembled = assemble(&text, &mut interner).unwrap();
+            assert_eq!(disassemble(&assembled, &interner), text);
+        }
+    }
+
+    #[test]
+    fn assembled_programs_run_like_compiled_ones() {
+        let mut interner = Rodeo::new();
+        let program = compile(SCRIPT, &mut interner, 0).unwrap();
+        let text = disassemble(&program, &interner);
+        let compiled = raised(Vm::new(program, &mut interner).unwrap().eval());
+        assert_eq!(raised(run(&text)), compiled);
+        assert_eq!(compiled, r#"[7, 3, 0..2, {"a": 1.5, "b": true}]"#);
+    }
+
+    #[test]
+    fn arithmetic() {
+        let consts = ".const 0 int 40\n.const 1 int 2\n";
+        let code = "    const 0\n    const 1\n    add\n    raise\n";
+        assert_eq!(raised(run(&program(consts, code, ""))), "42");
+    }
+
+    #[test]
+    fn jumps_to_labels() {
+        let consts = ".const 0 int 0\n.const 1 int 1\n.const 2 int 5\n";
+        let code = "    const 0
+    defslot i mut
+    assign i
+    const 0
+    defslot sum mut
+    assign sum
+top:
+    read i
+    const 2
+    lt
+    jump_if_false done
+    read i
+    const 1
+    add
+    assign i
+    read sum
+    read i
+    add
+    assign sum
+    jump top
+done:
+    read sum
+    raise
+";
+        assert_eq!(raised(run(&program(consts, code, ""))), "15");
+    }
+
+    #[test]
+    fn calls_pass_arguments_in_order() {
+        let consts = ".const 0 int 10\n.const 1 int 3\n";
+        let code = "    const 0\n    const 1\n    call minus 2\n    raise\n";
+        let funcs = ".func minus arity 2 module 0 path \"test.oni\"
+    defslot b immut
+    assign b
+    defslot a immut
+    assign a
+    read a
+    read b
+    sub
+    return
+.end
+";
+        assert_eq!(raised(run(&program(consts, code, funcs))), "7");
+    }
+
+    #[test]
+    fn handlers_catch_raised_values() {
+        let consts = ".const 0 str \"boom\"\n";
+        let code = "    push_handler caught
+    const 0
+    raise
+    pop_handler
+    nil
+    raise
+caught:
+    make_list 1
+    raise
+";
+        assert_eq!(raised(run(&program(consts, code, ""))), r#"["boom"]"#);
+    }
+
+    #[test]
+    fn errors_give_the_line() {
+        let src = program("", "    jump nowhere\n", "");
+        let e = run(&src).unwrap_err();
+        match e.kind() {
+            ErrorKind::Assembler { line, reason } => {
+                assert_eq!(*line, 7);
+                assert_eq!(reason, "undefined label nowhere");
+            }
+            kind => panic!("unexpected error {}", kind),
+        }
+        let e = run(&program("", "    frobnicate\n", "")).unwrap_err();
+        assert!(e
+            .kind()
+            .to_string()
+            .contains("unknown instruction frobnicate"));
+    }
+}
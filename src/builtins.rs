@@ -0,0 +1,226 @@
+use std::{cell::RefCell, collections::HashMap, mem, rc::Rc};
+
+use lasso::{Rodeo, Spur};
+
+use crate::{
+    error::Error,
+    eval::{slice_range, MapKey, Native, NativeFn, RuntimeFunc, Value, Vm},
+    stdlib,
+};
+
+pub fn len(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    if argc != 1 {
+        return Err(Error::arity(concat!(file!(), ":", line!()), 1, argc));
+    }
+    let len = match vm.pop()? {
+        Value::List(list) => list.borrow().len(),
+        Value::Map(map) => map.borrow().len(),
+        Value::String(s) => s.chars().count(),
+        Value::Str(s) => vm.interner().resolve(&s).chars().count(),
+        _ => return Err(Error::eval(concat!(file!(), ":", line!()))),
+    };
+    Ok(Value::Uint(len as u64))
+}
+
+/// `to_string(v)` writes a value as `print` does.
+pub fn to_string(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    if argc != 1 {
+        return Err(Error::arity(concat!(file!(), ":", line!()), 1, argc));
+    }
+    let value = vm.pop()?;
+    let s = vm.display(&value)?;
+    vm.allocate(s.len())?;
+    Ok(Value::String(s))
+}
+
+/// `inspect(v)` writes a value for debugging, with strings quoted and objects
+/// showing their fields.
+pub fn inspect(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    if argc != 1 {
+        return Err(Error::arity(concat!(file!(), ":", line!()), 1, argc));
+    }
+    let value = vm.pop()?;
+    let s = vm.inspect(&value);
+    vm.allocate(s.len())?;
+    Ok(Value::String(s))
+}
+
+pub fn list_methods(interner: &mut Rodeo) -> HashMap<Spur, RuntimeFunc> {
+    let methods: [(&'static str, NativeFn); 5] = [
+        ("len", len),
+        ("push", list_push),
+        ("pop", list_pop),
+        ("slice", list_slice),
+        ("join", stdlib::string::join),
+    ];
+    native_methods(interner, methods.map(|(name, func)| (name, func, None)))
+}
+
+/// The functions of the `string` module, as methods.
+pub fn string_methods(interner: &mut Rodeo) -> HashMap<Spur, RuntimeFunc> {
+    native_methods(interner, stdlib::string::NATIVES.iter().copied())
+}
+
+pub fn map_methods(interner: &mut Rodeo) -> HashMap<Spur, RuntimeFunc> {
+    let methods: [(&'static str, NativeFn); 5] = [
+        ("len", len),
+        ("has", map_has),
+        ("remove", map_remove),
+        ("keys", map_keys),
+        ("values", map_values),
+    ];
+    native_methods(interner, methods.map(|(name, func)| (name, func, None)))
+}
+
+fn native_methods(
+    interner: &mut Rodeo,
+    methods: impl IntoIterator<Item = Native>,
+) -> HashMap<Spur, RuntimeFunc> {
+    methods
+        .into_iter()
+        .map(|(name, func, needs)| {
+            (
+                interner.get_or_intern_static(name),
+                RuntimeFunc::Native(func, needs),
+            )
+        })
+        .collect()
+}
+
+fn list_push(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    if argc < 2 {
+        return Err(Error::arity(concat!(file!(), ":", line!()), 2, argc));
+    }
+    vm.allocate((argc - 1) * mem::size_of::<Value>())?;
+    let mut args = vm.pop_args(argc)?.into_iter();
+    let list = args.next().unwrap().into_list()?;
+    list.borrow_mut().extend(args);
+    Ok(Value::Nil)
+}
+
+fn list_pop(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    if argc != 1 {
+        return Err(Error::arity(concat!(file!(), ":", line!()), 1, argc));
+    }
+    let list = vm.pop()?.into_list()?;
+    let popped = list.borrow_mut().pop();
+    popped.ok_or_else(|| {
+        Error::invalid_argument(
+            concat!(file!(), ":", line!()),
+            "pop from an empty list".to_owned(),
+        )
+    })
+}
+
+/// `list.slice(from, to)` copies the elements in `from..to` into a new list.
+fn list_slice(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    if argc != 3 {
+        return Err(Error::arity(concat!(file!(), ":", line!()), 3, argc));
+    }
+    let to = vm.pop()?;
+    let from = vm.pop()?;
+    let list = vm.pop()?.into_list()?;
+    let list = list.borrow();
+    let range = slice_range(&from, &to, list.len())?;
+    vm.allocate(range.len() * mem::size_of::<Value>())?;
+    Ok(Value::List(Rc::new(RefCell::new(list[range].to_vec()))))
+}
+
+fn map_has(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    if argc != 2 {
+        return Err(Error::arity(concat!(file!(), ":", line!()), 2, argc));
+    }
+    let key = vm.pop()?;
+    let map = vm.pop()?.into_map()?;
+    let key = MapKey::new(&key, vm.interner())?;
+    let has = map.borrow().contains_key(&key);
+    Ok(Value::Bool(has))
+}
+
+/// `map.remove(key)` returns the removed value, keeping the order of the other entries.
+fn map_remove(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    if argc != 2 {
+        return Err(Error::arity(concat!(file!(), ":", line!()), 2, argc));
+    }
+    let key = vm.pop()?;
+    let map = vm.pop()?.into_map()?;
+    let key = MapKey::new(&key, vm.interner())?;
+    let removed = map.borrow_mut().shift_remove(&key);
+    removed.ok_or_else(|| Error::key_not_found(concat!(file!(), ":", line!()), key.to_string()))
+}
+
+/// `map.keys()` lists the keys in insertion order.
+fn map_keys(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    if argc != 1 {
+        return Err(Error::arity(concat!(file!(), ":", line!()), 1, argc));
+    }
+    let map = vm.pop()?.into_map()?;
+    vm.allocate(map.borrow().len() * mem::size_of::<Value>())?;
+    let keys = map.borrow().keys().map(MapKey::to_value).collect();
+    Ok(Value::List(Rc::new(RefCell::new(keys))))
+}
+
+fn map_values(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    if argc != 1 {
+        return Err(Error::arity(concat!(file!(), ":", line!()), 1, argc));
+    }
+    let map = vm.pop()?.into_map()?;
+    vm.allocate(map.borrow().len() * mem::size_of::<Value>())?;
+    let values = map.borrow().values().cloned().collect();
+    Ok(Value::List(Rc::new(RefCell::new(values))))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::ErrorKind,
+        testing::{eval, value},
+    };
+
+    #[test]
+    fn checks_argument_counts() {
+        let e = eval("len()").unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            ErrorKind::Arity {
+                expected: 1,
+                got: 0
+            }
+        ));
+        // Methods count their receiver
+        let e = eval("[1].pop(2)").unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            ErrorKind::Arity {
+                expected: 1,
+                got: 2
+            }
+        ));
+        let e = eval("{\"a\": 1}.has()").unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            ErrorKind::Arity {
+                expected: 2,
+                got: 1
+            }
+        ));
+        let e = eval("[].push()").unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            ErrorKind::Arity {
+                expected: 2,
+                got: 1
+            }
+        ));
+    }
+
+    #[test]
+    fn pops_from_lists() {
+        assert_eq!(value("[1, 2].pop()"), "2");
+        let e = eval("[].pop()").unwrap_err();
+        assert_eq!(
+            e.kind().to_string(),
+            "Invalid argument: pop from an empty list"
+        );
+    }
+}
@@ -0,0 +1,684 @@
+//! The `.onic` file format for compiled programs.
+//!
+//! A file starts with [`MAGIC`] and the format [`VERSION`], followed by a table
+//! of every string the program uses, so that names don't depend on the
+//! interner of the process that compiled them. Everything after the table
+//! refers to strings by their index in it. Integers are little endian, and
+//! lengths and indices are stored as `u64`. Loading checks that the file is
+//! well formed; its code is checked by [`crate::verify`] when it's loaded
+//! into a VM.
+
+use std::{collections::HashMap, path::Path, rc::Rc};
+
+use indexmap::IndexSet;
+use lasso::{Rodeo, Spur};
+
+use crate::{
+    compiler::{find_main, FuncProto, ImportBinding, ModuleProto, Program, TypeProto},
+    error::Error,
+    eval::{ConstValue, Flags, Opcode, RuntimeFunc},
+};
+
+pub const MAGIC: &[u8; 4] = b"ONIC";
+/// Bumped whenever the layout of the format changes.
+pub const VERSION: u16 = 1;
+
+/// Serializes a compiled program.
+pub fn write(program: &Program, interner: &Rodeo) -> Result<Vec<u8>, Error> {
+    let mut writer = Writer {
+        interner,
+        strings: IndexSet::new(),
+        out: Vec::new(),
+    };
+    writer.len(program.modules.len());
+    for module in &program.modules {
+        writer.module(module);
+    }
+    let mut types = program.types.values().collect::<Vec<_>>();
+    types.sort_by_key(|ty| interner.resolve(&ty.name));
+    writer.len(types.len());
+    for ty in types {
+        writer.type_(ty)?;
+    }
+
+    let mut bytes = Vec::with_capacity(writer.out.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(writer.strings.len() as u64).to_le_bytes());
+    for s in &writer.strings {
+        bytes.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(s.as_bytes());
+    }
+    bytes.extend_from_slice(&writer.out);
+    Ok(bytes)
+}
+
+/// Deserializes a compiled program, interning its strings into `interner`.
+pub fn read(bytes: &[u8], interner: &mut Rodeo) -> Result<Program, Error> {
+    let mut reader = Reader {
+        bytes,
+        pos: 0,
+        strings: Vec::new(),
+        spurs: Vec::new(),
+        paths: HashMap::new(),
+        interner,
+    };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(reader.malformed("not an onilang bytecode file"));
+    }
+    let version = u16::from_le_bytes(reader.array()?);
+    if version != VERSION {
+        return Err(Error::bytecode_version(
+            concat!(file!(), ":", line!()),
+            version,
+            VERSION,
+        ));
+    }
+    let count = reader.len()?;
+    for _ in 0..count {
+        let len = reader.len()?;
+        let s = std::str::from_utf8(reader.take(len)?)
+            .map_err(|_| reader.malformed("string is not valid UTF-8"))?
+            .to_owned();
+        reader.strings.push(s);
+    }
+    reader.spurs = vec![None; reader.strings.len()];
+
+    let count = reader.len()?;
+    let mut modules = Vec::new();
+    for _ in 0..count {
+        modules.push(reader.module()?);
+    }
+    let count = reader.len()?;
+    let mut types = HashMap::new();
+    for _ in 0..count {
+        let ty = reader.type_()?;
+        if types.insert(ty.name, ty).is_some() {
+            return Err(reader.malformed("duplicate type"));
+        }
+    }
+    if reader.pos != bytes.len() {
+        return Err(reader.malformed("trailing bytes"));
+    }
+
+    let main = find_main(&modules, reader.interner)?;
+    Ok(Program {
+        main,
+        modules,
+        types,
+        warnings: Vec::new(),
+    })
+}
+
+/// Whether `bytes` look like a bytecode file rather than source code.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+struct Writer<'i> {
+    interner: &'i Rodeo,
+    strings: IndexSet<String>,
+    out: Vec<u8>,
+}
+
+impl<'i> Writer<'i> {
+    fn u8(&mut self, n: u8) {
+        self.out.push(n);
+    }
+
+    fn u64(&mut self, n: u64) {
+        self.out.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn len(&mut self, n: usize) {
+        self.u64(n as u64);
+    }
+
+    fn str(&mut self, s: &str) {
+        let (index, _) = self.strings.insert_full(s.to_owned());
+        self.len(index);
+    }
+
+    fn spur(&mut self, spur: Spur) {
+        self.str(self.interner.resolve(&spur));
+    }
+
+    /// The entries of a map of names, in a stable order.
+    fn sorted<'m, T>(&self, map: &'m HashMap<Spur, T>) -> Vec<(Spur, &'m T)> {
+        let mut entries = map.iter().map(|(&k, v)| (k, v)).collect::<Vec<_>>();
+        entries.sort_by_key(|&(k, _)| self.interner.resolve(&k));
+        entries
+    }
+
+    fn module(&mut self, module: &ModuleProto) {
+        self.len(module.index);
+        self.spur(module.name);
+        let funcs = self.sorted(&module.funcs);
+        self.len(funcs.len());
+        for (name, func) in funcs {
+            self.spur(name);
+            self.func(func);
+        }
+        self.len(module.globals.len());
+        for &global in &module.globals {
+            self.spur(global);
+        }
+        self.func(&module.init);
+    }
+
+    fn type_(&mut self, ty: &TypeProto) -> Result<(), Error> {
+        self.spur(ty.name);
+        self.len(ty.fields.len());
+        for &(field, flags) in ty.fields.iter() {
+            self.spur(field);
+            self.u8(flags.bits());
+        }
+        let methods = self.sorted(&ty.methods);
+        self.len(methods.len());
+        for (name, method) in methods {
+            match method {
+                RuntimeFunc::Virtual(func) => {
+                    self.spur(name);
+                    self.func(func);
+                }
+                // Only builtin types have native methods, and they aren't compiled
+                RuntimeFunc::Native(..) => {
+                    return Err(Error::compiler(concat!(file!(), ":", line!())))
+                }
+            }
+        }
+        match &ty.ctor {
+            Some(ctor) => {
+                self.u8(1);
+                self.func(ctor);
+            }
+            None => self.u8(0),
+        }
+        Ok(())
+    }
+
+    fn func(&mut self, func: &FuncProto) {
+        self.len(func.arity);
+        self.len(func.module);
+        self.str(&func.path.to_string_lossy());
+        self.len(func.consts.len());
+        for &value in func.consts.iter() {
+            self.const_(value);
+        }
+        self.len(func.code.len());
+        for op in func.code.iter() {
+            self.op(op);
+        }
+        self.len(func.spans.len());
+        for span in func.spans.iter() {
+            self.len(span.start);
+            self.len(span.end);
+        }
+    }
+
+    fn const_(&mut self, value: ConstValue) {
+        match value {
+            ConstValue::Int(i) => {
+                self.u8(0);
+                self.out.extend_from_slice(&i.to_le_bytes());
+            }
+            ConstValue::Uint(u) => {
+                self.u8(1);
+                self.u64(u);
+            }
+            ConstValue::Float(f) => {
+                self.u8(2);
+                self.u64(f.to_bits());
+            }
+            ConstValue::Bool(b) => {
+                self.u8(3);
+                self.u8(b as u8);
+            }
+            ConstValue::Str(s) => {
+                self.u8(4);
+                self.spur(s);
+            }
+        }
+    }
+
+    fn op(&mut self, op: &Opcode) {
+        self.u8(opcode_tag(op));
+        match *op {
+            Opcode::Defslot(name, flags)
+            | Opcode::DefGlobal(name, flags)
+            | Opcode::DefField(name, flags) => {
+                self.spur(name);
+                self.u8(flags.bits());
+            }
+            Opcode::Import(binding) => {
+                self.spur(binding.name);
+                self.len(binding.module);
+                match binding.item {
+                    Some(item) => {
+                        self.u8(1);
+                        self.spur(item);
+                    }
+                    None => self.u8(0),
+                }
+            }
+            Opcode::Dropslot(name)
+            | Opcode::Assign(name)
+            | Opcode::Read(name)
+            | Opcode::LoadField(name)
+            | Opcode::StoreField(name)
+            | Opcode::InitField(name) => self.spur(name),
+            Opcode::Call(name, argc) | Opcode::CallMethod(name, argc) | Opcode::New(name, argc) => {
+                self.spur(name);
+                self.len(argc);
+            }
+            Opcode::CallValue(n)
+            | Opcode::MakeList(n)
+            | Opcode::MakeMap(n)
+            | Opcode::TestList(n)
+            | Opcode::Jump(n)
+            | Opcode::JumpIfFalse(n)
+            | Opcode::IterNext(n)
+            | Opcode::PushHandler(n)
+            | Opcode::Const(n) => self.len(n),
+            Opcode::MakeRange(b) | Opcode::Bool(b) => self.u8(b as u8),
+            Opcode::Return
+            | Opcode::Seal
+            | Opcode::LoadIndex
+            | Opcode::StoreIndex
+            | Opcode::TestKey
+            | Opcode::LoadKey
+            | Opcode::NoMatch
+            | Opcode::Iter
+            | Opcode::Add
+            | Opcode::Sub
+            | Opcode::Eq
+            | Opcode::Ne
+            | Opcode::Lt
+            | Opcode::Le
+            | Opcode::Gt
+            | Opcode::Ge
+            | Opcode::PopHandler
+            | Opcode::Raise
+            | Opcode::EndEnsure
+            | Opcode::Nil
+            | Opcode::Dup
+            | Opcode::Pop => {}
+        }
+    }
+}
+
+fn opcode_tag(op: &Opcode) -> u8 {
+    match op {
+        Opcode::Defslot(..) => 0,
+        Opcode::DefGlobal(..) => 1,
+        Opcode::Import(_) => 2,
+        Opcode::Dropslot(_) => 3,
+        Opcode::Assign(_) => 4,
+        Opcode::Call(..) => 5,
+        Opcode::CallValue(_) => 6,
+        Opcode::CallMethod(..) => 7,
+        Opcode::Return => 8,
+        Opcode::Read(_) => 9,
+        Opcode::LoadField(_) => 10,
+        Opcode::StoreField(_) => 11,
+        Opcode::DefField(..) => 12,
+        Opcode::InitField(_) => 13,
+        Opcode::Seal => 14,
+        Opcode::MakeList(_) => 15,
+        Opcode::MakeMap(_) => 16,
+        Opcode::LoadIndex => 17,
+        Opcode::StoreIndex => 18,
+        Opcode::TestList(_) => 19,
+        Opcode::TestKey => 20,
+        Opcode::LoadKey => 21,
+        Opcode::NoMatch => 22,
+        Opcode::New(..) => 23,
+        Opcode::Jump(_) => 24,
+        Opcode::JumpIfFalse(_) => 25,
+        Opcode::Iter => 26,
+        Opcode::IterNext(_) => 27,
+        Opcode::MakeRange(_) => 28,
+        Opcode::Add => 29,
+        Opcode::Sub => 30,
+        Opcode::Eq => 31,
+        Opcode::Ne => 32,
+        Opcode::Lt => 33,
+        Opcode::Le => 34,
+        Opcode::Gt => 35,
+        Opcode::Ge => 36,
+        Opcode::PushHandler(_) => 37,
+        Opcode::PopHandler => 38,
+        Opcode::Raise => 39,
+        Opcode::EndEnsure => 40,
+        Opcode::Bool(_) => 41,
+        Opcode::Const(_) => 42,
+        Opcode::Nil => 43,
+        Opcode::Dup => 44,
+        Opcode::Pop => 45,
+    }
+}
+
+struct Reader<'b, 'i> {
+    bytes: &'b [u8],
+    pos: usize,
+    strings: Vec<String>,
+    /// Strings of the table interned so far
+    spurs: Vec<Option<Spur>>,
+    /// Paths of the table, shared by every function of a file
+    paths: HashMap<usize, Rc<Path>>,
+    interner: &'i mut Rodeo,
+}
+
+impl<'b, 'i> Reader<'b, 'i> {
+    fn malformed(&self, reason: &str) -> Error {
+        Error::invalid_bytecode(
+            concat!(file!(), ":", line!()),
+            format!("{} at byte {}", reason, self.pos),
+        )
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'b [u8], Error> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| self.malformed("unexpected end of file"))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, Error> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(self.malformed("invalid boolean")),
+        }
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn len(&mut self) -> Result<usize, Error> {
+        let n = self.u64()?;
+        usize::try_from(n).map_err(|_| self.malformed("length too large"))
+    }
+
+    fn string_index(&mut self) -> Result<usize, Error> {
+        let index = self.len()?;
+        if index >= self.strings.len() {
+            return Err(self.malformed("string index out of bounds"));
+        }
+        Ok(index)
+    }
+
+    fn spur(&mut self) -> Result<Spur, Error> {
+        let index = self.string_index()?;
+        let spur = match self.spurs[index] {
+            Some(spur) => spur,
+            None => self.interner.get_or_intern(&self.strings[index]),
+        };
+        self.spurs[index] = Some(spur);
+        Ok(spur)
+    }
+
+    fn path(&mut self) -> Result<Rc<Path>, Error> {
+        let index = self.string_index()?;
+        let strings = &self.strings;
+        Ok(Rc::clone(
+            self.paths
+                .entry(index)
+                .or_insert_with(|| Rc::from(Path::new(&strings[index]))),
+        ))
+    }
+
+    fn flags(&mut self) -> Result<Flags, Error> {
+        let bits = self.u8()?;
+        Flags::from_bits(bits).ok_or_else(|| self.malformed("invalid flags"))
+    }
+
+    fn module(&mut self) -> Result<ModuleProto, Error> {
+        let index = self.len()?;
+        let name = self.spur()?;
+        let count = self.len()?;
+        let mut funcs = HashMap::new();
+        for _ in 0..count {
+            let name = self.spur()?;
+            let func = self.func()?;
+            if funcs.insert(name, func).is_some() {
+                return Err(self.malformed("duplicate function"));
+            }
+        }
+        let count = self.len()?;
+        let mut globals = Vec::new();
+        for _ in 0..count {
+            globals.push(self.spur()?);
+        }
+        let init = self.func()?;
+        Ok(ModuleProto {
+            index,
+            name,
+            funcs,
+            globals,
+            init,
+        })
+    }
+
+    fn type_(&mut self) -> Result<TypeProto, Error> {
+        let name = self.spur()?;
+        let count = self.len()?;
+        let mut fields = Vec::new();
+        for _ in 0..count {
+            fields.push((self.spur()?, self.flags()?));
+        }
+        let count = self.len()?;
+        let mut methods = HashMap::new();
+        for _ in 0..count {
+            let name = self.spur()?;
+            let func = self.func()?;
+            if methods.insert(name, RuntimeFunc::Virtual(func)).is_some() {
+                return Err(self.malformed("duplicate method"));
+            }
+        }
+        let ctor = if self.bool()? {
+            Some(self.func()?)
+        } else {
+            None
+        };
+        Ok(TypeProto {
+            name,
+            fields: fields.into(),
+            methods,
+            ctor,
+        })
+    }
+
+    fn func(&mut self) -> Result<FuncProto, Error> {
+        let arity = self.len()?;
+        let module = self.len()?;
+        let path = self.path()?;
+        let count = self.len()?;
+        let mut consts = Vec::new();
+        for _ in 0..count {
+            consts.push(self.const_()?);
+        }
+        let count = self.len()?;
+        let mut code = Vec::new();
+        for _ in 0..count {
+            code.push(self.op()?);
+        }
+        let count = self.len()?;
+        let mut spans = Vec::new();
+        for _ in 0..count {
+            spans.push(self.len()?..self.len()?);
+        }
+        Ok(FuncProto {
+            arity,
+            module,
+            code: code.into(),
+            consts: consts.into(),
+            path,
+            spans: spans.into(),
+        })
+    }
+
+    fn const_(&mut self) -> Result<ConstValue, Error> {
+        Ok(match self.u8()? {
+            0 => ConstValue::Int(i64::from_le_bytes(self.array()?)),
+            1 => ConstValue::Uint(self.u64()?),
+            2 => ConstValue::Float(f64::from_bits(self.u64()?)),
+            3 => ConstValue::Bool(self.bool()?),
+            4 => ConstValue::Str(self.spur()?),
+            _ => return Err(self.malformed("invalid constant")),
+        })
+    }
+
+    fn op(&mut self) -> Result<Opcode, Error> {
+        Ok(match self.u8()? {
+            0 => Opcode::Defslot(self.spur()?, self.flags()?),
+            1 => Opcode::DefGlobal(self.spur()?, self.flags()?),
+            2 => Opcode::Import(ImportBinding {
+                name: self.spur()?,
+                module: self.len()?,
+                item: if self.bool()? {
+                    Some(self.spur()?)
+                } else {
+                    None
+                },
+            }),
+            3 => Opcode::Dropslot(self.spur()?),
+            4 => Opcode::Assign(self.spur()?),
+            5 => Opcode::Call(self.spur()?, self.len()?),
+            6 => Opcode::CallValue(self.len()?),
+            7 => Opcode::CallMethod(self.spur()?, self.len()?),
+            8 => Opcode::Return,
+            9 => Opcode::Read(self.spur()?),
+            10 => Opcode::LoadField(self.spur()?),
+            11 => Opcode::StoreField(self.spur()?),
+            12 => Opcode::DefField(self.spur()?, self.flags()?),
+            13 => Opcode::InitField(self.spur()?),
+            14 => Opcode::Seal,
+            15 => Opcode::MakeList(self.len()?),
+            16 => Opcode::MakeMap(self.len()?),
+            17 => Opcode::LoadIndex,
+            18 => Opcode::StoreIndex,
+            19 => Opcode::TestList(self.len()?),
+            20 => Opcode::TestKey,
+            21 => Opcode::LoadKey,
+            22 => Opcode::NoMatch,
+            23 => Opcode::New(self.spur()?, self.len()?),
+            24 => Opcode::Jump(self.len()?),
+            25 => Opcode::JumpIfFalse(self.len()?),
+            26 => Opcode::Iter,
+            27 => Opcode::IterNext(self.len()?),
+            28 => Opcode::MakeRange(self.bool()?),
+            29 => Opcode::Add,
+            30 => Opcode::Sub,
+            31 => Opcode::Eq,
+            32 => Opcode::Ne,
+            33 => Opcode::Lt,
+            34 => Opcode::Le,
+            35 => Opcode::Gt,
+            36 => Opcode::Ge,
+            37 => Opcode::PushHandler(self.len()?),
+            38 => Opcode::PopHandler,
+            39 => Opcode::Raise,
+            40 => Opcode::EndEnsure,
+            41 => Opcode::Bool(self.bool()?),
+            42 => Opcode::Const(self.len()?),
+            43 => Opcode::Nil,
+            44 => Opcode::Dup,
+            45 => Opcode::Pop,
+            _ => return Err(self.malformed("invalid opcode")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lasso::Rodeo;
+
+    use super::*;
+    use crate::{
+        asm::disassemble,
+        error::ErrorKind,
+        eval::Vm,
+        testing::{compile, raised},
+    };
+
+    const SCRIPT: &str = r#"
+type Pair do
+    left := 0
+    right $= "right"
+    defn swap() do
+        [self.right, self.left]
+    end
+end
+
+limit := 2.5
+
+defn main() do
+    p := new Pair
+    raise [p.swap(), limit, -3, "a\tb", true]
+end
+"#;
+
+    fn compiled() -> (Vec<u8>, String) {
+        let mut interner = Rodeo::new();
+        let program = compile(SCRIPT, &mut interner, 0).unwrap();
+        let text = disassemble(&program, &interner);
+        (write(&program, &interner).unwrap(), text)
+    }
+
+    #[test]
+    fn round_trips_into_another_interner() {
+        let (bytes, text) = compiled();
+        assert!(is_bytecode(&bytes));
+        // Interned differently, so that names can't line up by accident
+        let mut interner = Rodeo::new();
+        interner.get_or_intern("unrelated");
+        let program = read(&bytes, &mut interner).unwrap();
+        assert_eq!(disassemble(&program, &interner), text);
+        assert_eq!(write(&program, &interner).unwrap(), bytes);
+        let mut vm = Vm::new(program, &mut interner).unwrap();
+        assert_eq!(
+            raised(vm.eval()),
+            r#"[["right", 0], 2.5, -3, "a\tb", true]"#
+        );
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let (mut bytes, _) = compiled();
+        bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let e = read(&bytes, &mut Rodeo::new()).unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            ErrorKind::BytecodeVersion { found, expected } if *found == VERSION + 1 && *expected == VERSION
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let (bytes, _) = compiled();
+        for bytes in [
+            &bytes[..bytes.len() - 1],
+            &[bytes.as_slice(), &[0]].concat(),
+        ] {
+            let e = read(bytes, &mut Rodeo::new()).unwrap_err();
+            assert!(matches!(e.kind(), ErrorKind::InvalidBytecode { .. }));
+        }
+        assert!(!is_bytecode(b"defn main() do nil end"));
+        let e = read(b"defn main() do nil end", &mut Rodeo::new()).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::InvalidBytecode { .. }));
+    }
+}
@@ -1,65 +1,646 @@
-use std::{collections::HashMap, rc::Rc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
+use chumsky::Span as _;
 use lasso::{Rodeo, Spur};
+use logos::Logos;
 
+use self::optimize::Optimizer;
 use crate::{
-    error::Error,
-    eval::{ConstValue, Flags, Opcode, Vm},
-    lexer::Token,
-    parser::{Assignment, Ast, Defn},
-    Spanned, SpannedAst,
+    error::{Error, SourceSpan},
+    eval::{ConstValue, Flags, Opcode, RuntimeFunc},
+    lexer::{self, Token},
+    parser::{
+        self, parse, Assignment, Ast, BinOp, Defn, For, Import, Loop, Match, MatchArm, Pattern,
+        Try, Type, While,
+    },
+    Span, Spanned, SpannedAst, SpannedAsts,
 };
 
+mod optimize;
+
+pub use self::optimize::OptLevel;
+
 #[derive(Clone, Debug)]
 pub struct FuncProto {
+    pub arity: usize,
+    /// Index of the module whose globals the function sees
+    pub module: usize,
     pub code: Rc<[Opcode]>,
+    /// Constants used by `code`, so that functions can be compiled and linked
+    /// independently of each other
+    pub consts: Rc<[ConstValue]>,
+    pub path: Rc<Path>,
+    /// Source range of every instruction in `code`
+    pub spans: Rc<[Range<usize>]>,
+}
+
+impl FuncProto {
+    pub fn span(&self, ip: usize) -> Option<SourceSpan> {
+        self.spans.get(ip).map(|span| SourceSpan {
+            path: self.path.to_path_buf(),
+            start: span.start,
+            end: span.end,
+        })
+    }
 }
 
 struct IncompleteFuncProto {
+    pub arity: usize,
+    module: usize,
     pub code: Vec<Opcode>,
+    pub spans: Vec<Range<usize>>,
+    consts: Vec<ConstValue>,
+    path: Rc<Path>,
+    /// Span of the expression currently being compiled
+    span: Range<usize>,
+    /// Block scopes nested inside the function body
+    scopes: Vec<Scope>,
+    /// Loops enclosing the code currently being compiled
+    loops: Vec<LoopLabels>,
+    /// `try` blocks enclosing the code currently being compiled, and whether
+    /// their `ensure` block is pending
+    handlers: Vec<bool>,
+    /// Whether this is a module initializer, whose top-level bindings are globals
+    module_init: bool,
+    /// Slots declared outside of any block
+    locals: Vec<Spur>,
+    /// Names used by the function that aren't its own slots
+    refs: Vec<Spur>,
 }
 
 impl IncompleteFuncProto {
+    fn new(arity: usize, module: usize, path: Rc<Path>, span: Range<usize>) -> Self {
+        Self {
+            arity,
+            module,
+            code: Default::default(),
+            spans: Default::default(),
+            consts: Default::default(),
+            path,
+            span,
+            scopes: Default::default(),
+            loops: Default::default(),
+            handlers: Default::default(),
+            module_init: false,
+            locals: Default::default(),
+            refs: Default::default(),
+        }
+    }
+
+    fn current_span(&self) -> SourceSpan {
+        SourceSpan {
+            path: self.path.to_path_buf(),
+            start: self.span.start,
+            end: self.span.end,
+        }
+    }
+
+    fn is_local(&self, name: Spur) -> bool {
+        self.locals.contains(&name) || self.scopes.iter().any(|s| s.slots.contains(&name))
+    }
+
+    fn push(&mut self, op: Opcode) {
+        self.code.push(op);
+        self.spans.push(self.span.clone());
+    }
+
+    /// Adds a constant to the pool and pushes it, returning its index.
+    fn push_const(&mut self, value: ConstValue) -> usize {
+        self.consts.push(value);
+        self.push(Opcode::Const(self.consts.len() - 1));
+        self.consts.len() - 1
+    }
+
+    /// Pushes a jump with a placeholder target, returning its address for [`Self::patch`].
+    fn jump(&mut self, op: fn(usize) -> Opcode) -> usize {
+        self.push(op(usize::MAX));
+        self.code.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.code.len();
+        self.code[at] = match self.code[at] {
+            Opcode::Jump(_) => Opcode::Jump(target),
+            Opcode::JumpIfFalse(_) => Opcode::JumpIfFalse(target),
+            Opcode::IterNext(_) => Opcode::IterNext(target),
+            Opcode::PushHandler(_) => Opcode::PushHandler(target),
+            _ => unreachable!("patching a non-jump instruction"),
+        };
+    }
+
+    fn declare(&mut self, name: Spur, flags: Flags) {
+        if self.scopes.is_empty() && self.module_init {
+            self.push(Opcode::DefGlobal(name, flags));
+            return;
+        }
+        self.push(Opcode::Defslot(name, flags));
+        match self.scopes.last_mut() {
+            Some(scope) => scope.slots.push(name),
+            None => self.locals.push(name),
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(Scope::default());
+    }
+
+    /// Closes the innermost scope, dropping the slots declared in it.
+    fn end_scope(&mut self) {
+        self.drop_scopes(self.scopes.len() - 1);
+        self.scopes.pop();
+    }
+
+    /// Drops the slots of every scope from `depth` inwards, without closing them.
+    fn drop_scopes(&mut self, depth: usize) {
+        let slots = self.scopes[depth..]
+            .iter()
+            .flat_map(|scope| scope.slots.iter().rev())
+            .copied()
+            .collect::<Vec<_>>();
+        for slot in slots {
+            self.push(Opcode::Dropslot(slot));
+        }
+    }
+
+    /// Drops every slot declared since `from`, for code that can be jumped to
+    /// from anywhere in between.
+    fn drop_declared_since(&mut self, from: usize) {
+        let slots = self.code[from..]
+            .iter()
+            .filter_map(|op| match op {
+                Opcode::Defslot(slot, _) => Some(*slot),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for slot in slots.into_iter().rev() {
+            self.push(Opcode::Dropslot(slot));
+        }
+    }
+
+    /// Leaves the `try` blocks entered since `depth`, for a `break` or
+    /// `continue` out of them.
+    fn leave_handlers(&mut self, depth: usize, keyword: &'static str) -> Result<(), Error> {
+        if self.handlers[depth..].iter().any(|&ensure| ensure) {
+            return Err(
+                Error::ensure_skipped(concat!(file!(), ":", line!()), keyword)
+                    .with_span(self.current_span()),
+            );
+        }
+        for _ in depth..self.handlers.len() {
+            self.push(Opcode::PopHandler);
+        }
+        Ok(())
+    }
+
     fn finalize(self) -> FuncProto {
         let code = self.code.into();
-        FuncProto { code }
+        FuncProto {
+            arity: self.arity,
+            module: self.module,
+            code,
+            consts: self.consts.into(),
+            path: self.path,
+            spans: self.spans.into(),
+        }
     }
 }
 
-pub struct Scope {}
+/// A user defined type. Builtin types have no constructor prototype and are
+/// constructed natively by the VM.
+#[derive(Clone, Debug)]
+pub struct TypeProto {
+    pub name: Spur,
+    pub fields: Rc<[(Spur, Flags)]>,
+    pub methods: HashMap<Spur, RuntimeFunc>,
+    pub ctor: Option<FuncProto>,
+}
 
-pub struct Compiler<'i> {
+/// A compiled source file.
+#[derive(Debug)]
+pub struct ModuleProto {
+    /// Index of the module's globals in the VM
+    pub index: usize,
+    pub name: Spur,
+    pub funcs: HashMap<Spur, FuncProto>,
+    /// Names bound by top-level declarations
+    pub globals: Vec<Spur>,
+    /// Imports and top-level statements, run before `main`
+    pub init: FuncProto,
+}
+
+/// A compiled program and every module it imports.
+#[derive(Debug)]
+pub struct Program {
+    pub main: FuncProto,
+    /// Modules in dependency order, each after the modules it imports
+    pub modules: Vec<ModuleProto>,
+    pub types: HashMap<Spur, TypeProto>,
+    /// Problems found while compiling, none for programs loaded from bytecode
+    pub warnings: Vec<Warning>,
+}
+
+/// Something the compiler found suspicious, which doesn't stop the program
+/// from compiling.
+#[derive(Clone, Debug)]
+pub struct Warning {
+    pub span: SourceSpan,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.span)
+    }
+}
+
+/// Compilation state of the module currently being compiled.
+struct ModuleState {
+    index: usize,
+    path: Rc<Path>,
     funcs: HashMap<Spur, FuncProto>,
+    imports: Vec<ImportBinding>,
+    /// Top-level declarations, in order
+    globals: Vec<Spur>,
+    /// Every name bound at the top level, by imports, functions or globals
+    names: HashSet<Spur>,
+    init: Option<IncompleteFuncProto>,
+    /// Names used by each function of the module
+    func_refs: HashMap<Spur, Vec<Spur>>,
+    /// Names used by the initializer, with the number of globals declared at that
+    /// point and where they are used
+    init_refs: Vec<(Spur, usize, SourceSpan)>,
+}
+
+impl ModuleState {
+    fn new(index: usize, path: Rc<Path>, span: Range<usize>) -> Self {
+        let mut init = IncompleteFuncProto::new(0, index, Rc::clone(&path), span);
+        init.module_init = true;
+        Self {
+            index,
+            path,
+            funcs: Default::default(),
+            imports: Default::default(),
+            globals: Default::default(),
+            names: Default::default(),
+            init: Some(init),
+            func_refs: Default::default(),
+            init_refs: Default::default(),
+        }
+    }
+}
+
+/// A global bound by an `import`: either the module itself, or one of its items.
+#[derive(Clone, Copy, Debug)]
+pub struct ImportBinding {
+    pub name: Spur,
+    pub module: usize,
+    pub item: Option<Spur>,
+}
+
+/// Slots declared in a block, which go out of scope when it ends.
+#[derive(Default)]
+pub struct Scope {
+    slots: Vec<Spur>,
+}
+
+struct LoopLabels {
+    /// Where `continue` jumps to
+    start: usize,
+    /// Number of scopes open outside of the loop body
+    scope_depth: usize,
+    /// Number of `try` blocks entered outside of the loop body
+    handler_depth: usize,
+    /// `break` jumps waiting to be patched with the loop exit
+    breaks: Vec<usize>,
+}
+
+pub struct Compiler<'i> {
+    /// Modules in the order they finished compiling, so every module comes
+    /// after the ones it imports
+    modules: Vec<ModuleProto>,
+    module_count: usize,
+    /// Canonical paths of compiled modules
+    loaded: HashMap<PathBuf, usize>,
+    /// Canonical paths of the modules currently being compiled, outermost first
+    loading: Vec<PathBuf>,
+    search_path: Vec<PathBuf>,
+    state: ModuleState,
+    types: HashMap<Spur, TypeProto>,
+    /// Globals declared with `:=` in each module, which can't be imported by name
+    mutable_globals: HashSet<(usize, Spur)>,
+    warnings: Vec<Warning>,
     interner: &'i mut Rodeo,
-    consts: Vec<ConstValue>,
+    opt_level: OptLevel,
 }
 
 impl<'i> Compiler<'i> {
+    /// Compiles the program rooted at `ast`. Imports are resolved relative to
+    /// the importing file first, then to each directory of `search_path`.
     pub fn compile<'s, 'p>(
         ast: SpannedAst<'s, 'p>,
         interner: &'i mut Rodeo,
-    ) -> Result<Vm<'i>, Error> {
+        search_path: Vec<PathBuf>,
+        opt_level: OptLevel,
+    ) -> Result<Program, Error> {
+        Self::compile_root(ast, interner, search_path, opt_level)?.emit()
+    }
+
+    /// Compiles a module and the modules it imports like [`Compiler::compile`],
+    /// without requiring a `main` function, so that library modules can be
+    /// checked too. Returns the warnings found.
+    pub fn check<'s, 'p>(
+        ast: SpannedAst<'s, 'p>,
+        interner: &'i mut Rodeo,
+        search_path: Vec<PathBuf>,
+    ) -> Result<Vec<Warning>, Error> {
+        Self::compile_root(ast, interner, search_path, 0).map(|this| this.warnings)
+    }
+
+    fn compile_root<'s, 'p>(
+        ast: SpannedAst<'s, 'p>,
+        interner: &'i mut Rodeo,
+        search_path: Vec<PathBuf>,
+        opt_level: OptLevel,
+    ) -> Result<Self, Error> {
+        let root = canonical(ast.span.path);
         let mut this = Self {
-            funcs: Default::default(),
-            consts: Default::default(),
+            modules: Default::default(),
+            module_count: 0,
+            loaded: Default::default(),
+            loading: vec![root.clone()],
+            search_path,
+            state: ModuleState::new(usize::MAX, Rc::from(ast.span.path), 0..0),
+            types: Default::default(),
+            mutable_globals: Default::default(),
+            warnings: Default::default(),
+            opt_level,
             interner,
         };
+        let index = this.compile_module(ast)?;
+        this.loaded.insert(root, index);
+        Ok(this)
+    }
+
+    /// Compiles a file into a new module, returning its index.
+    fn compile_module(&mut self, ast: SpannedAst<'_, '_>) -> Result<usize, Error> {
+        let index = self.module_count;
+        self.module_count += 1;
+        let name = ast
+            .span
+            .path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| Error::compiler(concat!(file!(), ":", line!())))?;
+        let name = self.interner.get_or_intern(name);
         let items = match ast.inner {
             Ast::Module(items) => items,
             _ => return Err(Error::compiler(concat!(file!(), ":", line!()))),
         };
-        for item in items {
-            this.compile_item(item)?;
+
+        let state = ModuleState::new(index, Rc::from(ast.span.path), ast.span.start..ast.span.end);
+        let outer = std::mem::replace(&mut self.state, state);
+        let res = items
+            .into_iter()
+            .try_for_each(|item| self.compile_item(item))
+            .and_then(|()| self.check_init_refs());
+        let state = std::mem::replace(&mut self.state, outer);
+        res?;
+
+        let mut init = state.init.unwrap();
+        init.push(Opcode::Nil);
+        init.push(Opcode::Return);
+        let init = self.finalize(init);
+        self.modules.push(ModuleProto {
+            index,
+            name,
+            funcs: state.funcs,
+            globals: state.globals,
+            init,
+        });
+        Ok(index)
+    }
+
+    /// Makes sure the initializer doesn't use globals before they are declared,
+    /// either directly or through the functions it calls.
+    fn check_init_refs(&self) -> Result<(), Error> {
+        for (name, declared, span) in &self.state.init_refs {
+            let mut seen = HashSet::new();
+            let mut pending = vec![*name];
+            while let Some(name) = pending.pop() {
+                if !seen.insert(name) {
+                    continue;
+                }
+                if let Some(at) = self.state.globals.iter().position(|&g| g == name) {
+                    if at >= *declared {
+                        return Err(Error::use_before_definition(
+                            concat!(file!(), ":", line!()),
+                            self.interner.resolve(&name).to_owned(),
+                        )
+                        .with_span(span.clone()));
+                    }
+                }
+                if let Some(refs) = self.state.func_refs.get(&name) {
+                    pending.extend(refs.iter().rev());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a use of a name that isn't a slot of `func`, for [`Self::check_init_refs`].
+    fn reference(&mut self, func: &mut IncompleteFuncProto, name: Spur) {
+        if func.is_local(name) {
+            return;
+        }
+        if func.module_init {
+            self.state
+                .init_refs
+                .push((name, self.state.globals.len(), func.current_span()));
+        } else {
+            func.refs.push(name);
+        }
+    }
+
+    /// Declares a slot of `func`. Locals of a function share one namespace while
+    /// it runs, so a declaration can't hide a local that's still in scope.
+    fn declare(
+        &mut self,
+        func: &mut IncompleteFuncProto,
+        name: Spur,
+        flags: Flags,
+    ) -> Result<(), Error> {
+        if func.is_local(name) {
+            return Err(self.already_defined(name));
+        }
+        func.declare(name, flags);
+        Ok(())
+    }
+
+    /// Binds a top-level name of the module, which can only be bound once.
+    fn bind_global(&mut self, name: Spur) -> Result<(), Error> {
+        match self.state.names.insert(name) {
+            true => Ok(()),
+            false => Err(self.already_defined(name)),
+        }
+    }
+
+    fn already_defined(&self, name: Spur) -> Error {
+        Error::already_defined(
+            concat!(file!(), ":", line!()),
+            self.interner.resolve(&name).to_owned(),
+        )
+    }
+
+    /// Compiles a top-level statement into the module initializer.
+    fn compile_statement(&mut self, stmt: SpannedAst<'_, '_>) -> Result<(), Error> {
+        let mut init = self.state.init.take().unwrap();
+        let produces_value = !matches!(
+            stmt.inner,
+            Ast::Assignment(_) | Ast::Break(_) | Ast::Continue(_)
+        );
+        let res = self.compile_expr(&mut init, stmt);
+        if produces_value {
+            init.push(Opcode::Pop);
         }
-        this.emit()
+        self.state.init = Some(init);
+        res
     }
 
     fn compile_item(&mut self, item: SpannedAst<'_, '_>) -> Result<(), Error> {
+        let span = item.span;
         match item.inner {
-            Ast::Module(_) => todo!(),
-            Ast::Defn(box defn) => self.compile_defn(defn),
-            _ => Err(Error::compiler(concat!(file!(), ":", line!()))),
+            Ast::Module(_) => Err(Error::compiler(concat!(file!(), ":", line!()))),
+            Ast::Import(box import) => self
+                .compile_import(import)
+                .map_err(|e| e.with_span(source_span(span))),
+            // Errors that aren't in an expression are reported at the name
+            Ast::Defn(box defn) => {
+                let name = source_span(defn.name.span);
+                self.compile_defn(defn).map_err(|e| e.with_span(name))
+            }
+            Ast::Type(box ty) => {
+                let name = source_span(ty.name.span);
+                self.compile_type(ty).map_err(|e| e.with_span(name))
+            }
+            inner => self.compile_statement(Spanned { span, inner }),
+        }
+    }
+
+    fn compile_import(&mut self, import: Import) -> Result<(), Error> {
+        let Import {
+            import: _,
+            path,
+            items,
+        } = import;
+        let mut segments = Vec::with_capacity(path.len());
+        for segment in path {
+            match segment.inner {
+                Ast::Identifier(segment) => segments.push(segment),
+                _ => return Err(Error::compiler(concat!(file!(), ":", line!()))),
+            }
+        }
+        let module = self.load_module(&segments)?;
+        let bindings = match items {
+            None => vec![ImportBinding {
+                name: self.interner.get_or_intern(segments.last().unwrap()),
+                module,
+                item: None,
+            }],
+            Some(items) => {
+                let mut bindings = Vec::with_capacity(items.len());
+                for item in items {
+                    let span = source_span(item.span);
+                    let item = self.ident_name(item)?;
+                    let exports = self.modules.iter().find(|m| m.index == module).unwrap();
+                    if !exports.funcs.contains_key(&item) && !exports.globals.contains(&item) {
+                        let name =
+                            format!("{}/{}", segments.join("/"), self.interner.resolve(&item));
+                        return Err(
+                            Error::undefined(concat!(file!(), ":", line!()), name).with_span(span)
+                        );
+                    }
+                    // The binding is a copy made when the importer starts, which
+                    // wouldn't see later assignments
+                    if self.mutable_globals.contains(&(module, item)) {
+                        let name = self.interner.resolve(&item).to_owned();
+                        return Err(Error::mutable_import(concat!(file!(), ":", line!()), name)
+                            .with_span(span));
+                    }
+                    bindings.push(ImportBinding {
+                        name: item,
+                        module,
+                        item: Some(item),
+                    });
+                }
+                bindings
+            }
+        };
+        // Bindings are made when the initializer runs, after the imported
+        // module's own initializer
+        for binding in bindings {
+            self.bind_global(binding.name)?;
+            let init = self.state.init.as_mut().unwrap();
+            init.push(Opcode::Import(binding));
+            self.state.imports.push(binding);
+        }
+        Ok(())
+    }
+
+    /// Finds, parses and compiles the module at `segments`, unless it was already compiled.
+    fn load_module(&mut self, segments: &[&str]) -> Result<usize, Error> {
+        let relative = segments.iter().collect::<PathBuf>().with_extension("oni");
+        let path = self
+            .state
+            .path
+            .parent()
+            .into_iter()
+            .chain(self.search_path.iter().map(PathBuf::as_path))
+            .map(|dir| dir.join(&relative))
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                Error::module_not_found(concat!(file!(), ":", line!()), segments.join("/"))
+            })?;
+        let path = canonical(&path);
+
+        if let Some(&index) = self.loaded.get(&path) {
+            return Ok(index);
         }
+        if let Some(at) = self.loading.iter().position(|p| *p == path) {
+            let cycle = self.loading[at..]
+                .iter()
+                .chain([&path])
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(Error::circular_import(
+                concat!(file!(), ":", line!()),
+                cycle,
+            ));
+        }
+
+        let src = fs::read_to_string(&path).map_err(|_| {
+            Error::module_not_found(concat!(file!(), ":", line!()), segments.join("/"))
+        })?;
+        let tokens = Token::lexer(&src)
+            .spanned()
+            .map(|(t, s)| (t, Span::new(&path, s)))
+            .collect::<Vec<_>>();
+        let ast = parse(tokens)
+            .map_err(|errors| parser::error(concat!(file!(), ":", line!()), errors))?;
+
+        self.loading.push(path.clone());
+        let res = self.compile_module(ast);
+        self.loading.pop();
+        let index = res?;
+        self.loaded.insert(path, index);
+        Ok(index)
     }
 
     fn compile_defn(&mut self, defn: Defn) -> Result<(), Error> {
@@ -71,124 +652,710 @@ impl<'i> Compiler<'i> {
             body,
             end: _,
         } = defn;
-        let name = if let Ast::Identifier(ident) = name.inner {
-            self.interner.get_or_intern(ident)
-        } else {
-            return Err(Error::compiler(concat!(file!(), ":", line!())));
-        };
+        let name = self.ident_name(name)?;
+        self.bind_global(name)?;
 
-        let mut func = IncompleteFuncProto {
-            code: Default::default(),
+        let mut func = self.compile_prologue(args)?;
+        self.compile_body(&mut func, body)?;
+        func.push(Opcode::Return);
+
+        self.state
+            .func_refs
+            .insert(name, std::mem::take(&mut func.refs));
+        let func = self.finalize(func);
+        self.state.funcs.insert(name, func);
+        Ok(())
+    }
+
+    fn compile_type(&mut self, ty: Type) -> Result<(), Error> {
+        let Type {
+            type_: _,
+            name,
+            _do: _,
+            body,
+            end: _,
+        } = ty;
+        let span = name.span;
+        let name = self.ident_name(name)?;
+        // Types are global to the program, so one module can't reuse the name
+        // of a type from another
+        if self.types.contains_key(&name)
+            || matches!(
+                self.interner.resolve(&name),
+                "Object" | "String" | "List" | "Map" | "Error"
+            )
+        {
+            return Err(self.already_defined(name));
+        }
+        let self_ = self.interner.get_or_intern_static("self");
+        let init_name = self.interner.get_or_intern_static("init");
+
+        let mut fields = Vec::new();
+        let mut defaults = Vec::new();
+        let mut methods = HashMap::new();
+        let mut init = None;
+        for item in body {
+            match item.inner {
+                Ast::Defn(box defn) => {
+                    let method_span = source_span(defn.name.span);
+                    let method = self.ident_name(defn.name)?;
+                    if method == init_name {
+                        if init.is_some() {
+                            return Err(self.already_defined(method).with_span(method_span));
+                        }
+                        init = Some((defn.args, defn.body));
+                        continue;
+                    }
+                    let mut func = self.compile_prologue(defn.args)?;
+                    self.compile_body(&mut func, defn.body)?;
+                    func.push(Opcode::Return);
+                    let func = self.finalize(func);
+                    if methods.insert(method, RuntimeFunc::Virtual(func)).is_some() {
+                        return Err(self.already_defined(method).with_span(method_span));
+                    }
+                }
+                Ast::Assignment(box Assignment {
+                    place:
+                        Spanned {
+                            span: _,
+                            inner: Ast::Place(box field, accessors),
+                        },
+                    assign,
+                    expr,
+                }) if accessors.is_empty() => {
+                    let field_span = source_span(field.span);
+                    let field = self.ident_name(field)?;
+                    let flags = match assign.inner {
+                        Token::ImmutDeclAssign => Flags::BINDING_MODE_IMMUT,
+                        Token::DeclAssign => Flags::BINDING_MODE_MUT,
+                        _ => return Err(Error::compiler(concat!(file!(), ":", line!()))),
+                    };
+                    if fields.iter().any(|&(f, _)| f == field) {
+                        return Err(self.already_defined(field).with_span(field_span));
+                    }
+                    fields.push((field, flags));
+                    defaults.push((field, expr));
+                }
+                _ => return Err(Error::compiler(concat!(file!(), ":", line!()))),
+            }
+        }
+
+        // The constructor runs with `self` bound to the freshly allocated object:
+        // it fills in field defaults, runs the body of `init` and then seals the
+        // object, so immutable fields can be assigned at most once during `init`.
+        let (mut ctor, init_body) = match init {
+            Some((args, body)) => (self.compile_prologue(args)?, Some(body)),
+            None => (
+                IncompleteFuncProto::new(
+                    0,
+                    self.state.index,
+                    Rc::clone(&self.state.path),
+                    span.start..span.end,
+                ),
+                None,
+            ),
         };
+        for (field, expr) in defaults {
+            ctor.push(Opcode::Read(self_));
+            self.compile_expr(&mut ctor, expr)?;
+            ctor.push(Opcode::InitField(field));
+        }
+        if let Some(body) = init_body {
+            self.compile_body(&mut ctor, body)?;
+            ctor.push(Opcode::Pop);
+        }
+        ctor.push(Opcode::Read(self_));
+        ctor.push(Opcode::Seal);
+        ctor.push(Opcode::Return);
+        let ctor = self.finalize(ctor);
+
+        self.types.insert(
+            name,
+            TypeProto {
+                name,
+                fields: fields.into(),
+                methods,
+                ctor: Some(ctor),
+            },
+        );
+        Ok(())
+    }
 
+    /// Starts a function by binding its arguments to immutable local slots.
+    fn compile_prologue(&mut self, args: SpannedAst<'_, '_>) -> Result<IncompleteFuncProto, Error> {
+        let span = args.span;
         let args = if let Ast::Arglist(args) = args.inner {
             args
         } else {
             return Err(Error::compiler(concat!(file!(), ":", line!())));
         };
-        for arg in args {
-            let arg = if let Ast::Identifier(ident) = arg.inner {
-                self.interner.get_or_intern(ident)
-            } else {
-                return Err(Error::compiler(concat!(file!(), ":", line!())));
-            };
-            func.code
-                .push(Opcode::Defslot(arg, Flags::BINDING_MODE_IMMUT));
+
+        let mut func = IncompleteFuncProto::new(
+            args.len(),
+            self.state.index,
+            Rc::clone(&self.state.path),
+            span.start..span.end,
+        );
+
+        // Arguments are pushed in order, so the last one is on top of the stack
+        for arg in args.into_iter().rev() {
+            let arg_span = source_span(arg.span);
+            let arg = self.ident_name(arg)?;
+            self.declare(&mut func, arg, Flags::BINDING_MODE_IMMUT)
+                .map_err(|e| e.with_span(arg_span))?;
+            func.push(Opcode::Assign(arg));
         }
 
+        Ok(func)
+    }
+
+    /// Compiles a sequence of statements, leaving the value of the last one on the stack.
+    fn compile_body(
+        &mut self,
+        func: &mut IncompleteFuncProto,
+        body: SpannedAsts<'_, '_>,
+    ) -> Result<(), Error> {
+        // The span of the expression whose value is on the stack, so that
+        // discarding it belongs to the same line
+        let mut produced_value: Option<Range<usize>> = None;
         for expr in body {
-            self.compile_expr(&mut func, expr)?;
+            if let Some(span) = produced_value.take() {
+                let outer = std::mem::replace(&mut func.span, span);
+                func.push(Opcode::Pop);
+                func.span = outer;
+            }
+            if !matches!(
+                expr.inner,
+                Ast::Assignment(_) | Ast::Break(_) | Ast::Continue(_)
+            ) {
+                produced_value = Some(expr.span.start..expr.span.end);
+            }
+            self.compile_expr(func, expr)?;
+        }
+        if produced_value.is_none() {
+            func.push(Opcode::Nil);
         }
+        Ok(())
+    }
 
-        self.funcs.insert(name, func.finalize());
+    /// Compiles a body in its own scope.
+    fn compile_block(
+        &mut self,
+        func: &mut IncompleteFuncProto,
+        body: SpannedAsts<'_, '_>,
+    ) -> Result<(), Error> {
+        func.begin_scope();
+        self.compile_body(func, body)?;
+        func.end_scope();
         Ok(())
     }
 
+    /// Compiles the body of a loop in its own scope, discarding its value.
+    fn compile_loop_body(
+        &mut self,
+        func: &mut IncompleteFuncProto,
+        body: SpannedAsts<'_, '_>,
+    ) -> Result<(), Error> {
+        self.compile_block(func, body)?;
+        func.push(Opcode::Pop);
+        Ok(())
+    }
+
+    /// Points every `break` of the innermost loop to the next instruction.
+    fn end_loop(&mut self, func: &mut IncompleteFuncProto) {
+        let labels = func.loops.pop().unwrap();
+        for jump in labels.breaks {
+            func.patch(jump);
+        }
+    }
+
+    /// Compiles the test of a pattern against the value on top of the stack, leaving the
+    /// stack as it was. `depth` is the number of values above the scrutinee, and a failed
+    /// test jumps to a label in `fails[depth]`.
+    fn compile_pattern(
+        &mut self,
+        func: &mut IncompleteFuncProto,
+        pattern: Spanned<Pattern>,
+        depth: usize,
+        fails: &mut Vec<Vec<usize>>,
+    ) -> Result<(), Error> {
+        match pattern.inner {
+            Pattern::Wildcard => {}
+            Pattern::Binding(name) => {
+                let name = self.interner.get_or_intern(name);
+                func.push(Opcode::Dup);
+                func.push(Opcode::Assign(name));
+            }
+            Pattern::Literal(literal) => {
+                func.push(Opcode::Dup);
+                self.compile_expr(func, literal)?;
+                func.push(Opcode::Eq);
+                let jump = func.jump(Opcode::JumpIfFalse);
+                fail_at(fails, depth, jump);
+            }
+            Pattern::List(items) => {
+                func.push(Opcode::TestList(items.len()));
+                let jump = func.jump(Opcode::JumpIfFalse);
+                fail_at(fails, depth, jump);
+                for (i, item) in items.into_iter().enumerate() {
+                    if matches!(item.inner, Pattern::Wildcard) {
+                        continue;
+                    }
+                    func.push(Opcode::Dup);
+                    func.push_const(ConstValue::Uint(i as u64));
+                    func.push(Opcode::LoadKey);
+                    self.compile_pattern(func, item, depth + 1, fails)?;
+                    func.push(Opcode::Pop);
+                }
+            }
+            Pattern::Map(entries) => {
+                for (key, value) in entries {
+                    let key = match key.inner {
+                        Ast::String(s) => ConstValue::Str(self.intern_literal(s)?),
+                        Ast::Int(i) => ConstValue::Int(i),
+                        Ast::Uint(i) => ConstValue::Uint(i),
+                        Ast::Bool(b) => ConstValue::Bool(b),
+                        _ => {
+                            return Err(Error::compiler(concat!(file!(), ":", line!()))
+                                .with_span(source_span(key.span)))
+                        }
+                    };
+                    func.push(Opcode::Dup);
+                    let key = func.push_const(key);
+                    func.push(Opcode::TestKey);
+                    let jump = func.jump(Opcode::JumpIfFalse);
+                    fail_at(fails, depth, jump);
+                    if matches!(value.inner, Pattern::Wildcard) {
+                        continue;
+                    }
+                    func.push(Opcode::Dup);
+                    func.push(Opcode::Const(key));
+                    func.push(Opcode::LoadKey);
+                    self.compile_pattern(func, value, depth + 1, fails)?;
+                    func.push(Opcode::Pop);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles the condition of a `break if`/`continue if`, returning the jump
+    /// that skips the statement when it doesn't hold.
+    fn compile_condition(
+        &mut self,
+        func: &mut IncompleteFuncProto,
+        cond: Option<Box<SpannedAst<'_, '_>>>,
+    ) -> Result<Option<usize>, Error> {
+        match cond {
+            Some(box cond) => {
+                self.compile_expr(func, cond)?;
+                Ok(Some(func.jump(Opcode::JumpIfFalse)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn ident_name(&mut self, field: SpannedAst<'_, '_>) -> Result<Spur, Error> {
+        if let Ast::Identifier(ident) = field.inner {
+            Ok(self.interner.get_or_intern(ident))
+        } else {
+            Err(Error::compiler(concat!(file!(), ":", line!())))
+        }
+    }
+
     fn compile_expr(
         &mut self,
         func: &mut IncompleteFuncProto,
         expr: Spanned<Ast>,
+    ) -> Result<(), Error> {
+        let span = expr.span;
+        let outer = std::mem::replace(&mut func.span, span.start..span.end);
+        let res = self.compile_expr_inner(func, expr);
+        func.span = outer;
+        // The innermost expression that failed gives the span
+        res.map_err(|e| e.with_span(source_span(span)))
+    }
+
+    fn compile_expr_inner(
+        &mut self,
+        func: &mut IncompleteFuncProto,
+        expr: Spanned<Ast>,
     ) -> Result<(), Error> {
         match expr.inner {
             Ast::Module(_) => Err(Error::compiler(concat!(file!(), ":", line!()))),
             Ast::Defn(_) => Err(Error::compiler(concat!(file!(), ":", line!()))),
+            Ast::Type(_) => Err(Error::compiler(concat!(file!(), ":", line!()))),
+            Ast::Import(_) => Err(Error::compiler(concat!(file!(), ":", line!()))),
             Ast::Assignment(box Assignment {
                 place:
                     Spanned {
                         span: _,
-                        inner:
-                            Ast::Place(
-                                box Spanned {
-                                    span: _,
-                                    inner: Ast::Identifier(object_ident),
-                                },
-                                mut accessors,
-                            ),
+                        inner: Ast::Place(box base, mut accessors),
                     },
                 assign,
                 expr,
             }) => {
-                let object_name = self.interner.get_or_intern(object_ident);
-                match assign.inner {
-                    Token::ImmutDeclAssign => func
-                        .code
-                        .push(Opcode::Defslot(object_name, Flags::BINDING_MODE_IMMUT)),
-                    Token::DeclAssign => func
-                        .code
-                        .push(Opcode::Defslot(object_name, Flags::BINDING_MODE_MUT)),
-                    Token::Assign => (),
-                    _ => return Err(Error::compiler(concat!(file!(), ":", line!()))),
-                }
-                if let Some(Spanned {
-                    span: _,
-                    inner: Ast::Identifier(field_ident),
-                }) = accessors.pop()
-                {
-                    func.code.push(Opcode::Read(object_name));
-                    let last_field_name = self.interner.get_or_intern(field_ident);
+                if let Some(last_field) = accessors.pop() {
+                    let last_field_name = self.ident_name(last_field)?;
 
+                    self.compile_expr(func, base)?;
                     for accessor in accessors {
-                        if let Spanned {
-                            span: _,
-                            inner: Ast::Identifier(ident),
-                        } = accessor
-                        {
-                            let name = self.interner.get_or_intern(ident);
-                            func.code.push(Opcode::LoadField(name));
-                        } else {
-                            return Err(Error::compiler(concat!(file!(), ":", line!())));
-                        }
+                        let name = self.ident_name(accessor)?;
+                        func.push(Opcode::LoadField(name));
                     }
 
                     self.compile_expr(func, expr)?;
 
-                    func.code.push(Opcode::StoreField(last_field_name));
+                    match assign.inner {
+                        Token::ImmutDeclAssign => {
+                            func.push(Opcode::DefField(last_field_name, Flags::BINDING_MODE_IMMUT))
+                        }
+                        Token::DeclAssign => {
+                            func.push(Opcode::DefField(last_field_name, Flags::BINDING_MODE_MUT))
+                        }
+                        Token::Assign => func.push(Opcode::StoreField(last_field_name)),
+                        _ => return Err(Error::compiler(concat!(file!(), ":", line!()))),
+                    }
                 } else {
+                    let object_name = self.ident_name(base)?;
+                    // The value is compiled first, so a declaration can't refer to itself
                     self.compile_expr(func, expr)?;
-                    func.code.push(Opcode::Assign(object_name));
+                    let flags = match assign.inner {
+                        Token::ImmutDeclAssign => Some(Flags::BINDING_MODE_IMMUT),
+                        Token::DeclAssign => Some(Flags::BINDING_MODE_MUT),
+                        Token::Assign => None,
+                        _ => return Err(Error::compiler(concat!(file!(), ":", line!()))),
+                    };
+                    match flags {
+                        Some(flags) => {
+                            if func.module_init && func.scopes.is_empty() {
+                                self.bind_global(object_name)?;
+                                self.state.globals.push(object_name);
+                                if flags == Flags::BINDING_MODE_MUT {
+                                    self.mutable_globals.insert((self.state.index, object_name));
+                                }
+                            }
+                            self.declare(func, object_name, flags)?;
+                        }
+                        None => self.reference(func, object_name),
+                    }
+                    func.push(Opcode::Assign(object_name));
                 };
 
                 Ok(())
             }
+            Ast::Assignment(box Assignment {
+                place:
+                    Spanned {
+                        span: _,
+                        inner: Ast::Index(box base, box index),
+                    },
+                assign,
+                expr,
+            }) => {
+                if assign.inner != Token::Assign {
+                    return Err(Error::compiler(concat!(file!(), ":", line!())));
+                }
+                self.compile_expr(func, base)?;
+                self.compile_expr(func, index)?;
+                self.compile_expr(func, expr)?;
+                func.push(Opcode::StoreIndex);
+                Ok(())
+            }
             Ast::Assignment(_) => Err(Error::compiler(concat!(file!(), ":", line!()))),
-            Ast::BinOp(_) => todo!(),
+            Ast::BinOp(box BinOp { lhs, op, rhs }) => {
+                self.compile_expr(func, lhs)?;
+                self.compile_expr(func, rhs)?;
+                match op.inner {
+                    Token::Plus => func.push(Opcode::Add),
+                    Token::Minus => func.push(Opcode::Sub),
+                    Token::Equals => func.push(Opcode::Eq),
+                    Token::NotEquals => func.push(Opcode::Ne),
+                    Token::Less => func.push(Opcode::Lt),
+                    Token::LessEquals => func.push(Opcode::Le),
+                    Token::Greater => func.push(Opcode::Gt),
+                    Token::GreaterEquals => func.push(Opcode::Ge),
+                    Token::Range => func.push(Opcode::MakeRange(false)),
+                    Token::RangeInclusive => func.push(Opcode::MakeRange(true)),
+                    _ => return Err(Error::compiler(concat!(file!(), ":", line!()))),
+                }
+                Ok(())
+            }
             Ast::String(s) => {
-                let s = self.interner.get_or_intern(s);
-                self.consts.push(ConstValue::Str(s));
-                func.code.push(Opcode::Const(self.consts.len() - 1));
+                let s = self.intern_literal(s)?;
+                func.push_const(ConstValue::Str(s));
                 Ok(())
             }
             Ast::Int(i) => {
-                self.consts.push(ConstValue::Int(i));
-                func.code.push(Opcode::Const(self.consts.len() - 1));
+                func.push_const(ConstValue::Int(i));
                 Ok(())
             }
             Ast::Uint(i) => {
-                self.consts.push(ConstValue::Uint(i));
-                func.code.push(Opcode::Const(self.consts.len() - 1));
+                func.push_const(ConstValue::Uint(i));
+                Ok(())
+            }
+            Ast::Float(f) => {
+                func.push_const(ConstValue::Float(f));
+                Ok(())
+            }
+            Ast::Nil => {
+                func.push(Opcode::Nil);
+                Ok(())
+            }
+            Ast::Bool(b) => {
+                func.push_const(ConstValue::Bool(b));
+                Ok(())
+            }
+            Ast::List(items) => {
+                let len = items.len();
+                for item in items {
+                    self.compile_expr(func, item)?;
+                }
+                func.push(Opcode::MakeList(len));
+                Ok(())
+            }
+            Ast::Map(entries) => {
+                let len = entries.len();
+                for (key, value) in entries {
+                    self.compile_expr(func, key)?;
+                    self.compile_expr(func, value)?;
+                }
+                func.push(Opcode::MakeMap(len));
+                Ok(())
+            }
+            Ast::Loop(Loop {
+                loop_: _,
+                body,
+                end: _,
+            }) => {
+                let start = func.code.len();
+                func.loops.push(LoopLabels {
+                    start,
+                    scope_depth: func.scopes.len(),
+                    handler_depth: func.handlers.len(),
+                    breaks: Vec::new(),
+                });
+                self.compile_loop_body(func, body)?;
+                func.push(Opcode::Jump(start));
+                self.end_loop(func);
+                func.push(Opcode::Nil);
+                Ok(())
+            }
+            Ast::While(box While {
+                while_,
+                cond,
+                _do: _,
+                body,
+                end: _,
+            }) => {
+                if matches!(cond.inner, Ast::Bool(true)) && !body.iter().any(breaks_out) {
+                    self.warnings.push(Warning {
+                        span: source_span(while_.span),
+                        message: "`while true` loop without `break` never terminates".to_owned(),
+                    });
+                }
+                let start = func.code.len();
+                func.loops.push(LoopLabels {
+                    start,
+                    scope_depth: func.scopes.len(),
+                    handler_depth: func.handlers.len(),
+                    breaks: Vec::new(),
+                });
+                self.compile_expr(func, cond)?;
+                let exit = func.jump(Opcode::JumpIfFalse);
+                self.compile_loop_body(func, body)?;
+                func.push(Opcode::Jump(start));
+                func.patch(exit);
+                self.end_loop(func);
+                func.push(Opcode::Nil);
+                Ok(())
+            }
+            Ast::For(box For {
+                for_: _,
+                var,
+                in_: _,
+                iterable,
+                _do: _,
+                body,
+                end: _,
+            }) => {
+                let var_span = source_span(var.span);
+                let var = self.ident_name(var)?;
+                self.compile_expr(func, iterable)?;
+                func.push(Opcode::Iter);
+                let start = func.code.len();
+                func.loops.push(LoopLabels {
+                    start,
+                    scope_depth: func.scopes.len(),
+                    handler_depth: func.handlers.len(),
+                    breaks: Vec::new(),
+                });
+                let exhausted = func.jump(Opcode::IterNext);
+                func.begin_scope();
+                self.declare(func, var, Flags::BINDING_MODE_IMMUT)
+                    .map_err(|e| e.with_span(var_span))?;
+                func.push(Opcode::Assign(var));
+                self.compile_body(func, body)?;
+                func.push(Opcode::Pop);
+                func.end_scope();
+                func.push(Opcode::Jump(start));
+                func.patch(exhausted);
+                self.end_loop(func);
+                // Drop the iterator
+                func.push(Opcode::Pop);
+                func.push(Opcode::Nil);
+                Ok(())
+            }
+            Ast::Try(box Try {
+                try_: _,
+                body,
+                rescue,
+                ensure,
+                end: _,
+            }) => {
+                // Errors in the body or the handler still run the `ensure` block
+                let ensure_handler = ensure.as_ref().map(|_| {
+                    func.handlers.push(true);
+                    func.jump(Opcode::PushHandler)
+                });
+                match rescue {
+                    Some((name, handler)) => {
+                        let name_span = source_span(name.span);
+                        let name = self.ident_name(name)?;
+                        func.handlers.push(false);
+                        let rescue_handler = func.jump(Opcode::PushHandler);
+                        self.compile_block(func, body)?;
+                        func.push(Opcode::PopHandler);
+                        func.handlers.pop();
+                        let done = func.jump(Opcode::Jump);
+
+                        // The error is on top of the stack
+                        func.patch(rescue_handler);
+                        func.drop_declared_since(rescue_handler);
+                        func.begin_scope();
+                        self.declare(func, name, Flags::BINDING_MODE_IMMUT)
+                            .map_err(|e| e.with_span(name_span))?;
+                        func.push(Opcode::Assign(name));
+                        self.compile_body(func, handler)?;
+                        func.end_scope();
+                        func.patch(done);
+                    }
+                    None => self.compile_block(func, body)?,
+                }
+                if let (Some(ensure), Some(ensure_handler)) = (ensure, ensure_handler) {
+                    // The ensure block runs with the value of the `try`, or the error,
+                    // and whether it has to be raised again on top of the stack
+                    func.push(Opcode::PopHandler);
+                    func.push(Opcode::Bool(false));
+                    let to_ensure = func.jump(Opcode::Jump);
+                    func.patch(ensure_handler);
+                    func.drop_declared_since(ensure_handler);
+                    func.push(Opcode::Bool(true));
+                    func.patch(to_ensure);
+                    self.compile_block(func, ensure)?;
+                    func.push(Opcode::Pop);
+                    func.handlers.pop();
+                    func.push(Opcode::EndEnsure);
+                }
+                Ok(())
+            }
+            Ast::Match(box Match { scrutinee, arms }) => {
+                for (i, arm) in arms.iter().enumerate() {
+                    let shadowed = arms[..i].iter().any(|earlier| {
+                        earlier.guard.is_none() && subsumes(&earlier.pattern, &arm.pattern)
+                    });
+                    if shadowed {
+                        self.warnings.push(Warning {
+                            span: source_span(arm.pattern.span),
+                            message: "unreachable match arm".to_owned(),
+                        });
+                    }
+                }
+                self.compile_expr(func, scrutinee)?;
+                let mut done = Vec::new();
+                for MatchArm {
+                    pattern,
+                    guard,
+                    body,
+                } in arms
+                {
+                    // The bindings are declared up front, so that a failed match can drop
+                    // all of them no matter where it failed
+                    func.begin_scope();
+                    let mut bindings = Vec::new();
+                    pattern_bindings(&pattern, &mut bindings);
+                    for (name, span) in bindings {
+                        let name = self.interner.get_or_intern(name);
+                        // Bound twice in the same pattern, or hiding a local
+                        self.declare(func, name, Flags::BINDING_MODE_IMMUT)
+                            .map_err(|e| e.with_span(source_span(span)))?;
+                    }
+                    let mut fails = Vec::new();
+                    self.compile_pattern(func, pattern, 0, &mut fails)?;
+                    if let Some(guard) = guard {
+                        self.compile_expr(func, guard)?;
+                        let jump = func.jump(Opcode::JumpIfFalse);
+                        fail_at(&mut fails, 0, jump);
+                    }
+                    func.push(Opcode::Pop);
+                    self.compile_expr(func, body)?;
+                    func.drop_scopes(func.scopes.len() - 1);
+                    done.push(func.jump(Opcode::Jump));
+
+                    // Each failure jump discards the values the pattern pushed above the
+                    // scrutinee so far
+                    for depth in (0..fails.len()).rev() {
+                        for &jump in &fails[depth] {
+                            func.patch(jump);
+                        }
+                        if depth > 0 {
+                            func.push(Opcode::Pop);
+                        }
+                    }
+                    func.end_scope();
+                }
+                func.push(Opcode::NoMatch);
+                for jump in done {
+                    func.patch(jump);
+                }
+                Ok(())
+            }
+            Ast::Raise(box value) => {
+                self.compile_expr(func, value)?;
+                func.push(Opcode::Raise);
+                Ok(())
+            }
+            Ast::Break(cond) => {
+                let (depth, handler_depth) = func
+                    .loops
+                    .last()
+                    .map(|labels| (labels.scope_depth, labels.handler_depth))
+                    .ok_or_else(|| Error::compiler(concat!(file!(), ":", line!())))?;
+                let skip = self.compile_condition(func, cond)?;
+                func.drop_scopes(depth);
+                func.leave_handlers(handler_depth, "break")?;
+                let jump = func.jump(Opcode::Jump);
+                func.loops.last_mut().unwrap().breaks.push(jump);
+                if let Some(skip) = skip {
+                    func.patch(skip);
+                }
+                Ok(())
+            }
+            Ast::Continue(cond) => {
+                let (start, depth, handler_depth) = func
+                    .loops
+                    .last()
+                    .map(|labels| (labels.start, labels.scope_depth, labels.handler_depth))
+                    .ok_or_else(|| Error::compiler(concat!(file!(), ":", line!())))?;
+                let skip = self.compile_condition(func, cond)?;
+                func.drop_scopes(depth);
+                func.leave_handlers(handler_depth, "continue")?;
+                func.push(Opcode::Jump(start));
+                if let Some(skip) = skip {
+                    func.patch(skip);
+                }
                 Ok(())
             }
-            Ast::Loop(_) => todo!(),
             Ast::Call(
                 box Spanned {
                     span: _name_span,
@@ -200,40 +1367,591 @@ impl<'i> Compiler<'i> {
                 },
             ) => {
                 let name = self.interner.get_or_intern(name);
+                self.reference(func, name);
+                let argc = params.len();
+                for param in params {
+                    self.compile_expr(func, param)?;
+                }
+                func.push(Opcode::Call(name, argc));
+                Ok(())
+            }
+            Ast::Call(
+                box callee,
+                box Spanned {
+                    span: _params_span,
+                    inner: Ast::Paramlist(params),
+                },
+            ) => {
+                self.compile_expr(func, callee)?;
+                let argc = params.len();
                 for param in params {
                     self.compile_expr(func, param)?;
                 }
-                func.code.push(Opcode::Call(name));
+                func.push(Opcode::CallValue(argc));
                 Ok(())
             }
-            Ast::Call(..) => unimplemented!(),
-            Ast::New(_, _, _) => todo!(),
-            Ast::Arglist(_) => todo!(),
-            Ast::Paramlist(_) => todo!(),
-            Ast::Identifier(_) => todo!(),
-            Ast::Place(
+            Ast::Call(..) => Err(Error::compiler(concat!(file!(), ":", line!()))),
+            Ast::MethodCall(
+                box receiver,
+                box method,
                 box Spanned {
-                    span: _,
-                    inner: Ast::Identifier(ident),
+                    span: _params_span,
+                    inner: Ast::Paramlist(params),
                 },
-                _accessors,
             ) => {
+                let method = self.ident_name(method)?;
+                self.compile_expr(func, receiver)?;
+                let argc = params.len();
+                for param in params {
+                    self.compile_expr(func, param)?;
+                }
+                func.push(Opcode::CallMethod(method, argc));
+                Ok(())
+            }
+            Ast::MethodCall(..) => Err(Error::compiler(concat!(file!(), ":", line!()))),
+            Ast::New(_, params, box ty) => {
+                let ty = self.ident_name(ty)?;
+                let params = match params {
+                    Some(box Spanned {
+                        span: _,
+                        inner: Ast::Paramlist(params),
+                    }) => params,
+                    Some(_) => return Err(Error::compiler(concat!(file!(), ":", line!()))),
+                    None => Vec::new(),
+                };
+                let argc = params.len();
+                for param in params {
+                    self.compile_expr(func, param)?;
+                }
+                func.push(Opcode::New(ty, argc));
+                Ok(())
+            }
+            Ast::Arglist(_) => Err(Error::compiler(concat!(file!(), ":", line!()))),
+            Ast::Paramlist(_) => Err(Error::compiler(concat!(file!(), ":", line!()))),
+            Ast::Identifier(ident) => {
                 let ident = self.interner.get_or_intern(ident);
-                func.code.push(Opcode::Read(ident));
+                self.reference(func, ident);
+                func.push(Opcode::Read(ident));
                 Ok(())
             }
-            Ast::Place(..) => Err(Error::compiler(concat!(file!(), ":", line!()))),
+            Ast::Index(box base, box index) => {
+                self.compile_expr(func, base)?;
+                self.compile_expr(func, index)?;
+                func.push(Opcode::LoadIndex);
+                Ok(())
+            }
+            Ast::Place(box base, accessors) => {
+                self.compile_expr(func, base)?;
+                for accessor in accessors {
+                    let name = self.ident_name(accessor)?;
+                    func.push(Opcode::LoadField(name));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Interns the text of a string literal, resolving its escapes.
+    fn intern_literal(&mut self, s: &str) -> Result<Spur, Error> {
+        let s =
+            lexer::unescape(s).ok_or_else(|| Error::compiler(concat!(file!(), ":", line!())))?;
+        Ok(self.interner.get_or_intern(s))
+    }
+
+    /// Optimizes a compiled function as requested by `-O`.
+    fn finalize(&mut self, mut func: IncompleteFuncProto) -> FuncProto {
+        Optimizer::new(self.opt_level, self.interner).run(&mut func);
+        func.finalize()
+    }
+
+    fn emit(self) -> Result<Program, Error> {
+        let main = find_main(&self.modules, self.interner)?;
+        Ok(Program {
+            main,
+            modules: self.modules,
+            types: self.types,
+            warnings: self.warnings,
+        })
+    }
+}
+
+/// The `main` function of the root module, which is always the first one to
+/// start compiling.
+pub(crate) fn find_main(modules: &[ModuleProto], interner: &Rodeo) -> Result<FuncProto, Error> {
+    interner
+        .get("main")
+        .and_then(|main| {
+            modules
+                .iter()
+                .find(|m| m.index == 0)
+                .and_then(|root| root.funcs.get(&main))
+        })
+        .cloned()
+        .ok_or_else(|| Error::compiler(concat!(file!(), ":", line!())))
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn source_span(span: Span) -> SourceSpan {
+    SourceSpan {
+        path: span.path.to_path_buf(),
+        start: span.start,
+        end: span.end,
+    }
+}
+
+fn fail_at(fails: &mut Vec<Vec<usize>>, depth: usize, jump: usize) {
+    if fails.len() <= depth {
+        fails.resize_with(depth + 1, Vec::new);
+    }
+    fails[depth].push(jump);
+}
+
+/// Whether `ast` contains a `break` that leaves the loop it's in, rather than
+/// a loop nested in it.
+fn breaks_out(ast: &SpannedAst) -> bool {
+    let any = |asts: &[SpannedAst]| asts.iter().any(breaks_out);
+    match &ast.inner {
+        Ast::Break(_) => true,
+        Ast::Loop(_) | Ast::While(_) | Ast::For(_) => false,
+        Ast::Module(_) | Ast::Defn(_) | Ast::Type(_) | Ast::Import(_) => false,
+        Ast::Assignment(assignment) => {
+            breaks_out(&assignment.place) || breaks_out(&assignment.expr)
+        }
+        Ast::BinOp(binop) => breaks_out(&binop.lhs) || breaks_out(&binop.rhs),
+        Ast::String(_)
+        | Ast::Int(_)
+        | Ast::Uint(_)
+        | Ast::Float(_)
+        | Ast::Bool(_)
+        | Ast::Nil
+        | Ast::Identifier(_) => false,
+        Ast::List(items) | Ast::Arglist(items) | Ast::Paramlist(items) => any(items),
+        Ast::Map(entries) => entries
+            .iter()
+            .any(|(key, value)| breaks_out(key) || breaks_out(value)),
+        Ast::Try(try_) => {
+            any(&try_.body)
+                || try_
+                    .rescue
+                    .as_ref()
+                    .is_some_and(|(_, handler)| any(handler))
+                || try_.ensure.as_deref().is_some_and(any)
+        }
+        Ast::Match(match_) => {
+            breaks_out(&match_.scrutinee)
+                || match_
+                    .arms
+                    .iter()
+                    .any(|arm| arm.guard.as_ref().is_some_and(breaks_out) || breaks_out(&arm.body))
+        }
+        Ast::Raise(value) => breaks_out(value),
+        Ast::Continue(cond) => cond.as_deref().is_some_and(breaks_out),
+        Ast::Call(callee, params) => breaks_out(callee) || breaks_out(params),
+        Ast::MethodCall(receiver, name, params) => {
+            breaks_out(receiver) || breaks_out(name) || breaks_out(params)
+        }
+        Ast::New(_, params, ty) => params.as_deref().is_some_and(breaks_out) || breaks_out(ty),
+        Ast::Place(base, path) => breaks_out(base) || any(path),
+        Ast::Index(base, index) => breaks_out(base) || breaks_out(index),
+    }
+}
+
+/// Collects the names a pattern binds and where, in order.
+fn pattern_bindings<'s, 'p>(
+    pattern: &Spanned<'p, Pattern<'s, 'p>>,
+    names: &mut Vec<(&'s str, Span<'p>)>,
+) {
+    match &pattern.inner {
+        Pattern::Binding(name) => names.push((name, pattern.span)),
+        Pattern::List(items) => {
+            for item in items {
+                pattern_bindings(item, names);
+            }
+        }
+        Pattern::Map(entries) => {
+            for (_, value) in entries {
+                pattern_bindings(value, names);
+            }
+        }
+        Pattern::Wildcard | Pattern::Literal(_) => {}
+    }
+}
+
+/// Whether every value matched by `b` is also matched by `a`.
+fn subsumes(a: &Spanned<Pattern>, b: &Spanned<Pattern>) -> bool {
+    match (&a.inner, &b.inner) {
+        (Pattern::Wildcard | Pattern::Binding(_), _) => true,
+        (Pattern::Literal(a), Pattern::Literal(b)) => same_literal(&a.inner, &b.inner),
+        (Pattern::List(a), Pattern::List(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| subsumes(a, b))
+        }
+        (Pattern::Map(a), Pattern::Map(b)) => a.iter().all(|(key, a)| {
+            b.iter()
+                .any(|(other, b)| same_literal(&key.inner, &other.inner) && subsumes(a, b))
+        }),
+        _ => false,
+    }
+}
+
+fn same_literal(a: &Ast, b: &Ast) -> bool {
+    match (a, b) {
+        (Ast::String(a), Ast::String(b)) => a == b,
+        (Ast::Int(a), Ast::Int(b)) => a == b,
+        (Ast::Uint(a), Ast::Uint(b)) => a == b,
+        (Ast::Float(a), Ast::Float(b)) => a == b,
+        (Ast::Bool(a), Ast::Bool(b)) => a == b,
+        (Ast::Nil, Ast::Nil) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lasso::Rodeo;
+
+    use super::*;
+    use crate::{
+        error::ErrorKind,
+        eval::Vm,
+        testing::{compile, compile_at, raised, run, temp_dir},
+    };
+
+    /// The error compiling `src` gives, and where it points.
+    fn error(src: &str) -> (Error, Range<usize>) {
+        let e = compile(src, &mut Rodeo::new(), 0).unwrap_err();
+        let span = e.span().expect("compiler errors have a span");
+        let range = span.start..span.end;
+        (e, range)
+    }
+
+    fn already_defined(e: &Error) -> &str {
+        match e.kind() {
+            ErrorKind::AlreadyDefined { name } => name,
+            kind => panic!("unexpected error {}", kind),
+        }
+    }
+
+    #[test]
+    fn loop_variables_cant_hide_locals() {
+        let src = "defn main() do\n    for i in 0..2 do\n        for i in 0..2 do\n            nil\n        end\n    end\nend\n";
+        let (e, at) = error(src);
+        assert_eq!(already_defined(&e), "i");
+        let inner = src.rfind("i in").unwrap();
+        assert_eq!(at, inner..inner + 1);
+
+        let src = "defn f(x) do\n    for x in [1] do\n        nil\n    end\nend\n\ndefn main() do\n    f(1)\nend\n";
+        assert_eq!(already_defined(&error(src).0), "x");
+        let src = "defn main() do\n    x := 1\n    for x in [1] do\n        nil\n    end\nend\n";
+        assert_eq!(already_defined(&error(src).0), "x");
+        let src = "defn f(a, a) do\n    a\nend\n\ndefn main() do\n    f(1, 2)\nend\n";
+        assert_eq!(already_defined(&error(src).0), "a");
+    }
+
+    #[test]
+    fn rescue_names_cant_hide_locals() {
+        let src = "defn main() do\n    e := 1\n    try\n        raise 2\n    rescue e do\n        e\n    end\nend\n";
+        let (e, at) = error(src);
+        assert_eq!(already_defined(&e), "e");
+        let name = src.find("e do").unwrap();
+        assert_eq!(at, name..name + 1);
+        let src = "defn f(err) do\n    try\n        raise 2\n    rescue err do\n        err\n    end\nend\n\ndefn main() do\n    f(1)\nend\n";
+        assert_eq!(already_defined(&error(src).0), "err");
+
+        // Handlers one after the other may use the same name
+        let src = "defn main() do
+    l := []
+    for i in [1, 2] do
+        try
+            raise i
+        rescue e do
+            l.push(e)
+        end
+    end
+    try
+        raise 3
+    rescue e do
+        l.push(e)
+    end
+    raise l
+end
+";
+        assert_eq!(raised(run(src)), "[1, 2, 3]");
+    }
+
+    #[test]
+    fn loops_cant_jump_past_ensure() {
+        for keyword in ["break", "continue"] {
+            let src = format!("defn main() do\n    loop\n        try\n            {}\n        ensure\n            nil\n        end\n    end\nend\n", keyword);
+            let (e, at) = error(&src);
+            assert!(matches!(e.kind(), ErrorKind::EnsureSkipped { keyword: k } if *k == keyword));
+            assert_eq!(&src[at], keyword);
+        }
+        // Loops inside the `try` are fine
+        let src = "defn main() do\n    try\n        loop\n            break\n        end\n    ensure\n        nil\n    end\nend\n";
+        run(src).unwrap();
+    }
+
+    #[test]
+    fn pattern_bindings_cant_hide_locals() {
+        let src = "defn main() do\n    x := 1\n    match [2] do\n        [x] => x,\n    end\nend\n";
+        let (e, at) = error(src);
+        assert_eq!(already_defined(&e), "x");
+        let binding = src.find("[x]").unwrap() + 1;
+        assert_eq!(at, binding..binding + 1);
+        let src = "defn main() do\n    match [1, 2] do\n        [a, a] => a,\n    end\nend\n";
+        let (e, at) = error(src);
+        assert_eq!(already_defined(&e), "a");
+        let second = src.find("a]").unwrap();
+        assert_eq!(at, second..second + 1);
+
+        // Each arm has its own bindings
+        let src = "defn main() do
+    l := []
+    for v in [[1], {\"k\": 2}] do
+        l.push(match v do
+            [n] => n,
+            {\"k\": n} => n,
+        end)
+    end
+    raise l
+end
+";
+        assert_eq!(raised(run(src)), "[1, 2]");
+    }
+
+    #[test]
+    fn types_and_their_members_are_defined_once() {
+        let src = "type Point do\n    x := 0\nend\n\ntype Point do\n    y := 0\nend\n";
+        let (e, at) = error(src);
+        assert_eq!(already_defined(&e), "Point");
+        let second = src.rfind("Point").unwrap();
+        assert_eq!(at, second..second + 5);
+        let (e, at) = error("type List do\n    x := 0\nend\n");
+        assert_eq!((already_defined(&e), at), ("List", 5..9));
+
+        let src = "type T do\n    x := 0\n    x := 1\nend\n";
+        let (e, at) = error(src);
+        let second = src.rfind('x').unwrap();
+        assert_eq!((already_defined(&e), at), ("x", second..second + 1));
+        let src = "type T do\n    defn f() do\n        1\n    end\n    defn f() do\n        2\n    end\nend\n";
+        let (e, at) = error(src);
+        let second = src.rfind('f').unwrap();
+        assert_eq!((already_defined(&e), at), ("f", second..second + 1));
+        let src = "type T do\n    defn init() do\n        nil\n    end\n    defn init() do\n        nil\n    end\nend\n";
+        let (e, at) = error(src);
+        let second = src.rfind("init").unwrap();
+        assert_eq!((already_defined(&e), at), ("init", second..second + 4));
+    }
+
+    #[test]
+    fn modules_cant_share_type_names() {
+        let dir = temp_dir("compiler-types");
+        fs::write(dir.join("shapes.oni"), "type Point do\n    x := 0\nend\n").unwrap();
+        let src = "import shapes\n\ntype Point do\n    y := 0\nend\n";
+        let e = compile_at(src, &dir.join("main.oni"), &mut Rodeo::new(), 0).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(already_defined(&e), "Point");
+        let span = e.span().unwrap();
+        assert_eq!(span.start..span.end, 20..25);
+    }
+
+    /// Compiles and runs `src` next to `modules`, given by name and text.
+    fn run_with(test: &str, modules: &[(&str, &str)], src: &str) -> Result<(), Error> {
+        let dir = temp_dir(test);
+        for (name, text) in modules {
+            let path = dir.join(name).with_extension("oni");
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        let mut interner = Rodeo::new();
+        let res = compile_at(src, &dir.join("main.oni"), &mut interner, 0)
+            .and_then(|program| Vm::new(program, &mut interner)?.eval());
+        fs::remove_dir_all(&dir).unwrap();
+        res
+    }
+
+    fn used_before_definition(src: &str) -> (String, &str) {
+        let (e, at) = error(src);
+        match e.kind() {
+            ErrorKind::UseBeforeDefinition { name } => (name.clone(), &src[at]),
+            kind => panic!("unexpected error {}", kind),
         }
     }
 
-    fn emit(self) -> Result<Vm<'i>, Error> {
-        let code = Rc::clone(
-            &self
-                .funcs
-                .get(&self.interner.get("main").unwrap())
-                .ok_or_else(|| Error::compiler(concat!(file!(), ":", line!())))?
-                .code,
+    #[test]
+    fn globals_are_defined_before_use() {
+        let src = "x := y\ny := 1\n";
+        assert_eq!(used_before_definition(src), ("y".to_owned(), "y"));
+        // Also through the functions the initializer calls
+        let src = "x := f()\ny := 1\n\ndefn f() do\n    g()\nend\n\ndefn g() do\n    y\nend\n";
+        assert_eq!(used_before_definition(src), ("y".to_owned(), "f()"));
+        // Functions only run once the initializer is done
+        let src =
+            "defn f() do\n    y\nend\n\ny := 1\nx := f()\n\ndefn main() do\n    raise x\nend\n";
+        assert_eq!(raised(run(src)), "1");
+    }
+
+    #[test]
+    fn imports_modules_once_each() {
+        let modules = [
+            (
+                "lib/util",
+                "defn double(x) do\n    x + x\nend\n\nlabel $= \"util\"\n",
+            ),
+            ("log", "entries $= []\n"),
+            ("a", "import log\n\nlog.entries.push(\"a\")\n"),
+            ("b", "import a\nimport log\n\nlog.entries.push(\"b\")\n"),
+        ];
+        let src = "import lib/util
+import lib/util (double, label)
+import b
+import a
+import log
+
+defn main() do
+    raise [util.double(2), double(3), label, util.label, log.entries]
+end
+";
+        assert_eq!(
+            raised(run_with("imports", &modules, src)),
+            "[4, 6, \"util\", \"util\", [\"a\", \"b\"]]"
+        );
+    }
+
+    #[test]
+    fn reports_missing_and_circular_imports() {
+        let e = run_with("imports-none", &[], "import nope\n").unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ModuleNotFound { name } if name == "nope"));
+        let modules = [("c", "import d\n"), ("d", "import c\n")];
+        let e = run_with("imports-cycle", &modules, "import c\n").unwrap_err();
+        let cycle = match e.kind() {
+            ErrorKind::CircularImport { cycle } => cycle,
+            kind => panic!("unexpected error {}", kind),
+        };
+        let names = cycle
+            .split(" -> ")
+            .map(|path| Path::new(path).file_stem().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["c", "d", "c"]);
+    }
+
+    #[test]
+    fn top_level_names_are_bound_once() {
+        let src = "defn f() do\n    1\nend\n\ndefn f() do\n    2\nend\n";
+        let (e, at) = error(src);
+        let second = src.rfind('f').unwrap();
+        assert_eq!((already_defined(&e), at), ("f", second..second + 1));
+        let src = "x $= 1\n\ndefn x() do\n    2\nend\n";
+        let (e, at) = error(src);
+        assert_eq!((already_defined(&e), at), ("x", 13..14));
+
+        let util = [("util", "defn f() do\n    1\nend\n")];
+        let src = "import util (f)\n\nf $= 2\n";
+        assert_eq!(
+            already_defined(&run_with("names-import", &util, src).unwrap_err()),
+            "f"
+        );
+        let src = "util := 1\nimport util\n";
+        let e = run_with("names-module", &util, src).unwrap_err();
+        assert_eq!(already_defined(&e), "util");
+        let span = e.span().unwrap();
+        assert_eq!(span.start..span.end, 10..21);
+    }
+
+    #[test]
+    fn imports_only_what_modules_define() {
+        let util = [("util", "defn f() do\n    1\nend\n")];
+        let src = "import util (f, g)\n";
+        let e = run_with("imports-missing", &util, src).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Undefined { name } if name == "util/g"));
+        let span = e.span().unwrap();
+        assert_eq!(span.start..span.end, 16..17);
+    }
+
+    #[test]
+    fn imports_mutable_globals_only_through_their_module() {
+        let counter = [(
+            "counter",
+            "count := 0\nstep $= 1\n\ndefn bump() do\n    count = count + step\nend\n",
+        )];
+        let src = "import counter (count)\n";
+        let e = run_with("imports-mutable", &counter, src).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::MutableImport { name } if name == "count"));
+        let span = e.span().unwrap();
+        assert_eq!(span.start..span.end, 16..21);
+
+        let src = "import counter\nimport counter (bump, step)\n\ndefn main() do\n    bump()\n    bump()\n    raise [counter.count, step]\nend\n";
+        assert_eq!(raised(run_with("imports-module", &counter, src)), "[2, 1]");
+    }
+
+    /// The warnings compiling `src` gives, with the source text they point at.
+    fn warnings(src: &str) -> Vec<(String, &str)> {
+        let program = compile(src, &mut Rodeo::new(), 0).unwrap();
+        program
+            .warnings
+            .into_iter()
+            .map(|w| (w.message, &src[w.span.start..w.span.end]))
+            .collect()
+    }
+
+    #[test]
+    fn warns_about_loops_that_never_end() {
+        let src = "defn main() do\n    while true do\n        nil\n    end\nend\n";
+        assert_eq!(
+            warnings(src),
+            [(
+                "`while true` loop without `break` never terminates".to_owned(),
+                "while"
+            )]
+        );
+        // A `break` in a nested loop doesn't leave the outer one
+        let src = "defn main() do\n    while true do\n        loop\n            break\n        end\n    end\nend\n";
+        assert_eq!(warnings(src).len(), 1);
+        let src = "defn main() do\n    while true do\n        try\n            break\n        rescue e do\n            nil\n        end\n    end\nend\n";
+        assert!(warnings(src).is_empty());
+    }
+
+    #[test]
+    fn warns_about_unreachable_match_arms() {
+        let src = "defn main() do
+    match [1, 2] do
+        [a, _] if a == 0 => a,
+        [1, b] => b,
+        [1, 2] => 0,
+        [x, y] => x,
+        [3, 4] => 0,
+        _ => nil,
+    end
+end
+";
+        let unreachable = "unreachable match arm".to_owned();
+        assert_eq!(
+            warnings(src),
+            [(unreachable.clone(), "[1, 2]"), (unreachable, "[3, 4]")]
+        );
+    }
+
+    #[test]
+    fn loop_variables_go_out_of_scope() {
+        let src = "defn main() do
+    pairs := []
+    for i in 0..2 do
+        for j in [\"a\", \"b\"] do
+            pairs.push([i, j])
+        end
+    end
+    for i in [5] do
+        pairs.push(i)
+    end
+    raise pairs
+end
+";
+        assert_eq!(
+            raised(run(src)),
+            r#"[[0, "a"], [0, "b"], [1, "a"], [1, "b"], 5]"#
         );
-        Ok(Vm::new(code, self.consts, self.interner))
     }
 }
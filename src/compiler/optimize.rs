@@ -0,0 +1,449 @@
+//! Optimisations over the code of a function, run before it's finalized.
+
+use std::collections::HashMap;
+
+use lasso::{Rodeo, Spur};
+
+use super::IncompleteFuncProto;
+use crate::eval::{ConstValue, Opcode, Value};
+
+/// How much to optimise, as given by `-O`.
+///
+/// - `0` leaves the code as compiled
+/// - `1` dedupes constants, simplifies jump chains and drops unreachable code
+/// - `2` also folds constant arithmetic and comparisons
+pub type OptLevel = u8;
+
+/// The hashable form of a [`ConstValue`]. Floats compare by their bits, so
+/// that `-0.0` and `0.0` stay distinct constants.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstKey {
+    Int(i64),
+    Uint(u64),
+    Float(u64),
+    Bool(bool),
+    Str(Spur),
+}
+
+impl From<ConstValue> for ConstKey {
+    fn from(value: ConstValue) -> Self {
+        match value {
+            ConstValue::Int(i) => ConstKey::Int(i),
+            ConstValue::Uint(u) => ConstKey::Uint(u),
+            ConstValue::Float(f) => ConstKey::Float(f.to_bits()),
+            ConstValue::Bool(b) => ConstKey::Bool(b),
+            ConstValue::Str(s) => ConstKey::Str(s),
+        }
+    }
+}
+
+pub(super) struct Optimizer<'c> {
+    level: OptLevel,
+    interner: &'c Rodeo,
+    /// Index of every distinct value in the constant pool of the function
+    consts: HashMap<ConstKey, usize>,
+}
+
+impl<'c> Optimizer<'c> {
+    pub fn new(level: OptLevel, interner: &'c Rodeo) -> Self {
+        Self {
+            level,
+            interner,
+            consts: HashMap::default(),
+        }
+    }
+
+    pub fn run(&mut self, func: &mut IncompleteFuncProto) {
+        if self.level == 0 {
+            return;
+        }
+        self.dedupe_consts(func);
+        // Each pass can open up opportunities for the others
+        loop {
+            let mut changed = false;
+            if self.level >= 2 {
+                changed |= self.fold_constants(func);
+            }
+            changed |= thread_jumps(func);
+            changed |= remove_dead_code(func);
+            if !changed {
+                break;
+            }
+        }
+        // Drop the constants that were folded away
+        self.dedupe_consts(func);
+    }
+
+    /// Rebuilds the constant pool with only the distinct values that are used.
+    fn dedupe_consts(&mut self, func: &mut IncompleteFuncProto) {
+        self.consts.clear();
+        let old = std::mem::take(&mut func.consts);
+        for op in &mut func.code {
+            if let Opcode::Const(i) = op {
+                *i = *self.consts.entry(old[*i].into()).or_insert_with(|| {
+                    func.consts.push(old[*i]);
+                    func.consts.len() - 1
+                });
+            }
+        }
+    }
+
+    /// The index of `value` in the constant pool, adding it if it's not there yet.
+    fn intern(&mut self, func: &mut IncompleteFuncProto, value: ConstValue) -> usize {
+        *self.consts.entry(value.into()).or_insert_with(|| {
+            func.consts.push(value);
+            func.consts.len() - 1
+        })
+    }
+
+    /// The value an instruction pushes, if it's known at compile time.
+    fn constant(func: &IncompleteFuncProto, op: &Opcode) -> Option<Value> {
+        match *op {
+            Opcode::Const(i) => Some(func.consts[i].into()),
+            Opcode::Bool(b) => Some(Value::Bool(b)),
+            Opcode::Nil => Some(Value::Nil),
+            _ => None,
+        }
+    }
+
+    /// An instruction pushing `value`, if it can be a constant.
+    fn push_constant(&mut self, func: &mut IncompleteFuncProto, value: Value) -> Option<Opcode> {
+        let value = match value {
+            Value::Bool(b) => return Some(Opcode::Bool(b)),
+            Value::Nil => return Some(Opcode::Nil),
+            Value::Int(i) => ConstValue::Int(i),
+            Value::Uint(u) => ConstValue::Uint(u),
+            Value::Float(f) => ConstValue::Float(f),
+            _ => return None,
+        };
+        Some(Opcode::Const(self.intern(func, value)))
+    }
+
+    /// Evaluates a binary operation the way the VM would, unless it would fail.
+    fn binary(&self, op: &Opcode, lhs: &Value, rhs: &Value) -> Option<Value> {
+        let int = |f: fn(i128, i128) -> Option<i128>| {
+            f(lhs.as_int()?, rhs.as_int()?).and_then(crate::eval::int_value)
+        };
+        let ordering = || match (lhs, rhs) {
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            _ => Some(lhs.as_int()?.cmp(&rhs.as_int()?)),
+        };
+        match (op, lhs, rhs) {
+            (Opcode::Add, Value::Float(a), Value::Float(b)) => Some(Value::Float(a + b)),
+            (Opcode::Sub, Value::Float(a), Value::Float(b)) => Some(Value::Float(a - b)),
+            (Opcode::Add, ..) => int(i128::checked_add),
+            (Opcode::Sub, ..) => int(i128::checked_sub),
+            (Opcode::Eq, ..) => Some(Value::Bool(lhs.equals(rhs, self.interner))),
+            (Opcode::Ne, ..) => Some(Value::Bool(!lhs.equals(rhs, self.interner))),
+            (Opcode::Lt, ..) => Some(Value::Bool(ordering()?.is_lt())),
+            (Opcode::Le, ..) => Some(Value::Bool(ordering()?.is_le())),
+            (Opcode::Gt, ..) => Some(Value::Bool(ordering()?.is_gt())),
+            (Opcode::Ge, ..) => Some(Value::Bool(ordering()?.is_ge())),
+            _ => None,
+        }
+    }
+
+    /// Replaces operations on constants with their result, and branches on
+    /// constants with the branch taken.
+    fn fold_constants(&mut self, func: &mut IncompleteFuncProto) -> bool {
+        let targets = jump_targets(&func.code);
+        let mut dead = vec![false; func.code.len()];
+        let mut changed = false;
+        let mut i = 0;
+        while i < func.code.len() {
+            let lhs = match Self::constant(func, &func.code[i]) {
+                Some(lhs) => lhs,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+            // Nothing may jump into the middle of the folded instructions
+            let next = func.code.get(i + 1).filter(|_| !targets[i + 1]);
+            match next {
+                Some(Opcode::Pop) => {
+                    dead[i] = true;
+                    dead[i + 1] = true;
+                    changed = true;
+                    i += 2;
+                    continue;
+                }
+                Some(&Opcode::JumpIfFalse(target)) => {
+                    if lhs.is_truthy() {
+                        dead[i] = true;
+                    } else {
+                        func.code[i] = Opcode::Jump(target);
+                    }
+                    dead[i + 1] = true;
+                    changed = true;
+                    i += 2;
+                    continue;
+                }
+                _ => {}
+            }
+            let folded = next
+                .and_then(|next| Self::constant(func, next))
+                .zip(func.code.get(i + 2).filter(|_| !targets[i + 2]))
+                .and_then(|(rhs, op)| self.binary(op, &lhs, &rhs));
+            match folded.and_then(|value| self.push_constant(func, value)) {
+                Some(op) => {
+                    func.code[i] = op;
+                    func.spans[i] = func.spans[i + 2].clone();
+                    dead[i + 1] = true;
+                    dead[i + 2] = true;
+                    changed = true;
+                    i += 3;
+                }
+                None => i += 1,
+            }
+        }
+        remove(func, &dead);
+        changed
+    }
+}
+
+fn jump_target(op: &Opcode) -> Option<usize> {
+    match *op {
+        Opcode::Jump(target)
+        | Opcode::JumpIfFalse(target)
+        | Opcode::IterNext(target)
+        | Opcode::PushHandler(target) => Some(target),
+        _ => None,
+    }
+}
+
+fn set_jump_target(op: &mut Opcode, to: usize) {
+    match op {
+        Opcode::Jump(target)
+        | Opcode::JumpIfFalse(target)
+        | Opcode::IterNext(target)
+        | Opcode::PushHandler(target) => *target = to,
+        _ => {}
+    }
+}
+
+/// Whether each address, including the one past the end, is jumped to.
+fn jump_targets(code: &[Opcode]) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    for target in code.iter().filter_map(jump_target) {
+        targets[target] = true;
+    }
+    targets
+}
+
+/// Removes the instructions marked as dead, retargeting jumps at them to the
+/// instruction that follows.
+fn remove(func: &mut IncompleteFuncProto, dead: &[bool]) {
+    if !dead.contains(&true) {
+        return;
+    }
+    let mut moved = Vec::with_capacity(dead.len() + 1);
+    let mut kept = 0;
+    for &dead in dead {
+        moved.push(kept);
+        kept += usize::from(!dead);
+    }
+    moved.push(kept);
+
+    let code = std::mem::take(&mut func.code);
+    let spans = std::mem::take(&mut func.spans);
+    for ((mut op, span), _) in code.into_iter().zip(spans).zip(dead).filter(|(_, &d)| !d) {
+        if let Some(target) = jump_target(&op) {
+            set_jump_target(&mut op, moved[target]);
+        }
+        func.code.push(op);
+        func.spans.push(span);
+    }
+}
+
+/// Points jumps at unconditional jumps straight to their final target, and
+/// drops jumps to the next instruction.
+fn thread_jumps(func: &mut IncompleteFuncProto) -> bool {
+    let mut changed = false;
+    for i in 0..func.code.len() {
+        let mut target = match jump_target(&func.code[i]) {
+            Some(target) => target,
+            None => continue,
+        };
+        // Bounded, in case of a loop made only of jumps
+        for _ in 0..func.code.len() {
+            match func.code.get(target) {
+                Some(&Opcode::Jump(next)) if next != target => target = next,
+                _ => break,
+            }
+        }
+        if Some(target) != jump_target(&func.code[i]) {
+            set_jump_target(&mut func.code[i], target);
+            changed = true;
+        }
+    }
+
+    let dead = func
+        .code
+        .iter()
+        .enumerate()
+        .map(|(i, op)| matches!(op, &Opcode::Jump(target) if target == i + 1))
+        .collect::<Vec<_>>();
+    changed |= dead.contains(&true);
+    remove(func, &dead);
+    changed
+}
+
+/// Drops the instructions that can't be reached from the start of the function,
+/// such as the code following a `break` or `return`.
+fn remove_dead_code(func: &mut IncompleteFuncProto) -> bool {
+    let mut reachable = vec![false; func.code.len()];
+    let mut pending = vec![0];
+    while let Some(ip) = pending.pop() {
+        if ip >= func.code.len() || reachable[ip] {
+            continue;
+        }
+        reachable[ip] = true;
+        match func.code[ip] {
+            Opcode::Jump(target) => pending.push(target),
+            Opcode::JumpIfFalse(target)
+            | Opcode::IterNext(target)
+            | Opcode::PushHandler(target) => pending.extend([ip + 1, target]),
+            Opcode::Return | Opcode::Raise | Opcode::NoMatch => {}
+            _ => pending.push(ip + 1),
+        }
+    }
+    let dead = reachable.iter().map(|&r| !r).collect::<Vec<_>>();
+    let changed = dead.contains(&true);
+    remove(func, &dead);
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use lasso::Rodeo;
+
+    use super::*;
+    use crate::{
+        compiler::FuncProto,
+        eval::Vm,
+        testing::{compile, raised},
+    };
+
+    fn main(src: &str, level: OptLevel) -> FuncProto {
+        compile(src, &mut Rodeo::new(), level).unwrap().main
+    }
+
+    #[test]
+    fn folds_constants_and_drops_what_follows_a_raise() {
+        let src = "defn main() do\n    raise 40 + 2 - 1\nend\n";
+        let func = main(src, 2);
+        assert!(matches!(func.code[..], [Opcode::Const(0), Opcode::Raise]));
+        assert!(matches!(func.consts[..], [ConstValue::Uint(41)]));
+        let func = main(src, 1);
+        assert!(func.code.iter().any(|op| matches!(op, Opcode::Add)));
+    }
+
+    #[test]
+    fn leaves_operations_that_would_fail() {
+        let func = main(
+            "defn main() do\n    raise 18446744073709551615 + 1\nend\n",
+            2,
+        );
+        assert!(func.code.iter().any(|op| matches!(op, Opcode::Add)));
+    }
+
+    #[test]
+    fn folds_branches_on_constants() {
+        let src =
+            "defn main() do\n    while false do\n        raise 1\n    end\n    raise 2\nend\n";
+        let func = main(src, 2);
+        assert!(!func.code.iter().any(|op| jump_target(op).is_some()));
+        assert!(matches!(func.consts[..], [ConstValue::Uint(2)]));
+    }
+
+    #[test]
+    fn dedupes_constants() {
+        let func = main("defn main() do\n    raise [7, 7, 7]\nend\n", 1);
+        assert_eq!(func.consts.len(), 1);
+        assert_eq!(
+            main("defn main() do\n    raise [7, 7, 7]\nend\n", 0)
+                .consts
+                .len(),
+            3
+        );
+    }
+
+    const LOOPS: &str = r#"
+defn main() do
+    total := 0
+    for x in [1, 2, 3, 4] do
+        i := 0
+        while i < x do
+            i = i + 1
+            continue if i == 2
+            total = total + match i do
+                1 => 1,
+                _ => try
+                    raise i
+                rescue e do
+                    e
+                end,
+            end
+        end
+        break if x == 3
+    end
+    raise total
+end
+"#;
+
+    /// Whether a jump goes to another jump, or to the next instruction.
+    fn has_redundant_jump(func: &FuncProto) -> bool {
+        func.code
+            .iter()
+            .enumerate()
+            .any(|(i, op)| match jump_target(op) {
+                Some(target) => {
+                    matches!(func.code.get(target), Some(Opcode::Jump(_)))
+                        || matches!(op, Opcode::Jump(_)) && target == i + 1
+                }
+                None => false,
+            })
+    }
+
+    #[test]
+    fn threads_jumps() {
+        assert!(has_redundant_jump(&main(LOOPS, 0)));
+        assert!(!has_redundant_jump(&main(LOOPS, 1)));
+    }
+
+    #[test]
+    fn keeps_behaviour_at_every_level() {
+        for level in 0..=2 {
+            let mut interner = Rodeo::new();
+            let program = compile(LOOPS, &mut interner, level).unwrap();
+            let mut vm = Vm::new(program, &mut interner).unwrap();
+            assert_eq!(raised(vm.eval()), "6", "at -O{}", level);
+        }
+    }
+
+    #[test]
+    fn keeps_constants_per_function() {
+        let src = "defn a() do\n    [1, 1, 1.5]\nend\n\ndefn b() do\n    [2.5, 2, 2.5]\nend\n\ndefn main() do\n    raise [a(), b()]\nend\n";
+        for level in 0..=2 {
+            let mut interner = Rodeo::new();
+            let program = compile(src, &mut interner, level).unwrap();
+            let funcs = &program.modules.last().unwrap().funcs;
+            let a = &funcs[&interner.get("a").unwrap()];
+            let b = &funcs[&interner.get("b").unwrap()];
+            if level > 0 {
+                assert!(matches!(
+                    a.consts[..],
+                    [ConstValue::Uint(1), ConstValue::Float(f)] if f == 1.5
+                ));
+                assert!(matches!(
+                    b.consts[..],
+                    [ConstValue::Float(f), ConstValue::Uint(2)] if f == 2.5
+                ));
+            }
+            let mut vm = Vm::new(program, &mut interner).unwrap();
+            let value = raised(vm.eval());
+            assert_eq!(value, "[[1, 1, 1.5], [2.5, 2, 2.5]]", "at -O{}", level);
+        }
+    }
+}
@@ -0,0 +1,693 @@
+//! Stepping through programs as they run.
+//!
+//! The VM calls a [`Debugger`] before every instruction. A [`Session`] turns
+//! that into source level debugging: it maps instructions to lines through the
+//! span table, stops at breakpoints and after steps, and hands control to a
+//! [`Frontend`] while the program is stopped. Frontends are the command line
+//! debugger in this module and the DAP server in [`dap`].
+
+pub mod dap;
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    compiler::Warning,
+    error::{Error, SourceSpan},
+    eval::{Opcode, Value, Vm},
+};
+
+/// Looks at the VM before every instruction.
+pub trait Debugger {
+    fn before(&mut self, vm: &Vm, op: &Opcode) -> Result<(), Error>;
+}
+
+/// Prints every instruction before it runs.
+pub struct Tracer;
+
+impl Debugger for Tracer {
+    fn before(&mut self, vm: &Vm, op: &Opcode) -> Result<(), Error> {
+        eprintln!("{}|\t{:?}", vm.frame_ip(0).unwrap_or_default(), op);
+        Ok(())
+    }
+}
+
+/// A line in a source file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub path: PathBuf,
+    /// Counted from 1
+    pub line: usize,
+    /// Counted from 1
+    pub column: usize,
+}
+
+/// Source files, for turning spans into lines.
+#[derive(Default)]
+pub struct Sources {
+    files: HashMap<PathBuf, Option<SourceFile>>,
+}
+
+struct SourceFile {
+    path: PathBuf,
+    text: String,
+    line_starts: Vec<usize>,
+}
+
+impl Sources {
+    pub fn location(&mut self, span: &SourceSpan) -> Option<Location> {
+        let file = self.file(&span.path)?;
+        let line = file
+            .line_starts
+            .partition_point(|&start| start <= span.start);
+        let column = file.text[file.line_starts[line - 1]..span.start.min(file.text.len())]
+            .chars()
+            .count()
+            + 1;
+        Some(Location {
+            path: file.path.clone(),
+            line,
+            column,
+        })
+    }
+
+    /// The text of a line, without the line break.
+    pub fn line(&mut self, location: &Location) -> Option<&str> {
+        let file = self.file(&location.path)?;
+        let start = *file.line_starts.get(location.line - 1)?;
+        file.text[start..].lines().next()
+    }
+
+    fn file(&mut self, path: &Path) -> Option<&SourceFile> {
+        self.files
+            .entry(path.to_owned())
+            .or_insert_with(|| {
+                let text = fs::read_to_string(path).ok()?;
+                let line_starts = std::iter::once(0)
+                    .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+                    .collect();
+                Some(SourceFile {
+                    path: canonical(path),
+                    text,
+                    line_starts,
+                })
+            })
+            .as_ref()
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+/// Lines to stop at, by file.
+#[derive(Default)]
+pub struct Breakpoints {
+    lines: HashMap<PathBuf, BTreeSet<usize>>,
+}
+
+impl Breakpoints {
+    pub fn add(&mut self, path: &Path, line: usize) {
+        self.lines.entry(canonical(path)).or_default().insert(line);
+    }
+
+    /// Returns whether there was a breakpoint.
+    pub fn remove(&mut self, path: &Path, line: usize) -> bool {
+        self.lines
+            .get_mut(&canonical(path))
+            .is_some_and(|lines| lines.remove(&line))
+    }
+
+    /// Replaces the breakpoints of a file.
+    pub fn set(&mut self, path: &Path, lines: impl IntoIterator<Item = usize>) {
+        self.lines
+            .insert(canonical(path), lines.into_iter().collect());
+    }
+
+    pub fn contains(&self, location: &Location) -> bool {
+        self.lines
+            .get(&location.path)
+            .is_some_and(|lines| lines.contains(&location.line))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, usize)> {
+        self.lines
+            .iter()
+            .flat_map(|(path, lines)| lines.iter().map(move |&line| (path.as_path(), line)))
+    }
+}
+
+/// Why the program stopped.
+#[derive(Clone, Copy, Debug)]
+pub enum Stop {
+    Entry,
+    Breakpoint,
+    Step,
+    Pause,
+}
+
+/// How a stopped program goes on.
+#[derive(Clone, Copy, Debug)]
+pub enum Resume {
+    Continue,
+    /// To the next line, entering calls
+    StepIn,
+    /// To the next line of this frame or its callers
+    StepOver,
+    /// To the next line of a caller
+    StepOut,
+}
+
+/// What a frontend can look at and change while the program runs.
+pub struct Context<'a, 'i> {
+    pub vm: &'a Vm<'i>,
+    pub breakpoints: &'a mut Breakpoints,
+    pub sources: &'a mut Sources,
+}
+
+/// The user facing side of a debugging session.
+pub trait Frontend {
+    /// Called before the program starts with what the compiler warned about.
+    fn warned(&mut self, _warnings: &[Warning]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called when the program stops, returning once it should go on.
+    fn stopped(&mut self, cx: Context, reason: Stop) -> Result<Resume, Error>;
+
+    /// Called whenever the program reaches a new line without stopping,
+    /// returning whether it should stop anyway.
+    fn poll(&mut self, _cx: Context) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    /// Called once the program has finished.
+    fn exited(&mut self, _result: &Result<(), Error>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+enum Mode {
+    Run,
+    Entry,
+    StepIn,
+    /// Stepping over calls made at this depth
+    StepOver(usize),
+    /// Stepping out of the frame at this depth
+    StepOut(usize),
+}
+
+/// Drives a frontend from the instructions the VM runs.
+pub struct Session<F> {
+    frontend: Rc<RefCell<F>>,
+    breakpoints: Breakpoints,
+    sources: Sources,
+    mode: Mode,
+    /// The line each frame is on, outermost first, so that each line stops
+    /// once, even when a call made from it returns to it
+    lines: Vec<Option<(PathBuf, usize)>>,
+}
+
+impl<F: Frontend> Session<F> {
+    pub fn new(frontend: Rc<RefCell<F>>, breakpoints: Breakpoints, stop_on_entry: bool) -> Self {
+        Self {
+            frontend,
+            breakpoints,
+            sources: Sources::default(),
+            mode: match stop_on_entry {
+                true => Mode::Entry,
+                false => Mode::Run,
+            },
+            lines: Vec::new(),
+        }
+    }
+}
+
+impl<F: Frontend> Debugger for Session<F> {
+    fn before(&mut self, vm: &Vm, op: &Opcode) -> Result<(), Error> {
+        // Returns carry the span of the whole function, which isn't a line
+        // worth stopping at
+        if let Opcode::Return = op {
+            return Ok(());
+        }
+        let depth = vm.depth();
+        let location = match vm
+            .frame_span(0)
+            .and_then(|span| self.sources.location(&span))
+        {
+            Some(location) => location,
+            None => return Ok(()),
+        };
+        // Frames deeper than this one have returned
+        self.lines.resize(depth, None);
+        let here = Some((location.path.clone(), location.line));
+        if self.lines[depth - 1] == here {
+            return Ok(());
+        }
+        self.lines[depth - 1] = here;
+
+        let mut frontend = self.frontend.borrow_mut();
+        let mut reason = match self.mode {
+            Mode::Run => None,
+            Mode::Entry => Some(Stop::Entry),
+            Mode::StepIn => Some(Stop::Step),
+            Mode::StepOver(from) if depth <= from => Some(Stop::Step),
+            Mode::StepOut(from) if depth < from => Some(Stop::Step),
+            Mode::StepOver(_) | Mode::StepOut(_) => None,
+        };
+        if reason.is_none() && self.breakpoints.contains(&location) {
+            reason = Some(Stop::Breakpoint);
+        }
+        if reason.is_none() {
+            let cx = Context {
+                vm,
+                breakpoints: &mut self.breakpoints,
+                sources: &mut self.sources,
+            };
+            if frontend.poll(cx)? {
+                reason = Some(Stop::Pause);
+            }
+        }
+        if let Some(reason) = reason {
+            let cx = Context {
+                vm,
+                breakpoints: &mut self.breakpoints,
+                sources: &mut self.sources,
+            };
+            self.mode = match frontend.stopped(cx, reason)? {
+                Resume::Continue => Mode::Run,
+                Resume::StepIn => Mode::StepIn,
+                Resume::StepOver => Mode::StepOver(depth),
+                Resume::StepOut => Mode::StepOut(depth),
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Runs a program under a debugging session, telling the frontend about the
+/// compiler's `warnings` first and how the program ended last.
+pub fn run<F: Frontend + 'static>(
+    mut vm: Vm,
+    frontend: F,
+    warnings: &[Warning],
+    breakpoints: Breakpoints,
+    stop_on_entry: bool,
+) -> Result<(), Error> {
+    let frontend = Rc::new(RefCell::new(frontend));
+    frontend.borrow_mut().warned(warnings)?;
+    vm.set_debugger(box Session::new(
+        Rc::clone(&frontend),
+        breakpoints,
+        stop_on_entry,
+    ));
+    let result = vm.eval();
+    frontend.borrow_mut().exited(&result)?;
+    result
+}
+
+/// Evaluates a name followed by any number of `.field` and `[index]`
+/// accessors, as code running in `frame` would see it.
+pub fn evaluate(vm: &Vm, frame: usize, expr: &str) -> Option<Value> {
+    let expr = expr.trim();
+    let end = expr.find(['.', '[']).unwrap_or(expr.len());
+    let mut value = vm.lookup(frame, expr[..end].trim())?;
+    let mut rest = &expr[end..];
+    while !rest.is_empty() {
+        let (key, next) = if let Some(field) = rest.strip_prefix('.') {
+            let end = field.find(['.', '[']).unwrap_or(field.len());
+            (field[..end].trim(), &field[end..])
+        } else {
+            let index = rest.strip_prefix('[')?;
+            let end = index.find(']')?;
+            (index[..end].trim(), &index[end + 1..])
+        };
+        // Map keys are shown like literals, so string keys are quoted
+        let quoted = format!("{:?}", key.trim_matches('"'));
+        value = vm
+            .members(&value)
+            .into_iter()
+            .find(|(name, _)| name == key || *name == quoted)
+            .map(|(_, value)| value)?;
+        rest = next;
+    }
+    Some(value)
+}
+
+/// A debugger reading commands from stdin.
+#[derive(Default)]
+pub struct Cli {
+    /// The frame commands look at, counting from the innermost one
+    frame: usize,
+}
+
+const HELP: &str = "\
+commands:
+  c, continue             run to the next breakpoint
+  s, step                 step to the next line, entering calls
+  n, next                 step to the next line, stepping over calls
+  o, out                  run until the current function returns
+  b, break [FILE:]LINE    set a breakpoint, or list them without a line
+  d, delete [FILE:]LINE   remove a breakpoint
+  bt, backtrace           show the call frames
+  f, frame N              look at the Nth frame from the innermost one
+  l, locals               show the locals of the frame
+  g, globals              show the globals of the frame's module
+  p, print EXPR           show a value, like `p`, `p.x` or `items[0]`
+  q, quit                 stop the program";
+
+impl Cli {
+    fn location(&self, cx: &mut Context, frame: usize) -> Option<Location> {
+        cx.vm
+            .frame_span(frame)
+            .and_then(|span| cx.sources.location(&span))
+    }
+
+    fn show(&self, cx: &mut Context, value: &Value) {
+        eprintln!("{}", cx.vm.describe(value));
+        for (name, member) in cx.vm.members(value) {
+            eprintln!("  {} = {}", name, cx.vm.describe(&member));
+        }
+    }
+
+    /// Parses `[FILE:]LINE`, defaulting to the file of the current frame.
+    fn line(&self, cx: &mut Context, arg: &str) -> Option<(PathBuf, usize)> {
+        let (path, line) = match arg.rsplit_once(':') {
+            Some((path, line)) => (PathBuf::from(path), line),
+            None => (self.location(cx, self.frame)?.path, arg),
+        };
+        Some((path, line.trim().parse().ok()?))
+    }
+}
+
+impl Frontend for Cli {
+    fn stopped(&mut self, mut cx: Context, reason: Stop) -> Result<Resume, Error> {
+        self.frame = 0;
+        if let Some(location) = self.location(&mut cx, 0) {
+            let reason = match reason {
+                Stop::Entry => "program start",
+                Stop::Breakpoint => "breakpoint",
+                Stop::Step => "step",
+                Stop::Pause => "pause",
+            };
+            eprintln!(
+                "stopped at {}:{} ({})",
+                location.path.display(),
+                location.line,
+                reason
+            );
+            if let Some(text) = cx.sources.line(&location) {
+                eprintln!("{:>5} | {}", location.line, text);
+            }
+        }
+        let stdin = io::stdin();
+        loop {
+            eprint!("(oni) ");
+            io::stderr().flush().ok();
+            let mut command = String::new();
+            // Without any more commands the program runs to the end
+            if stdin.lock().read_line(&mut command).unwrap_or(0) == 0 {
+                eprintln!();
+                cx.breakpoints.lines.clear();
+                return Ok(Resume::Continue);
+            }
+            let (command, arg) = command
+                .trim()
+                .split_once(' ')
+                .unwrap_or((command.trim(), ""));
+            let arg = arg.trim();
+            match command {
+                "" => {}
+                "c" | "continue" => return Ok(Resume::Continue),
+                "s" | "step" => return Ok(Resume::StepIn),
+                "n" | "next" => return Ok(Resume::StepOver),
+                "o" | "out" => return Ok(Resume::StepOut),
+                "b" | "break" if arg.is_empty() => {
+                    for (path, line) in cx.breakpoints.iter() {
+                        eprintln!("{}:{}", path.display(), line);
+                    }
+                }
+                "b" | "break" => match self.line(&mut cx, arg) {
+                    Some((path, line)) => cx.breakpoints.add(&path, line),
+                    None => eprintln!("expected [FILE:]LINE"),
+                },
+                "d" | "delete" => match self.line(&mut cx, arg) {
+                    Some((path, line)) if cx.breakpoints.remove(&path, line) => {}
+                    Some(_) => eprintln!("no breakpoint there"),
+                    None => eprintln!("expected [FILE:]LINE"),
+                },
+                "bt" | "backtrace" => {
+                    for frame in 0..cx.vm.depth() {
+                        let name = cx.vm.frame_name(frame);
+                        let name = name.as_deref().unwrap_or("<module>");
+                        let marker = if frame == self.frame { '>' } else { ' ' };
+                        match self.location(&mut cx, frame) {
+                            Some(location) => eprintln!(
+                                "{} {} {} at {}:{}",
+                                marker,
+                                frame,
+                                name,
+                                location.path.display(),
+                                location.line
+                            ),
+                            None => eprintln!("{} {} {}", marker, frame, name),
+                        }
+                    }
+                }
+                "f" | "frame" => match arg.parse() {
+                    Ok(frame) if frame < cx.vm.depth() => self.frame = frame,
+                    _ => eprintln!("no frame {}", arg),
+                },
+                "l" | "locals" => {
+                    for (name, value) in cx.vm.locals(self.frame) {
+                        eprintln!("{} = {}", name, cx.vm.describe(&value));
+                    }
+                }
+                "g" | "globals" => {
+                    for (name, value) in cx.vm.globals(self.frame) {
+                        eprintln!("{} = {}", name, cx.vm.describe(&value));
+                    }
+                }
+                "p" | "print" => match evaluate(cx.vm, self.frame, arg) {
+                    Some(value) => self.show(&mut cx, &value),
+                    None => eprintln!("{} is not defined", arg),
+                },
+                "h" | "help" => eprintln!("{}", HELP),
+                "q" | "quit" => std::process::exit(0),
+                _ => eprintln!("unknown command {}, try `help`", command),
+            }
+        }
+    }
+
+    fn exited(&mut self, result: &Result<(), Error>) -> Result<(), Error> {
+        match result {
+            Ok(()) => eprintln!("program finished"),
+            Err(_) => eprintln!("program failed"),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lasso::Rodeo;
+
+    use super::*;
+    use crate::testing::{compile, compile_at, raised, temp_dir};
+
+    const SCRIPT: &str = "defn add(a, b) do
+    c := a + b
+    c
+end
+
+defn main() do
+    x := 1
+    y := add(x, 2)
+    z := y + 1
+    raise z
+end
+";
+
+    /// Where the program stopped: why, the line, the depth and the value of
+    /// the watched name.
+    type Stopped = (String, usize, usize, Option<String>);
+
+    /// Resumes as told, then continues.
+    struct Scripted {
+        resumes: Vec<Resume>,
+        watch: &'static str,
+        stops: Vec<Stopped>,
+    }
+
+    impl Frontend for Scripted {
+        fn stopped(&mut self, cx: Context, reason: Stop) -> Result<Resume, Error> {
+            let location = cx
+                .vm
+                .frame_span(0)
+                .and_then(|span| cx.sources.location(&span))
+                .unwrap();
+            let value = evaluate(cx.vm, 0, self.watch).map(|value| cx.vm.describe(&value));
+            let stop = (format!("{:?}", reason), location.line, cx.vm.depth(), value);
+            self.stops.push(stop);
+            Ok(match self.resumes.is_empty() {
+                true => Resume::Continue,
+                false => self.resumes.remove(0),
+            })
+        }
+    }
+
+    /// Writes the file a test reads, in a directory removed by [`remove`].
+    fn temp_file(test: &str, text: &str) -> PathBuf {
+        let path = temp_dir(test).join("test.oni");
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn remove(path: &Path) {
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    /// Debugs the script, returning where it stopped and what it raised.
+    fn debug(
+        test: &str,
+        resumes: Vec<Resume>,
+        watch: &'static str,
+        breakpoints: &[usize],
+        stop_on_entry: bool,
+    ) -> (Vec<Stopped>, String) {
+        let path = temp_file(test, SCRIPT);
+        let mut interner = Rodeo::new();
+        let program = compile_at(SCRIPT, &path, &mut interner, 0).unwrap();
+        let mut vm = Vm::new(program, &mut interner).unwrap();
+        let frontend = Rc::new(RefCell::new(Scripted {
+            resumes,
+            watch,
+            stops: Vec::new(),
+        }));
+        let mut lines = Breakpoints::default();
+        lines.set(&path, breakpoints.iter().copied());
+        let session = Session::new(Rc::clone(&frontend), lines, stop_on_entry);
+        vm.set_debugger(box session);
+        let value = raised(vm.eval());
+        remove(&path);
+        let stops = std::mem::take(&mut frontend.borrow_mut().stops);
+        (stops, value)
+    }
+
+    fn lines(stops: &[Stopped]) -> Vec<usize> {
+        stops.iter().map(|stop| stop.1).collect()
+    }
+
+    #[test]
+    fn steps_over_calls() {
+        let (stops, value) = debug("over", vec![Resume::StepOver; 10], "y", &[], true);
+        assert_eq!(value, "4");
+        assert_eq!(lines(&stops), [1, 7, 8, 9, 10]);
+        assert_eq!(stops[0].0, "Entry");
+        assert_eq!(stops[2].3, None);
+        assert_eq!(stops[3].3.as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn steps_into_and_out_of_calls() {
+        let resumes = vec![
+            Resume::StepOver,
+            Resume::StepOver,
+            Resume::StepIn,
+            Resume::StepOver,
+            Resume::StepOver,
+            Resume::StepOut,
+        ];
+        let (stops, _) = debug("in-out", resumes, "c", &[], true);
+        // Stepping in stops first where the parameters are bound
+        assert_eq!(lines(&stops), [1, 7, 8, 1, 2, 3, 9]);
+        let depths = stops.iter().map(|stop| stop.2).collect::<Vec<_>>();
+        assert_eq!(depths, [1, 1, 1, 2, 2, 2, 1]);
+        assert_eq!(stops[5].3.as_deref(), Some("3"));
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let (stops, value) = debug("breakpoints", Vec::new(), "c", &[3, 9], false);
+        assert_eq!(value, "4");
+        assert_eq!(
+            stops,
+            [
+                ("Breakpoint".to_owned(), 3, 2, Some("3".to_owned())),
+                ("Breakpoint".to_owned(), 9, 1, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn breakpoints_are_kept_by_file_and_line() {
+        let path = temp_file("breakpoint-set", SCRIPT);
+        let mut breakpoints = Breakpoints::default();
+        breakpoints.add(&path, 3);
+        breakpoints.add(&path, 9);
+        let location = |line| Location {
+            path: fs::canonicalize(&path).unwrap(),
+            line,
+            column: 1,
+        };
+        assert!(breakpoints.contains(&location(3)));
+        assert!(breakpoints.remove(&path, 3));
+        assert!(!breakpoints.remove(&path, 3));
+        assert!(!breakpoints.contains(&location(3)));
+        breakpoints.set(&path, [1, 2]);
+        let lines = breakpoints.iter().map(|(_, line)| line).collect::<Vec<_>>();
+        assert_eq!(lines, [1, 2]);
+        remove(&path);
+    }
+
+    #[test]
+    fn locations_count_columns_in_characters() {
+        let path = temp_file("sources", "x := 1\ny := \"é\" + z\n");
+        let mut sources = Sources::default();
+        let span = SourceSpan {
+            path: path.clone(),
+            start: 17,
+            end: 18,
+        };
+        let location = sources.location(&span).unwrap();
+        // At the `+`, after a two byte character
+        assert_eq!((location.line, location.column), (2, 10));
+        assert_eq!(sources.line(&location), Some("y := \"é\" + z"));
+        remove(&path);
+    }
+
+    /// Keeps the warnings it's told about, never stopping.
+    struct Warned(Rc<RefCell<Vec<String>>>);
+
+    impl Frontend for Warned {
+        fn warned(&mut self, warnings: &[Warning]) -> Result<(), Error> {
+            let messages = warnings.iter().map(|warning| warning.message.clone());
+            self.0.borrow_mut().extend(messages);
+            Ok(())
+        }
+
+        fn stopped(&mut self, _cx: Context, _reason: Stop) -> Result<Resume, Error> {
+            Ok(Resume::Continue)
+        }
+    }
+
+    #[test]
+    fn tells_frontends_about_warnings() {
+        let src = "defn main() do\n    raise match 1 do\n        _ => 1,\n        2 => 2,\n    end\nend\n";
+        let mut interner = Rodeo::new();
+        let program = compile(src, &mut interner, 0).unwrap();
+        let warnings = program.warnings.clone();
+        let vm = Vm::new(program, &mut interner).unwrap();
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        let frontend = Warned(Rc::clone(&messages));
+        let result = run(vm, frontend, &warnings, Breakpoints::default(), false);
+        assert_eq!(raised(result), "1");
+        assert_eq!(*messages.borrow(), ["unreachable match arm"]);
+    }
+}
@@ -0,0 +1,375 @@
+//! A Debug Adapter Protocol server, for debugging from an editor.
+//!
+//! The server listens on a TCP port rather than stdin and stdout, which stay
+//! with the program being debugged. There is a single thread, with id 1, and
+//! stack frame ids count from the innermost frame.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+use serde_json::{json, Value as Json};
+
+use super::{evaluate, Breakpoints, Context, Frontend, Resume, Stop};
+use crate::{compiler::Warning, error::Error, eval::Value};
+
+const THREAD: i64 = 1;
+
+/// What a variables reference points to.
+enum Handle {
+    Locals(usize),
+    Globals(usize),
+    Members(Value),
+}
+
+pub struct Dap {
+    requests: Receiver<Json>,
+    stream: TcpStream,
+    seq: i64,
+    /// Variables references handed out since the program last stopped
+    handles: Vec<Handle>,
+}
+
+/// How the client asked for the program to be run.
+pub struct Launch {
+    pub breakpoints: Breakpoints,
+    pub stop_on_entry: bool,
+}
+
+fn io_error(e: std::io::Error) -> Error {
+    Error::debugger(concat!(file!(), ":", line!()), e.to_string())
+}
+
+impl Dap {
+    /// Waits for a client on `addr` and goes through the handshake, returning
+    /// once it's done configuring.
+    pub fn accept(addr: &str) -> Result<(Self, Launch), Error> {
+        let listener = TcpListener::bind(addr).map_err(io_error)?;
+        eprintln!(
+            "waiting for a debugger on {}",
+            listener.local_addr().map_err(io_error)?
+        );
+        let (stream, _) = listener.accept().map_err(io_error)?;
+        let reader = BufReader::new(stream.try_clone().map_err(io_error)?);
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            let mut reader = reader;
+            while let Some(message) = read_message(&mut reader) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut dap = Self {
+            requests,
+            stream,
+            seq: 1,
+            handles: Vec::new(),
+        };
+
+        let mut launch = Launch {
+            breakpoints: Breakpoints::default(),
+            stop_on_entry: false,
+        };
+        let (mut launched, mut configured) = (false, false);
+        while !(launched && configured) {
+            let request = dap.recv()?;
+            let args = &request["arguments"];
+            match request["command"].as_str().unwrap_or_default() {
+                "initialize" => {
+                    dap.respond(
+                        &request,
+                        json!({
+                            "supportsConfigurationDoneRequest": true,
+                            "supportsEvaluateForHovers": true,
+                        }),
+                    )?;
+                    dap.event("initialized", json!({}))?;
+                }
+                "launch" | "attach" => {
+                    launch.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                    launched = true;
+                    dap.respond(&request, json!({}))?;
+                }
+                "configurationDone" => {
+                    configured = true;
+                    dap.respond(&request, json!({}))?;
+                }
+                _ => dap.common(&request, &mut launch.breakpoints)?,
+            }
+        }
+        Ok((dap, launch))
+    }
+
+    fn recv(&mut self) -> Result<Json, Error> {
+        self.requests.recv().map_err(|_| {
+            Error::debugger(
+                concat!(file!(), ":", line!()),
+                "the client disconnected".to_owned(),
+            )
+        })
+    }
+
+    fn send(&mut self, mut message: Json) -> Result<(), Error> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        let body = message.to_string();
+        write!(
+            self.stream,
+            "Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .map_err(io_error)
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> Result<(), Error> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) -> Result<(), Error> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> Result<(), Error> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    /// Answers the requests that don't depend on whether the program is
+    /// stopped.
+    fn common(&mut self, request: &Json, breakpoints: &mut Breakpoints) -> Result<(), Error> {
+        let args = &request["arguments"];
+        match request["command"].as_str().unwrap_or_default() {
+            "setBreakpoints" => {
+                let path = args["source"]["path"].as_str().unwrap_or_default();
+                let lines = args["breakpoints"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|breakpoint| breakpoint["line"].as_u64())
+                    .map(|line| line as usize)
+                    .collect::<Vec<_>>();
+                breakpoints.set(Path::new(path), lines.iter().copied());
+                let verified = lines
+                    .iter()
+                    .map(|line| json!({ "verified": true, "line": line }))
+                    .collect::<Vec<_>>();
+                self.respond(request, json!({ "breakpoints": verified }))
+            }
+            "setExceptionBreakpoints" => self.respond(request, json!({})),
+            "threads" => self.respond(
+                request,
+                json!({ "threads": [{ "id": THREAD, "name": "main" }] }),
+            ),
+            "disconnect" => {
+                self.respond(request, json!({}))?;
+                std::process::exit(0)
+            }
+            "continue" | "next" | "stepIn" | "stepOut" | "stackTrace" | "scopes" | "variables"
+            | "evaluate" => self.fail(request, "the program is running"),
+            command => self.fail(request, &format!("{} is not supported", command)),
+        }
+    }
+
+    fn handle(&mut self, handle: Handle) -> usize {
+        self.handles.push(handle);
+        self.handles.len()
+    }
+
+    /// A variable, with a reference to its members if it has any.
+    fn variable(&mut self, cx: &Context, name: String, value: Value) -> Json {
+        let reference = match cx.vm.members(&value).is_empty() {
+            true => 0,
+            false => self.handle(Handle::Members(value.clone())),
+        };
+        json!({
+            "name": name,
+            "value": cx.vm.describe(&value),
+            "variablesReference": reference,
+        })
+    }
+}
+
+impl Frontend for Dap {
+    fn stopped(&mut self, cx: Context, reason: Stop) -> Result<Resume, Error> {
+        let reason = match reason {
+            Stop::Entry => "entry",
+            Stop::Breakpoint => "breakpoint",
+            Stop::Step => "step",
+            Stop::Pause => "pause",
+        };
+        self.handles.clear();
+        self.event(
+            "stopped",
+            json!({ "reason": reason, "threadId": THREAD, "allThreadsStopped": true }),
+        )?;
+        loop {
+            let request = self.recv()?;
+            let args = &request["arguments"];
+            match request["command"].as_str().unwrap_or_default() {
+                "continue" => {
+                    self.respond(&request, json!({ "allThreadsContinued": true }))?;
+                    return Ok(Resume::Continue);
+                }
+                "next" => {
+                    self.respond(&request, json!({}))?;
+                    return Ok(Resume::StepOver);
+                }
+                "stepIn" => {
+                    self.respond(&request, json!({}))?;
+                    return Ok(Resume::StepIn);
+                }
+                "stepOut" => {
+                    self.respond(&request, json!({}))?;
+                    return Ok(Resume::StepOut);
+                }
+                "pause" => self.respond(&request, json!({}))?,
+                "stackTrace" => {
+                    let frames = (0..cx.vm.depth())
+                        .map(|frame| {
+                            let name = cx.vm.frame_name(frame);
+                            let location = cx
+                                .vm
+                                .frame_span(frame)
+                                .and_then(|span| cx.sources.location(&span));
+                            let mut json = json!({
+                                "id": frame,
+                                "name": name.as_deref().unwrap_or("<module>"),
+                                "line": 0,
+                                "column": 0,
+                            });
+                            if let Some(location) = location {
+                                json["source"] = json!({ "path": location.path });
+                                json["line"] = json!(location.line);
+                                json["column"] = json!(location.column);
+                            }
+                            json
+                        })
+                        .collect::<Vec<_>>();
+                    let total = frames.len();
+                    self.respond(
+                        &request,
+                        json!({ "stackFrames": frames, "totalFrames": total }),
+                    )?;
+                }
+                "scopes" => {
+                    let frame = args["frameId"].as_u64().unwrap_or(0) as usize;
+                    let locals = self.handle(Handle::Locals(frame));
+                    let globals = self.handle(Handle::Globals(frame));
+                    self.respond(
+                        &request,
+                        json!({ "scopes": [
+                            { "name": "Locals", "variablesReference": locals, "expensive": false },
+                            { "name": "Globals", "variablesReference": globals, "expensive": false },
+                        ] }),
+                    )?;
+                }
+                "variables" => {
+                    let reference = args["variablesReference"].as_u64().unwrap_or(0) as usize;
+                    let values = match reference.checked_sub(1).and_then(|i| self.handles.get(i)) {
+                        Some(Handle::Locals(frame)) => cx.vm.locals(*frame),
+                        Some(Handle::Globals(frame)) => cx.vm.globals(*frame),
+                        Some(Handle::Members(value)) => cx.vm.members(value),
+                        None => Vec::new(),
+                    };
+                    let variables = values
+                        .into_iter()
+                        .map(|(name, value)| self.variable(&cx, name, value))
+                        .collect::<Vec<_>>();
+                    self.respond(&request, json!({ "variables": variables }))?;
+                }
+                "evaluate" => {
+                    let frame = args["frameId"].as_u64().unwrap_or(0) as usize;
+                    let expr = args["expression"].as_str().unwrap_or_default();
+                    match evaluate(cx.vm, frame, expr) {
+                        Some(value) => {
+                            let variable = self.variable(&cx, String::new(), value);
+                            self.respond(
+                                &request,
+                                json!({
+                                    "result": variable["value"],
+                                    "variablesReference": variable["variablesReference"],
+                                }),
+                            )?;
+                        }
+                        None => self.fail(&request, &format!("{} is not defined", expr))?,
+                    }
+                }
+                _ => self.common(&request, cx.breakpoints)?,
+            }
+        }
+    }
+
+    fn warned(&mut self, warnings: &[Warning]) -> Result<(), Error> {
+        for warning in warnings {
+            self.event(
+                "output",
+                json!({ "category": "console", "output": format!("warning: {}\n", warning) }),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self, cx: Context) -> Result<bool, Error> {
+        loop {
+            let request = match self.requests.try_recv() {
+                Ok(request) => request,
+                Err(TryRecvError::Empty) => return Ok(false),
+                Err(TryRecvError::Disconnected) => std::process::exit(0),
+            };
+            if request["command"] == "pause" {
+                self.respond(&request, json!({}))?;
+                return Ok(true);
+            }
+            self.common(&request, cx.breakpoints)?;
+        }
+    }
+
+    fn exited(&mut self, result: &Result<(), Error>) -> Result<(), Error> {
+        if let Err(e) = result {
+            self.event(
+                "output",
+                json!({ "category": "stderr", "output": format!("{}\n", e) }),
+            )?;
+        }
+        self.event("exited", json!({ "exitCode": result.is_err() as i32 }))?;
+        self.event("terminated", json!({}))
+    }
+}
+
+/// Reads a message framed by a `Content-Length` header.
+fn read_message(reader: &mut impl BufRead) -> Option<Json> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    reader.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
@@ -1,20 +1,106 @@
+use std::{fmt, path::PathBuf};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum ErrorKind {
     #[error("Parser error")]
     Parser,
+    #[error("Syntax error: {reason}")]
+    Syntax { reason: String },
     #[error("Compiler error")]
     Compiler,
     #[error("Eval error")]
     Eval,
+    #[error("Index {index} out of bounds for length {len}")]
+    IndexOutOfBounds { index: i128, len: usize },
+    #[error("Key {key} not found")]
+    KeyNotFound { key: String },
+    #[error("Module {name} not found")]
+    ModuleNotFound { name: String },
+    #[error("Circular import: {cycle}")]
+    CircularImport { cycle: String },
+    #[error(
+        "`{keyword}` can't jump out of a `try` with an `ensure` block, which would be skipped"
+    )]
+    EnsureSkipped { keyword: &'static str },
+    #[error("{name} is already defined")]
+    AlreadyDefined { name: String },
+    #[error("{name} is mutable, so it can't be imported by name; import its module instead")]
+    MutableImport { name: String },
+    #[error("Global {name} is used before its definition")]
+    UseBeforeDefinition { name: String },
+    #[error("Uncaught error {value}")]
+    Raised { value: String },
+    #[error("{name} is not defined")]
+    Undefined { name: String },
+    #[error("Assignment to an immutable binding")]
+    ImmutableAssignment,
+    #[error("Value is not callable")]
+    NotCallable,
+    #[error("Expected {expected} arguments, got {got}")]
+    Arity { expected: usize, got: usize },
+    #[error("No match arm for {value}")]
+    NoMatch { value: String },
+    #[error("Invalid bytecode: {reason}")]
+    InvalidBytecode { reason: String },
+    #[error("Bytecode format version {found} is not supported, expected {expected}")]
+    BytecodeVersion { found: u16, expected: u16 },
+    #[error("Assembler error on line {line}: {reason}")]
+    Assembler { line: usize, reason: String },
+    #[error("Debugger error: {reason}")]
+    Debugger { reason: String },
+    #[error("Exceeded the {limit} limit")]
+    LimitExceeded { limit: Limit },
+    #[error("Interrupted")]
+    Interrupted,
+    #[error("Permission denied: {reason}")]
+    PermissionDenied { reason: String },
+    #[error("Invalid argument: {reason}")]
+    InvalidArgument { reason: String },
+    #[error("IO error: {reason}")]
+    Io { reason: String },
+}
+
+/// A resource the host can limit, see [`crate::eval::Limits`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Instructions,
+    CallDepth,
+    Stack,
+    Heap,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Instructions => "instruction",
+            Limit::CallDepth => "call depth",
+            Limit::Stack => "stack size",
+            Limit::Heap => "heap size",
+        })
+    }
+}
+
+/// Source location of the instruction that failed.
+#[derive(Clone, Debug)]
+pub struct SourceSpan {
+    pub path: PathBuf,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl fmt::Display for SourceSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}..{}", self.path.display(), self.start, self.end)
+    }
 }
 
 #[derive(Debug, Error)]
-#[error("{}@{}", .kind, .location)]
+#[error("{}@{}{}", .kind, .location, .span.as_ref().map(|s| format!(" at {}", s)).unwrap_or_default())]
 pub struct Error {
     location: &'static str,
     kind: ErrorKind,
+    span: Option<SourceSpan>,
 }
 
 impl Error {
@@ -22,6 +108,15 @@ impl Error {
         Self {
             location,
             kind: ErrorKind::Parser,
+            span: None,
+        }
+    }
+
+    pub fn syntax(location: &'static str, reason: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::Syntax { reason },
+            span: None,
         }
     }
 
@@ -29,6 +124,7 @@ impl Error {
         Self {
             location,
             kind: ErrorKind::Compiler,
+            span: None,
         }
     }
 
@@ -36,6 +132,214 @@ impl Error {
         Self {
             location,
             kind: ErrorKind::Eval,
+            span: None,
+        }
+    }
+
+    pub fn index_out_of_bounds(location: &'static str, index: i128, len: usize) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::IndexOutOfBounds { index, len },
+            span: None,
+        }
+    }
+
+    pub fn key_not_found(location: &'static str, key: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::KeyNotFound { key },
+            span: None,
+        }
+    }
+
+    pub fn module_not_found(location: &'static str, name: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::ModuleNotFound { name },
+            span: None,
+        }
+    }
+
+    pub fn circular_import(location: &'static str, cycle: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::CircularImport { cycle },
+            span: None,
+        }
+    }
+
+    pub fn ensure_skipped(location: &'static str, keyword: &'static str) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::EnsureSkipped { keyword },
+            span: None,
+        }
+    }
+
+    pub fn already_defined(location: &'static str, name: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::AlreadyDefined { name },
+            span: None,
+        }
+    }
+
+    pub fn mutable_import(location: &'static str, name: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::MutableImport { name },
+            span: None,
+        }
+    }
+
+    pub fn use_before_definition(location: &'static str, name: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::UseBeforeDefinition { name },
+            span: None,
+        }
+    }
+
+    pub fn raised(location: &'static str, value: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::Raised { value },
+            span: None,
+        }
+    }
+
+    pub fn undefined(location: &'static str, name: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::Undefined { name },
+            span: None,
+        }
+    }
+
+    pub fn immutable_assignment(location: &'static str) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::ImmutableAssignment,
+            span: None,
+        }
+    }
+
+    pub fn not_callable(location: &'static str) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::NotCallable,
+            span: None,
+        }
+    }
+
+    pub fn arity(location: &'static str, expected: usize, got: usize) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::Arity { expected, got },
+            span: None,
         }
     }
+
+    pub fn no_match(location: &'static str, value: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::NoMatch { value },
+            span: None,
+        }
+    }
+
+    pub fn invalid_bytecode(location: &'static str, reason: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::InvalidBytecode { reason },
+            span: None,
+        }
+    }
+
+    pub fn bytecode_version(location: &'static str, found: u16, expected: u16) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::BytecodeVersion { found, expected },
+            span: None,
+        }
+    }
+
+    pub fn assembler(location: &'static str, line: usize, reason: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::Assembler { line, reason },
+            span: None,
+        }
+    }
+
+    pub fn debugger(location: &'static str, reason: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::Debugger { reason },
+            span: None,
+        }
+    }
+
+    pub fn limit_exceeded(location: &'static str, limit: Limit) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::LimitExceeded { limit },
+            span: None,
+        }
+    }
+
+    pub fn interrupted(location: &'static str) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::Interrupted,
+            span: None,
+        }
+    }
+
+    pub fn permission_denied(location: &'static str, reason: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::PermissionDenied { reason },
+            span: None,
+        }
+    }
+
+    pub fn invalid_argument(location: &'static str, reason: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::InvalidArgument { reason },
+            span: None,
+        }
+    }
+
+    pub fn io(location: &'static str, reason: String) -> Self {
+        Self {
+            location,
+            kind: ErrorKind::Io { reason },
+            span: None,
+        }
+    }
+
+    /// Whether the error stops the program outright, without running its
+    /// `rescue` blocks, because the host asked for it.
+    pub fn is_abort(&self) -> bool {
+        matches!(
+            self.kind,
+            ErrorKind::LimitExceeded { .. } | ErrorKind::Interrupted
+        )
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    pub fn span(&self) -> Option<&SourceSpan> {
+        self.span.as_ref()
+    }
+
+    /// Attaches a source location, unless the error already has a more precise one.
+    pub fn with_span(mut self, span: SourceSpan) -> Self {
+        self.span.get_or_insert(span);
+        self
+    }
 }
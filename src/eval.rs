@@ -1,107 +1,1239 @@
+mod display;
+
+use indexmap::IndexMap;
 use lasso::{Rodeo, Spur};
 
-use crate::{compiler::FuncProto, error::Error};
-use std::{collections::HashMap, rc::Rc};
+use crate::{
+    builtins,
+    compiler::{FuncProto, ImportBinding, Program, TypeProto},
+    debug::Debugger,
+    error::{Error, ErrorKind, Limit, SourceSpan},
+    sandbox::{Capabilities, Capability},
+    stdlib::{self, random::Rng},
+    verify,
+};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::HashMap,
+    fmt,
+    ops::Range,
+    rc::Rc,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc,
+    },
+};
 
 pub struct Vm<'i> {
+    /// Builtins visible from every module
     env: HashMap<Spur, Slot>,
+    /// Globals of each module
+    modules: Vec<HashMap<Spur, Slot>>,
+    types: HashMap<Spur, Rc<TypeProto>>,
     interner: &'i mut Rodeo,
     stack: Vec<Value>,
-    code: Rc<[Opcode]>,
+    frames: Vec<Frame>,
+    /// Enclosing `try` blocks, innermost last
+    handlers: Vec<Handler>,
+    /// The value of the last `raise`, until it's rescued
+    raised: Option<Value>,
+    /// Module initializers that haven't run yet, dependencies first
+    inits: Vec<FuncProto>,
+    main: FuncProto,
+    debugger: Option<Box<dyn Debugger + 'i>>,
+    limits: Limits,
+    /// Instructions run so far
+    executed: u64,
+    /// Bytes allocated so far, by the estimates of [`Vm::allocate`]
+    allocated: usize,
+    interrupt: Arc<AtomicBool>,
+    capabilities: Capabilities,
+    /// The generator of the `random` module
+    rng: Rng,
+    /// Lists, maps and objects being written by [`Vm::display`] or
+    /// [`Vm::inspect`], outermost first
+    displaying: Vec<*const ()>,
+}
+
+/// Bounds on the resources a program may use, none by default. A program that
+/// goes over one stops with [`ErrorKind::LimitExceeded`], which `rescue`
+/// doesn't catch.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    /// Instructions run over the lifetime of the VM
+    pub instructions: Option<u64>,
+    /// Nested calls
+    pub call_depth: Option<usize>,
+    /// Values on the value stack
+    pub stack: Option<usize>,
+    /// Bytes allocated for strings, lists, maps and objects over the lifetime
+    /// of the VM. Values are reference counted, so freed memory isn't given
+    /// back; this bounds how much work a program can make the allocator do.
+    pub heap: Option<usize>,
+}
+
+/// Rough sizes of what programs allocate, for the heap limit.
+const VALUE_SIZE: usize = std::mem::size_of::<Value>();
+const ENTRY_SIZE: usize = std::mem::size_of::<(MapKey, Value)>();
+const FIELD_SIZE: usize = std::mem::size_of::<(Spur, Slot)>();
+
+struct Frame {
+    func: FuncProto,
     ip: usize,
-    consts: Vec<ConstValue>,
+    locals: HashMap<Spur, Slot>,
+}
+
+/// Where to resume when an error is raised inside a `try` block.
+struct Handler {
+    frames: usize,
+    stack: usize,
+    target: usize,
 }
 
 impl<'i> Vm<'i> {
-    pub fn new(code: Rc<[Opcode]>, consts: Vec<ConstValue>, interner: &'i mut Rodeo) -> Self {
-        let mut env = HashMap::default();
-        env.insert(
-            interner.get_or_intern_static("print"),
+    /// Loads a program, after checking that its code is safe to run.
+    pub fn new(program: Program, interner: &'i mut Rodeo) -> Result<Self, Error> {
+        verify::program(&program, interner)?;
+        let Program {
+            main,
+            modules,
+            types,
+            warnings: _,
+        } = program;
+        let mut globals = Vec::new();
+        globals.resize_with(modules.len(), HashMap::default);
+        let mut inits = Vec::with_capacity(modules.len());
+        for module in modules {
+            globals[module.index] = module
+                .funcs
+                .into_iter()
+                .map(|(name, func)| {
+                    (
+                        name,
+                        Slot {
+                            flags: Flags::ASSIGNED,
+                            value: Value::Func(RuntimeFunc::Virtual(func)),
+                        },
+                    )
+                })
+                .collect();
+            inits.push(module.init);
+        }
+        let mut types = types
+            .into_iter()
+            .map(|(name, ty)| (name, Rc::new(ty)))
+            .collect::<HashMap<_, _>>();
+        let builtin_types = [
+            ("Object", HashMap::default()),
+            ("String", builtins::string_methods(interner)),
+            ("List", builtins::list_methods(interner)),
+            ("Map", builtins::map_methods(interner)),
+            ("Error", HashMap::default()),
+        ];
+        for (builtin, methods) in builtin_types {
+            let name = interner.get_or_intern_static(builtin);
+            types.insert(
+                name,
+                Rc::new(TypeProto {
+                    name,
+                    fields: Rc::new([]),
+                    methods,
+                    ctor: None,
+                }),
+            );
+        }
+        let mut vm = Self {
+            env: HashMap::default(),
+            modules: globals,
+            types,
+            interner,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            handlers: Vec::new(),
+            raised: None,
+            inits,
+            main,
+            debugger: None,
+            limits: Limits::default(),
+            executed: 0,
+            allocated: 0,
+            interrupt: Arc::default(),
+            capabilities: Capabilities::none(),
+            rng: Rng::default(),
+            displaying: Vec::new(),
+        };
+        vm.register("len", builtins::len, None);
+        vm.register("to_string", builtins::to_string, None);
+        vm.register("inspect", builtins::inspect, None);
+        stdlib::register(&mut vm);
+        Ok(vm)
+    }
+
+    /// Makes a native function visible from every module. Natives that reach
+    /// outside the VM need a capability, which is checked before every call.
+    pub fn register(&mut self, name: &'static str, func: NativeFn, needs: Option<Capability>) {
+        self.env.insert(
+            self.interner.get_or_intern_static(name),
             Slot {
                 flags: Flags::ASSIGNED,
-                value: Value::Func(RuntimeFunc::Native(|vm| {
-                    let arg = vm.stack.pop().unwrap();
-                    println!("{:?}", arg);
-                })),
+                value: Value::Func(RuntimeFunc::Native(func, needs)),
             },
         );
-        Self {
-            env,
-            interner,
-            stack: Vec::new(),
-            code,
-            ip: 0,
-            consts,
+    }
+
+    /// Makes a module of natives and constants visible from every module.
+    pub fn register_module(
+        &mut self,
+        name: &'static str,
+        members: impl IntoIterator<Item = (&'static str, Value)>,
+    ) {
+        let globals = members
+            .into_iter()
+            .map(|(name, value)| {
+                (
+                    self.interner.get_or_intern_static(name),
+                    Slot {
+                        flags: Flags::ASSIGNED,
+                        value,
+                    },
+                )
+            })
+            .collect();
+        self.modules.push(globals);
+        self.env.insert(
+            self.interner.get_or_intern_static(name),
+            Slot {
+                flags: Flags::ASSIGNED,
+                value: Value::Module(self.modules.len() - 1),
+            },
+        );
+    }
+
+    /// Grants the program access to the outside world, which it has none of
+    /// by default.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    pub fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// A flag that stops the VM with [`ErrorKind::Interrupted`] before its next
+    /// instruction once set, from any thread. The VM clears it again when it
+    /// stops.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupt)
+    }
+
+    /// Counts an allocation against the heap limit.
+    pub fn allocate(&mut self, bytes: usize) -> Result<(), Error> {
+        self.allocated = self.allocated.saturating_add(bytes);
+        match self.limits.heap {
+            Some(max) if self.allocated > max => Err(Error::limit_exceeded(
+                concat!(file!(), ":", line!()),
+                Limit::Heap,
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the limits that apply before every instruction.
+    fn check_limits(&mut self) -> Result<(), Error> {
+        if self.interrupt.swap(false, atomic::Ordering::Relaxed) {
+            return Err(Error::interrupted(concat!(file!(), ":", line!())));
         }
+        self.executed += 1;
+        let limit = if self
+            .limits
+            .instructions
+            .is_some_and(|max| self.executed > max)
+        {
+            Limit::Instructions
+        } else if self.limits.stack.is_some_and(|max| self.stack.len() > max) {
+            Limit::Stack
+        } else {
+            return Ok(());
+        };
+        Err(Error::limit_exceeded(concat!(file!(), ":", line!()), limit))
+    }
+
+    /// Lets `debugger` look at the VM before every instruction.
+    pub fn set_debugger(&mut self, debugger: Box<dyn Debugger + 'i>) {
+        self.debugger = Some(debugger);
     }
 
+    /// Runs the initializer of every module, then `main`.
     pub fn eval(&mut self) -> Result<(), Error> {
-        loop {
-            let op = &self.code[self.ip];
-            println!("{}|\t{:?}", self.ip, op);
-            self.ip += 1;
-
-            match op {
-                Opcode::Defslot(s, f) => {
-                    if self
-                        .env
-                        .insert(
-                            *s,
-                            Slot {
-                                flags: *f,
-                                value: Value::Undefined,
-                            },
-                        )
-                        .is_some()
-                    {
-                        return Err(Error::eval(concat!(file!(), ":", line!())));
-                    }
+        for init in std::mem::take(&mut self.inits) {
+            self.invoke(Value::Func(RuntimeFunc::Virtual(init)), Vec::new(), None)?;
+        }
+        let main = Value::Func(RuntimeFunc::Virtual(self.main.clone()));
+        self.invoke(main, Vec::new(), None)?;
+        Ok(())
+    }
+
+    /// Executes instructions until only `depth` frames are left, with the
+    /// frames above it using the stack above `stack`.
+    fn run(&mut self, depth: usize, stack: usize) -> Result<(), Error> {
+        while self.frames.len() > depth {
+            let frame = self.frames.last_mut().unwrap();
+            let op = frame
+                .func
+                .code
+                .get(frame.ip)
+                .cloned()
+                .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+            if let Some(mut debugger) = self.debugger.take() {
+                let res = debugger.before(self, &op);
+                self.debugger = Some(debugger);
+                res?;
+            }
+            let frame = self.frames.last_mut().unwrap();
+            frame.ip += 1;
+
+            if let Err(e) = self.check_limits().and_then(|()| self.step(op)) {
+                let e = self.locate(e);
+                self.unwind(e, depth, stack)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Resumes at the innermost handler with the error on the stack, or gives up
+    /// on the frames above `depth` if there's no handler among them or the
+    /// error is an abort, dropping their handlers and everything they left
+    /// above `stack`.
+    fn unwind(&mut self, e: Error, depth: usize, stack: usize) -> Result<(), Error> {
+        match self.handlers.last() {
+            Some(handler) if handler.frames > depth && !e.is_abort() => {
+                let handler = self.handlers.pop().unwrap();
+                let value = self.error_value(e);
+                self.frames.truncate(handler.frames);
+                self.stack.truncate(handler.stack);
+                self.stack.push(value);
+                self.jump(handler.target)
+            }
+            _ => {
+                self.frames.truncate(depth);
+                self.handlers.retain(|handler| handler.frames <= depth);
+                self.stack.truncate(stack);
+                Err(e)
+            }
+        }
+    }
+
+    /// The value a `rescue` sees: the raised value, or an `Error` describing a
+    /// failure of the VM itself.
+    fn error_value(&mut self, e: Error) -> Value {
+        if let ErrorKind::Raised { .. } = e.kind() {
+            if let Some(value) = self.raised.take() {
+                return value;
+            }
+        }
+        self.error_object(e.kind().to_string())
+    }
+
+    fn error_object(&mut self, message: String) -> Value {
+        // Errors are made while unwinding, where going over the heap limit
+        // can't be reported anymore
+        self.allocated = self.allocated.saturating_add(message.len() + FIELD_SIZE);
+        let ty = self.interner.get_or_intern_static("Error");
+        let mut fields = HashMap::default();
+        fields.insert(
+            self.interner.get_or_intern_static("message"),
+            Slot {
+                flags: Flags::ASSIGNED,
+                value: Value::String(message),
+            },
+        );
+        Value::Object(Rc::new(RefCell::new(Object { ty, fields })))
+    }
+
+    /// Calls `callee` from native code and runs it to completion, returning its result.
+    pub fn invoke(
+        &mut self,
+        callee: Value,
+        args: Vec<Value>,
+        receiver: Option<Value>,
+    ) -> Result<Value, Error> {
+        let depth = self.frames.len();
+        let stack = self.stack.len();
+        let argc = args.len();
+        self.stack.extend(args);
+        self.call(callee, argc, receiver)?;
+        self.run(depth, stack)?;
+        self.pop()
+    }
+
+    /// Attaches the span of the instruction that was being executed to an error.
+    fn locate(&self, e: Error) -> Error {
+        match self
+            .frames
+            .last()
+            .and_then(|frame| frame.func.span(frame.ip - 1))
+        {
+            Some(span) => e.with_span(span),
+            None => e,
+        }
+    }
+
+    fn step(&mut self, op: Opcode) -> Result<(), Error> {
+        match op {
+            Opcode::Defslot(s, f) => {
+                let frame = self
+                    .frames
+                    .last_mut()
+                    .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+                if frame
+                    .locals
+                    .insert(
+                        s,
+                        Slot {
+                            flags: f,
+                            value: Value::Undefined,
+                        },
+                    )
+                    .is_some()
+                {
+                    return Err(Error::eval(concat!(file!(), ":", line!())));
                 }
+            }
 
-                Opcode::Assign(s) => {
-                    let val = self
-                        .stack
-                        .pop()
-                        .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
-                    self.env.entry(*s).and_modify(|slot| {
-                        slot.flags |= Flags::ASSIGNED;
-                        slot.value = val;
-                    });
-                }
-                Opcode::Read(s) => {
-                    let v = self
-                        .env
-                        .get(s)
-                        .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
-                    self.stack.push(v.value.clone());
-                }
-                Opcode::LoadField(_) => todo!(),
-                Opcode::StoreField(_) => todo!(),
-                Opcode::Call(name) => match self.env.get(name) {
-                    Some(Slot {
-                        flags: _,
-                        value: Value::Func(RuntimeFunc::Virtual(func)),
-                    }) => {
-                        self.code = Rc::clone(&func.code);
+            Opcode::DefGlobal(s, f) => {
+                let module = self.module()?;
+                let slot = Slot {
+                    flags: f,
+                    value: Value::Undefined,
+                };
+                if self.modules[module].insert(s, slot).is_some() {
+                    return Err(Error::eval(concat!(file!(), ":", line!())));
+                }
+            }
+            Opcode::Import(ImportBinding { name, module, item }) => {
+                let value = match item {
+                    Some(item) => self.global(module, item)?,
+                    None => Value::Module(module),
+                };
+                let slot = Slot {
+                    flags: Flags::ASSIGNED,
+                    value,
+                };
+                let importer = self.module()?;
+                self.modules[importer].insert(name, slot);
+            }
+            Opcode::Dropslot(s) => {
+                let frame = self
+                    .frames
+                    .last_mut()
+                    .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+                frame.locals.remove(&s);
+            }
+            Opcode::Assign(s) => {
+                let val = self.pop()?;
+                self.slot_mut(s)?.assign(val)?;
+            }
+            Opcode::Read(s) => {
+                let v = self.slot(s)?.value.clone();
+                self.stack.push(v);
+            }
+            Opcode::LoadField(name) => {
+                let object = match self.pop()? {
+                    Value::Module(module) => {
+                        let v = self.global(module, name)?;
+                        self.stack.push(v);
+                        return Ok(());
+                    }
+                    object => object.into_object()?,
+                };
+                let v = object
+                    .borrow()
+                    .fields
+                    .get(&name)
+                    .map(|slot| slot.value.clone())
+                    .ok_or_else(|| self.undefined(name))?;
+                self.stack.push(v);
+            }
+            Opcode::StoreField(name) => {
+                let val = self.pop()?;
+                let object = self.pop()?.into_object()?;
+                let mut object = object.borrow_mut();
+                object
+                    .fields
+                    .get_mut(&name)
+                    .ok_or_else(|| self.undefined(name))?
+                    .assign(val)?;
+            }
+            Opcode::DefField(name, flags) => {
+                self.allocate(FIELD_SIZE)?;
+                let val = self.pop()?;
+                let object = self.pop()?.into_object()?;
+                let mut object = object.borrow_mut();
+                if object.fields.contains_key(&name) {
+                    return Err(Error::eval(concat!(file!(), ":", line!())));
+                }
+                object.fields.insert(
+                    name,
+                    Slot {
+                        flags: flags | Flags::ASSIGNED,
+                        value: val,
+                    },
+                );
+            }
+            Opcode::Call(name, argc) => {
+                let callee = self.slot(name)?.value.clone();
+                self.call(callee, argc, None)?;
+            }
+            Opcode::CallValue(argc) => {
+                let callee_idx = self
+                    .stack
+                    .len()
+                    .checked_sub(argc + 1)
+                    .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+                let callee = self.stack.remove(callee_idx);
+                self.call(callee, argc, None)?;
+            }
+            Opcode::CallMethod(name, argc) => {
+                let receiver_idx = self
+                    .stack
+                    .len()
+                    .checked_sub(argc + 1)
+                    .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+                let receiver = self.stack.remove(receiver_idx);
+                match receiver {
+                    // Qualified calls don't pass the module as a receiver
+                    Value::Module(module) => {
+                        let func = self.global(module, name)?;
+                        self.call(func, argc, None)?;
+                    }
+                    receiver => {
+                        let method = self.method(&receiver, name)?;
+                        self.call(method, argc, Some(receiver))?;
+                    }
+                }
+            }
+            Opcode::Return => {
+                let val = self.pop()?;
+                self.frames.pop();
+                self.stack.push(val);
+            }
+            Opcode::InitField(name) => {
+                let val = self.pop()?;
+                let object = self.pop()?.into_object()?;
+                let mut object = object.borrow_mut();
+                object
+                    .fields
+                    .get_mut(&name)
+                    .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?
+                    .value = val;
+            }
+            Opcode::Seal => {
+                let object = self.pop()?.into_object()?;
+                for slot in object.borrow_mut().fields.values_mut() {
+                    slot.flags |= Flags::ASSIGNED;
+                }
+                self.stack.push(Value::Object(object));
+            }
+            Opcode::New(ty, argc) => {
+                let ty = self
+                    .types
+                    .get(&ty)
+                    .cloned()
+                    .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+                match &ty.ctor {
+                    Some(ctor) => {
+                        self.allocate(ty.fields.len() * FIELD_SIZE)?;
+                        let object = Object::new(&ty);
+                        self.call(
+                            Value::Func(RuntimeFunc::Virtual(ctor.clone())),
+                            argc,
+                            Some(Value::Object(Rc::new(RefCell::new(object)))),
+                        )?;
                     }
-                    Some(Slot {
-                        flags: _,
-                        value: Value::Func(RuntimeFunc::Native(func)),
-                    }) => {
-                        func(self);
+                    None => {
+                        let val = self.construct_builtin(ty.name, argc)?;
+                        self.stack.push(val);
                     }
-                    Some(_) => return Err(Error::eval(concat!(file!(), ":", line!()))),
+                }
+            }
+            Opcode::MakeList(len) => {
+                let at = self
+                    .stack
+                    .len()
+                    .checked_sub(len)
+                    .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+                self.allocate(len * VALUE_SIZE)?;
+                let items = self.stack.split_off(at);
+                self.stack.push(Value::List(Rc::new(RefCell::new(items))));
+            }
+            Opcode::MakeMap(len) => {
+                let at = self
+                    .stack
+                    .len()
+                    .checked_sub(len * 2)
+                    .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+                self.allocate(len * ENTRY_SIZE)?;
+                let items = self.stack.split_off(at);
+                let mut map = IndexMap::with_capacity(len);
+                for entry in items.chunks(2) {
+                    map.insert(MapKey::new(&entry[0], self.interner)?, entry[1].clone());
+                }
+                self.stack.push(Value::Map(Rc::new(RefCell::new(map))));
+            }
+            Opcode::LoadIndex => {
+                let index = self.pop()?;
+                let val = match self.pop()? {
+                    Value::List(list) => {
+                        let list = list.borrow();
+                        let index = list_index(&index, list.len())?;
+                        list[index].clone()
+                    }
+                    Value::Map(map) => {
+                        let key = MapKey::new(&index, self.interner)?;
+                        let map = map.borrow();
+                        map.get(&key).cloned().ok_or_else(|| {
+                            Error::key_not_found(concat!(file!(), ":", line!()), key.to_string())
+                        })?
+                    }
+                    _ => return Err(Error::eval(concat!(file!(), ":", line!()))),
+                };
+                self.stack.push(val);
+            }
+            Opcode::StoreIndex => {
+                let val = self.pop()?;
+                let index = self.pop()?;
+                match self.pop()? {
+                    Value::List(list) => {
+                        let mut list = list.borrow_mut();
+                        let index = list_index(&index, list.len())?;
+                        list[index] = val;
+                    }
+                    Value::Map(map) => {
+                        let key = MapKey::new(&index, self.interner)?;
+                        if !map.borrow().contains_key(&key) {
+                            self.allocate(ENTRY_SIZE)?;
+                        }
+                        map.borrow_mut().insert(key, val);
+                    }
+                    _ => return Err(Error::eval(concat!(file!(), ":", line!()))),
+                }
+            }
+            Opcode::TestList(len) => {
+                let matches = match self.stack.last() {
+                    Some(Value::List(list)) => list.borrow().len() == len,
+                    Some(_) => false,
                     None => return Err(Error::eval(concat!(file!(), ":", line!()))),
-                },
-                &Opcode::Const(c) => self.stack.push(self.consts[c].into()),
+                };
+                self.stack.push(Value::Bool(matches));
+            }
+            Opcode::TestKey => {
+                let key = self.pop()?;
+                let has = match self.pop()? {
+                    Value::Map(map) => MapKey::new(&key, self.interner)
+                        .map(|key| map.borrow().contains_key(&key))
+                        .unwrap_or(false),
+                    Value::Object(object) => match key {
+                        Value::Str(_) | Value::String(_) => self
+                            .interner
+                            .get(self.as_str(&key))
+                            .is_some_and(|field| object.borrow().fields.contains_key(&field)),
+                        _ => false,
+                    },
+                    _ => false,
+                };
+                self.stack.push(Value::Bool(has));
+            }
+            Opcode::LoadKey => {
+                let key = self.pop()?;
+                let val = match self.pop()? {
+                    Value::Object(object) if matches!(key, Value::Str(_) | Value::String(_)) => {
+                        let field = self
+                            .interner
+                            .get(self.as_str(&key))
+                            .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+                        let object = object.borrow();
+                        object
+                            .fields
+                            .get(&field)
+                            .map(|slot| slot.value.clone())
+                            .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?
+                    }
+                    container => {
+                        self.stack.push(container);
+                        self.stack.push(key);
+                        return self.step(Opcode::LoadIndex);
+                    }
+                };
+                self.stack.push(val);
+            }
+            Opcode::NoMatch => {
+                let value = self.pop()?;
+                return Err(Error::no_match(
+                    concat!(file!(), ":", line!()),
+                    self.inspect(&value),
+                ));
+            }
+            Opcode::Jump(target) => self.jump(target)?,
+            Opcode::JumpIfFalse(target) => {
+                if !self.pop()?.is_truthy() {
+                    self.jump(target)?;
+                }
+            }
+            Opcode::Iter => {
+                let iterable = self.pop()?;
+                let iter = self.iter(iterable)?;
+                self.stack.push(Value::Iter(Rc::new(RefCell::new(iter))));
+            }
+            Opcode::IterNext(exhausted) => {
+                let iter = match self.stack.last() {
+                    Some(Value::Iter(iter)) => Rc::clone(iter),
+                    _ => return Err(Error::eval(concat!(file!(), ":", line!()))),
+                };
+                let next = iter.borrow_mut().next();
+                let next = match next {
+                    IterNext::Value(value) => Some(value),
+                    IterNext::Done => None,
+                    IterNext::Call(object) => {
+                        let next = self.interner.get_or_intern_static("next");
+                        let method = self.method(&object, next)?;
+                        match self.invoke(method, Vec::new(), Some(object))? {
+                            Value::Nil => None,
+                            value => Some(value),
+                        }
+                    }
+                };
+                match next {
+                    Some(value) => self.stack.push(value),
+                    None => self.jump(exhausted)?,
+                }
+            }
+            Opcode::MakeRange(inclusive) => {
+                let end = self.pop()?;
+                let start = self.pop()?;
+                match (start.as_int(), end.as_int()) {
+                    (Some(start), Some(end)) => self
+                        .stack
+                        .push(Value::Range(start, end + inclusive as i128)),
+                    _ => return Err(Error::eval(concat!(file!(), ":", line!()))),
+                }
+            }
+            Opcode::Add => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+                let val = match (&lhs, &rhs) {
+                    (Value::Float(a), Value::Float(b)) => Value::Float(a + b),
+                    (Value::String(_) | Value::Str(_), Value::String(_) | Value::Str(_)) => {
+                        self.allocate(self.as_str(&lhs).len() + self.as_str(&rhs).len())?;
+                        let mut s = self.as_str(&lhs).to_owned();
+                        s.push_str(self.as_str(&rhs));
+                        Value::String(s)
+                    }
+                    _ => match (lhs.as_int(), rhs.as_int()) {
+                        (Some(a), Some(b)) => int_value(a + b)
+                            .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?,
+                        _ => return Err(Error::eval(concat!(file!(), ":", line!()))),
+                    },
+                };
+                self.stack.push(val);
+            }
+            Opcode::Sub => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+                let val = match (&lhs, &rhs) {
+                    (Value::Float(a), Value::Float(b)) => Value::Float(a - b),
+                    _ => match (lhs.as_int(), rhs.as_int()) {
+                        (Some(a), Some(b)) => int_value(a - b)
+                            .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?,
+                        _ => return Err(Error::eval(concat!(file!(), ":", line!()))),
+                    },
+                };
+                self.stack.push(val);
+            }
+            Opcode::Eq => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+                let eq = lhs.equals(&rhs, self.interner);
+                self.stack.push(Value::Bool(eq));
+            }
+            Opcode::Ne => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+                let eq = lhs.equals(&rhs, self.interner);
+                self.stack.push(Value::Bool(!eq));
+            }
+            Opcode::Lt | Opcode::Le | Opcode::Gt | Opcode::Ge => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+                let ord = self.compare(&lhs, &rhs)?;
+                let res = match op {
+                    Opcode::Lt => ord.is_lt(),
+                    Opcode::Le => ord.is_le(),
+                    Opcode::Gt => ord.is_gt(),
+                    _ => ord.is_ge(),
+                };
+                self.stack.push(Value::Bool(res));
+            }
+            Opcode::PushHandler(target) => self.handlers.push(Handler {
+                frames: self.frames.len(),
+                stack: self.stack.len(),
+                target,
+            }),
+            Opcode::PopHandler => {
+                self.handlers
+                    .pop()
+                    .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+            }
+            Opcode::Raise => {
+                let value = self.pop()?;
+                return Err(self.raise(value));
+            }
+            Opcode::EndEnsure => {
+                if self.pop()?.is_truthy() {
+                    let value = self.pop()?;
+                    return Err(self.raise(value));
+                }
             }
+            Opcode::Bool(b) => self.stack.push(Value::Bool(b)),
+            Opcode::Const(c) => {
+                let frame = self
+                    .frames
+                    .last()
+                    .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+                let value = frame
+                    .func
+                    .consts
+                    .get(c)
+                    .copied()
+                    .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+                self.stack.push(value.into());
+            }
+            Opcode::Nil => self.stack.push(Value::Nil),
+            Opcode::Dup => {
+                let top = self
+                    .stack
+                    .last()
+                    .cloned()
+                    .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+                self.stack.push(top);
+            }
+            Opcode::Pop => {
+                self.pop()?;
+            }
+        }
+        Ok(())
+    }
 
-            if self.ip == self.code.len() {
-                break;
+    fn call(&mut self, callee: Value, argc: usize, receiver: Option<Value>) -> Result<(), Error> {
+        match callee {
+            Value::Func(RuntimeFunc::Virtual(func)) => {
+                if self
+                    .limits
+                    .call_depth
+                    .is_some_and(|max| self.frames.len() >= max)
+                {
+                    return Err(Error::limit_exceeded(
+                        concat!(file!(), ":", line!()),
+                        Limit::CallDepth,
+                    ));
+                }
+                if func.arity != argc {
+                    return Err(Error::arity(
+                        concat!(file!(), ":", line!()),
+                        func.arity,
+                        argc,
+                    ));
+                }
+                let mut locals = HashMap::default();
+                if let Some(receiver) = receiver {
+                    locals.insert(
+                        self.interner.get_or_intern_static("self"),
+                        Slot {
+                            flags: Flags::ASSIGNED,
+                            value: receiver,
+                        },
+                    );
+                }
+                self.frames.push(Frame {
+                    func,
+                    ip: 0,
+                    locals,
+                });
+            }
+            Value::Func(RuntimeFunc::Native(func, needs)) => {
+                if let Some(needs) = needs {
+                    self.capabilities.check(needs)?;
+                }
+                // Natives see the receiver as their first argument
+                let argc = match receiver {
+                    Some(receiver) => {
+                        self.stack.insert(self.stack.len() - argc, receiver);
+                        argc + 1
+                    }
+                    None => argc,
+                };
+                let ret = func(self, argc)?;
+                self.stack.push(ret);
             }
+            _ => return Err(Error::not_callable(concat!(file!(), ":", line!()))),
         }
         Ok(())
     }
+
+    fn method(&mut self, receiver: &Value, name: Spur) -> Result<Value, Error> {
+        let ty = match receiver {
+            Value::Object(object) => {
+                let object = object.borrow();
+                if let Some(slot) = object.fields.get(&name) {
+                    return Ok(slot.value.clone());
+                }
+                object.ty
+            }
+            Value::String(_) | Value::Str(_) => self.interner.get_or_intern_static("String"),
+            Value::List(_) => self.interner.get_or_intern_static("List"),
+            Value::Map(_) => self.interner.get_or_intern_static("Map"),
+            _ => return Err(self.undefined(name)),
+        };
+        self.types
+            .get(&ty)
+            .and_then(|ty| ty.methods.get(&name))
+            .map(|method| Value::Func(method.clone()))
+            .ok_or_else(|| self.undefined(name))
+    }
+
+    fn raise(&mut self, value: Value) -> Error {
+        let e = Error::raised(concat!(file!(), ":", line!()), self.inspect(&value));
+        self.raised = Some(value);
+        e
+    }
+
+    fn jump(&mut self, target: usize) -> Result<(), Error> {
+        let frame = self
+            .frames
+            .last_mut()
+            .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+        frame.ip = target;
+        Ok(())
+    }
+
+    fn iter(&mut self, iterable: Value) -> Result<Iter, Error> {
+        // Iterating over a collection copies it
+        let copied = match &iterable {
+            Value::List(list) => list.borrow().len(),
+            Value::Map(map) => map.borrow().len(),
+            Value::String(_) | Value::Str(_) => self.as_str(&iterable).len(),
+            _ => 0,
+        };
+        self.allocate(copied * VALUE_SIZE)?;
+        let iter = match iterable {
+            Value::List(list) => Iter::Items(list.borrow().clone().into_iter()),
+            Value::Map(map) => Iter::Items(
+                map.borrow()
+                    .keys()
+                    .map(MapKey::to_value)
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            ),
+            Value::String(_) | Value::Str(_) => Iter::Items(
+                self.as_str(&iterable)
+                    .chars()
+                    .map(|c| Value::String(c.to_string()))
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            ),
+            Value::Range(start, end) => Iter::Range(start..end),
+            Value::Iter(iter) => return Ok(iter.borrow().clone()),
+            object @ Value::Object(_) => Iter::Object(object),
+            _ => {
+                return Err(Error::invalid_argument(
+                    concat!(file!(), ":", line!()),
+                    "only lists, maps, strings, ranges and objects can be iterated".to_owned(),
+                ))
+            }
+        };
+        Ok(iter)
+    }
+
+    /// Orders numbers by value and strings lexicographically.
+    fn compare(&self, lhs: &Value, rhs: &Value) -> Result<Ordering, Error> {
+        match (lhs, rhs) {
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::String(_) | Value::Str(_), Value::String(_) | Value::Str(_)) => {
+                Some(self.as_str(lhs).cmp(self.as_str(rhs)))
+            }
+            _ => match (lhs.as_int(), rhs.as_int()) {
+                (Some(a), Some(b)) => Some(a.cmp(&b)),
+                _ => None,
+            },
+        }
+        .ok_or_else(|| {
+            Error::invalid_argument(
+                concat!(file!(), ":", line!()),
+                "only two integers, two floats or two strings can be ordered".to_owned(),
+            )
+        })
+    }
+
+    /// The text of a `String` or `Str` value.
+    fn as_str<'v>(&'v self, value: &'v Value) -> &'v str {
+        match value {
+            Value::String(s) => s,
+            Value::Str(s) => self.interner.resolve(s),
+            _ => unreachable!("not a string value"),
+        }
+    }
+
+    fn construct_builtin(&mut self, ty: Spur, argc: usize) -> Result<Value, Error> {
+        match (self.interner.resolve(&ty), argc) {
+            ("Object", 0) => Ok(Value::Object(Rc::new(RefCell::new(Object {
+                ty,
+                fields: HashMap::default(),
+            })))),
+            ("Map", 0) => Ok(Value::Map(Default::default())),
+            ("Error", 1) => match self.pop()? {
+                Value::Str(s) => Ok(self.error_object(self.interner.resolve(&s).to_owned())),
+                Value::String(s) => Ok(self.error_object(s)),
+                _ => Err(Error::eval(concat!(file!(), ":", line!()))),
+            },
+            ("String", 0) => Ok(Value::String(String::new())),
+            ("String", 1) => match self.pop()? {
+                Value::Str(s) => {
+                    self.allocate(self.interner.resolve(&s).len())?;
+                    Ok(Value::String(self.interner.resolve(&s).to_owned()))
+                }
+                Value::String(s) => Ok(Value::String(s)),
+                _ => Err(Error::eval(concat!(file!(), ":", line!()))),
+            },
+            _ => Err(Error::eval(concat!(file!(), ":", line!()))),
+        }
+    }
+
+    /// The text of a string value.
+    pub fn text<'v>(&'v self, value: &'v Value) -> Result<&'v str, Error> {
+        match value {
+            Value::String(s) => Ok(s),
+            Value::Str(s) => Ok(self.interner.resolve(s)),
+            _ => Err(Error::eval(concat!(file!(), ":", line!()))),
+        }
+    }
+
+    pub fn pop(&mut self) -> Result<Value, Error> {
+        self.stack
+            .pop()
+            .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))
+    }
+
+    /// Pops the arguments of a native call, in the order they were passed.
+    pub fn pop_args(&mut self, argc: usize) -> Result<Vec<Value>, Error> {
+        let at = self
+            .stack
+            .len()
+            .checked_sub(argc)
+            .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+        Ok(self.stack.split_off(at))
+    }
+
+    pub fn interner(&mut self) -> &mut Rodeo {
+        self.interner
+    }
+
+    /// Looks `name` up in the locals of the current frame, then in the globals
+    /// of its module, then in the builtins.
+    fn slot(&self, name: Spur) -> Result<&Slot, Error> {
+        let frame = self
+            .frames
+            .last()
+            .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+        frame
+            .locals
+            .get(&name)
+            .or_else(|| self.modules[frame.func.module].get(&name))
+            .or_else(|| self.env.get(&name))
+            .ok_or_else(|| self.undefined(name))
+    }
+
+    fn slot_mut(&mut self, name: Spur) -> Result<&mut Slot, Error> {
+        let frame = self
+            .frames
+            .last()
+            .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+        let module = frame.func.module;
+        if frame.locals.contains_key(&name) {
+            Ok(self
+                .frames
+                .last_mut()
+                .unwrap()
+                .locals
+                .get_mut(&name)
+                .unwrap())
+        } else if self.modules[module].contains_key(&name) {
+            Ok(self.modules[module].get_mut(&name).unwrap())
+        } else if self.env.contains_key(&name) {
+            Ok(self.env.get_mut(&name).unwrap())
+        } else {
+            Err(self.undefined(name))
+        }
+    }
+
+    /// The number of call frames.
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The `frame`th frame counting from the innermost one.
+    fn frame(&self, frame: usize) -> Option<&Frame> {
+        self.frames.get(self.frames.len().checked_sub(frame + 1)?)
+    }
+
+    /// The instruction about to run in a frame, or the call it's waiting on.
+    pub fn frame_ip(&self, frame: usize) -> Option<usize> {
+        let ip = self.frame(frame)?.ip;
+        match frame {
+            0 => Some(ip),
+            _ => ip.checked_sub(1),
+        }
+    }
+
+    /// Where in the source a frame is, as for [`Vm::frame_ip`].
+    pub fn frame_span(&self, frame: usize) -> Option<SourceSpan> {
+        self.frame(frame)?.func.span(self.frame_ip(frame)?)
+    }
+
+    /// The name of the function a frame is running. Module initializers have
+    /// no name.
+    pub fn frame_name(&self, frame: usize) -> Option<String> {
+        let func = &self.frame(frame)?.func;
+        let same = |f: &RuntimeFunc| matches!(f, RuntimeFunc::Virtual(f) if Rc::ptr_eq(&f.code, &func.code));
+        let global = self.modules[func.module]
+            .iter()
+            .find(|(_, slot)| matches!(&slot.value, Value::Func(f) if same(f)));
+        if let Some((name, _)) = global {
+            return Some(self.interner.resolve(name).to_owned());
+        }
+        self.types.values().find_map(|ty| {
+            let ty_name = self.interner.resolve(&ty.name);
+            match ty.methods.iter().find(|(_, method)| same(method)) {
+                Some((name, _)) => Some(format!("{}.{}", ty_name, self.interner.resolve(name))),
+                None if ty
+                    .ctor
+                    .as_ref()
+                    .is_some_and(|ctor| Rc::ptr_eq(&ctor.code, &func.code)) =>
+                {
+                    Some(format!("new {}", ty_name))
+                }
+                None => None,
+            }
+        })
+    }
+
+    /// The locals of a frame, by name.
+    pub fn locals(&self, frame: usize) -> Vec<(String, Value)> {
+        match self.frame(frame) {
+            Some(frame) => self.named_values(&frame.locals),
+            None => Vec::new(),
+        }
+    }
+
+    /// The globals of the module a frame belongs to, by name.
+    pub fn globals(&self, frame: usize) -> Vec<(String, Value)> {
+        match self.frame(frame) {
+            Some(frame) => self.named_values(&self.modules[frame.func.module]),
+            None => Vec::new(),
+        }
+    }
+
+    fn named_values(&self, slots: &HashMap<Spur, Slot>) -> Vec<(String, Value)> {
+        let mut values = slots
+            .iter()
+            .map(|(name, slot)| (self.interner.resolve(name).to_owned(), slot.value.clone()))
+            .collect::<Vec<_>>();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        values
+    }
+
+    /// Looks a name up as code running in a frame would.
+    pub fn lookup(&self, frame: usize, name: &str) -> Option<Value> {
+        let frame = self.frame(frame)?;
+        let name = self.interner.get(name)?;
+        frame
+            .locals
+            .get(&name)
+            .or_else(|| self.modules[frame.func.module].get(&name))
+            .or_else(|| self.env.get(&name))
+            .map(|slot| slot.value.clone())
+    }
+
+    /// The fields of an object, the items of a list or the entries of a map.
+    pub fn members(&self, value: &Value) -> Vec<(String, Value)> {
+        match value {
+            Value::Object(object) => self.named_values(&object.borrow().fields),
+            Value::List(list) => list
+                .borrow()
+                .iter()
+                .enumerate()
+                .map(|(i, item)| (i.to_string(), item.clone()))
+                .collect(),
+            Value::Map(map) => map
+                .borrow()
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// A one line description of a value, without its members.
+    pub fn describe(&self, value: &Value) -> String {
+        match value {
+            Value::Int(i) => i.to_string(),
+            Value::Uint(u) => u.to_string(),
+            Value::Float(f) => format!("{:?}", f),
+            Value::Bool(b) => b.to_string(),
+            Value::String(s) => format!("{:?}", s),
+            Value::Str(s) => format!("{:?}", self.interner.resolve(s)),
+            Value::Func(RuntimeFunc::Native(..)) => "<native function>".to_owned(),
+            Value::Func(RuntimeFunc::Virtual(func)) => format!("<function/{}>", func.arity),
+            Value::Object(object) => self.interner.resolve(&object.borrow().ty).to_owned(),
+            Value::List(list) => format!("[{} items]", list.borrow().len()),
+            Value::Map(map) => format!("{{{} entries}}", map.borrow().len()),
+            Value::Range(start, end) => format!("{}..{}", start, end),
+            Value::Iter(_) => "<iterator>".to_owned(),
+            Value::Module(module) => format!("<module {}>", module),
+            Value::Nil => "nil".to_owned(),
+            Value::Undefined => "<undefined>".to_owned(),
+        }
+    }
+
+    fn undefined(&self, name: Spur) -> Error {
+        Error::undefined(
+            concat!(file!(), ":", line!()),
+            self.interner.resolve(&name).to_owned(),
+        )
+    }
+
+    /// The module of the function being executed.
+    fn module(&self) -> Result<usize, Error> {
+        self.frames
+            .last()
+            .map(|frame| frame.func.module)
+            .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))
+    }
+
+    /// Reads a global of another module, as in `module.name`.
+    fn global(&self, module: usize, name: Spur) -> Result<Value, Error> {
+        self.modules[module]
+            .get(&name)
+            .map(|slot| slot.value.clone())
+            .ok_or_else(|| self.undefined(name))
+    }
 }
 
 #[derive(Debug)]
@@ -110,6 +1242,45 @@ pub struct Slot {
     pub value: Value,
 }
 
+impl Slot {
+    fn assign(&mut self, value: Value) -> Result<(), Error> {
+        if self.flags.contains(Flags::ASSIGNED) && !self.flags.contains(Flags::BINDING_MODE_MUT) {
+            return Err(Error::immutable_assignment(concat!(file!(), ":", line!())));
+        }
+        self.flags |= Flags::ASSIGNED;
+        self.value = value;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Object {
+    pub ty: Spur,
+    pub fields: HashMap<Spur, Slot>,
+}
+
+impl Object {
+    fn new(ty: &TypeProto) -> Self {
+        let fields = ty
+            .fields
+            .iter()
+            .map(|&(name, flags)| {
+                (
+                    name,
+                    Slot {
+                        flags,
+                        value: Value::Undefined,
+                    },
+                )
+            })
+            .collect();
+        Self {
+            ty: ty.name,
+            fields,
+        }
+    }
+}
+
 bitflags::bitflags! {
     pub struct Flags: u8 {
         const BINDING_MODE_MUT =     0b00000001;
@@ -123,22 +1294,196 @@ pub enum Value {
     Int(i64),
     Uint(u64),
     Float(f64),
+    Bool(bool),
     String(String),
     Str(Spur),
     Func(RuntimeFunc),
+    Object(Rc<RefCell<Object>>),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<IndexMap<MapKey, Value>>>),
+    /// Integers from the first up to, but not including, the second
+    Range(i128, i128),
+    Iter(Rc<RefCell<Iter>>),
+    /// An imported module, by index
+    Module(usize),
+    Nil,
     Undefined,
 }
 
+impl Value {
+    fn into_object(self) -> Result<Rc<RefCell<Object>>, Error> {
+        match self {
+            Value::Object(object) => Ok(object),
+            _ => Err(Error::eval(concat!(file!(), ":", line!()))),
+        }
+    }
+
+    pub fn into_list(self) -> Result<Rc<RefCell<Vec<Value>>>, Error> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(Error::eval(concat!(file!(), ":", line!()))),
+        }
+    }
+
+    pub fn into_map(self) -> Result<Rc<RefCell<IndexMap<MapKey, Value>>>, Error> {
+        match self {
+            Value::Map(map) => Ok(map),
+            _ => Err(Error::eval(concat!(file!(), ":", line!()))),
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i128> {
+        match *self {
+            Value::Int(i) => Some(i as i128),
+            Value::Uint(u) => Some(u as i128),
+            _ => None,
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Bool(false) | Value::Nil)
+    }
+
+    /// Structural equality for scalars and strings, identity for heap values.
+    pub fn equals(&self, other: &Value, interner: &Rodeo) -> bool {
+        match (self, other) {
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Object(a), Value::Object(b)) => Rc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+            (Value::Map(a), Value::Map(b)) => Rc::ptr_eq(a, b),
+            (Value::Range(a0, a1), Value::Range(b0, b1)) => a0 == b0 && a1 == b1,
+            (a, b) => match (MapKey::new(a, interner), MapKey::new(b, interner)) {
+                (Ok(a), Ok(b)) => a == b,
+                _ => false,
+            },
+        }
+    }
+}
+
+/// The hashable form of a [`Value`]. Integers compare by numeric value
+/// regardless of signedness, and interned and owned strings by their text.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Int(i128),
+    Str(Rc<str>),
+    Bool(bool),
+}
+
+impl MapKey {
+    pub fn new(value: &Value, interner: &Rodeo) -> Result<Self, Error> {
+        match value {
+            Value::Int(i) => Ok(MapKey::Int(*i as i128)),
+            Value::Uint(u) => Ok(MapKey::Int(*u as i128)),
+            Value::Bool(b) => Ok(MapKey::Bool(*b)),
+            Value::String(s) => Ok(MapKey::Str(Rc::from(s.as_str()))),
+            Value::Str(s) => Ok(MapKey::Str(Rc::from(interner.resolve(s)))),
+            _ => Err(Error::invalid_argument(
+                concat!(file!(), ":", line!()),
+                "only integers, strings and booleans can be map keys".to_owned(),
+            )),
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        match self {
+            // Integer keys are always made from an `Int` or a `Uint`
+            MapKey::Int(i) => int_value(*i).unwrap(),
+            MapKey::Str(s) => Value::String(s.to_string()),
+            MapKey::Bool(b) => Value::Bool(*b),
+        }
+    }
+}
+
+impl fmt::Display for MapKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapKey::Int(i) => write!(f, "{}", i),
+            MapKey::Str(s) => write!(f, "{:?}", s),
+            MapKey::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+/// Converts the result of integer arithmetic back into a value, preferring
+/// `Uint` for non-negative numbers. Returns `None` if it doesn't fit either.
+pub fn int_value(i: i128) -> Option<Value> {
+    u64::try_from(i)
+        .map(Value::Uint)
+        .or_else(|_| i64::try_from(i).map(Value::Int))
+        .ok()
+}
+
+#[derive(Clone, Debug)]
+pub enum Iter {
+    Items(std::vec::IntoIter<Value>),
+    Range(std::ops::Range<i128>),
+    /// A user object whose `next` method returns `nil` once it's exhausted
+    Object(Value),
+}
+
+enum IterNext {
+    Value(Value),
+    Done,
+    Call(Value),
+}
+
+impl Iter {
+    fn next(&mut self) -> IterNext {
+        let next = match self {
+            Iter::Items(items) => items.next(),
+            Iter::Range(range) => range.next().and_then(int_value),
+            Iter::Object(object) => return IterNext::Call(object.clone()),
+        };
+        next.map_or(IterNext::Done, IterNext::Value)
+    }
+}
+
+/// Checks that `index` is an integer within `0..len`.
+pub fn list_index(index: &Value, len: usize) -> Result<usize, Error> {
+    let index = index
+        .as_int()
+        .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+    usize::try_from(index)
+        .ok()
+        .filter(|&i| i < len)
+        .ok_or_else(|| Error::index_out_of_bounds(concat!(file!(), ":", line!()), index, len))
+}
+
+/// The positions from `from` up to `to` in a sequence of `len` elements. Both
+/// bounds may be equal to the length, and `from` can't be after `to`.
+pub fn slice_range(from: &Value, to: &Value, len: usize) -> Result<Range<usize>, Error> {
+    let bound = |value: &Value, max: usize| {
+        let index = value
+            .as_int()
+            .ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))?;
+        usize::try_from(index)
+            .ok()
+            .filter(|&i| i <= max)
+            .ok_or_else(|| Error::index_out_of_bounds(concat!(file!(), ":", line!()), index, len))
+    };
+    let to = bound(to, len)?;
+    let from = bound(from, to)?;
+    Ok(from..to)
+}
+
+pub type NativeFn = fn(&mut Vm, usize) -> Result<Value, Error>;
+
+/// A native as it's registered: its name, the function, and the capability
+/// the function needs to run, if any.
+pub type Native = (&'static str, NativeFn, Option<Capability>);
+
 #[derive(Clone)]
 pub enum RuntimeFunc {
-    Native(fn(&mut Vm)),
+    /// Checked against the VM's capabilities before every call
+    Native(NativeFn, Option<Capability>),
     Virtual(FuncProto),
 }
 
 impl std::fmt::Debug for RuntimeFunc {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Native(_) => f.debug_tuple("Native").finish(),
+            Self::Native(..) => f.debug_tuple("Native").finish(),
             Self::Virtual(arg0) => f.debug_tuple("Virtual").field(arg0).finish(),
         }
     }
@@ -149,6 +1494,7 @@ pub enum ConstValue {
     Int(i64),
     Uint(u64),
     Float(f64),
+    Bool(bool),
     Str(Spur),
 }
 
@@ -158,6 +1504,7 @@ impl From<ConstValue> for Value {
             ConstValue::Int(i) => Value::Int(i),
             ConstValue::Uint(u) => Value::Uint(u),
             ConstValue::Float(f) => Value::Float(f),
+            ConstValue::Bool(b) => Value::Bool(b),
             ConstValue::Str(s) => Value::Str(s),
         }
     }
@@ -166,10 +1513,610 @@ impl From<ConstValue> for Value {
 #[derive(Debug, Clone)]
 pub enum Opcode {
     Defslot(Spur, Flags),
+    DefGlobal(Spur, Flags),
+    Import(ImportBinding),
+    Dropslot(Spur),
     Assign(Spur),
-    Call(Spur),
+    Call(Spur, usize),
+    CallValue(usize),
+    CallMethod(Spur, usize),
+    Return,
     Read(Spur),
     LoadField(Spur),
     StoreField(Spur),
+    DefField(Spur, Flags),
+    InitField(Spur),
+    Seal,
+    MakeList(usize),
+    MakeMap(usize),
+    LoadIndex,
+    StoreIndex,
+    /// Pushes whether the value on top of the stack is a list of the given length
+    TestList(usize),
+    /// Pops a key and a container, and pushes whether the container has the key
+    TestKey,
+    /// Like `LoadIndex`, but also loads the fields of objects
+    LoadKey,
+    /// Raises the error for a `match` without a matching arm, with the scrutinee on top of the stack
+    NoMatch,
+    New(Spur, usize),
+    Jump(usize),
+    JumpIfFalse(usize),
+    /// Replaces the value on top of the stack with an iterator over it
+    Iter,
+    /// Pushes the next item of the iterator on top of the stack, or jumps if it's exhausted
+    IterNext(usize),
+    MakeRange(bool),
+    Add,
+    Sub,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// Starts a `try` block whose handler is at the given address
+    PushHandler(usize),
+    PopHandler,
+    Raise,
+    /// Ends an `ensure` block, raising the error below the flag on top of the stack if it's set
+    EndEnsure,
+    Bool(bool),
     Const(usize),
+    Nil,
+    Dup,
+    Pop,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::ErrorKind,
+        testing::{self, compile, eval, raised, reason, value},
+    };
+
+    /// Runs a script under `limits`, interrupted before it starts if
+    /// `interrupt` is set.
+    fn run(src: &str, limits: Limits, interrupt: bool) -> Result<(), Error> {
+        let mut interner = Rodeo::new();
+        let program = compile(src, &mut interner, 0).unwrap();
+        let mut vm = Vm::new(program, &mut interner)?;
+        vm.set_limits(limits);
+        vm.interrupt_handle()
+            .store(interrupt, atomic::Ordering::Relaxed);
+        let res = vm.eval();
+        assert!(!vm.interrupt_handle().load(atomic::Ordering::Relaxed));
+        res
+    }
+
+    fn exceeded(res: Result<(), Error>) -> Limit {
+        match res.unwrap_err().kind() {
+            ErrorKind::LimitExceeded { limit } => *limit,
+            kind => panic!("unexpected error {}", kind),
+        }
+    }
+
+    const FOREVER: &str = "defn main() do\n    try\n        loop\n            nil\n        end\n    rescue e do\n        nil\n    end\nend\n";
+
+    #[test]
+    fn stops_after_the_instruction_limit_despite_rescue() {
+        let limits = Limits {
+            instructions: Some(1000),
+            ..Limits::default()
+        };
+        assert_eq!(exceeded(run(FOREVER, limits, false)), Limit::Instructions);
+    }
+
+    #[test]
+    fn aborts_leave_no_handlers_behind() {
+        let mut interner = Rodeo::new();
+        let program = compile(FOREVER, &mut interner, 0).unwrap();
+        let mut vm = Vm::new(program, &mut interner).unwrap();
+        vm.set_limits(Limits {
+            instructions: Some(1000),
+            ..Limits::default()
+        });
+        assert_eq!(exceeded(vm.eval()), Limit::Instructions);
+        assert!(vm.frames.is_empty());
+        assert!(vm.handlers.is_empty());
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn limits_call_depth() {
+        let src = "defn down(n) do\n    down(n + 1)\nend\n\ndefn main() do\n    down(0)\nend\n";
+        let limits = Limits {
+            call_depth: Some(50),
+            ..Limits::default()
+        };
+        assert_eq!(exceeded(run(src, limits, false)), Limit::CallDepth);
+    }
+
+    #[test]
+    fn limits_the_stack() {
+        let src = "defn main() do\n    x := [1, 2, 3, 4, 5, 6, 7, 8, 9, 10]\nend\n";
+        let limits = Limits {
+            stack: Some(8),
+            ..Limits::default()
+        };
+        assert_eq!(exceeded(run(src, limits, false)), Limit::Stack);
+        let limits = Limits {
+            stack: Some(16),
+            ..Limits::default()
+        };
+        run(src, limits, false).unwrap();
+    }
+
+    #[test]
+    fn limits_the_heap() {
+        let src = "defn main() do\n    s := \"ab\"\n    loop\n        s = s + s\n    end\nend\n";
+        let limits = Limits {
+            heap: Some(1 << 16),
+            ..Limits::default()
+        };
+        assert_eq!(exceeded(run(src, limits, false)), Limit::Heap);
+    }
+
+    #[test]
+    fn stops_when_interrupted() {
+        let e = run(FOREVER, Limits::default(), true).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Interrupted));
+    }
+
+    fn undefined(res: Result<impl std::fmt::Debug, Error>) -> String {
+        match res.unwrap_err().kind() {
+            ErrorKind::Undefined { name } => name.clone(),
+            kind => panic!("unexpected error {}", kind),
+        }
+    }
+
+    const GREETER: &str = "type Greeter do
+    name := \"a\"
+    defn greet(who) do
+        self.name + \" greets \" + who
+    end
+    defn renamed(name) do
+        self.name = name
+        self
+    end
+    defn twice(who) do
+        self.greet(who) + \", \" + self.greet(who)
+    end
+end
+";
+
+    #[test]
+    fn dispatches_methods_on_their_receiver() {
+        let greet = |body: &str| {
+            let src = format!(
+                "{}\ndefn main() do\n    g := new Greeter\n    raise {}\nend\n",
+                GREETER, body
+            );
+            raised(testing::run(&src))
+        };
+        assert_eq!(greet("g.greet(\"b\")"), "\"a greets b\"");
+        assert_eq!(greet("g.twice(\"b\")"), "\"a greets b, a greets b\"");
+        // Chained calls, each on what the previous one returned
+        assert_eq!(greet("g.renamed(\"c\").greet(\"b\")"), "\"c greets b\"");
+        assert_eq!(
+            greet("[g.renamed(\"c\"), g.name]"),
+            "[Greeter { name: \"c\" }, \"c\"]"
+        );
+        assert_eq!(value("\"abc\".upper().len()"), "3");
+        assert_eq!(
+            value("[\"a\", \"b\", \"c\"].slice(1, 3).join(\"-\")"),
+            "\"b-c\""
+        );
+
+        let src = format!(
+            "{}\ndefn main() do\n    (new Greeter).wave()\nend\n",
+            GREETER
+        );
+        assert_eq!(undefined(testing::run(&src)), "wave");
+        assert_eq!(undefined(eval("1.len()")), "len");
+    }
+
+    const POINT: &str = "type Point do
+    x := 0
+    y $= 0
+    defn init(x, y) do
+        self.x = x
+        self.y = y
+    end
+    defn sum() do
+        self.x + self.y
+    end
+end
+
+type Tagged do
+    tag := \"t\"
+end
+";
+
+    /// Runs `body` in `main` after the declarations of `types`.
+    fn with(types: &str, body: &str) -> Result<(), Error> {
+        testing::run(&format!("{}\ndefn main() do\n{}\nend\n", types, body))
+    }
+
+    #[test]
+    fn constructs_types_through_init() {
+        let src = "    p := new(1, 2) Point\n    p.x = 5\n    raise [p, p.sum()]";
+        assert_eq!(raised(with(POINT, src)), "[Point { x: 5, y: 2 }, 7]");
+        assert_eq!(
+            raised(with(POINT, "    raise new Tagged")),
+            "Tagged { tag: \"t\" }"
+        );
+        assert_eq!(value("new(\"Hello\") String"), "\"Hello\"");
+        assert_eq!(value("new Object"), "Object {}");
+
+        let e = with(POINT, "    p := new(1, 2) Point\n    p.y = 3").unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ImmutableAssignment));
+        let e = with(POINT, "    new(1) Point").unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            ErrorKind::Arity {
+                expected: 2,
+                got: 1
+            }
+        ));
+        assert_eq!(undefined(with(POINT, "    (new Tagged).z = 1")), "z");
+        assert_eq!(undefined(with(POINT, "    (new Tagged).z")), "z");
+
+        // Immutable fields can be assigned once, by `init`
+        let once = "type Once do\n    y $= 0\n    defn init() do\n        self.y = 1\n        self.y = 2\n    end\nend\n";
+        let e = with(once, "    new Once").unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ImmutableAssignment));
+    }
+
+    fn out_of_bounds(res: Result<impl std::fmt::Debug, Error>) -> (i128, usize) {
+        match res.unwrap_err().kind() {
+            ErrorKind::IndexOutOfBounds { index, len } => (*index, *len),
+            kind => panic!("unexpected error {}", kind),
+        }
+    }
+
+    #[test]
+    fn indexes_and_changes_lists() {
+        assert_eq!(value("[1, \"a\", [2]][2][0]"), "2");
+        let src = "    l := [1, \"a\", [2]]
+    l[1] = \"b\"
+    l[2][0] = 3
+    l.push(4, 5)
+    raise [l, l.pop(), len(l), l.len()]";
+        assert_eq!(raised(with("", src)), "[[1, \"b\", [3], 4], 5, 4, 4]");
+        // Lists are shared, not copied
+        let src = "    l := [1]\n    m := l\n    m.push(2)\n    raise l";
+        assert_eq!(raised(with("", src)), "[1, 2]");
+
+        assert_eq!(value("[1, 2, 3].slice(1, 3)"), "[2, 3]");
+        assert_eq!(value("[1, 2, 3].slice(3, 3)"), "[]");
+        assert_eq!(out_of_bounds(eval("[1, 2, 3].slice(2, 4)")), (4, 3));
+        assert_eq!(out_of_bounds(eval("[1, 2, 3].slice(-1, 2)")), (-1, 3));
+
+        let src = "defn main() do\n    l := [1, 2]\n    l[2]\nend\n";
+        let e = testing::run(src).unwrap_err();
+        let span = e.span().unwrap();
+        assert_eq!(&src[span.start..span.end], "l[2]");
+        assert_eq!(out_of_bounds(Err::<(), _>(e)), (2, 2));
+        assert_eq!(
+            out_of_bounds(with("", "    l := [1]\n    l[-1] = 2")),
+            (-1, 1)
+        );
+    }
+
+    #[test]
+    fn keys_maps_by_value() {
+        assert_eq!(
+            value("{\"a\": 1, b: 2, 3: \"c\", true: nil}"),
+            "{\"a\": 1, \"b\": 2, 3: \"c\", true: nil}"
+        );
+        assert_eq!(value("{b: 2}[\"b\"]"), "2");
+        // Strings made at runtime find the keys of literals and the other way
+        // around, and integers are the same key whatever their sign
+        let src = "    m := {\"ab\": 1, 2: \"two\"}
+    k := \"a\" + \"b\"
+    m[k] = 3
+    m[\"c\" + \"d\"] = 4
+    n := 0 - 2
+    m[n + 4] = \"too\"
+    raise [m, m[\"cd\"], m.has(k), m.has(\"x\")]";
+        assert_eq!(
+            raised(with("", src)),
+            "[{\"ab\": 3, 2: \"too\", \"cd\": 4}, 4, true, false]"
+        );
+        let src = "    m := {\"a\": 1, \"b\": 2, \"c\": 3}
+    raise [m.remove(\"b\"), m.keys(), m.values(), m.len(), len(m)]";
+        assert_eq!(raised(with("", src)), "[2, [\"a\", \"c\"], [1, 3], 2, 2]");
+
+        let e = eval("{\"a\": 1}[\"b\"]").unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::KeyNotFound { key } if key == "\"b\""));
+        let e = eval("{}.remove(1)").unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::KeyNotFound { key } if key == "1"));
+        assert_eq!(
+            reason(eval("{[1]: 1}")),
+            "only integers, strings and booleans can be map keys"
+        );
+        assert_eq!(
+            reason(eval("{}[1.5]")),
+            "only integers, strings and booleans can be map keys"
+        );
+    }
+
+    /// What a `for` loop over `iterable` sees, given as a list.
+    fn items(iterable: &str) -> String {
+        let src = format!(
+            "    l := []\n    for x in {} do\n        l.push(x)\n    end\n    raise l",
+            iterable
+        );
+        raised(with(COUNTDOWN, &src))
+    }
+
+    const COUNTDOWN: &str = "type Countdown do
+    n := 3
+    defn next() do
+        match self.n do
+            0 => nil,
+            _ => self.step(),
+        end
+    end
+    defn step() do
+        self.n = self.n - 1
+        self.n
+    end
+end
+";
+
+    #[test]
+    fn iterates_over_everything_iterable() {
+        assert_eq!(items("[1, \"a\", nil]"), "[1, \"a\", nil]");
+        assert_eq!(items("{\"a\": 1, 2: 3}"), "[\"a\", 2]");
+        assert_eq!(items("\"hé!\""), "[\"h\", \"é\", \"!\"]");
+        assert_eq!(items("0..3"), "[0, 1, 2]");
+        assert_eq!(items("0..=3"), "[0, 1, 2, 3]");
+        assert_eq!(items("3..0"), "[]");
+        assert_eq!(items("new Countdown"), "[2, 1, 0]");
+        assert_eq!(
+            reason(eval("for x in 1 do\n    nil\nend")),
+            "only lists, maps, strings, ranges and objects can be iterated"
+        );
+
+        // The loop runs over a copy, so changing the list doesn't change it
+        let src = "    l := [1, 2]
+    for x in l do
+        l.push(x)
+    end
+    raise l";
+        assert_eq!(raised(with("", src)), "[1, 2, 1, 2]");
+    }
+
+    #[test]
+    fn breaks_and_continues_for_loops() {
+        let src = "    l := []
+    for i in 0..10 do
+        continue if i == 1
+        break if i == 4
+        for j in [i, i + 10] do
+            break if j > 10
+            l.push(j)
+        end
+    end
+    raise l";
+        assert_eq!(raised(with("", src)), "[0, 10, 2, 3]");
+    }
+
+    #[test]
+    fn runs_while_loops_until_their_condition_fails() {
+        let src = "    l := []
+    i := 0
+    while i < 6 do
+        i = i + 1
+        continue if i == 2
+        break if i == 5
+        j := 0
+        while j < i do
+            j = j + 1
+        end
+        l.push(j)
+    end
+    while false do
+        l.push(nil)
+    end
+    while true do
+        break
+    end
+    raise [l, i]";
+        assert_eq!(raised(with("", src)), "[[1, 3, 4], 5]");
+    }
+
+    #[test]
+    fn compares_numbers_and_strings() {
+        assert_eq!(
+            value("[1 < 2, 2 <= 2, 3 > 4, 4 >= 5, 1 == 1, 1 != 1]"),
+            "[true, true, false, false, true, false]"
+        );
+        assert_eq!(
+            value("[0 - 1 < 1, 1.5 < 2.5, 2.5 >= 2.5]"),
+            "[true, true, true]"
+        );
+        assert_eq!(
+            value("[\"a\" < \"b\", \"b\" <= \"a\", \"ab\" == \"a\" + \"b\"]"),
+            "[true, false, true]"
+        );
+        assert_eq!(
+            value("[nil == nil, nil == false, 1 == \"1\"]"),
+            "[true, false, false]"
+        );
+        for expr in ["1 < \"a\"", "1.5 < 2", "nil >= nil"] {
+            assert_eq!(
+                reason(eval(expr)),
+                "only two integers, two floats or two strings can be ordered"
+            );
+        }
+    }
+
+    const GLOBALS: &str = "count := 0
+limit $= 3
+names $= [\"a\"]
+
+defn bump() do
+    count = count + 1
+    count
+end
+
+bump()
+names.push(\"b\")
+";
+
+    #[test]
+    fn runs_top_level_statements_before_main() {
+        let src = "    bump()\n    raise [count, limit, names]";
+        assert_eq!(raised(with(GLOBALS, src)), "[2, 3, [\"a\", \"b\"]]");
+        let e = with(GLOBALS, "    limit = 4").unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::ImmutableAssignment));
+        // A raise at the top level stops the program before `main`
+        let src = "raise \"early\"\n\ndefn main() do\n    raise \"late\"\nend\n";
+        assert_eq!(raised(testing::run(src)), "\"early\"");
+    }
+
+    const FAIL: &str = "defn fail(v) do
+    raise v
+end
+
+defn deep(n) do
+    match n do
+        0 => fail(\"bottom\"),
+        _ => deep(n - 1),
+    end
+end
+";
+
+    #[test]
+    fn rescues_raises_from_any_depth() {
+        let src = "    l := []
+    try
+        deep(3)
+    rescue e do
+        l.push(e)
+    end
+    x := try
+        fail(5)
+    rescue e do
+        e + 1
+    end
+    l.push(x)
+    try
+        [][1]
+    rescue e do
+        l.push(e.message)
+    end
+    for i in [1, 2, 3] do
+        try
+            continue if i == 1
+            break if i == 3
+            l.push(i)
+        rescue e do
+            l.push(\"rescued\")
+        end
+    end
+    raise l";
+        // The last raise isn't caught by a handler that `break` left behind
+        assert_eq!(
+            raised(with(FAIL, src)),
+            "[\"bottom\", 6, \"Index 1 out of bounds for length 0\", 2]"
+        );
+        assert_eq!(raised(with(FAIL, "    deep(2)")), "\"bottom\"");
+        let src = "    try\n        fail(1)\n    rescue e do\n        raise e + 1\n    end";
+        assert_eq!(raised(with(FAIL, src)), "2");
+    }
+
+    #[test]
+    fn runs_ensure_blocks_however_try_ends() {
+        let src = "    l := []
+    try
+        l.push(\"body\")
+    rescue e do
+        l.push(\"rescue\")
+    ensure
+        l.push(\"ensure\")
+    end
+    try
+        try
+            fail(1)
+        ensure
+            l.push(\"inner\")
+        end
+    rescue e do
+        l.push(e)
+    end
+    raise l";
+        assert_eq!(
+            raised(with(FAIL, src)),
+            "[\"body\", \"ensure\", \"inner\", 1]"
+        );
+        // Without a rescue, the error goes on once `ensure` has run
+        let src = "log $= []\n";
+        let body = "    try\n        try\n            raise 1\n        ensure\n            log.push(\"ensure\")\n        end\n    rescue e do\n        raise [e, log]\n    end";
+        assert_eq!(raised(with(src, body)), "[1, [\"ensure\"]]");
+        assert_eq!(
+            raised(with(
+                FAIL,
+                "    try\n        fail(2)\n    ensure\n        nil\n    end"
+            )),
+            "2"
+        );
+    }
+
+    const CLASSIFY: &str = "type Person do
+    name := \"p\"
+    age := 3
+end
+
+defn classify(v) do
+    match v do
+        1 => \"one\",
+        \"s\" => \"string\",
+        true => \"true\",
+        nil => \"nil\",
+        [] => \"empty\",
+        [a] => [\"single\", a],
+        [a, [b, c]] => [\"nested\", a, b, c],
+        [a, b] if a == b => [\"same\", a],
+        [a, b] => [\"pair\", a, b],
+        {\"k\": k} => [\"map\", k],
+        {name: n, age: 3} => [\"named\", n],
+        n if n == 500 => \"big\",
+    end
+end
+";
+
+    #[test]
+    fn matches_patterns_in_order() {
+        let classify = |v: &str| raised(with(CLASSIFY, &format!("    raise classify({})", v)));
+        assert_eq!(classify("1"), "\"one\"");
+        assert_eq!(classify("\"s\""), "\"string\"");
+        assert_eq!(classify("true"), "\"true\"");
+        assert_eq!(classify("nil"), "\"nil\"");
+        assert_eq!(classify("[]"), "\"empty\"");
+        assert_eq!(classify("[5]"), "[\"single\", 5]");
+        assert_eq!(classify("[1, [2, 3]]"), "[\"nested\", 1, 2, 3]");
+        assert_eq!(classify("[4, 4]"), "[\"same\", 4]");
+        assert_eq!(classify("[4, 5]"), "[\"pair\", 4, 5]");
+        assert_eq!(classify("{\"k\": 9, \"x\": 1}"), "[\"map\", 9]");
+        assert_eq!(classify("{name: \"m\", age: 3}"), "[\"named\", \"m\"]");
+        assert_eq!(classify("new Person"), "[\"named\", \"p\"]");
+        assert_eq!(classify("500"), "\"big\"");
+        assert_eq!(
+            value("match 2 do\n    1 => \"one\",\n    _ => \"other\",\nend"),
+            "\"other\""
+        );
+
+        // Values no arm matches are raised with the value
+        let e = with(CLASSIFY, "    classify(7)").unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NoMatch { value } if value == "7"));
+        let e = with(CLASSIFY, "    classify([1, 2, 3])").unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NoMatch { value } if value == "[1, 2, 3]"));
+        let e = with(CLASSIFY, "    classify({age: 4})").unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::NoMatch { value } if value == "{\"age\": 4}"));
+    }
 }
@@ -0,0 +1,237 @@
+//! Writing values as text, for `print` and `to_string` and for `inspect`.
+//!
+//! Lists, maps and objects are written with their members, so a container
+//! that holds itself would never end; one that's met again while it's being
+//! written shows as `[...]`, `{...}` or `Type {...}` instead. The same check
+//! stops a `to_string` method that displays its own object from recursing.
+
+use std::{cell::RefCell, fmt::Write, rc::Rc};
+
+use super::{Object, Value, Vm};
+use crate::error::Error;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// For users: objects go through their type's `to_string` method
+    Display,
+    /// For debugging: objects always show their fields
+    Inspect,
+}
+
+impl<'i> Vm<'i> {
+    /// A value as `print` and `to_string` write it: strings as their text,
+    /// objects through their type's `to_string` method if it has one, and
+    /// containers with their members, strings among them quoted.
+    pub fn display(&mut self, value: &Value) -> Result<String, Error> {
+        match value {
+            Value::String(s) => Ok(s.clone()),
+            Value::Str(s) => Ok(self.interner.resolve(s).to_owned()),
+            _ => self.write(value, Mode::Display),
+        }
+    }
+
+    /// A value as `inspect` writes it: like [`Vm::display`], but with every
+    /// string quoted and every object showing its fields.
+    pub fn inspect(&mut self, value: &Value) -> String {
+        // Inspecting never runs program code, which is the only way to fail
+        self.write(value, Mode::Inspect).unwrap()
+    }
+
+    fn write(&mut self, value: &Value, mode: Mode) -> Result<String, Error> {
+        let mut out = String::new();
+        let depth = self.displaying.len();
+        let res = self.write_value(&mut out, value, mode);
+        self.displaying.truncate(depth);
+        res.map(|()| out)
+    }
+
+    fn write_value(&mut self, out: &mut String, value: &Value, mode: Mode) -> Result<(), Error> {
+        match value {
+            Value::String(s) => write!(out, "{:?}", s).unwrap(),
+            Value::Str(s) => write!(out, "{:?}", self.interner.resolve(s)).unwrap(),
+            Value::List(list) => {
+                let address = Rc::as_ptr(list).cast();
+                if self.displaying.contains(&address) {
+                    out.push_str("[...]");
+                    return Ok(());
+                }
+                self.displaying.push(address);
+                out.push('[');
+                // Copied, since methods called while writing may change the list
+                let items = list.borrow().clone();
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    self.write_value(out, item, mode)?;
+                }
+                out.push(']');
+                self.displaying.pop();
+            }
+            Value::Map(map) => {
+                let address = Rc::as_ptr(map).cast();
+                if self.displaying.contains(&address) {
+                    out.push_str("{...}");
+                    return Ok(());
+                }
+                self.displaying.push(address);
+                out.push('{');
+                let entries = map.borrow().clone();
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    write!(out, "{}: ", key).unwrap();
+                    self.write_value(out, value, mode)?;
+                }
+                out.push('}');
+                self.displaying.pop();
+            }
+            Value::Object(object) => {
+                let address = Rc::as_ptr(object).cast();
+                let ty = object.borrow().ty;
+                let name = self.interner.resolve(&ty).to_owned();
+                if self.displaying.contains(&address) {
+                    write!(out, "{} {{...}}", name).unwrap();
+                    return Ok(());
+                }
+                self.displaying.push(address);
+                let to_string = self.interner.get_or_intern_static("to_string");
+                let method = self
+                    .types
+                    .get(&ty)
+                    .and_then(|ty| ty.methods.get(&to_string))
+                    .cloned();
+                match method {
+                    Some(method) if mode == Mode::Display => {
+                        let s =
+                            self.invoke(Value::Func(method), Vec::new(), Some(value.clone()))?;
+                        let s = self.text(&s).map_err(|_| {
+                            Error::invalid_argument(
+                                concat!(file!(), ":", line!()),
+                                format!("{}.to_string must return a string", name),
+                            )
+                        })?;
+                        out.push_str(s);
+                    }
+                    _ => self.write_fields(out, object, &name, mode)?,
+                }
+                self.displaying.pop();
+            }
+            _ => out.push_str(&self.describe(value)),
+        }
+        Ok(())
+    }
+
+    /// The fields of an object in the order its type declares them. Fields
+    /// the type doesn't declare, like an error's message, go last by name.
+    pub fn fields(&self, object: &Rc<RefCell<Object>>) -> Vec<(String, Value)> {
+        let object = object.borrow();
+        let mut fields = object
+            .fields
+            .iter()
+            .map(|(name, slot)| (*name, slot.value.clone()))
+            .collect::<Vec<_>>();
+        let order = self.types.get(&object.ty).map(|ty| &ty.fields);
+        fields.sort_by_cached_key(|(field, _)| {
+            let position = order.and_then(|order| order.iter().position(|(name, _)| name == field));
+            (position.unwrap_or(usize::MAX), self.interner.resolve(field))
+        });
+        fields
+            .into_iter()
+            .map(|(name, value)| (self.interner.resolve(&name).to_owned(), value))
+            .collect()
+    }
+
+    /// Writes `Type { a: 1, b: 2 }`.
+    fn write_fields(
+        &mut self,
+        out: &mut String,
+        object: &Rc<RefCell<Object>>,
+        name: &str,
+        mode: Mode,
+    ) -> Result<(), Error> {
+        let fields = self.fields(object);
+        if fields.is_empty() {
+            write!(out, "{} {{}}", name).unwrap();
+            return Ok(());
+        }
+        write!(out, "{} {{ ", name).unwrap();
+        for (i, (field, value)) in fields.iter().enumerate() {
+            if i > 0 {
+                out.push_str(", ");
+            }
+            write!(out, "{}: ", field).unwrap();
+            self.write_value(out, value, mode)?;
+        }
+        out.push_str(" }");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{raised, reason, run, value};
+
+    /// Declares `Point`, whose fields are declared out of alphabetical order,
+    /// and `Named`, which writes itself with `to_string`, then raises `expr`.
+    fn with_types(expr: &str) -> String {
+        raised(run(&format!(
+            "type Point do
+    y := 2
+    x := 1
+end
+
+type Named do
+    name := \"n\"
+    defn to_string() do
+        self.name + \"!\"
+    end
+end
+
+defn main() do
+    {}
+end
+",
+            expr
+        )))
+    }
+
+    #[test]
+    fn quotes_strings_only_when_inspecting() {
+        assert_eq!(value("to_string(\"a\")"), "\"a\"");
+        assert_eq!(
+            value("to_string([\"a\", 1, nil])"),
+            "\"[\\\"a\\\", 1, nil]\""
+        );
+        assert_eq!(value("inspect(\"a\")"), "\"\\\"a\\\"\"");
+    }
+
+    #[test]
+    fn writes_objects_through_to_string() {
+        assert_eq!(with_types("raise new Point"), "Point { y: 2, x: 1 }");
+        assert_eq!(
+            with_types("raise to_string(new Point)"),
+            "\"Point { y: 2, x: 1 }\""
+        );
+        assert_eq!(with_types("raise to_string([new Named])"), "\"[n!]\"");
+        assert_eq!(
+            with_types("raise inspect(new Named)"),
+            "\"Named { name: \\\"n\\\" }\""
+        );
+    }
+
+    #[test]
+    fn requires_to_string_to_give_a_string() {
+        let src = "type Odd do\n    defn to_string() do\n        1\n    end\nend\n\ndefn main() do\n    to_string(new Odd)\nend\n";
+        assert_eq!(reason(run(src)), "Odd.to_string must return a string");
+    }
+
+    #[test]
+    fn stops_at_cycles() {
+        let list = "l := [1]\n    l.push(l)\n    raise [to_string(l), inspect(l)]";
+        assert_eq!(with_types(list), "[\"[1, [...]]\", \"[1, [...]]\"]");
+        let object = "p := new Point\n    p.x = p\n    raise p";
+        assert_eq!(with_types(object), "Point { y: 2, x: Point {...} }");
+    }
+}
@@ -0,0 +1,276 @@
+//! Prints scripts in the canonical style.
+//!
+//! The formatter works on the token stream rather than the AST, so comments
+//! and the author's line breaks are kept. Only the whitespace changes:
+//!
+//! - blocks opened by `do`, `loop` and `try`, and brackets left open at the
+//!   end of a line, are indented by four spaces until their `end` or closing
+//!   bracket; `rescue` and `ensure` line up with their `try`
+//! - binary operators, `:=`, `$=` and `=>` get a space on each side, and
+//!   commas and colons a space after; the `/` between the segments of an
+//!   import path gets none, and the names imported get one before them
+//! - runs of blank lines shrink to one
+//!
+//! Formatting a formatted script gives the same script back.
+
+use std::{ops::Range, path::Path};
+
+use chumsky::Span as _;
+use logos::Logos;
+
+use crate::{
+    error::Error,
+    lexer::Token,
+    parser::{self, parse},
+    Span,
+};
+
+const INDENT: &str = "    ";
+
+/// Formats a script, refusing scripts that don't parse.
+pub fn source(src: &str, path: &Path) -> Result<String, Error> {
+    let tokens = Token::lexer(src).spanned().collect::<Vec<_>>();
+    if tokens.is_empty() {
+        return Ok(String::new());
+    }
+    let spanned = tokens
+        .iter()
+        .map(|(t, s)| (t.clone(), Span::new(path, s.clone())))
+        .collect();
+    if let Err(errors) = parse(spanned) {
+        return Err(parser::error(concat!(file!(), ":", line!()), errors));
+    }
+
+    let mut out = String::new();
+    for line in lines(src, &tokens) {
+        if line.tokens.is_empty() {
+            out.push('\n');
+            continue;
+        }
+        for _ in 0..line.indent {
+            out.push_str(INDENT);
+        }
+        let mut prev: Option<&Token> = None;
+        let mut unary = false;
+        // Whether the tokens so far are `import` and a module path, whose
+        // segments are joined by `/` without spaces and which is followed by
+        // the names imported rather than called
+        let mut import_path = false;
+        for (token, span) in line.tokens {
+            if let Some(prev) = prev {
+                let space = match import_path {
+                    true if *token == Token::Slash || *prev == Token::Slash => false,
+                    // The names imported, rather than a call
+                    true if *token == Token::LParen => true,
+                    _ => spaced(prev, token),
+                };
+                if !unary && space {
+                    out.push(' ');
+                }
+            }
+            out.push_str(&src[span.clone()]);
+            unary = *token == Token::Minus && !prev.is_some_and(is_operand);
+            import_path = match token {
+                Token::KwImport => true,
+                Token::Identifier(_) | Token::Slash => import_path,
+                _ => false,
+            };
+            prev = Some(token);
+        }
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// A line of output; blank lines have no tokens.
+struct Line<'t, 's> {
+    indent: usize,
+    tokens: &'t [(Token<'s>, Range<usize>)],
+}
+
+/// Splits the tokens where the source has line breaks, and works out how deep
+/// each line is nested.
+fn lines<'t, 's>(src: &str, tokens: &'t [(Token<'s>, Range<usize>)]) -> Vec<Line<'t, 's>> {
+    let mut lines = Vec::new();
+    let mut depth = 0usize;
+    // The `do` after `rescue e` continues the `try` block instead of opening one
+    let mut in_rescue = false;
+    let mut start = 0;
+    while start < tokens.len() {
+        let mut end = start + 1;
+        while end < tokens.len() && !src[tokens[end - 1].1.end..tokens[end].1.start].contains('\n')
+        {
+            end += 1;
+        }
+        let line = &tokens[start..end];
+        let outdent = matches!(
+            line[0].0,
+            Token::KwEnd
+                | Token::KwRescue
+                | Token::KwEnsure
+                | Token::RParen
+                | Token::RBracket
+                | Token::RBrace
+        );
+        lines.push(Line {
+            indent: depth.saturating_sub(outdent as usize),
+            tokens: line,
+        });
+        for (token, _) in line {
+            match token {
+                Token::KwDo if in_rescue => in_rescue = false,
+                Token::KwDo
+                | Token::KwLoop
+                | Token::KwTry
+                | Token::LParen
+                | Token::LBracket
+                | Token::LBrace => depth += 1,
+                Token::KwEnd | Token::RParen | Token::RBracket | Token::RBrace => {
+                    depth = depth.saturating_sub(1)
+                }
+                Token::KwRescue => in_rescue = true,
+                _ => {}
+            }
+        }
+        if end < tokens.len() {
+            let gap = &src[tokens[end - 1].1.end..tokens[end].1.start];
+            if gap.matches('\n').count() > 1 {
+                lines.push(Line {
+                    indent: 0,
+                    tokens: &[],
+                });
+            }
+        }
+        start = end;
+    }
+    lines
+}
+
+/// Whether two tokens on the same line are separated by a space.
+fn spaced(prev: &Token, token: &Token) -> bool {
+    match (prev, token) {
+        (
+            _,
+            Token::Comma
+            | Token::Colon
+            | Token::Semicolon
+            | Token::RParen
+            | Token::RBracket
+            | Token::RBrace
+            | Token::Accessor
+            | Token::Range
+            | Token::RangeInclusive,
+        ) => false,
+        (
+            Token::LParen
+            | Token::LBracket
+            | Token::LBrace
+            | Token::Accessor
+            | Token::Range
+            | Token::RangeInclusive,
+            _,
+        ) => false,
+        // Calls and indexing
+        (Token::Identifier(_) | Token::RParen | Token::RBracket | Token::KwNew, Token::LParen) => {
+            false
+        }
+        (
+            Token::Identifier(_)
+            | Token::String(_)
+            | Token::RParen
+            | Token::RBracket
+            | Token::RBrace,
+            Token::LBracket,
+        ) => false,
+        _ => true,
+    }
+}
+
+/// Whether a token can end an operand, which makes a `-` after it a binary
+/// minus rather than a negation.
+fn is_operand(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Identifier(_)
+            | Token::Number(_)
+            | Token::Float(_)
+            | Token::String(_)
+            | Token::KwTrue
+            | Token::KwFalse
+            | Token::KwNil
+            | Token::KwEnd
+            | Token::RParen
+            | Token::RBracket
+            | Token::RBrace
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    fn format(src: &str) -> String {
+        source(src, Path::new("test.oni")).unwrap()
+    }
+
+    #[test]
+    fn indents_blocks_and_spaces_tokens() {
+        let src = "// note\ntotal:=0\n\n\n\ndefn main( ) do\nx:=[1,2 ,3]\nm := {a:1}\n\
+                   y := x[0]+ -2-1\ntry\nraise  x\nrescue e do\nprint(e)\nensure\nnil\nend\nend\n";
+        let formatted = "// note
+total := 0
+
+defn main() do
+    x := [1, 2, 3]
+    m := {a: 1}
+    y := x[0] + -2 - 1
+    try
+        raise x
+    rescue e do
+        print(e)
+    ensure
+        nil
+    end
+end
+";
+        assert_eq!(format(src), formatted);
+        assert_eq!(format(formatted), formatted);
+    }
+
+    #[test]
+    fn indents_open_brackets_and_match_arms() {
+        let src = "defn main() do\nitems := [\n1,\n2,\n]\nmatch items do\n[a, _] => a,\n_ => nil,\nend\nend\n";
+        let formatted = "defn main() do
+    items := [
+        1,
+        2,
+    ]
+    match items do
+        [a, _] => a,
+        _ => nil,
+    end
+end
+";
+        assert_eq!(format(src), formatted);
+    }
+
+    #[test]
+    fn joins_import_paths() {
+        assert_eq!(
+            format("import  std / string ( upper , lower )\n"),
+            "import std/string (upper, lower)\n"
+        );
+        assert_eq!(format("import lib/util\n"), "import lib/util\n");
+    }
+
+    #[test]
+    fn refuses_scripts_that_dont_parse() {
+        let src = "defn main() do\n    x :=\nend\n";
+        let e = source(src, Path::new("test.oni")).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Syntax { .. }));
+        // At the `end` where the value was expected
+        assert_eq!(e.span().map(|span| span.start), src.rfind("end"));
+        assert_eq!(format(""), "");
+    }
+}
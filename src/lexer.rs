@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use logos::Logos;
 
 #[derive(Debug, Logos, Clone, PartialEq, Eq, Hash)]
@@ -7,7 +9,15 @@ pub enum Token<'s> {
 
     #[regex("[1-9][0-9]*|0", |l| l.slice().parse())]
     Number(u64),
-    #[regex(r#""[a-zA-Z]+""#, |l| l.slice())]
+    /// Kept as text, so that tokens can be compared and hashed
+    #[regex(r"[0-9]+\.[0-9]+([eE][+-]?[0-9]+)?", |l| l.slice())]
+    Float(&'s str),
+    /// The text between the quotes, with escapes still in it; see [`unescape`]
+    #[regex(r#""([^"\\\n]|\\.)*""#, |l| {
+        let s = l.slice();
+        let s = &s[1..s.len() - 1];
+        unescape(s).map(|_| s)
+    })]
     String(&'s str),
 
     #[token("let")]
@@ -20,27 +30,81 @@ pub enum Token<'s> {
     KwEnd,
     #[token("defn")]
     KwDefn,
+    #[token("type")]
+    KwType,
+    #[token("import")]
+    KwImport,
     #[token("loop")]
     KwLoop,
     #[token("break")]
     KwBreak,
+    #[token("continue")]
+    KwContinue,
+    #[token("while")]
+    KwWhile,
+    #[token("for")]
+    KwFor,
+    #[token("in")]
+    KwIn,
     #[token("if")]
     KwIf,
+    #[token("match")]
+    KwMatch,
+    #[token("try")]
+    KwTry,
+    #[token("rescue")]
+    KwRescue,
+    #[token("ensure")]
+    KwEnsure,
+    #[token("raise")]
+    KwRaise,
+    #[token("true")]
+    KwTrue,
+    #[token("false")]
+    KwFalse,
+    #[token("nil")]
+    KwNil,
 
     #[token("+")]
     Plus,
     #[token("-")]
     Minus,
+    #[token("/")]
+    Slash,
     #[token("==")]
     Equals,
+    #[token("!=")]
+    NotEquals,
+    #[token("<")]
+    Less,
+    #[token("<=")]
+    LessEquals,
+    #[token(">")]
+    Greater,
+    #[token(">=")]
+    GreaterEquals,
     #[token(".")]
     Accessor,
+    #[token("..")]
+    Range,
+    #[token("..=")]
+    RangeInclusive,
     #[token(",")]
     Comma,
     #[token("(")]
     LParen,
     #[token(")")]
     RParen,
+    #[token("[")]
+    LBracket,
+    #[token("]")]
+    RBracket,
+    #[token("{")]
+    LBrace,
+    #[token("}")]
+    RBrace,
+    #[token(":")]
+    Colon,
     #[token(";")]
     Semicolon,
 
@@ -50,12 +114,83 @@ pub enum Token<'s> {
     DeclAssign,
     #[token("=")]
     Assign,
+    #[token("=>")]
+    FatArrow,
 
     #[error]
     Error,
 
-    #[regex(r"//.*\n", logos::skip)]
+    /// Comments are kept for the formatter; the parser skips them.
+    #[regex(r"//[^\n]*")]
     Comment,
     #[regex(r"\s+", logos::skip)]
     Whitespace,
 }
+
+/// Resolves the escapes in the text of a string literal: `\n`, `\r`, `\t`,
+/// `\0`, `\\`, `\"` and `\u{...}` with up to six hex digits. Returns `None` for
+/// unknown escapes.
+pub fn unescape(s: &str) -> Option<Cow<'_, str>> {
+    if !s.contains('\\') {
+        return Some(Cow::Borrowed(s));
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next()? {
+            'n' => '\n',
+            'r' => '\r',
+            't' => '\t',
+            '0' => '\0',
+            '\\' => '\\',
+            '"' => '"',
+            'u' => {
+                if chars.next()? != '{' {
+                    return None;
+                }
+                let rest = chars.as_str();
+                let end = rest.find('}')?;
+                if end == 0 || end > 6 {
+                    return None;
+                }
+                let code = u32::from_str_radix(&rest[..end], 16).ok()?;
+                chars = rest[end + 1..].chars();
+                char::from_u32(code)?
+            }
+            _ => return None,
+        });
+    }
+    Some(Cow::Owned(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes_string_literals() {
+        assert!(matches!(unescape("plain"), Some(Cow::Borrowed("plain"))));
+        assert_eq!(unescape(r#"a\n\t\"b\"\\"#).unwrap(), "a\n\t\"b\"\\");
+        assert_eq!(unescape(r"caf\u{e9} \u{1F600}").unwrap(), "café 😀");
+        for invalid in [r"\q", r"trailing\", r"\u{}", r"\u{1234567}", r"\u{d800}"] {
+            assert_eq!(unescape(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn lexes_strings_with_any_text() {
+        let tokens = Token::lexer(r#"x := "é \"q\" // not a comment""#).collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Token::Identifier("x"),
+                Token::DeclAssign,
+                Token::String(r#"é \"q\" // not a comment"#),
+            ]
+        );
+    }
+}
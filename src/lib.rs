@@ -5,11 +5,22 @@
 use parser::Ast;
 use std::path::Path;
 
+pub mod asm;
+pub mod builtins;
+pub mod bytecode;
 pub mod compiler;
+pub mod debug;
 pub mod error;
 pub mod eval;
+pub mod format;
 pub mod lexer;
+pub mod lsp;
 pub mod parser;
+pub mod sandbox;
+pub mod stdlib;
+#[cfg(test)]
+mod testing;
+pub mod verify;
 
 type SpannedAst<'s, 'p> = Spanned<'p, Ast<'s, 'p>>;
 type SpannedAsts<'s, 'p> = Vec<Spanned<'p, Ast<'s, 'p>>>;
@@ -37,6 +48,16 @@ pub struct Span<'p> {
     end: usize,
 }
 
+impl<'p> Span<'p> {
+    pub fn union(self, other: Self) -> Self {
+        Span {
+            path: self.path,
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
 impl<'p> std::fmt::Debug for Span<'p> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if f.alternate() {
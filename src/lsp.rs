@@ -0,0 +1,811 @@
+//! A language server speaking LSP over stdio.
+//!
+//! Documents are synced in full. Every change is lexed, parsed and compiled
+//! again to publish diagnostics, without requiring a `main` function, and the
+//! names of the last version that parsed are indexed for navigation, hover,
+//! symbols and completion.
+
+mod index;
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error as StdError,
+    fs,
+    ops::Range,
+    path::PathBuf,
+};
+
+use chumsky::Span as _;
+use lasso::Rodeo;
+use logos::Logos;
+use lsp_server::{Connection, Message, Notification, Request, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Notification as _,
+        PublishDiagnostics,
+    },
+    request::{
+        Completion, DocumentSymbolRequest, GotoDefinition, HoverRequest, Request as _,
+        SemanticTokensFullRequest,
+    },
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionParams, Diagnostic,
+    DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DocumentSymbol, DocumentSymbolParams, GotoDefinitionParams, Hover,
+    HoverContents, HoverParams, HoverProviderCapability, Location, MarkupContent, MarkupKind,
+    OneOf, Position, PublishDiagnosticsParams, SemanticToken, SemanticTokenModifier,
+    SemanticTokenType, SemanticTokens, SemanticTokensFullOptions, SemanticTokensLegend,
+    SemanticTokensOptions, SemanticTokensParams, SemanticTokensServerCapabilities,
+    ServerCapabilities, SymbolKind, TextDocumentSyncCapability, TextDocumentSyncKind, Url,
+};
+
+use self::index::{Def, Index, Kind};
+use crate::{
+    compiler::Compiler,
+    lexer::Token,
+    parser::{self, parse},
+    stdlib, Span,
+};
+
+/// Native functions and types every module can use.
+const BUILTINS: &[&str] = &[
+    "print",
+    "eprint",
+    "read_line",
+    "len",
+    "to_string",
+    "inspect",
+];
+const BUILTIN_TYPES: &[&str] = &["Object", "String", "List", "Map", "Error"];
+/// Native methods of lists, maps and errors. Strings have the functions of
+/// the `string` module as methods.
+const BUILTIN_MEMBERS: &[&str] = &[
+    "len", "push", "pop", "slice", "join", "has", "remove", "keys", "values", "message",
+];
+
+const TOKEN_TYPES: &[SemanticTokenType] = &[
+    SemanticTokenType::KEYWORD,
+    SemanticTokenType::NUMBER,
+    SemanticTokenType::STRING,
+    SemanticTokenType::COMMENT,
+    SemanticTokenType::OPERATOR,
+    SemanticTokenType::FUNCTION,
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::PARAMETER,
+    SemanticTokenType::TYPE,
+    SemanticTokenType::PROPERTY,
+    SemanticTokenType::METHOD,
+    SemanticTokenType::NAMESPACE,
+];
+const TOKEN_MODIFIERS: &[SemanticTokenModifier] = &[
+    SemanticTokenModifier::DECLARATION,
+    SemanticTokenModifier::READONLY,
+];
+const DECLARATION: u32 = 1;
+const READONLY: u32 = 2;
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// Serves requests on stdin and stdout until the client shuts the server down.
+pub fn run() -> Result<(), BoxError> {
+    let (connection, io_threads) = Connection::stdio();
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![".".to_owned()]),
+            ..Default::default()
+        }),
+        semantic_tokens_provider: Some(SemanticTokensServerCapabilities::SemanticTokensOptions(
+            SemanticTokensOptions {
+                legend: SemanticTokensLegend {
+                    token_types: TOKEN_TYPES.to_vec(),
+                    token_modifiers: TOKEN_MODIFIERS.to_vec(),
+                },
+                full: Some(SemanticTokensFullOptions::Bool(true)),
+                ..Default::default()
+            },
+        )),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+    let server = Server {
+        connection,
+        documents: HashMap::new(),
+    };
+    // The connection has to be closed before the IO threads can finish
+    server.serve()?;
+    io_threads.join()?;
+    Ok(())
+}
+
+struct Document {
+    text: String,
+    /// The index of the last version that parsed, moved onto the current text
+    index: Option<Index>,
+}
+
+struct Server {
+    connection: Connection,
+    documents: HashMap<Url, Document>,
+}
+
+impl Server {
+    fn serve(mut self) -> Result<(), BoxError> {
+        while let Ok(message) = self.connection.receiver.recv() {
+            match message {
+                Message::Request(request) => {
+                    if self.connection.handle_shutdown(&request)? {
+                        return Ok(());
+                    }
+                    self.request(request)?;
+                }
+                Message::Notification(notification) => self.notification(notification)?,
+                Message::Response(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn request(&mut self, request: Request) -> Result<(), BoxError> {
+        let Request { id, method, params } = request;
+        let result = match method.as_str() {
+            GotoDefinition::METHOD => {
+                let params: GotoDefinitionParams = serde_json::from_value(params)?;
+                let position = params.text_document_position_params;
+                let uri = position.text_document.uri;
+                let location = self
+                    .def_at(&uri, position.position)
+                    .map(|(doc, def)| Location {
+                        uri: uri.clone(),
+                        range: range(&doc.text, &def.span),
+                    });
+                serde_json::to_value(location)?
+            }
+            HoverRequest::METHOD => {
+                let params: HoverParams = serde_json::from_value(params)?;
+                let position = params.text_document_position_params;
+                let hover = self
+                    .def_at(&position.text_document.uri, position.position)
+                    .map(|(doc, def)| Hover {
+                        contents: HoverContents::Markup(MarkupContent {
+                            kind: MarkupKind::Markdown,
+                            value: describe(doc.index.as_ref().unwrap(), def),
+                        }),
+                        range: None,
+                    });
+                serde_json::to_value(hover)?
+            }
+            DocumentSymbolRequest::METHOD => {
+                let params: DocumentSymbolParams = serde_json::from_value(params)?;
+                let symbols = self
+                    .documents
+                    .get(&params.text_document.uri)
+                    .and_then(|doc| Some(symbols(&doc.text, doc.index.as_ref()?)));
+                serde_json::to_value(symbols)?
+            }
+            Completion::METHOD => {
+                let params: CompletionParams = serde_json::from_value(params)?;
+                let position = params.text_document_position;
+                let items = self
+                    .documents
+                    .get(&position.text_document.uri)
+                    .map(|doc| completions(doc, offset(&doc.text, position.position)));
+                serde_json::to_value(items)?
+            }
+            SemanticTokensFullRequest::METHOD => {
+                let params: SemanticTokensParams = serde_json::from_value(params)?;
+                let tokens = self
+                    .documents
+                    .get(&params.text_document.uri)
+                    .map(semantic_tokens);
+                serde_json::to_value(tokens)?
+            }
+            _ => {
+                let response = Response::new_err(
+                    id,
+                    lsp_server::ErrorCode::MethodNotFound as i32,
+                    format!("unsupported request {}", method),
+                );
+                self.connection.sender.send(Message::Response(response))?;
+                return Ok(());
+            }
+        };
+        let response = Response::new_ok(id, result);
+        self.connection.sender.send(Message::Response(response))?;
+        Ok(())
+    }
+
+    fn notification(&mut self, notification: Notification) -> Result<(), BoxError> {
+        let Notification { method, params } = notification;
+        match method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params: DidOpenTextDocumentParams = serde_json::from_value(params)?;
+                let document = params.text_document;
+                self.update(document.uri, document.text)?;
+            }
+            DidChangeTextDocument::METHOD => {
+                let params: DidChangeTextDocumentParams = serde_json::from_value(params)?;
+                // Documents are synced in full, so the last change is the whole text
+                if let Some(change) = params.content_changes.into_iter().last() {
+                    self.update(params.text_document.uri, change.text)?;
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let params: DidCloseTextDocumentParams = serde_json::from_value(params)?;
+                let uri = params.text_document.uri;
+                self.documents.remove(&uri);
+                self.publish(uri, Vec::new())?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, uri: Url, text: String) -> Result<(), BoxError> {
+        let path = uri
+            .to_file_path()
+            .unwrap_or_else(|_| PathBuf::from(uri.path()));
+        let (index, diagnostics) = analyze(&text, &path);
+        let diagnostics = diagnostics
+            .into_iter()
+            .map(|(span, severity, message)| Diagnostic {
+                range: range(&text, &span),
+                severity: Some(severity),
+                source: Some("onilang".to_owned()),
+                message,
+                ..Default::default()
+            })
+            .collect();
+        let document = self.documents.entry(uri.clone()).or_insert(Document {
+            text: String::new(),
+            index: None,
+        });
+        match (index, &mut document.index) {
+            (Some(index), _) => document.index = Some(index),
+            // The names of the last version that parsed, where they are now
+            (None, Some(stale)) => stale.shift(&document.text, &text),
+            (None, None) => {}
+        }
+        document.text = text;
+        self.publish(uri, diagnostics)
+    }
+
+    fn publish(&self, uri: Url, diagnostics: Vec<Diagnostic>) -> Result<(), BoxError> {
+        let params = PublishDiagnosticsParams {
+            uri,
+            diagnostics,
+            version: None,
+        };
+        let notification = Notification::new(PublishDiagnostics::METHOD.to_owned(), params);
+        self.connection
+            .sender
+            .send(Message::Notification(notification))?;
+        Ok(())
+    }
+
+    fn def_at(&self, uri: &Url, position: Position) -> Option<(&Document, &Def)> {
+        let doc = self.documents.get(uri)?;
+        let def = doc.index.as_ref()?.def_at(offset(&doc.text, position))?;
+        Some((doc, def))
+    }
+}
+
+/// Problems found in a document, with how severe they are.
+type Problems = Vec<(Range<usize>, DiagnosticSeverity, String)>;
+
+/// Parses and compiles a document, returning its index if it parsed and the
+/// problems found.
+fn analyze(text: &str, path: &std::path::Path) -> (Option<Index>, Problems) {
+    let tokens = Token::lexer(text)
+        .spanned()
+        .map(|(t, s)| (t, Span::new(path, s)))
+        .collect::<Vec<_>>();
+    if tokens.is_empty() {
+        return (Some(Index::default()), Vec::new());
+    }
+    let ast = match parse(tokens) {
+        Ok(ast) => ast,
+        Err(errors) => {
+            let diagnostics = errors
+                .into_iter()
+                .map(|e| {
+                    let span = e.span();
+                    let message = parser::message(&e);
+                    (span.start()..span.end(), DiagnosticSeverity::ERROR, message)
+                })
+                .collect();
+            return (None, diagnostics);
+        }
+    };
+    let index = Index::new(&ast);
+    let mut interner = Rodeo::new();
+    let diagnostics = match Compiler::check(ast, &mut interner, Vec::new()) {
+        // Imported modules are checked when they're open themselves
+        Ok(warnings) => warnings
+            .into_iter()
+            .filter(|warning| warning.span.path == path)
+            .map(|warning| {
+                let span = warning.span.start..warning.span.end;
+                (span, DiagnosticSeverity::WARNING, warning.message)
+            })
+            .collect(),
+        Err(e) => {
+            let message = e.kind().to_string();
+            let (at, message) = match e.span() {
+                Some(span) if span.path == path => (span.start..span.end, message),
+                // Errors in imported modules are reported at the import, or at
+                // the start of the document if it's imported indirectly
+                Some(span) => {
+                    let message = match fs::read_to_string(&span.path) {
+                        Ok(text) => {
+                            let at = position(&text, span.start);
+                            format!(
+                                "{} in {}:{}:{}",
+                                message,
+                                span.path.display(),
+                                at.line + 1,
+                                at.character + 1
+                            )
+                        }
+                        Err(_) => format!("{} in {}", message, span.path.display()),
+                    };
+                    let module = span.path.file_stem().and_then(|stem| stem.to_str());
+                    let import = index
+                        .defs
+                        .iter()
+                        .find(|def| def.kind == Kind::Module && Some(def.name.as_str()) == module)
+                        .map_or(0..0, |def| def.span.clone());
+                    (import, message)
+                }
+                None => (0..0, message),
+            };
+            vec![(at, DiagnosticSeverity::ERROR, message)]
+        }
+    };
+    (Some(index), diagnostics)
+}
+
+/// Markdown describing a definition, including how a binding can be assigned.
+fn describe(index: &Index, def: &Def) -> String {
+    let parent = def.parent.map(|parent| &index.defs[parent].name);
+    let (code, note) = match def.kind {
+        Kind::Function => (
+            format!("defn {}({})", def.name, def.params.join(", ")),
+            None,
+        ),
+        Kind::Method => (
+            format!("defn {}({})", def.name, def.params.join(", ")),
+            parent.map(|ty| format!("Method of `{}`", ty)),
+        ),
+        Kind::Type => (format!("type {}", def.name), None),
+        Kind::Module => (format!("import {}", def.name), None),
+        Kind::Mutable => (
+            format!("{} :=", def.name),
+            Some("Mutable binding, can be reassigned with `=`".to_owned()),
+        ),
+        Kind::Immutable => (
+            format!("{} $=", def.name),
+            Some("Immutable binding".to_owned()),
+        ),
+        Kind::Parameter => (def.name.clone(), Some("Immutable parameter".to_owned())),
+        Kind::Field(true) => (
+            format!("{} :=", def.name),
+            parent.map(|ty| format!("Mutable field of `{}`", ty)),
+        ),
+        Kind::Field(false) => (
+            format!("{} $=", def.name),
+            parent.map(|ty| format!("Immutable field of `{}`", ty)),
+        ),
+    };
+    match note {
+        Some(note) => format!("```onilang\n{}\n```\n{}", code, note),
+        None => format!("```onilang\n{}\n```", code),
+    }
+}
+
+#[allow(deprecated)]
+fn symbols(text: &str, index: &Index) -> Vec<DocumentSymbol> {
+    let symbol = |def: &Def, children: Option<Vec<DocumentSymbol>>| DocumentSymbol {
+        name: def.name.clone(),
+        detail: match def.kind {
+            Kind::Function | Kind::Method => Some(format!("({})", def.params.join(", "))),
+            _ => None,
+        },
+        kind: match def.kind {
+            Kind::Function => SymbolKind::FUNCTION,
+            Kind::Method => SymbolKind::METHOD,
+            Kind::Type => SymbolKind::CLASS,
+            Kind::Module => SymbolKind::MODULE,
+            Kind::Mutable => SymbolKind::VARIABLE,
+            Kind::Immutable | Kind::Parameter => SymbolKind::CONSTANT,
+            Kind::Field(_) => SymbolKind::FIELD,
+        },
+        tags: None,
+        deprecated: None,
+        range: range(text, &def.extent),
+        selection_range: range(text, &def.span),
+        children,
+    };
+    index
+        .defs
+        .iter()
+        .enumerate()
+        .filter(|(_, def)| def.top_level && def.kind != Kind::Module)
+        .map(|(i, def)| {
+            let children = (def.kind == Kind::Type).then(|| {
+                index
+                    .defs
+                    .iter()
+                    .filter(|member| member.parent == Some(i))
+                    .map(|member| symbol(member, None))
+                    .collect()
+            });
+            symbol(def, children)
+        })
+        .collect()
+}
+
+fn completions(doc: &Document, offset: usize) -> Vec<CompletionItem> {
+    let index = match &doc.index {
+        Some(index) => index,
+        None => return Vec::new(),
+    };
+    let item = |label: &str, kind| CompletionItem {
+        label: label.to_owned(),
+        kind: Some(kind),
+        ..Default::default()
+    };
+    // After a `.`, with or without the start of a name typed
+    let before = doc.text[..offset.min(doc.text.len())]
+        .trim_end_matches(|c: char| c.is_alphanumeric() || c == '_' || c == '$');
+    if before.ends_with('.') {
+        let mut members = BTreeSet::new();
+        for def in index.defs.iter().filter(|def| def.parent.is_some()) {
+            members.insert((def.name.as_str(), def.kind == Kind::Method));
+        }
+        for (_, name, kind) in &index.members {
+            members.insert((name.as_str(), *kind == Kind::Method));
+        }
+        for name in BUILTIN_MEMBERS {
+            members.insert((name, true));
+        }
+        for (_, natives, constants) in stdlib::MODULES {
+            members.extend(natives.iter().map(|(name, ..)| (*name, true)));
+            members.extend(constants.iter().map(|(name, _)| (*name, false)));
+        }
+        return members
+            .into_iter()
+            .map(|(name, method)| match method {
+                true => item(name, CompletionItemKind::METHOD),
+                false => item(name, CompletionItemKind::FIELD),
+            })
+            .collect();
+    }
+    let mut items = index
+        .visible_at(offset)
+        .into_iter()
+        .map(|def| {
+            let kind = match def.kind {
+                Kind::Function | Kind::Method => CompletionItemKind::FUNCTION,
+                Kind::Type => CompletionItemKind::CLASS,
+                Kind::Module => CompletionItemKind::MODULE,
+                Kind::Mutable | Kind::Parameter => CompletionItemKind::VARIABLE,
+                Kind::Immutable => CompletionItemKind::CONSTANT,
+                Kind::Field(_) => CompletionItemKind::FIELD,
+            };
+            item(&def.name, kind)
+        })
+        .collect::<Vec<_>>();
+    items.extend(
+        BUILTINS
+            .iter()
+            .map(|name| item(name, CompletionItemKind::FUNCTION)),
+    );
+    items.extend(
+        stdlib::MODULES
+            .iter()
+            .map(|(name, ..)| item(name, CompletionItemKind::MODULE)),
+    );
+    items.extend(
+        BUILTIN_TYPES
+            .iter()
+            .map(|name| item(name, CompletionItemKind::CLASS)),
+    );
+    items
+}
+
+fn semantic_tokens(doc: &Document) -> SemanticTokens {
+    let mut names = HashMap::new();
+    if let Some(index) = &doc.index {
+        for def in &index.defs {
+            names.insert(def.span.start, classify(def.kind) | DECLARATION);
+        }
+        for (span, def) in &index.refs {
+            names.insert(span.start, classify(index.defs[*def].kind));
+        }
+        for (span, _, kind) in &index.members {
+            names.insert(span.start, classify(*kind));
+        }
+        for span in &index.types {
+            names.insert(span.start, classify(Kind::Type));
+        }
+    }
+    let tokens = Token::lexer(&doc.text).spanned().collect::<Vec<_>>();
+    let mut data = Vec::new();
+    let mut last = Position::new(0, 0);
+    for (i, (token, span)) in tokens.iter().enumerate() {
+        let (token_type, modifiers) = match token {
+            Token::Identifier(_) => match names.get(&span.start) {
+                Some(&encoded) => (encoded >> 8, encoded & 0xff),
+                // Unresolved names are builtins or globals of other modules
+                None if matches!(tokens.get(i + 1), Some((Token::LParen, _))) => (5, 0),
+                None => (6, 0),
+            },
+            Token::Number(_) | Token::Float(_) => (1, 0),
+            Token::String(_) => (2, 0),
+            Token::Comment => (3, 0),
+            Token::Plus
+            | Token::Minus
+            | Token::Slash
+            | Token::Equals
+            | Token::NotEquals
+            | Token::Less
+            | Token::LessEquals
+            | Token::Greater
+            | Token::GreaterEquals
+            | Token::Range
+            | Token::RangeInclusive
+            | Token::ImmutDeclAssign
+            | Token::DeclAssign
+            | Token::Assign
+            | Token::FatArrow => (4, 0),
+            Token::Accessor
+            | Token::Comma
+            | Token::LParen
+            | Token::RParen
+            | Token::LBracket
+            | Token::RBracket
+            | Token::LBrace
+            | Token::RBrace
+            | Token::Colon
+            | Token::Semicolon
+            | Token::Error
+            | Token::Whitespace => continue,
+            _ => (0, 0),
+        };
+        let start = position(&doc.text, span.start);
+        let length = doc.text[span.clone()].encode_utf16().count() as u32;
+        data.push(SemanticToken {
+            delta_line: start.line - last.line,
+            delta_start: match start.line == last.line {
+                true => start.character - last.character,
+                false => start.character,
+            },
+            length,
+            token_type,
+            token_modifiers_bitset: modifiers,
+        });
+        last = start;
+    }
+    SemanticTokens {
+        result_id: None,
+        data,
+    }
+}
+
+/// The token type of a definition in the legend, shifted above its modifiers.
+fn classify(kind: Kind) -> u32 {
+    let (token_type, modifiers) = match kind {
+        Kind::Function => (5, 0),
+        Kind::Mutable => (6, 0),
+        Kind::Immutable => (6, READONLY),
+        Kind::Parameter => (7, READONLY),
+        Kind::Type => (8, 0),
+        Kind::Field(true) => (9, 0),
+        Kind::Field(false) => (9, READONLY),
+        Kind::Method => (10, 0),
+        Kind::Module => (11, 0),
+    };
+    token_type << 8 | modifiers
+}
+
+/// The LSP position of a byte offset, counting UTF-16 code units as the
+/// protocol requires.
+fn position(text: &str, offset: usize) -> Position {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &text[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let character = before[line_start..].encode_utf16().count();
+    Position::new(line as u32, character as u32)
+}
+
+fn offset(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= position.character as usize || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn range(text: &str, span: &Range<usize>) -> lsp_types::Range {
+    lsp_types::Range::new(position(text, span.start), position(text, span.end))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::testing::temp_dir;
+
+    const OLD: &str = "defn double(x) do\n    x + 2\nend\n\ndefn main() do\n    double(1)\nend\n";
+
+    fn index(text: &str) -> Index {
+        analyze(text, Path::new("test.oni")).0.unwrap()
+    }
+
+    fn diagnostics(text: &str) -> Problems {
+        analyze(text, Path::new("test.oni")).1
+    }
+
+    #[test]
+    fn checks_modules_without_main() {
+        assert!(diagnostics("defn helper(x) do\n    x + 1\nend\n").is_empty());
+        assert!(diagnostics(OLD).is_empty());
+    }
+
+    #[test]
+    fn reports_errors_where_they_are() {
+        let text = "defn helper() do\n    y := 1\n    break\nend\n";
+        let at = text.find("break").unwrap();
+        assert_eq!(
+            diagnostics(text),
+            [(
+                at..at + 5,
+                DiagnosticSeverity::ERROR,
+                "Compiler error".to_owned()
+            )]
+        );
+        let text = "defn main() do\n    x :=\nend\n";
+        let at = text.rfind("end").unwrap();
+        let found = diagnostics(text);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, at..at + 3);
+    }
+
+    #[test]
+    fn reports_errors_in_imports_at_the_import() {
+        let dir = temp_dir("lsp-imports");
+        fs::write(dir.join("broken.oni"), "defn f() do\n    x :=\nend\n").unwrap();
+        let text = "import broken\n\ndefn main() do\n    nil\nend\n";
+        let found = analyze(text, &dir.join("main.oni")).1;
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0, 7..13);
+        assert!(found[0].2.ends_with("broken.oni:3:1"), "{}", found[0].2);
+    }
+
+    #[test]
+    fn reports_compiler_warnings() {
+        let text = "defn main() do\n    while true do\n        nil\n    end\n    match 1 do\n        _ => 1,\n        2 => 2,\n    end\nend\n";
+        let at = text.find("while").unwrap();
+        let arm = text.find("2 =>").unwrap();
+        assert_eq!(
+            diagnostics(text),
+            [
+                (
+                    at..at + 5,
+                    DiagnosticSeverity::WARNING,
+                    "`while true` loop without `break` never terminates".to_owned()
+                ),
+                (
+                    arm..arm + 1,
+                    DiagnosticSeverity::WARNING,
+                    "unreachable match arm".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolves_names_to_the_innermost_declaration() {
+        let text =
+            "defn f(x) do\n    y := x\n    y\nend\n\ndefn main() do\n    y := 1\n    f(y)\nend\n";
+        let index = index(text);
+        let inner = text.find("y :=").unwrap();
+        let outer = text.rfind("y :=").unwrap();
+        let def = index.def_at(text.find("    y\n").unwrap() + 4).unwrap();
+        assert_eq!((def.span.start, def.kind), (inner, Kind::Mutable));
+        let def = index.def_at(text.rfind("f(y)").unwrap() + 2).unwrap();
+        assert_eq!((def.span.start, def.kind), (outer, Kind::Mutable));
+        let def = index.def_at(text.find("= x").unwrap() + 2).unwrap();
+        assert_eq!((def.name.as_str(), def.kind), ("x", Kind::Parameter));
+        let def = index.def_at(text.rfind("f(y)").unwrap()).unwrap();
+        assert_eq!((def.name.as_str(), def.kind), ("f", Kind::Function));
+    }
+
+    #[test]
+    fn lists_names_visible_at_an_offset() {
+        let text =
+            "defn f(x) do\n    y := x\n    y\nend\n\ndefn main() do\n    z := 1\n    z\nend\n";
+        let index = index(text);
+        let mut names = index
+            .visible_at(text.rfind("z\n").unwrap())
+            .iter()
+            .map(|def| def.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["f", "main", "z"]);
+    }
+
+    #[test]
+    fn shift_moves_names_after_the_change() {
+        let new = OLD.replace("x + 2", "x + (é");
+        assert!(analyze(&new, Path::new("test.oni")).0.is_none());
+        let mut index = index(OLD);
+        index.shift(OLD, &new);
+        let call = new.rfind("double").unwrap();
+        let def = index.def_at(call + 1).unwrap();
+        assert_eq!(def.name, "double");
+        assert_eq!(&new[def.span.clone()], "double");
+        assert!(index
+            .refs
+            .iter()
+            .all(|(span, _)| new.is_char_boundary(span.start)));
+    }
+
+    #[test]
+    fn shift_drops_names_inside_the_change() {
+        let new = OLD.replace("double(1)", "dou(");
+        let mut index = index(OLD);
+        index.shift(OLD, &new);
+        let call = new.rfind("dou").unwrap();
+        assert!(index.def_at(call + 1).is_none());
+        assert_eq!(
+            index.defs.iter().filter(|def| def.name == "double").count(),
+            1
+        );
+    }
+
+    #[test]
+    fn shift_keeps_parents_of_members() {
+        let old = "type Point do\n    x := 0\nend\n\ndefn main() do\n    1\nend\n";
+        let new = format!("(\n{}", old);
+        let mut index = index(old);
+        index.shift(old, &new);
+        let field = index.defs.iter().find(|def| def.name == "x").unwrap();
+        assert_eq!(index.defs[field.parent.unwrap()].name, "Point");
+        assert_eq!(&new[field.span.clone()], "x");
+    }
+
+    #[test]
+    fn position_clamps_to_char_boundaries() {
+        let text = "a\né";
+        assert_eq!(position(text, 3), Position::new(1, 0));
+        assert_eq!(position(text, 4), Position::new(1, 1));
+        assert_eq!(position(text, 100), Position::new(1, 1));
+    }
+
+    #[test]
+    fn offset_counts_utf16_units() {
+        let text = "é𝄞x\n";
+        assert_eq!(offset(text, Position::new(0, 3)), "é𝄞".len());
+        assert_eq!(offset(text, Position::new(1, 0)), text.len());
+    }
+}
@@ -0,0 +1,560 @@
+//! Resolves the names of a parsed document to the places they're declared.
+//!
+//! Like the compiler, the index treats functions, types and imports as visible
+//! everywhere in their module, and bindings as visible from their declaration
+//! to the end of the enclosing block. Names the document doesn't declare, like
+//! builtins or the globals of other modules, are left unresolved.
+
+use std::{collections::BTreeSet, ops::Range};
+
+use crate::{
+    lexer::Token,
+    parser::{Assignment, Ast, BinOp, Defn, For, Import, Loop, Match, Pattern, Try, Type, While},
+    Span, Spanned, SpannedAst,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Function,
+    Type,
+    Module,
+    /// A binding declared with `:=`
+    Mutable,
+    /// A binding declared with `$=`, or bound by a loop, pattern or `rescue`
+    Immutable,
+    Parameter,
+    /// A field of a type, and whether it's declared with `:=`
+    Field(bool),
+    Method,
+}
+
+pub struct Def {
+    pub name: String,
+    pub kind: Kind,
+    /// The name where it's declared
+    pub span: Range<usize>,
+    /// The whole declaration of a function or type, or the name
+    pub extent: Range<usize>,
+    /// Whether it's declared at the top of the module
+    pub top_level: bool,
+    /// Where the name can be used
+    pub scope: Range<usize>,
+    /// The type a field or method belongs to
+    pub parent: Option<usize>,
+    /// The parameters of a function or method
+    pub params: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct Index {
+    pub defs: Vec<Def>,
+    /// Names that refer to a definition, and which one
+    pub refs: Vec<(Range<usize>, usize)>,
+    /// Field and method names used on values, which may not be declared here
+    pub members: Vec<(Range<usize>, String, Kind)>,
+    /// Type names after `new`, which may be builtin types
+    pub types: Vec<Range<usize>>,
+}
+
+impl Index {
+    pub fn new(module: &SpannedAst) -> Self {
+        let mut resolver = Resolver {
+            index: Index::default(),
+            scopes: vec![Vec::new()],
+            ends: vec![module.span.end],
+        };
+        let items = match &module.inner {
+            Ast::Module(items) => items,
+            _ => return resolver.index,
+        };
+        // Functions, types and imports can be used before they're declared, so
+        // they're declared before any code is resolved
+        for item in items {
+            match &item.inner {
+                Ast::Defn(box defn) => {
+                    let params = params(defn);
+                    let def = resolver.declare(&defn.name, Kind::Function, None, params);
+                    resolver.extend(def, item.span);
+                }
+                Ast::Type(box ty) => {
+                    let parent = resolver.declare(&ty.name, Kind::Type, None, Vec::new());
+                    resolver.extend(parent, item.span);
+                    for member in &ty.body {
+                        match &member.inner {
+                            Ast::Defn(box defn) => {
+                                let params = params(defn);
+                                let def =
+                                    resolver.declare(&defn.name, Kind::Method, parent, params);
+                                resolver.extend(def, member.span);
+                            }
+                            Ast::Assignment(box Assignment {
+                                place:
+                                    Spanned {
+                                        span: _,
+                                        inner: Ast::Place(box field, _),
+                                    },
+                                assign,
+                                expr: _,
+                            }) => {
+                                let kind = Kind::Field(assign.inner == Token::DeclAssign);
+                                resolver.declare(field, kind, parent, Vec::new());
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Ast::Import(box import) => resolver.import(import),
+                _ => {}
+            }
+        }
+        // Globals are declared in order by the module's own code, which runs
+        // before any function
+        for item in items {
+            if !matches!(item.inner, Ast::Defn(_) | Ast::Type(_) | Ast::Import(_)) {
+                resolver.expr(item);
+            }
+        }
+        for item in items {
+            match &item.inner {
+                Ast::Defn(box defn) => resolver.defn(defn),
+                Ast::Type(box ty) => resolver.ty(ty),
+                _ => {}
+            }
+        }
+        resolver.index
+    }
+
+    /// The definition a name at `offset` declares or refers to. Members are
+    /// matched to fields and methods by name, since the type of a value isn't
+    /// known.
+    pub fn def_at(&self, offset: usize) -> Option<&Def> {
+        let at = |span: &Range<usize>| span.contains(&offset) || span.end == offset;
+        if let Some(def) = self.defs.iter().find(|def| at(&def.span)) {
+            return Some(def);
+        }
+        if let Some(&(_, def)) = self.refs.iter().find(|(span, _)| at(span)) {
+            return Some(&self.defs[def]);
+        }
+        let (_, name, _) = self.members.iter().find(|(span, _, _)| at(span))?;
+        self.defs
+            .iter()
+            .find(|def| def.parent.is_some() && def.name == *name)
+    }
+
+    /// The names that can be used at `offset`, innermost first.
+    pub fn visible_at(&self, offset: usize) -> Vec<&Def> {
+        let mut visible = self
+            .defs
+            .iter()
+            .filter(|def| def.parent.is_none() && def.scope.contains(&offset))
+            .collect::<Vec<_>>();
+        visible.sort_by_key(|def| std::cmp::Reverse(def.scope.start));
+        let mut seen = BTreeSet::new();
+        visible.retain(|def| seen.insert(def.name.as_str()));
+        visible
+    }
+
+    /// Moves the index of `old` onto `new`, for when `new` doesn't parse.
+    /// Names after the changed text move with it, and names inside it are
+    /// dropped, along with the fields and methods of a type whose name is.
+    pub fn shift(&mut self, old: &str, new: &str) {
+        let prefix = old
+            .char_indices()
+            .zip(new.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(old.len().min(new.len()), |((i, _), _)| i);
+        let suffix = old[prefix..]
+            .chars()
+            .rev()
+            .zip(new[prefix..].chars().rev())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum::<usize>();
+        let (old_end, new_end) = (old.len() - suffix, new.len() - suffix);
+        // Ranges entirely before or after the change, moved
+        let moved = |range: &Range<usize>| match range {
+            _ if range.end <= prefix => Some(range.clone()),
+            _ if range.start >= old_end => {
+                Some(range.start - old_end + new_end..range.end - old_end + new_end)
+            }
+            _ => None,
+        };
+        // Ranges that may span the change, taking in all of the new text
+        let clamped = |range: &Range<usize>| {
+            let bound = |offset: usize, inside: usize| match offset {
+                _ if offset <= prefix => offset,
+                _ if offset >= old_end => offset - old_end + new_end,
+                _ => inside,
+            };
+            bound(range.start, prefix)..bound(range.end, new_end)
+        };
+
+        let mut kept = Vec::with_capacity(self.defs.len());
+        let mut defs = Vec::with_capacity(self.defs.len());
+        for mut def in self.defs.drain(..) {
+            let parent = match def.parent {
+                Some(parent) => kept[parent],
+                None => Some(usize::MAX),
+            };
+            match (moved(&def.span), parent) {
+                (Some(span), Some(parent)) => {
+                    kept.push(Some(defs.len()));
+                    def.span = span;
+                    def.extent = clamped(&def.extent);
+                    def.scope = clamped(&def.scope);
+                    def.parent = def.parent.map(|_| parent);
+                    defs.push(def);
+                }
+                _ => kept.push(None),
+            }
+        }
+        self.defs = defs;
+        self.refs = self
+            .refs
+            .iter()
+            .filter_map(|(span, def)| Some((moved(span)?, kept[*def]?)))
+            .collect();
+        self.members = self
+            .members
+            .iter()
+            .filter_map(|(span, name, kind)| Some((moved(span)?, name.clone(), *kind)))
+            .collect();
+        self.types = self.types.iter().filter_map(moved).collect();
+    }
+}
+
+fn params(defn: &Defn) -> Vec<String> {
+    match &defn.args.inner {
+        Ast::Arglist(args) => args
+            .iter()
+            .filter_map(ident)
+            .map(|(name, _)| name.to_owned())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn ident<'s>(ast: &SpannedAst<'s, '_>) -> Option<(&'s str, Range<usize>)> {
+    match ast.inner {
+        Ast::Identifier(name) => Some((name, range(ast.span))),
+        _ => None,
+    }
+}
+
+fn range(span: Span) -> Range<usize> {
+    span.start..span.end
+}
+
+struct Resolver {
+    index: Index,
+    /// The definitions visible in each enclosing block, innermost last
+    scopes: Vec<Vec<usize>>,
+    /// Where each enclosing block ends
+    ends: Vec<usize>,
+}
+
+impl Resolver {
+    fn declare(
+        &mut self,
+        name: &SpannedAst,
+        kind: Kind,
+        parent: Option<usize>,
+        params: Vec<String>,
+    ) -> Option<usize> {
+        let (name, span) = ident(name)?;
+        Some(self.declare_name(name, span, kind, parent, params))
+    }
+
+    fn declare_name(
+        &mut self,
+        name: &str,
+        span: Range<usize>,
+        kind: Kind,
+        parent: Option<usize>,
+        params: Vec<String>,
+    ) -> usize {
+        let scope = match kind {
+            Kind::Function | Kind::Type | Kind::Module => 0..*self.ends.last().unwrap(),
+            _ => span.end..*self.ends.last().unwrap(),
+        };
+        let def = self.index.defs.len();
+        self.index.defs.push(Def {
+            name: name.to_owned(),
+            kind,
+            extent: span.clone(),
+            span,
+            scope,
+            top_level: parent.is_none() && self.scopes.len() == 1,
+            parent,
+            params,
+        });
+        if parent.is_none() {
+            self.scopes.last_mut().unwrap().push(def);
+        }
+        def
+    }
+
+    fn extend(&mut self, def: Option<usize>, span: Span) {
+        if let Some(def) = def {
+            self.index.defs[def].extent = range(span);
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .copied()
+            .find(|&def| self.index.defs[def].name == name)
+    }
+
+    fn reference(&mut self, name: &SpannedAst) {
+        if let Some((name, span)) = ident(name) {
+            if let Some(def) = self.lookup(name) {
+                self.index.refs.push((span, def));
+            }
+        }
+    }
+
+    fn member(&mut self, name: &SpannedAst, kind: Kind) {
+        if let Some((name, span)) = ident(name) {
+            self.index.members.push((span, name.to_owned(), kind));
+        }
+    }
+
+    fn import(&mut self, import: &Import) {
+        match &import.items {
+            Some(items) => {
+                for item in items {
+                    self.declare(item, Kind::Function, None, Vec::new());
+                }
+            }
+            None => {
+                if let Some(module) = import.path.last() {
+                    self.declare(module, Kind::Module, None, Vec::new());
+                }
+            }
+        }
+    }
+
+    fn begin_block(&mut self, end: usize) {
+        self.scopes.push(Vec::new());
+        self.ends.push(end);
+    }
+
+    fn end_block(&mut self) {
+        self.scopes.pop();
+        self.ends.pop();
+    }
+
+    fn block(&mut self, body: &[SpannedAst], end: usize) {
+        self.begin_block(end);
+        for stmt in body {
+            self.expr(stmt);
+        }
+        self.end_block();
+    }
+
+    fn defn(&mut self, defn: &Defn) {
+        self.begin_block(defn.end.span.start);
+        if let Ast::Arglist(args) = &defn.args.inner {
+            for arg in args {
+                self.declare(arg, Kind::Parameter, None, Vec::new());
+            }
+        }
+        for stmt in &defn.body {
+            self.expr(stmt);
+        }
+        self.end_block();
+    }
+
+    fn ty(&mut self, ty: &Type) {
+        for member in &ty.body {
+            match &member.inner {
+                Ast::Defn(box defn) => self.defn(defn),
+                Ast::Assignment(box assignment) => self.expr(&assignment.expr),
+                _ => {}
+            }
+        }
+    }
+
+    fn pattern(&mut self, pattern: &Spanned<Pattern>) {
+        match &pattern.inner {
+            Pattern::Wildcard => {}
+            Pattern::Binding(name) => {
+                self.declare_name(name, range(pattern.span), Kind::Immutable, None, Vec::new());
+            }
+            Pattern::Literal(_) => {}
+            Pattern::List(items) => {
+                for item in items {
+                    self.pattern(item);
+                }
+            }
+            Pattern::Map(entries) => {
+                for (_, value) in entries {
+                    self.pattern(value);
+                }
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &SpannedAst) {
+        match &expr.inner {
+            Ast::Module(_) | Ast::Defn(_) | Ast::Type(_) | Ast::Import(_) => {}
+            Ast::Assignment(box Assignment {
+                place,
+                assign,
+                expr,
+            }) => {
+                // The value comes first, so a declaration can't refer to itself
+                self.expr(expr);
+                match &place.inner {
+                    Ast::Place(box base, accessors) if accessors.is_empty() => match assign.inner {
+                        Token::DeclAssign => {
+                            self.declare(base, Kind::Mutable, None, Vec::new());
+                        }
+                        Token::ImmutDeclAssign => {
+                            self.declare(base, Kind::Immutable, None, Vec::new());
+                        }
+                        _ => self.reference(base),
+                    },
+                    Ast::Place(box base, accessors) => {
+                        self.expr(base);
+                        let mutable = assign.inner != Token::ImmutDeclAssign;
+                        for accessor in accessors {
+                            self.member(accessor, Kind::Field(mutable));
+                        }
+                    }
+                    _ => self.expr(place),
+                }
+            }
+            Ast::BinOp(box BinOp { lhs, op: _, rhs }) => {
+                self.expr(lhs);
+                self.expr(rhs);
+            }
+            Ast::String(_)
+            | Ast::Int(_)
+            | Ast::Uint(_)
+            | Ast::Float(_)
+            | Ast::Bool(_)
+            | Ast::Nil => {}
+            Ast::List(items) | Ast::Arglist(items) | Ast::Paramlist(items) => {
+                for item in items {
+                    self.expr(item);
+                }
+            }
+            Ast::Map(entries) => {
+                for (key, value) in entries {
+                    // Bare identifiers as keys are strings
+                    if !matches!(key.inner, Ast::String(_)) {
+                        self.expr(key);
+                    }
+                    self.expr(value);
+                }
+            }
+            Ast::Loop(Loop {
+                loop_: _,
+                body,
+                end,
+            }) => self.block(body, end.span.start),
+            Ast::While(box While {
+                while_: _,
+                cond,
+                _do: _,
+                body,
+                end,
+            }) => {
+                self.expr(cond);
+                self.block(body, end.span.start);
+            }
+            Ast::For(box For {
+                for_: _,
+                var,
+                in_: _,
+                iterable,
+                _do: _,
+                body,
+                end,
+            }) => {
+                self.expr(iterable);
+                self.begin_block(end.span.start);
+                self.declare(var, Kind::Immutable, None, Vec::new());
+                for stmt in body {
+                    self.expr(stmt);
+                }
+                self.end_block();
+            }
+            Ast::Try(box Try {
+                try_: _,
+                body,
+                rescue,
+                ensure,
+                end,
+            }) => {
+                self.block(body, end.span.start);
+                if let Some((name, handler)) = rescue {
+                    self.begin_block(end.span.start);
+                    self.declare(name, Kind::Immutable, None, Vec::new());
+                    for stmt in handler {
+                        self.expr(stmt);
+                    }
+                    self.end_block();
+                }
+                if let Some(ensure) = ensure {
+                    self.block(ensure, end.span.start);
+                }
+            }
+            Ast::Match(box Match { scrutinee, arms }) => {
+                self.expr(scrutinee);
+                for arm in arms {
+                    self.begin_block(arm.body.span.end);
+                    self.pattern(&arm.pattern);
+                    if let Some(guard) = &arm.guard {
+                        self.expr(guard);
+                    }
+                    self.expr(&arm.body);
+                    self.end_block();
+                }
+            }
+            Ast::Raise(box value) => self.expr(value),
+            Ast::Break(cond) | Ast::Continue(cond) => {
+                if let Some(box cond) = cond {
+                    self.expr(cond);
+                }
+            }
+            Ast::Call(box callee, box params) => {
+                self.expr(callee);
+                self.expr(params);
+            }
+            Ast::MethodCall(box receiver, box method, box params) => {
+                self.expr(receiver);
+                self.member(method, Kind::Method);
+                self.expr(params);
+            }
+            Ast::New(_, params, box ty) => {
+                if let Some(box params) = params {
+                    self.expr(params);
+                }
+                match ident(ty) {
+                    Some((name, span)) => match self.lookup(name) {
+                        Some(def) => self.index.refs.push((span, def)),
+                        None => self.index.types.push(span),
+                    },
+                    None => self.expr(ty),
+                }
+            }
+            Ast::Identifier(_) => self.reference(expr),
+            Ast::Place(box base, accessors) => {
+                self.expr(base);
+                for accessor in accessors {
+                    self.member(accessor, Kind::Field(true));
+                }
+            }
+            Ast::Index(box base, box index) => {
+                self.expr(base);
+                self.expr(index);
+            }
+        }
+    }
+}
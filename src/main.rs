@@ -1,50 +1,274 @@
 use chumsky::Span as _;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use lasso::Rodeo;
 use logos::Logos;
-use onilang::{compiler::Compiler, error::Error, lexer::Token, parser::parse, Span};
-use std::{fs::File, io::Read, path::PathBuf};
+use onilang::{
+    asm, bytecode,
+    compiler::{Compiler, OptLevel, Program},
+    debug::{self, dap::Dap, Breakpoints, Cli, Tracer},
+    error::Error,
+    eval::{Limits, Vm},
+    format,
+    lexer::Token,
+    lsp,
+    parser::{self, parse},
+    sandbox::{Access, Capabilities},
+    Span,
+};
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::PathBuf,
+};
 
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[clap()]
-    file: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(flatten)]
+    source: SourceArgs,
+    /// Print every instruction to stderr before it runs
+    #[clap(long)]
+    trace: bool,
+    #[clap(flatten)]
+    limits: LimitArgs,
+    #[clap(flatten)]
+    sandbox: SandboxArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Compile a script to a bytecode file that can be run directly
+    Compile {
+        #[clap(flatten)]
+        source: SourceArgs,
+        /// Where to write the bytecode, next to the script by default
+        #[clap(short = 'o', long)]
+        output: Option<PathBuf>,
+    },
+    /// Print the bytecode of a script or a compiled file as assembly
+    Disasm {
+        #[clap(flatten)]
+        source: SourceArgs,
+    },
+    /// Assemble a program into a bytecode file
+    Asm {
+        file: PathBuf,
+        /// Where to write the bytecode, next to the assembly by default
+        #[clap(short = 'o', long)]
+        output: Option<PathBuf>,
+    },
+    /// Rewrite scripts in the canonical style
+    Fmt {
+        #[clap(required = true)]
+        files: Vec<PathBuf>,
+        /// List the scripts that aren't formatted instead of rewriting them,
+        /// failing if there are any
+        #[clap(long)]
+        check: bool,
+    },
+    /// Run a language server on stdin and stdout
+    Lsp,
+    /// Step through a script, stopping before its first line
+    Debug {
+        #[clap(flatten)]
+        source: SourceArgs,
+        /// Serve the Debug Adapter Protocol on this address instead of
+        /// reading commands from stdin
+        #[clap(long, value_name = "ADDR")]
+        dap: Option<String>,
+        #[clap(flatten)]
+        limits: LimitArgs,
+        #[clap(flatten)]
+        sandbox: SandboxArgs,
+    },
+}
+
+#[derive(clap::Args)]
+struct SourceArgs {
+    /// A script, or a compiled `.onic` file
+    #[clap(required = true)]
+    file: Option<PathBuf>,
     #[clap(short = 't', long)]
     dump_tokens: bool,
     #[clap(short = 'a', long)]
     dump_ast: bool,
+    /// Additional directory to search for imported modules
+    #[clap(short = 'I', long = "include")]
+    search_path: Vec<PathBuf>,
+    /// Optimization level: 0 for none, 1 for cheap cleanups, 2 to also fold constants
+    #[clap(short = 'O', long = "opt-level", default_value_t = 0)]
+    opt_level: OptLevel,
+}
+
+// Limits for running untrusted scripts, unlimited by default. Not a doc
+// comment, which clap would take as the description of the whole program.
+#[derive(clap::Args)]
+struct LimitArgs {
+    /// Stop after running this many instructions
+    #[clap(long, value_name = "N")]
+    max_instructions: Option<u64>,
+    /// Stop when calls nest deeper than this
+    #[clap(long, value_name = "N")]
+    max_depth: Option<usize>,
+    /// Stop when the value stack grows past this many values
+    #[clap(long, value_name = "N")]
+    max_stack: Option<usize>,
+    /// Stop after allocating this many bytes
+    #[clap(long, value_name = "BYTES")]
+    max_heap: Option<usize>,
+}
+
+// What a script may access, everything by default.
+#[derive(clap::Args)]
+struct SandboxArgs {
+    /// Deny filesystem and standard input access, except as granted below
+    #[clap(long)]
+    sandbox: bool,
+    /// Allow reading files in a directory
+    #[clap(long, value_name = "DIR", requires = "sandbox")]
+    allow_read: Vec<PathBuf>,
+    /// Allow reading and writing files in a directory
+    #[clap(long, value_name = "DIR", requires = "sandbox")]
+    allow_write: Vec<PathBuf>,
+    /// Allow reading standard input
+    #[clap(long, requires = "sandbox")]
+    allow_stdin: bool,
+}
+
+impl LimitArgs {
+    fn limits(self) -> Limits {
+        Limits {
+            instructions: self.max_instructions,
+            call_depth: self.max_depth,
+            stack: self.max_stack,
+            heap: self.max_heap,
+        }
+    }
+}
+
+impl SandboxArgs {
+    fn capabilities(self) -> Capabilities {
+        if !self.sandbox {
+            return Capabilities::all();
+        }
+        let mut capabilities = Capabilities::none();
+        for dir in self.allow_read {
+            capabilities = capabilities.grant_dir(dir, Access::Read);
+        }
+        for dir in self.allow_write {
+            capabilities = capabilities.grant_dir(dir, Access::ReadWrite);
+        }
+        if self.allow_stdin {
+            capabilities = capabilities.grant_stdin();
+        }
+        capabilities
+    }
 }
 
 fn main() -> Result<(), Error> {
     let args = Args::parse();
-    let mut file = File::open(&args.file).unwrap();
-    let mut src = String::new();
-    file.read_to_string(&mut src).unwrap();
+    let mut interner = Rodeo::new();
+    match args.command {
+        Some(Command::Compile { source, output }) => {
+            let file = source.file.clone().unwrap();
+            let program = load(source, &mut interner)?;
+            let output = output.unwrap_or_else(|| file.with_extension("onic"));
+            fs::write(output, bytecode::write(&program, &interner)?).unwrap();
+        }
+        Some(Command::Disasm { source }) => {
+            let program = load(source, &mut interner)?;
+            print!("{}", asm::disassemble(&program, &interner));
+        }
+        Some(Command::Asm { file, output }) => {
+            let src = fs::read_to_string(&file).unwrap();
+            let program = asm::assemble(&src, &mut interner)?;
+            let output = output.unwrap_or_else(|| file.with_extension("onic"));
+            fs::write(output, bytecode::write(&program, &interner)?).unwrap();
+        }
+        Some(Command::Fmt { files, check }) => {
+            let mut unformatted = false;
+            for file in files {
+                let src = fs::read_to_string(&file).unwrap();
+                let formatted = format::source(&src, &file)?;
+                if formatted == src {
+                    continue;
+                }
+                if check {
+                    println!("{}", file.display());
+                    unformatted = true;
+                } else {
+                    fs::write(&file, formatted).unwrap();
+                }
+            }
+            if unformatted {
+                std::process::exit(1);
+            }
+        }
+        Some(Command::Lsp) => lsp::run().unwrap(),
+        Some(Command::Debug {
+            source,
+            dap,
+            limits,
+            sandbox,
+        }) => {
+            let program = load(source, &mut interner)?;
+            let warnings = program.warnings.clone();
+            let mut vm = Vm::new(program, &mut interner)?;
+            vm.set_limits(limits.limits());
+            vm.set_capabilities(sandbox.capabilities());
+            match dap {
+                Some(addr) => {
+                    let (dap, launch) = Dap::accept(&addr)?;
+                    debug::run(vm, dap, &warnings, launch.breakpoints, launch.stop_on_entry)?;
+                }
+                // `load` has already printed the warnings
+                None => debug::run(vm, Cli::default(), &[], Breakpoints::default(), true)?,
+            }
+        }
+        None => {
+            let program = load(args.source, &mut interner)?;
+            let mut vm = Vm::new(program, &mut interner)?;
+            vm.set_limits(args.limits.limits());
+            vm.set_capabilities(args.sandbox.capabilities());
+            if args.trace {
+                vm.set_debugger(Box::new(Tracer));
+            }
+            vm.eval()?;
+        }
+    }
+    Ok(())
+}
+
+/// Compiles a script, or loads it if it's already compiled.
+fn load(args: SourceArgs, interner: &mut Rodeo) -> Result<Program, Error> {
+    let path = args.file.unwrap();
+    let mut file = File::open(&path).unwrap();
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).unwrap();
+    if bytecode::is_bytecode(&bytes) {
+        return bytecode::read(&bytes, interner);
+    }
+    let src = String::from_utf8(bytes).unwrap();
 
     let tokens = Token::lexer(&src)
         .spanned()
-        .map(|(t, s)| (t, Span::new(&args.file, s)))
+        .map(|(t, s)| (t, Span::new(&path, s)))
         .collect::<Vec<_>>();
     if args.dump_tokens {
         println!("{:?}", &tokens);
     }
 
-    let ast = match parse(tokens) {
-        Ok(ast) => ast,
-        Err(errors) => {
-            for e in errors {
-                eprintln!("{:?}", e);
-            }
-            return Err(Error::parser(concat!(file!(), ":", line!())));
-        }
-    };
+    let ast =
+        parse(tokens).map_err(|errors| parser::error(concat!(file!(), ":", line!()), errors))?;
     if args.dump_ast {
         println!("{:#?}", &ast);
     }
 
-    let mut interner = Rodeo::new();
-    let mut vm = Compiler::compile(ast, &mut interner)?;
-    vm.eval()?;
-
-    Ok(())
+    let program = Compiler::compile(ast, interner, args.search_path, args.opt_level)?;
+    for warning in &program.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(program)
 }
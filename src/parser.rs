@@ -1,11 +1,17 @@
-use crate::{lexer::Token, BoxedSpannedAst, Span, Spanned, SpannedAst, SpannedAsts};
-use chumsky::{prelude::*, Parser, Stream};
+use crate::{
+    error::{Error, SourceSpan},
+    lexer::Token,
+    BoxedSpannedAst, Span, Spanned, SpannedAst, SpannedAsts,
+};
+use chumsky::{error::SimpleReason, prelude::*, Parser, Stream};
 
 #[derive(Debug)]
 pub enum Ast<'s, 'p> {
     Module(SpannedAsts<'s, 'p>),
 
     Defn(Box<Defn<'s, 'p>>),
+    Type(Box<Type<'s, 'p>>),
+    Import(Box<Import<'s, 'p>>),
 
     Assignment(Box<Assignment<'s, 'p>>),
     BinOp(Box<BinOp<'s, 'p>>),
@@ -13,10 +19,28 @@ pub enum Ast<'s, 'p> {
     String(&'s str),
     Int(i64),
     Uint(u64),
+    Float(f64),
+    Bool(bool),
+    Nil,
+
+    List(SpannedAsts<'s, 'p>),
+    Map(Vec<(SpannedAst<'s, 'p>, SpannedAst<'s, 'p>)>),
 
     Loop(Loop<'s, 'p>),
+    While(Box<While<'s, 'p>>),
+    For(Box<For<'s, 'p>>),
+    Try(Box<Try<'s, 'p>>),
+    Match(Box<Match<'s, 'p>>),
+    Raise(BoxedSpannedAst<'s, 'p>),
+    Break(Option<BoxedSpannedAst<'s, 'p>>),
+    Continue(Option<BoxedSpannedAst<'s, 'p>>),
 
     Call(BoxedSpannedAst<'s, 'p>, BoxedSpannedAst<'s, 'p>),
+    MethodCall(
+        BoxedSpannedAst<'s, 'p>,
+        BoxedSpannedAst<'s, 'p>,
+        BoxedSpannedAst<'s, 'p>,
+    ),
     New(
         Spanned<'p, Token<'s>>,
         Option<Box<SpannedAst<'s, 'p>>>,
@@ -29,6 +53,7 @@ pub enum Ast<'s, 'p> {
     Identifier(&'s str),
 
     Place(BoxedSpannedAst<'s, 'p>, SpannedAsts<'s, 'p>),
+    Index(BoxedSpannedAst<'s, 'p>, BoxedSpannedAst<'s, 'p>),
 }
 
 #[derive(Debug)]
@@ -52,6 +77,61 @@ pub struct Loop<'s, 'p> {
     pub end: Spanned<'p, Token<'s>>,
 }
 
+#[derive(Debug)]
+pub struct While<'s, 'p> {
+    pub while_: Spanned<'p, Token<'s>>,
+    pub cond: SpannedAst<'s, 'p>,
+    pub _do: Spanned<'p, Token<'s>>,
+    pub body: SpannedAsts<'s, 'p>,
+    pub end: Spanned<'p, Token<'s>>,
+}
+
+#[derive(Debug)]
+pub struct For<'s, 'p> {
+    pub for_: Spanned<'p, Token<'s>>,
+    pub var: SpannedAst<'s, 'p>,
+    pub in_: Spanned<'p, Token<'s>>,
+    pub iterable: SpannedAst<'s, 'p>,
+    pub _do: Spanned<'p, Token<'s>>,
+    pub body: SpannedAsts<'s, 'p>,
+    pub end: Spanned<'p, Token<'s>>,
+}
+
+#[derive(Debug)]
+pub struct Try<'s, 'p> {
+    pub try_: Spanned<'p, Token<'s>>,
+    pub body: SpannedAsts<'s, 'p>,
+    /// The name the error is bound to, and the handler
+    pub rescue: Option<(SpannedAst<'s, 'p>, SpannedAsts<'s, 'p>)>,
+    pub ensure: Option<SpannedAsts<'s, 'p>>,
+    pub end: Spanned<'p, Token<'s>>,
+}
+
+#[derive(Debug)]
+pub struct Match<'s, 'p> {
+    pub scrutinee: SpannedAst<'s, 'p>,
+    pub arms: Vec<MatchArm<'s, 'p>>,
+}
+
+#[derive(Debug)]
+pub struct MatchArm<'s, 'p> {
+    pub pattern: Spanned<'p, Pattern<'s, 'p>>,
+    pub guard: Option<SpannedAst<'s, 'p>>,
+    pub body: SpannedAst<'s, 'p>,
+}
+
+#[derive(Debug)]
+pub enum Pattern<'s, 'p> {
+    /// `_`, matching anything
+    Wildcard,
+    Binding(&'s str),
+    Literal(SpannedAst<'s, 'p>),
+    /// A list of exactly this many items
+    List(Vec<Spanned<'p, Pattern<'s, 'p>>>),
+    /// A map with these keys, or an object with these fields
+    Map(Vec<(SpannedAst<'s, 'p>, Spanned<'p, Pattern<'s, 'p>>)>),
+}
+
 #[derive(Debug)]
 pub struct Defn<'s, 'p> {
     pub defn: Spanned<'p, Token<'s>>,
@@ -62,63 +142,252 @@ pub struct Defn<'s, 'p> {
     pub end: Spanned<'p, Token<'s>>,
 }
 
-fn ident<'s, 'p>() -> impl Parser<Token<'s>, SpannedAst<'s, 'p>, Error = Simple<Token<'s>, Span<'p>>>
-{
+#[derive(Debug)]
+pub struct Type<'s, 'p> {
+    pub type_: Spanned<'p, Token<'s>>,
+    pub name: SpannedAst<'s, 'p>,
+    pub _do: Spanned<'p, Token<'s>>,
+    pub body: SpannedAsts<'s, 'p>,
+    pub end: Spanned<'p, Token<'s>>,
+}
+
+#[derive(Debug)]
+pub struct Import<'s, 'p> {
+    pub import: Spanned<'p, Token<'s>>,
+    /// Segments of the module path, the last one names the module
+    pub path: SpannedAsts<'s, 'p>,
+    /// Names imported into scope unqualified, if any
+    pub items: Option<SpannedAsts<'s, 'p>>,
+}
+
+enum Postfix<'s, 'p> {
+    Field(SpannedAst<'s, 'p>),
+    Method(SpannedAst<'s, 'p>, SpannedAst<'s, 'p>),
+    Call(SpannedAst<'s, 'p>),
+    Index(SpannedAst<'s, 'p>, Span<'p>),
+}
+
+fn ident<'s, 'p>(
+) -> impl Parser<Token<'s>, SpannedAst<'s, 'p>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
     select! {
         Token::Identifier(i), span =>  Spanned{ span, inner: Ast::Identifier(i) }
     }
 }
 
 fn kw_do<'s, 'p>(
-) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> {
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
     select! {
         token @ Token::KwDo, span =>  Spanned { span, inner: token }
     }
 }
 
 fn kw_defn<'s, 'p>(
-) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> {
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
     select! {
         token @ Token::KwDefn, span =>  Spanned { span, inner: token }
     }
 }
 
+fn kw_type<'s, 'p>(
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
+    select! {
+        token @ Token::KwType, span =>  Spanned { span, inner: token }
+    }
+}
+
+fn kw_import<'s, 'p>(
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
+    select! {
+        token @ Token::KwImport, span =>  Spanned { span, inner: token }
+    }
+}
+
 fn kw_new<'s, 'p>(
-) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> {
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
     select! {
         token @ Token::KwNew, span =>  Spanned { span, inner: token }
     }
 }
 
 fn kw_end<'s, 'p>(
-) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> {
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
     select! {
         token @ Token::KwEnd, span =>  Spanned { span, inner: token }
     }
 }
 
 fn kw_loop<'s, 'p>(
-) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> {
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
     select! {
         token @ Token::KwLoop, span =>  Spanned { span, inner: token }
     }
 }
 
-fn equals<'s, 'p>(
-) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> {
+fn kw_while<'s, 'p>(
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
     select! {
-        token @ Token::Equals, span =>  Spanned { span, inner: token }
+        token @ Token::KwWhile, span =>  Spanned { span, inner: token }
     }
 }
 
-fn place<'s, 'p>() -> impl Parser<Token<'s>, SpannedAst<'s, 'p>, Error = Simple<Token<'s>, Span<'p>>>
-{
-    ident()
-        .then(just(Token::Accessor).ignore_then(ident()).repeated())
-        .map_with_span(|(base, accessors), span| Spanned {
-            span,
-            inner: Ast::Place(box base, accessors),
-        })
+fn kw_for<'s, 'p>(
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
+    select! {
+        token @ Token::KwFor, span =>  Spanned { span, inner: token }
+    }
+}
+
+fn kw_try<'s, 'p>(
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
+    select! {
+        token @ Token::KwTry, span =>  Spanned { span, inner: token }
+    }
+}
+
+fn kw_in<'s, 'p>(
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
+    select! {
+        token @ Token::KwIn, span =>  Spanned { span, inner: token }
+    }
+}
+
+fn kw_if<'s, 'p>(
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
+    select! {
+        token @ Token::KwIf, span =>  Spanned { span, inner: token }
+    }
+}
+
+fn range_op<'s, 'p>(
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
+    select! {
+        token @ Token::Range, span =>  Spanned { span, inner: token },
+        token @ Token::RangeInclusive, span =>  Spanned { span, inner: token },
+    }
+}
+
+fn comparison_op<'s, 'p>(
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
+    select! {
+        token @ Token::Equals, span =>  Spanned { span, inner: token },
+        token @ Token::NotEquals, span =>  Spanned { span, inner: token },
+        token @ Token::Less, span =>  Spanned { span, inner: token },
+        token @ Token::LessEquals, span =>  Spanned { span, inner: token },
+        token @ Token::Greater, span =>  Spanned { span, inner: token },
+        token @ Token::GreaterEquals, span =>  Spanned { span, inner: token },
+    }
+}
+
+fn add_op<'s, 'p>(
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
+    select! {
+        token @ Token::Plus, span =>  Spanned { span, inner: token },
+        token @ Token::Minus, span =>  Spanned { span, inner: token },
+    }
+}
+
+fn assign_op<'s, 'p>(
+) -> impl Parser<Token<'s>, Spanned<'p, Token<'s>>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
+    select! {
+        token @ Token::Assign, span => Spanned { span, inner: token },
+        token @ Token::ImmutDeclAssign, span => Spanned { span, inner: token },
+        token @ Token::DeclAssign, span => Spanned { span, inner: token },
+    }
+}
+
+fn literal<'s, 'p>(
+) -> impl Parser<Token<'s>, SpannedAst<'s, 'p>, Error = Simple<Token<'s>, Span<'p>>> + Clone {
+    let string = select! {
+        Token::String(s), span => Spanned { span, inner: Ast::String(s) }
+    };
+
+    let uint = select! {
+        Token::Number(n), span => Spanned { span, inner: Ast::Uint(n) }
+    };
+
+    let int = just(Token::Minus).ignore_then(select! {
+        Token::Number(n), span => Spanned { span, inner: Ast::Int(-(n as i64)) }
+    });
+
+    // The lexer only lets valid floats through
+    let float = select! {
+        Token::Float(f), span => Spanned { span, inner: Ast::Float(f.parse().unwrap()) }
+    };
+
+    let negative_float = just(Token::Minus).ignore_then(select! {
+        Token::Float(f), span => Spanned { span, inner: Ast::Float(-f.parse::<f64>().unwrap()) }
+    });
+
+    let number = choice((uint, int, float, negative_float));
+
+    let bool_ = select! {
+        Token::KwTrue, span => Spanned { span, inner: Ast::Bool(true) },
+        Token::KwFalse, span => Spanned { span, inner: Ast::Bool(false) },
+    };
+
+    let nil = select! {
+        Token::KwNil, span => Spanned { span, inner: Ast::Nil }
+    };
+
+    choice((string, number, bool_, nil))
+}
+
+fn pattern<'s: 'r, 'p: 'r, 'r>(
+) -> impl Parser<Token<'s>, Spanned<'p, Pattern<'s, 'p>>, Error = Simple<Token<'s>, Span<'p>>>
+       + 'r
+       + Clone {
+    recursive(|pattern| {
+        let binding = select! {
+            Token::Identifier("_"), span => Spanned { span, inner: Pattern::Wildcard },
+            Token::Identifier(name), span => Spanned { span, inner: Pattern::Binding(name) },
+        };
+
+        let literal_ = literal().map(|literal| Spanned {
+            span: literal.span,
+            inner: Pattern::Literal(literal),
+        });
+
+        let list = just(Token::LBracket)
+            .ignore_then(
+                pattern
+                    .clone()
+                    .separated_by(just(Token::Comma))
+                    .allow_trailing(),
+            )
+            .then_ignore(just(Token::RBracket))
+            .map_with_span(|items, span| Spanned {
+                span,
+                inner: Pattern::List(items),
+            });
+
+        // `{name}` is shorthand for `{name: name}`
+        let entry = choice((
+            select! {
+                Token::Identifier(k), span => Spanned { span, inner: Ast::String(k) }
+            }
+            .then_ignore(just(Token::Colon))
+            .then(pattern.clone()),
+            literal()
+                .then_ignore(just(Token::Colon))
+                .then(pattern.clone()),
+            select! {
+                Token::Identifier(k), span => (
+                    Spanned { span, inner: Ast::String(k) },
+                    Spanned { span, inner: Pattern::Binding(k) },
+                )
+            },
+        ));
+
+        let map = just(Token::LBrace)
+            .ignore_then(entry.separated_by(just(Token::Comma)).allow_trailing())
+            .then_ignore(just(Token::RBrace))
+            .map_with_span(|entries, span| Spanned {
+                span,
+                inner: Pattern::Map(entries),
+            });
+
+        choice((binding, literal_, list, map))
+    })
 }
 
 fn expression<'s: 'r, 'p: 'r, 'r>(
@@ -132,34 +401,89 @@ fn expression<'s: 'r, 'p: 'r, 'r>(
                 inner: Ast::Paramlist(args),
             });
 
-        let bin_op = expression
-            .clone()
-            .then(choice((equals(),)))
-            .then(expression.clone())
-            .map_with_span(|((lhs, op), rhs), span| Spanned {
-                span,
-                inner: Ast::BinOp(box BinOp { lhs, op, rhs }),
-            });
-
         let loop_ = kw_loop()
-            .then(expression.repeated())
+            .then(statement(expression.clone()).repeated())
             .then(kw_end())
             .map_with_span(|((loop_, body), end), span| Spanned {
                 span,
                 inner: Ast::Loop(Loop { loop_, body, end }),
             });
 
-        let string = select! {
-            Token::String(s), span => Spanned { span, inner: Ast::String(s) }
-        };
+        let while_ = kw_while()
+            .then(expression.clone())
+            .then(kw_do())
+            .then(statement(expression.clone()).repeated())
+            .then(kw_end())
+            .map_with_span(|((((while_, cond), _do), body), end), span| Spanned {
+                span,
+                inner: Ast::While(box While {
+                    while_,
+                    cond,
+                    _do,
+                    body,
+                    end,
+                }),
+            });
 
-        let uint = select! {
-            Token::Number(n), span => Spanned { span, inner: Ast::Uint(n) }
-        };
+        let for_ = kw_for()
+            .then(ident())
+            .then(kw_in())
+            .then(expression.clone())
+            .then(kw_do())
+            .then(statement(expression.clone()).repeated())
+            .then(kw_end())
+            .map_with_span(
+                |((((((for_, var), in_), iterable), _do), body), end), span| Spanned {
+                    span,
+                    inner: Ast::For(box For {
+                        for_,
+                        var,
+                        in_,
+                        iterable,
+                        _do,
+                        body,
+                        end,
+                    }),
+                },
+            );
+
+        let try_ = kw_try()
+            .then(statement(expression.clone()).repeated())
+            .then(
+                just(Token::KwRescue)
+                    .ignore_then(ident())
+                    .then_ignore(kw_do())
+                    .then(statement(expression.clone()).repeated())
+                    .or_not(),
+            )
+            .then(
+                just(Token::KwEnsure)
+                    .ignore_then(statement(expression.clone()).repeated())
+                    .or_not(),
+            )
+            .then(kw_end())
+            .try_map(|((((try_, body), rescue), ensure), end), span| {
+                if rescue.is_none() && ensure.is_none() {
+                    return Err(Simple::custom(span, "try without rescue or ensure"));
+                }
+                Ok(Spanned {
+                    span,
+                    inner: Ast::Try(box Try {
+                        try_,
+                        body,
+                        rescue,
+                        ensure,
+                        end,
+                    }),
+                })
+            });
 
-        let int = just(Token::Minus).ignore_then(select! {
-            Token::Number(n), span => Spanned { span, inner: Ast::Int(-(n as i64)) }
-        });
+        let raise = just(Token::KwRaise)
+            .ignore_then(expression.clone())
+            .map_with_span(|value, span| Spanned {
+                span,
+                inner: Ast::Raise(box value),
+            });
 
         let new = kw_new()
             .then(paramlist.clone().or_not())
@@ -169,38 +493,219 @@ fn expression<'s: 'r, 'p: 'r, 'r>(
                 inner: Ast::New(new, params.map(|p| box p), box ty),
             });
 
-        let call = ident()
-            .then(paramlist)
-            .map_with_span(|(name, params), span| Spanned {
+        let match_ = just(Token::KwMatch)
+            .ignore_then(expression.clone())
+            .then_ignore(kw_do())
+            .then(
+                pattern()
+                    .then(kw_if().ignore_then(expression.clone()).or_not())
+                    .then_ignore(just(Token::FatArrow))
+                    .then(expression.clone())
+                    .map(|((pattern, guard), body)| MatchArm {
+                        pattern,
+                        guard,
+                        body,
+                    })
+                    .separated_by(just(Token::Comma))
+                    .allow_trailing(),
+            )
+            .then_ignore(kw_end())
+            .map_with_span(|(scrutinee, arms), span| Spanned {
                 span,
-                inner: Ast::Call(box name, box params),
+                inner: Ast::Match(box Match { scrutinee, arms }),
             });
 
-        let number = choice((uint, int));
+        let variable = ident().map_with_span(|name, span| Spanned {
+            span,
+            inner: Ast::Place(box name, Vec::new()),
+        });
 
-        let literal = choice((string, number));
+        let parens = just(Token::LParen)
+            .ignore_then(expression.clone())
+            .then_ignore(just(Token::RParen));
+
+        let list = just(Token::LBracket)
+            .ignore_then(
+                expression
+                    .clone()
+                    .separated_by(just(Token::Comma))
+                    .allow_trailing(),
+            )
+            .then_ignore(just(Token::RBracket))
+            .map_with_span(|items, span| Spanned {
+                span,
+                inner: Ast::List(items),
+            });
+
+        // A bare identifier as a key is shorthand for a string key
+        let key = choice((
+            select! {
+                Token::Identifier(k), span => Spanned { span, inner: Ast::String(k) }
+            },
+            expression.clone(),
+        ))
+        .then_ignore(just(Token::Colon));
+
+        let map = just(Token::LBrace)
+            .ignore_then(
+                key.then(expression.clone())
+                    .separated_by(just(Token::Comma))
+                    .allow_trailing(),
+            )
+            .then_ignore(just(Token::RBrace))
+            .map_with_span(|entries, span| Spanned {
+                span,
+                inner: Ast::Map(entries),
+            });
+
+        let atom = choice((
+            literal(),
+            new,
+            variable,
+            list,
+            map,
+            loop_,
+            while_,
+            for_,
+            try_,
+            match_,
+            raise,
+            parens,
+        ))
+        .boxed();
+
+        let postfix = atom
+            .then(
+                choice((
+                    just(Token::Accessor)
+                        .ignore_then(ident())
+                        .then(paramlist.clone())
+                        .map(|(method, params)| Postfix::Method(method, params)),
+                    just(Token::Accessor)
+                        .ignore_then(ident())
+                        .map(Postfix::Field),
+                    paramlist.map(Postfix::Call),
+                    just(Token::LBracket)
+                        .ignore_then(expression.clone())
+                        .then_ignore(just(Token::RBracket))
+                        .map_with_span(Postfix::Index),
+                ))
+                .repeated(),
+            )
+            .foldl(|lhs, postfix| match postfix {
+                Postfix::Field(field) => match lhs {
+                    Spanned {
+                        span,
+                        inner: Ast::Place(base, mut accessors),
+                    } => {
+                        let span = span.union(field.span);
+                        accessors.push(field);
+                        Spanned {
+                            span,
+                            inner: Ast::Place(base, accessors),
+                        }
+                    }
+                    lhs => Spanned {
+                        span: lhs.span.union(field.span),
+                        inner: Ast::Place(box lhs, vec![field]),
+                    },
+                },
+                Postfix::Method(method, params) => Spanned {
+                    span: lhs.span.union(params.span),
+                    inner: Ast::MethodCall(box lhs, box method, box params),
+                },
+                Postfix::Call(params) => match lhs {
+                    Spanned {
+                        span,
+                        inner: Ast::Place(box name, accessors),
+                    } if accessors.is_empty() => Spanned {
+                        span: span.union(params.span),
+                        inner: Ast::Call(box name, box params),
+                    },
+                    lhs => Spanned {
+                        span: lhs.span.union(params.span),
+                        inner: Ast::Call(box lhs, box params),
+                    },
+                },
+                Postfix::Index(index, span) => Spanned {
+                    span: lhs.span.union(span),
+                    inner: Ast::Index(box lhs, box index),
+                },
+            })
+            .boxed();
+
+        let sum = postfix
+            .clone()
+            .then(add_op().then(postfix).repeated())
+            .foldl(|lhs, (op, rhs)| Spanned {
+                span: lhs.span.union(rhs.span),
+                inner: Ast::BinOp(box BinOp { lhs, op, rhs }),
+            });
 
-        choice((literal, new, call, place(), loop_, bin_op))
+        let range = sum
+            .clone()
+            .then(range_op().then(sum).or_not())
+            .map(|(lhs, rhs)| match rhs {
+                Some((op, rhs)) => Spanned {
+                    span: lhs.span.union(rhs.span),
+                    inner: Ast::BinOp(box BinOp { lhs, op, rhs }),
+                },
+                None => lhs,
+            })
+            .boxed();
+
+        range
+            .clone()
+            .then(comparison_op().then(range).repeated())
+            .foldl(|lhs, (op, rhs)| Spanned {
+                span: lhs.span.union(rhs.span),
+                inner: Ast::BinOp(box BinOp { lhs, op, rhs }),
+            })
     })
 }
 
-fn assignment<'s: 'r, 'p: 'r, 'r>(
-) -> impl Parser<Token<'s>, SpannedAst<'s, 'p>, Error = Simple<Token<'s>, Span<'p>>> + 'r {
-    place()
-        .then(select! {
-            token @ Token::Assign, span => Spanned { span, inner: token },
-            token @ Token::ImmutDeclAssign, span => Spanned { span, inner: token },
-            token @ Token::DeclAssign, span => Spanned { span, inner: token },
-        })
-        .then(expression())
-        .map_with_span(|((place, assign), expr), span| Spanned {
+/// An expression, optionally followed by an assignment to it if it's a place,
+/// or a possibly conditional `break`/`continue`.
+fn statement<'s: 'r, 'p: 'r, 'r>(
+    expression: impl Parser<Token<'s>, SpannedAst<'s, 'p>, Error = Simple<Token<'s>, Span<'p>>>
+        + 'r
+        + Clone,
+) -> impl Parser<Token<'s>, SpannedAst<'s, 'p>, Error = Simple<Token<'s>, Span<'p>>> + 'r + Clone {
+    let condition = kw_if().ignore_then(expression.clone()).or_not();
+
+    let break_ = just(Token::KwBreak)
+        .ignore_then(condition.clone())
+        .map_with_span(|cond, span| Spanned {
             span,
-            inner: Ast::Assignment(box Assignment {
-                place,
-                assign,
-                expr,
-            }),
-        })
+            inner: Ast::Break(cond.map(|c| box c)),
+        });
+
+    let continue_ = just(Token::KwContinue)
+        .ignore_then(condition)
+        .map_with_span(|cond, span| Spanned {
+            span,
+            inner: Ast::Continue(cond.map(|c| box c)),
+        });
+
+    let assignment = expression
+        .clone()
+        .then(assign_op().then(expression).or_not())
+        .try_map(|(place, assign), span| match assign {
+            None => Ok(place),
+            Some((assign, expr)) if matches!(place.inner, Ast::Place(..) | Ast::Index(..)) => {
+                Ok(Spanned {
+                    span,
+                    inner: Ast::Assignment(box Assignment {
+                        place,
+                        assign,
+                        expr,
+                    }),
+                })
+            }
+            Some(_) => Err(Simple::custom(place.span, "invalid assignment target")),
+        });
+
+    choice((break_, continue_, assignment)).boxed()
 }
 
 fn arglist<'s, 'p>(
@@ -216,7 +721,7 @@ fn arglist<'s, 'p>(
 
 fn body<'s: 'r, 'p: 'r, 'r>(
 ) -> impl Parser<Token<'s>, SpannedAsts<'s, 'p>, Error = Simple<Token<'s>, Span<'p>>> + 'r {
-    choice((assignment(), expression())).repeated()
+    statement(expression()).repeated()
 }
 
 fn defn<'s: 'r, 'p: 'r, 'r>(
@@ -240,9 +745,48 @@ fn defn<'s: 'r, 'p: 'r, 'r>(
         })
 }
 
+fn type_decl<'s: 'r, 'p: 'r, 'r>(
+) -> impl Parser<Token<'s>, SpannedAst<'s, 'p>, Error = Simple<Token<'s>, Span<'p>>> + 'r {
+    kw_type()
+        .then(ident())
+        .then(kw_do())
+        .then(choice((defn(), statement(expression()))).repeated())
+        .then(kw_end())
+        .map_with_span(|((((type_, name), _do), body), end), span| Spanned {
+            span,
+            inner: Ast::Type(box Type {
+                type_,
+                name,
+                _do,
+                body,
+                end,
+            }),
+        })
+}
+
+fn import<'s, 'p>(
+) -> impl Parser<Token<'s>, SpannedAst<'s, 'p>, Error = Simple<Token<'s>, Span<'p>>> {
+    kw_import()
+        .then(ident().separated_by(just(Token::Slash)).at_least(1))
+        .then(
+            just(Token::LParen)
+                .ignore_then(ident().separated_by(just(Token::Comma)).allow_trailing())
+                .then_ignore(just(Token::RParen))
+                .or_not(),
+        )
+        .map_with_span(|((import, path), items), span| Spanned {
+            span,
+            inner: Ast::Import(box Import {
+                import,
+                path,
+                items,
+            }),
+        })
+}
+
 fn implicit_module<'s: 'r, 'p: 'r, 'r>(
 ) -> impl Parser<Token<'s>, SpannedAst<'s, 'p>, Error = Simple<Token<'s>, Span<'p>>> + 'r {
-    choice((defn(),))
+    choice((import(), defn(), type_decl(), statement(expression())))
         .repeated()
         .then_ignore(end())
         .map_with_span(|items, span| Spanned {
@@ -254,6 +798,47 @@ fn implicit_module<'s: 'r, 'p: 'r, 'r>(
 pub fn parse<'s, 'p>(
     tokens: Vec<(Token<'s>, Span<'p>)>,
 ) -> Result<SpannedAst<'s, 'p>, Vec<Simple<Token<'s>, Span<'p>>>> {
-    let stream = Stream::from_iter(tokens.last().unwrap().1, tokens.into_iter());
+    let eoi = tokens.last().unwrap().1;
+    let tokens = tokens.into_iter().filter(|(t, _)| *t != Token::Comment);
+    let stream = Stream::from_iter(eoi, tokens);
     implicit_module().parse(stream)
 }
+
+/// A readable description of a parse error.
+pub fn message(e: &Simple<Token, Span>) -> String {
+    if let SimpleReason::Custom(message) = e.reason() {
+        return message.clone();
+    }
+    let found = match e.found() {
+        Some(token) => format!("{:?}", token),
+        None => "end of input".to_owned(),
+    };
+    let mut expected = e
+        .expected()
+        .map(|token| match token {
+            Some(token) => format!("{:?}", token),
+            None => "end of input".to_owned(),
+        })
+        .collect::<Vec<_>>();
+    expected.sort();
+    match e.reason() {
+        SimpleReason::Unclosed { delimiter, .. } => format!("unclosed {:?}", delimiter),
+        _ if expected.is_empty() => format!("unexpected {}", found),
+        _ => format!("unexpected {}, expected {}", found, expected.join(", ")),
+    }
+}
+
+/// The first of the errors [`parse`] gives, with its message and span.
+pub fn error(location: &'static str, errors: Vec<Simple<Token, Span>>) -> Error {
+    match errors.first() {
+        Some(e) => {
+            let span = e.span();
+            Error::syntax(location, message(e)).with_span(SourceSpan {
+                path: span.path.to_path_buf(),
+                start: span.start,
+                end: span.end,
+            })
+        }
+        None => Error::parser(location),
+    }
+}
@@ -0,0 +1,243 @@
+//! What programs may touch outside the VM.
+//!
+//! A VM starts with no capabilities. Natives that read or write files or read
+//! standard input are registered with the [`Capability`] they need, which the
+//! VM checks against its [`Capabilities`] before calling them, raising a
+//! permission error when the host hasn't granted it. File natives then check
+//! the paths they're given as well.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::error::Error;
+
+/// Access to a directory. Writing includes reading.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    ReadWrite,
+}
+
+/// What a native needs before it may run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// Access to some files; which ones is up to the paths the native is given
+    Fs(Access),
+    Stdin,
+}
+
+/// Capabilities granted to a program, none by default.
+#[derive(Clone, Debug, Default)]
+pub struct Capabilities {
+    /// Directories files may be accessed in, `None` for anywhere
+    fs: Vec<(Option<PathBuf>, Access)>,
+    stdin: bool,
+}
+
+impl Capabilities {
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Everything, as for a script the user runs themselves.
+    pub fn all() -> Self {
+        Self {
+            fs: vec![(None, Access::ReadWrite)],
+            stdin: true,
+        }
+    }
+
+    /// Grants access to the files in `dir` and below it.
+    pub fn grant_dir(mut self, dir: impl AsRef<Path>, access: Access) -> Self {
+        let dir = dir.as_ref();
+        let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_owned());
+        self.fs.push((Some(dir), access));
+        self
+    }
+
+    /// Grants access to any file.
+    pub fn grant_fs(mut self, access: Access) -> Self {
+        self.fs.push((None, access));
+        self
+    }
+
+    /// Grants reading standard input.
+    pub fn grant_stdin(mut self) -> Self {
+        self.stdin = true;
+        self
+    }
+
+    /// Checks that a native needing `capability` may run.
+    pub fn check(&self, capability: Capability) -> Result<(), Error> {
+        let (granted, what) = match capability {
+            Capability::Fs(access) => (
+                self.fs.iter().any(|&(_, granted)| granted >= access),
+                match access {
+                    Access::Read => "no read access to files",
+                    Access::ReadWrite => "no write access to files",
+                },
+            ),
+            Capability::Stdin => (self.stdin, "no standard input access"),
+        };
+        match granted {
+            true => Ok(()),
+            false => Err(Error::permission_denied(
+                concat!(file!(), ":", line!()),
+                what.to_owned(),
+            )),
+        }
+    }
+
+    /// Checks that `path` may be accessed, returning it resolved so that
+    /// symbolic links and `..` can't lead out of the granted directories.
+    /// Files that don't exist yet are resolved through their directory.
+    pub fn check_fs(&self, path: &Path, access: Access) -> Result<PathBuf, Error> {
+        let verb = match access {
+            Access::Read => "read",
+            Access::ReadWrite => "write",
+        };
+        let denied = || {
+            Error::permission_denied(
+                concat!(file!(), ":", line!()),
+                format!("no {} access to {}", verb, path.display()),
+            )
+        };
+        let resolved = match fs::canonicalize(path) {
+            Ok(resolved) => resolved,
+            Err(_) => {
+                let parent = match path.parent() {
+                    Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+                    Some(parent) => parent,
+                    None => return Err(denied()),
+                };
+                let name = path.file_name().ok_or_else(denied)?;
+                fs::canonicalize(parent).map_err(|_| denied())?.join(name)
+            }
+        };
+        let granted = self.fs.iter().any(|(dir, granted)| {
+            *granted >= access && dir.as_ref().is_none_or(|dir| resolved.starts_with(dir))
+        });
+        match granted {
+            true => Ok(resolved),
+            false => Err(denied()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lasso::Rodeo;
+
+    use super::*;
+    use crate::{
+        error::ErrorKind,
+        eval::{Value, Vm},
+        testing::{compile, run, temp_dir},
+    };
+
+    /// A directory of its own for a test, with a file `granted/a.txt` and a
+    /// file `outside.txt` next to `granted`.
+    fn dir(test: &str) -> PathBuf {
+        let dir = temp_dir(test);
+        fs::create_dir(dir.join("granted")).unwrap();
+        fs::write(dir.join("granted/a.txt"), "a").unwrap();
+        fs::write(dir.join("outside.txt"), "outside").unwrap();
+        dir
+    }
+
+    fn denied(res: Result<impl std::fmt::Debug, Error>) -> bool {
+        matches!(res.unwrap_err().kind(), ErrorKind::PermissionDenied { .. })
+    }
+
+    #[test]
+    fn grants_nothing_by_default() {
+        let none = Capabilities::none();
+        assert!(denied(none.check(Capability::Stdin)));
+        assert!(denied(none.check(Capability::Fs(Access::Read))));
+        assert!(denied(none.check_fs(Path::new("Cargo.toml"), Access::Read)));
+        let all = Capabilities::all();
+        all.check(Capability::Stdin).unwrap();
+        all.check(Capability::Fs(Access::ReadWrite)).unwrap();
+        all.check_fs(Path::new("Cargo.toml"), Access::ReadWrite)
+            .unwrap();
+        let read = Capabilities::none().grant_dir(".", Access::Read);
+        read.check(Capability::Fs(Access::Read)).unwrap();
+        assert!(denied(read.check(Capability::Fs(Access::ReadWrite))));
+    }
+
+    #[test]
+    fn grants_directories_by_access() {
+        let dir = dir("sandbox-access");
+        let capabilities = Capabilities::none().grant_dir(dir.join("granted"), Access::Read);
+        let file = dir.join("granted/a.txt");
+        let resolved = capabilities.check_fs(&file, Access::Read).unwrap();
+        assert_eq!(resolved, fs::canonicalize(&file).unwrap());
+        assert!(denied(capabilities.check_fs(&file, Access::ReadWrite)));
+        assert!(denied(
+            capabilities.check_fs(&dir.join("outside.txt"), Access::Read)
+        ));
+
+        // Writing includes reading, and files may not exist yet
+        let capabilities = capabilities.grant_dir(dir.join("granted"), Access::ReadWrite);
+        let new = dir.join("granted/new.txt");
+        capabilities.check_fs(&new, Access::ReadWrite).unwrap();
+        capabilities.check_fs(&file, Access::Read).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolves_paths_before_checking_them() {
+        let dir = dir("sandbox-escape");
+        let capabilities = Capabilities::none().grant_dir(dir.join("granted"), Access::ReadWrite);
+        let dotted = dir.join("granted/../outside.txt");
+        assert!(denied(capabilities.check_fs(&dotted, Access::Read)));
+        let missing = dir.join("granted/../missing.txt");
+        assert!(denied(capabilities.check_fs(&missing, Access::ReadWrite)));
+        #[cfg(unix)]
+        {
+            let link = dir.join("granted/link.txt");
+            std::os::unix::fs::symlink(dir.join("outside.txt"), &link).unwrap();
+            assert!(denied(capabilities.check_fs(&link, Access::Read)));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn natives_check_capabilities() {
+        let dir = dir("sandbox-natives");
+        let file = dir.join("granted/a.txt");
+        let src = format!("defn main() do\n    raise fs.read_file({:?})\nend\n", file);
+        let mut interner = Rodeo::new();
+        let program = compile(&src, &mut interner, 0).unwrap();
+        let mut vm = Vm::new(program, &mut interner).unwrap();
+        assert!(denied(vm.eval()));
+
+        let mut interner = Rodeo::new();
+        let program = compile(&src, &mut interner, 0).unwrap();
+        let mut vm = Vm::new(program, &mut interner).unwrap();
+        vm.set_capabilities(Capabilities::none().grant_dir(dir.join("granted"), Access::Read));
+        let e = vm.eval().unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Raised { value } if value == "\"a\""));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Would do something the program hasn't been granted, if it ever ran.
+    fn forbidden(_vm: &mut Vm, _argc: usize) -> Result<Value, Error> {
+        unreachable!("the VM checks the capability first")
+    }
+
+    #[test]
+    fn checks_capabilities_before_calling_natives() {
+        let src = "defn main() do\n    forbidden(1)\nend\n";
+        let mut interner = Rodeo::new();
+        let program = compile(src, &mut interner, 0).unwrap();
+        let mut vm = Vm::new(program, &mut interner).unwrap();
+        vm.register("forbidden", forbidden, Some(Capability::Stdin));
+        assert!(denied(vm.eval()));
+
+        // Before looking at the arguments
+        assert!(denied(run("defn main() do\n    fs.exists(nil)\nend\n")));
+    }
+}
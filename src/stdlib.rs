@@ -0,0 +1,67 @@
+//! Natives every program can use: globals like `print`, and modules like
+//! `string.split(s, ",")`.
+
+pub mod fs;
+pub mod io;
+pub mod json;
+pub mod math;
+pub mod path;
+pub mod random;
+pub mod string;
+
+use crate::{
+    error::Error,
+    eval::{Native, RuntimeFunc, Value, Vm},
+};
+
+/// The modules by name, with their natives and constants.
+pub type Module = (
+    &'static str,
+    &'static [Native],
+    &'static [(&'static str, f64)],
+);
+
+pub const MODULES: &[Module] = &[
+    ("string", string::NATIVES, &[]),
+    ("math", math::NATIVES, math::CONSTANTS),
+    ("random", random::NATIVES, &[]),
+    ("fs", fs::NATIVES, &[]),
+    ("path", path::NATIVES, &[]),
+    ("json", json::NATIVES, &[]),
+];
+
+/// Registers every module, and the natives outside modules, with the VM.
+pub fn register(vm: &mut Vm) {
+    for &(name, func, needs) in io::GLOBALS {
+        vm.register(name, func, needs);
+    }
+    for &(name, natives, constants) in MODULES {
+        let natives = natives
+            .iter()
+            .map(|&(name, func, needs)| (name, Value::Func(RuntimeFunc::Native(func, needs))));
+        let constants = constants
+            .iter()
+            .map(|&(name, value)| (name, Value::Float(value)));
+        vm.register_module(name, natives.chain(constants));
+    }
+}
+
+/// Pops the arguments of a native that takes exactly `N` of them.
+fn args<const N: usize>(vm: &mut Vm, argc: usize) -> Result<[Value; N], Error> {
+    if argc != N {
+        return Err(Error::arity(concat!(file!(), ":", line!()), N, argc));
+    }
+    vm.pop_args(argc)?
+        .try_into()
+        .map_err(|_| Error::eval(concat!(file!(), ":", line!())))
+}
+
+/// A new string, counted against the heap limit.
+fn string(vm: &mut Vm, s: String) -> Result<Value, Error> {
+    vm.allocate(s.len())?;
+    Ok(Value::String(s))
+}
+
+fn invalid(reason: String) -> Error {
+    Error::invalid_argument(concat!(file!(), ":", line!()), reason)
+}
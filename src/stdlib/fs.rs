@@ -0,0 +1,189 @@
+//! The `fs` module, for reading and writing files.
+//!
+//! Each native needs file access of some kind, and the paths it's given are
+//! checked against the directories the host granted before anything is
+//! touched. Failures raise IO errors naming the path.
+
+use std::{
+    cell::RefCell,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    mem,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use super::{args, string};
+use crate::{
+    error::Error,
+    eval::{Native, Value, Vm},
+    sandbox::{Access, Capability},
+};
+
+pub const NATIVES: &[Native] = &[
+    ("read_file", read_file, Some(Capability::Fs(Access::Read))),
+    (
+        "write_file",
+        write_file,
+        Some(Capability::Fs(Access::ReadWrite)),
+    ),
+    (
+        "append_file",
+        append_file,
+        Some(Capability::Fs(Access::ReadWrite)),
+    ),
+    ("list_dir", list_dir, Some(Capability::Fs(Access::Read))),
+    ("exists", exists, Some(Capability::Fs(Access::Read))),
+];
+
+/// The path a string names, if the program may access it.
+fn path(vm: &Vm, value: &Value, access: Access) -> Result<PathBuf, Error> {
+    let path = Path::new(vm.text(value)?);
+    vm.capabilities().check_fs(path, access)
+}
+
+fn io_error(path: &Path, e: io::Error) -> Error {
+    Error::io(
+        concat!(file!(), ":", line!()),
+        format!("{}: {}", path.display(), e),
+    )
+}
+
+/// `fs.read_file(path)` reads a whole UTF-8 file.
+fn read_file(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [file] = args(vm, argc)?;
+    let file = path(vm, &file, Access::Read)?;
+    let text = fs::read_to_string(&file).map_err(|e| io_error(&file, e))?;
+    string(vm, text)
+}
+
+/// `fs.write_file(path, text)` creates or replaces a file.
+fn write_file(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [file, text] = args(vm, argc)?;
+    let file = path(vm, &file, Access::ReadWrite)?;
+    fs::write(&file, vm.text(&text)?).map_err(|e| io_error(&file, e))?;
+    Ok(Value::Nil)
+}
+
+/// `fs.append_file(path, text)` adds to the end of a file, creating it if
+/// needed.
+fn append_file(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [file, text] = args(vm, argc)?;
+    let file = path(vm, &file, Access::ReadWrite)?;
+    let text = vm.text(&text)?;
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&file)
+        .and_then(|mut f| f.write_all(text.as_bytes()))
+        .map_err(|e| io_error(&file, e))?;
+    Ok(Value::Nil)
+}
+
+/// `fs.list_dir(path)` is the names of the entries in a directory, sorted.
+fn list_dir(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [dir] = args(vm, argc)?;
+    let dir = path(vm, &dir, Access::Read)?;
+    let mut names = fs::read_dir(&dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .map_err(|e| io_error(&dir, e))?;
+    names.sort();
+    let mut items = Vec::with_capacity(names.len());
+    for name in names {
+        items.push(string(vm, name)?);
+    }
+    vm.allocate(items.len() * mem::size_of::<Value>())?;
+    Ok(Value::List(Rc::new(RefCell::new(items))))
+}
+
+/// `fs.exists(path)` is whether a file or directory exists.
+fn exists(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [file] = args(vm, argc)?;
+    let file = path(vm, &file, Access::Read)?;
+    Ok(Value::Bool(file.exists()))
+}
+
+#[cfg(test)]
+mod tests {
+    use lasso::Rodeo;
+
+    use super::*;
+    use crate::{
+        error::ErrorKind,
+        sandbox::Capabilities,
+        testing::{compile, raised, temp_dir},
+    };
+
+    /// Runs `body` in `main` with access to every file, giving the value it
+    /// raises. `$dir` stands for the test's directory.
+    fn run(dir: &Path, body: &str) -> Result<String, Error> {
+        let body = body.replace("$dir", &format!("{:?}", dir.to_string_lossy()));
+        let src = format!("defn main() do\n{}\nend\n", body);
+        let mut interner = Rodeo::new();
+        let program = compile(&src, &mut interner, 0)?;
+        let mut vm = Vm::new(program, &mut interner)?;
+        vm.set_capabilities(Capabilities::all());
+        match vm.eval() {
+            Err(e) if !matches!(e.kind(), ErrorKind::Raised { .. }) => Err(e),
+            res => Ok(raised(res)),
+        }
+    }
+
+    #[test]
+    fn writes_and_reads_files() {
+        let dir = temp_dir("fs-files");
+        let value = run(
+            &dir,
+            "    file := path.join($dir, \"a.txt\")
+    before := fs.exists(file)
+    fs.write_file(file, \"one\\n\")
+    fs.append_file(file, \"two\\n\")
+    fs.append_file(path.join($dir, \"b.txt\"), \"new\")
+    raise [before, fs.exists(file), fs.read_file(file)]",
+        )
+        .unwrap();
+        assert_eq!(value, "[false, true, \"one\\ntwo\\n\"]");
+        assert_eq!(fs::read_to_string(dir.join("b.txt")).unwrap(), "new");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lists_directories_in_order() {
+        let dir = temp_dir("fs-list");
+        for name in ["b", "c", "a"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        fs::create_dir(dir.join("d")).unwrap();
+        let value = run(&dir, "    raise fs.list_dir($dir)").unwrap();
+        assert_eq!(value, "[\"a\", \"b\", \"c\", \"d\"]");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reports_failures_with_the_path() {
+        let dir = temp_dir("fs-errors");
+        let e = run(
+            &dir,
+            "    raise fs.read_file(path.join($dir, \"missing.txt\"))",
+        )
+        .unwrap_err();
+        match e.kind() {
+            ErrorKind::Io { reason } => {
+                let missing = fs::canonicalize(&dir).unwrap().join("missing.txt");
+                assert!(
+                    reason.starts_with(&format!("{}: ", missing.display())),
+                    "{}",
+                    reason
+                );
+            }
+            kind => panic!("expected an I/O error, got {:?}", kind),
+        }
+        let e = run(&dir, "    raise fs.list_dir(path.join($dir, \"missing\"))").unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::Io { .. }));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+//! Standard input and output, as natives visible from every module.
+//!
+//! Writing to standard output and standard error is always allowed; reading
+//! standard input needs the host's permission.
+
+use std::io::{self, BufRead, Write};
+
+use super::{args, string};
+use crate::{
+    error::Error,
+    eval::{Native, Value, Vm},
+    sandbox::Capability,
+};
+
+pub const GLOBALS: &[Native] = &[
+    ("print", print, None),
+    ("eprint", eprint, None),
+    ("read_line", read_line, Some(Capability::Stdin)),
+];
+
+/// Writes the arguments separated by spaces, then a newline.
+fn write_line(vm: &mut Vm, argc: usize, out: &mut impl Write) -> Result<Value, Error> {
+    let parts = vm.pop_args(argc)?;
+    let mut line = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            line.push(' ');
+        }
+        line.push_str(&vm.display(part)?);
+    }
+    writeln!(out, "{}", line)
+        .and_then(|_| out.flush())
+        .map_err(|e| Error::io(concat!(file!(), ":", line!()), e.to_string()))?;
+    Ok(Value::Nil)
+}
+
+/// `print(a, b, ...)` writes any number of values to standard output.
+fn print(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    write_line(vm, argc, &mut io::stdout().lock())
+}
+
+/// `eprint(a, b, ...)` writes any number of values to standard error.
+fn eprint(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    write_line(vm, argc, &mut io::stderr().lock())
+}
+
+/// `read_line()` reads a line from standard input without its line ending,
+/// or gives nil at the end of the input.
+fn read_line(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [] = args(vm, argc)?;
+    let mut line = String::new();
+    let read = io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|e| Error::io(concat!(file!(), ":", line!()), e.to_string()))?;
+    if read == 0 {
+        return Ok(Value::Nil);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    string(vm, line)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::ErrorKind, testing::run};
+
+    #[test]
+    fn reads_standard_input_only_when_granted() {
+        let e = run("defn main() do\n    read_line()\nend\n").unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::PermissionDenied { .. }));
+    }
+}
@@ -0,0 +1,215 @@
+//! The `json` module, for reading and writing JSON text.
+//!
+//! JSON objects read as maps, arrays as lists, numbers as integers when they
+//! have no fraction or exponent and fit, and as floats otherwise. Writing
+//! takes maps and objects to JSON objects, with map keys that aren't strings
+//! written as strings; functions, ranges, iterators and modules have no JSON
+//! form.
+
+use std::{cell::RefCell, mem, rc::Rc};
+
+use indexmap::IndexMap;
+use serde_json::{Map, Number};
+
+use super::{args, invalid, string};
+use crate::{
+    error::Error,
+    eval::{MapKey, Native, Value, Vm},
+};
+
+pub const NATIVES: &[Native] = &[("parse", parse, None), ("stringify", stringify, None)];
+
+/// `json.parse(s)` reads a JSON value, raising an error that gives the line
+/// and column if `s` isn't valid JSON.
+fn parse(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [s] = args(vm, argc)?;
+    // The message ends with the line and column
+    let json =
+        serde_json::from_str(vm.text(&s)?).map_err(|e| invalid(format!("invalid JSON: {}", e)))?;
+    from_json(vm, json)
+}
+
+fn from_json(vm: &mut Vm, json: serde_json::Value) -> Result<Value, Error> {
+    Ok(match json {
+        serde_json::Value::Null => Value::Nil,
+        serde_json::Value::Bool(b) => Value::Bool(b),
+        serde_json::Value::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
+            (Some(u), _, _) => Value::Uint(u),
+            (_, Some(i), _) => Value::Int(i),
+            (_, _, Some(f)) => Value::Float(f),
+            _ => return Err(invalid(format!("unsupported number {}", n))),
+        },
+        serde_json::Value::String(s) => string(vm, s)?,
+        serde_json::Value::Array(items) => {
+            vm.allocate(items.len() * mem::size_of::<Value>())?;
+            let items = items
+                .into_iter()
+                .map(|item| from_json(vm, item))
+                .collect::<Result<_, _>>()?;
+            Value::List(Rc::new(RefCell::new(items)))
+        }
+        serde_json::Value::Object(entries) => {
+            vm.allocate(entries.len() * mem::size_of::<(MapKey, Value)>())?;
+            let mut map = IndexMap::with_capacity(entries.len());
+            for (key, value) in entries {
+                map.insert(MapKey::Str(Rc::from(key)), from_json(vm, value)?);
+            }
+            Value::Map(Rc::new(RefCell::new(map)))
+        }
+    })
+}
+
+/// `json.stringify(value, pretty)` writes a value as JSON, indented over
+/// several lines if `pretty` is true. `pretty` may be left out.
+fn stringify(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let (value, pretty) = match argc {
+        1 => {
+            let [value] = args(vm, argc)?;
+            (value, false)
+        }
+        _ => {
+            let [value, pretty] = args(vm, argc)?;
+            (value, pretty.is_truthy())
+        }
+    };
+    let json = to_json(vm, &value, &mut Vec::new())?;
+    let s = match pretty {
+        true => serde_json::to_string_pretty(&json),
+        false => serde_json::to_string(&json),
+    }
+    .map_err(|e| invalid(e.to_string()))?;
+    string(vm, s)
+}
+
+/// Converts a value, with `path` holding the lists, maps and objects it's
+/// inside of so that cycles are found rather than followed forever.
+fn to_json(vm: &Vm, value: &Value, path: &mut Vec<*const ()>) -> Result<serde_json::Value, Error> {
+    let unsupported = |what: &str| invalid(format!("{} can't be written as JSON", what));
+    Ok(match value {
+        Value::Nil => serde_json::Value::Null,
+        Value::Bool(b) => serde_json::Value::Bool(*b),
+        Value::Int(i) => serde_json::Value::from(*i),
+        Value::Uint(u) => serde_json::Value::from(*u),
+        Value::Float(f) => Number::from_f64(*f)
+            .map(serde_json::Value::Number)
+            .ok_or_else(|| unsupported(&format!("{:?}", f)))?,
+        Value::String(_) | Value::Str(_) => serde_json::Value::String(vm.text(value)?.to_owned()),
+        Value::List(list) => {
+            enter(path, Rc::as_ptr(list).cast())?;
+            let items = list
+                .borrow()
+                .iter()
+                .map(|item| to_json(vm, item, path))
+                .collect::<Result<_, _>>()?;
+            path.pop();
+            serde_json::Value::Array(items)
+        }
+        Value::Map(map) => {
+            enter(path, Rc::as_ptr(map).cast())?;
+            let mut entries = Map::new();
+            for (key, value) in map.borrow().iter() {
+                let key = match key {
+                    MapKey::Str(s) => s.to_string(),
+                    MapKey::Int(i) => i.to_string(),
+                    MapKey::Bool(b) => b.to_string(),
+                };
+                // `1` and `"1"` are different keys, which JSON can't tell apart
+                if entries.contains_key(&key) {
+                    return Err(invalid(format!(
+                        "more than one key would be written as the JSON key {:?}",
+                        key
+                    )));
+                }
+                entries.insert(key, to_json(vm, value, path)?);
+            }
+            path.pop();
+            serde_json::Value::Object(entries)
+        }
+        Value::Object(object) => {
+            enter(path, Rc::as_ptr(object).cast())?;
+            let mut entries = Map::new();
+            for (name, value) in vm.fields(object) {
+                entries.insert(name, to_json(vm, &value, path)?);
+            }
+            path.pop();
+            serde_json::Value::Object(entries)
+        }
+        Value::Func(_) => return Err(unsupported("a function")),
+        Value::Range(..) => return Err(unsupported("a range")),
+        Value::Iter(_) => return Err(unsupported("an iterator")),
+        Value::Module(_) => return Err(unsupported("a module")),
+        Value::Undefined => return Err(unsupported("an undefined value")),
+    })
+}
+
+fn enter(path: &mut Vec<*const ()>, address: *const ()) -> Result<(), Error> {
+    if path.contains(&address) {
+        return Err(invalid(
+            "a value that contains itself can't be written as JSON".to_owned(),
+        ));
+    }
+    path.push(address);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{eval, raised, reason, run, value};
+
+    #[test]
+    fn parses_values() {
+        assert_eq!(
+            value(r#"json.parse("[1, -1, 1.5, 1e2]")"#),
+            "[1, -1, 1.5, 100.0]"
+        );
+        assert_eq!(
+            value(r#"json.parse("{\"b\": [true, null], \"a\": {\"c\": \"d\"}}")"#),
+            r#"{"b": [true, nil], "a": {"c": "d"}}"#
+        );
+        let message = reason(eval(r#"json.parse("[1,\n 2")"#));
+        assert!(message.starts_with("invalid JSON: "), "{}", message);
+        assert!(message.ends_with("line 2 column 2"), "{}", message);
+    }
+
+    #[test]
+    fn writes_values() {
+        assert_eq!(
+            value(r#"json.stringify({"b": [1, -1, 1.5, nil], "a": "x"})"#),
+            r#""{\"b\":[1,-1,1.5,null],\"a\":\"x\"}""#
+        );
+        assert_eq!(
+            value(r#"json.stringify({1: true}, true)"#),
+            r#""{\n  \"1\": true\n}""#
+        );
+        let round_trip = r#"json.parse(json.stringify({"z": [1, {"y": 2.5}], "a": nil}))"#;
+        assert_eq!(value(round_trip), r#"{"z": [1, {"y": 2.5}], "a": nil}"#);
+    }
+
+    #[test]
+    fn refuses_what_json_cannot_hold() {
+        assert_eq!(
+            reason(eval("json.stringify([main])")),
+            "a function can't be written as JSON"
+        );
+        assert_eq!(
+            reason(eval("json.stringify(math.NAN)")),
+            "NaN can't be written as JSON"
+        );
+        let src = "defn main() do\n    l := [1]\n    l.push(l)\n    json.stringify(l)\nend\n";
+        assert_eq!(
+            reason(run(src)),
+            "a value that contains itself can't be written as JSON"
+        );
+        // Writing the same list twice isn't a cycle
+        let src = "defn main() do\n    l := [1]\n    raise json.stringify([l, l])\nend\n";
+        assert_eq!(raised(run(src)), r#""[[1],[1]]""#);
+        assert_eq!(
+            reason(eval(r#"json.stringify({1: nil, "1": nil})"#)),
+            r#"more than one key would be written as the JSON key "1""#
+        );
+        assert_eq!(
+            reason(eval(r#"json.stringify({"true": 1, true: 2})"#)),
+            r#"more than one key would be written as the JSON key "true""#
+        );
+    }
+}
@@ -0,0 +1,324 @@
+//! The `math` module.
+//!
+//! Integers and floats mix by these rules:
+//!
+//! - `abs`, `floor`, `ceil` and `round` keep integers as integers and floats
+//!   as floats
+//! - `min`, `max` and `pow` give an integer when both arguments are integers,
+//!   and otherwise turn both into floats first; `pow` with a negative integer
+//!   exponent gives a float
+//! - `sqrt`, the trigonometric functions, `log` and `exp` always give floats
+//! - `checked_add`, `checked_sub`, `checked_mul`, `checked_div` and
+//!   `checked_rem` take integers only, and give nil rather than overflowing or
+//!   dividing by zero
+//! - `int` truncates a float towards zero and `float` turns an integer into a
+//!   float
+//!
+//! Integer results are unsigned when they aren't negative, like integer
+//! literals.
+
+use super::{args, invalid};
+use crate::{
+    error::Error,
+    eval::{int_value, Native, Value, Vm},
+};
+
+pub const NATIVES: &[Native] = &[
+    ("abs", abs, None),
+    ("min", min, None),
+    ("max", max, None),
+    ("pow", pow, None),
+    ("sqrt", sqrt, None),
+    ("floor", floor, None),
+    ("ceil", ceil, None),
+    ("round", round, None),
+    ("sin", sin, None),
+    ("cos", cos, None),
+    ("tan", tan, None),
+    ("asin", asin, None),
+    ("acos", acos, None),
+    ("atan", atan, None),
+    ("atan2", atan2, None),
+    ("log", log, None),
+    ("log2", log2, None),
+    ("log10", log10, None),
+    ("exp", exp, None),
+    ("checked_add", checked_add, None),
+    ("checked_sub", checked_sub, None),
+    ("checked_mul", checked_mul, None),
+    ("checked_div", checked_div, None),
+    ("checked_rem", checked_rem, None),
+    ("int", int, None),
+    ("float", float, None),
+];
+
+pub const CONSTANTS: &[(&str, f64)] = &[
+    ("PI", std::f64::consts::PI),
+    ("TAU", std::f64::consts::TAU),
+    ("E", std::f64::consts::E),
+    ("INFINITY", f64::INFINITY),
+    ("NAN", f64::NAN),
+];
+
+enum Number {
+    Int(i128),
+    Float(f64),
+}
+
+fn number(value: &Value) -> Result<Number, Error> {
+    match value {
+        Value::Float(f) => Ok(Number::Float(*f)),
+        _ => value
+            .as_int()
+            .map(Number::Int)
+            .ok_or_else(|| invalid("expected a number".to_owned())),
+    }
+}
+
+fn to_float(value: &Value) -> Result<f64, Error> {
+    Ok(match number(value)? {
+        Number::Int(i) => i as f64,
+        Number::Float(f) => f,
+    })
+}
+
+fn to_int(value: &Value) -> Result<i128, Error> {
+    value
+        .as_int()
+        .ok_or_else(|| invalid("expected an integer".to_owned()))
+}
+
+fn int_result(i: i128) -> Result<Value, Error> {
+    int_value(i).ok_or_else(|| invalid("integer overflow".to_owned()))
+}
+
+fn abs(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [x] = args(vm, argc)?;
+    match number(&x)? {
+        Number::Int(i) => int_result(i.abs()),
+        Number::Float(f) => Ok(Value::Float(f.abs())),
+    }
+}
+
+/// Applies one of two functions to two numbers, depending on whether both
+/// are integers.
+fn binary(
+    vm: &mut Vm,
+    argc: usize,
+    int: impl FnOnce(i128, i128) -> Result<Value, Error>,
+    float: impl FnOnce(f64, f64) -> f64,
+) -> Result<Value, Error> {
+    let [a, b] = args(vm, argc)?;
+    match (number(&a)?, number(&b)?) {
+        (Number::Int(a), Number::Int(b)) => int(a, b),
+        _ => Ok(Value::Float(float(to_float(&a)?, to_float(&b)?))),
+    }
+}
+
+fn min(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    binary(vm, argc, |a, b| int_result(a.min(b)), f64::min)
+}
+
+fn max(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    binary(vm, argc, |a, b| int_result(a.max(b)), f64::max)
+}
+
+fn pow(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    binary(
+        vm,
+        argc,
+        |base, exp| match u32::try_from(exp) {
+            Ok(exp) => base
+                .checked_pow(exp)
+                .ok_or_else(|| invalid("integer overflow".to_owned()))
+                .and_then(int_result),
+            Err(_) if exp < 0 => Ok(Value::Float((base as f64).powf(exp as f64))),
+            Err(_) => Err(invalid("integer overflow".to_owned())),
+        },
+        f64::powf,
+    )
+}
+
+/// Applies a function from floats to floats, promoting integers.
+fn float_fn(vm: &mut Vm, argc: usize, f: impl FnOnce(f64) -> f64) -> Result<Value, Error> {
+    let [x] = args(vm, argc)?;
+    Ok(Value::Float(f(to_float(&x)?)))
+}
+
+fn sqrt(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    float_fn(vm, argc, f64::sqrt)
+}
+
+/// Rounds floats, leaving integers as they are.
+fn rounding(vm: &mut Vm, argc: usize, f: impl FnOnce(f64) -> f64) -> Result<Value, Error> {
+    let [x] = args(vm, argc)?;
+    match number(&x)? {
+        Number::Int(_) => Ok(x),
+        Number::Float(x) => Ok(Value::Float(f(x))),
+    }
+}
+
+fn floor(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    rounding(vm, argc, f64::floor)
+}
+
+fn ceil(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    rounding(vm, argc, f64::ceil)
+}
+
+/// Rounds halfway cases away from zero.
+fn round(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    rounding(vm, argc, f64::round)
+}
+
+fn sin(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    float_fn(vm, argc, f64::sin)
+}
+
+fn cos(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    float_fn(vm, argc, f64::cos)
+}
+
+fn tan(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    float_fn(vm, argc, f64::tan)
+}
+
+fn asin(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    float_fn(vm, argc, f64::asin)
+}
+
+fn acos(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    float_fn(vm, argc, f64::acos)
+}
+
+fn atan(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    float_fn(vm, argc, f64::atan)
+}
+
+/// `math.atan2(y, x)` is the angle of the point `(x, y)`.
+fn atan2(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [y, x] = args(vm, argc)?;
+    Ok(Value::Float(to_float(&y)?.atan2(to_float(&x)?)))
+}
+
+/// The natural logarithm.
+fn log(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    float_fn(vm, argc, f64::ln)
+}
+
+fn log2(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    float_fn(vm, argc, f64::log2)
+}
+
+fn log10(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    float_fn(vm, argc, f64::log10)
+}
+
+fn exp(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    float_fn(vm, argc, f64::exp)
+}
+
+/// Applies an integer operation, giving nil if it fails or the result doesn't
+/// fit in an integer value.
+fn checked(
+    vm: &mut Vm,
+    argc: usize,
+    f: impl FnOnce(i128, i128) -> Option<i128>,
+) -> Result<Value, Error> {
+    let [a, b] = args(vm, argc)?;
+    Ok(f(to_int(&a)?, to_int(&b)?)
+        .and_then(int_value)
+        .unwrap_or(Value::Nil))
+}
+
+fn checked_add(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    checked(vm, argc, i128::checked_add)
+}
+
+fn checked_sub(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    checked(vm, argc, i128::checked_sub)
+}
+
+fn checked_mul(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    checked(vm, argc, i128::checked_mul)
+}
+
+/// Divides rounding towards zero.
+fn checked_div(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    checked(vm, argc, i128::checked_div)
+}
+
+/// The remainder of `checked_div`, with the sign of the dividend.
+fn checked_rem(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    checked(vm, argc, i128::checked_rem)
+}
+
+/// `math.int(x)` truncates a float towards zero.
+fn int(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [x] = args(vm, argc)?;
+    match number(&x)? {
+        Number::Int(_) => Ok(x),
+        Number::Float(f) if f.is_finite() => int_result(f.trunc() as i128),
+        Number::Float(f) => Err(invalid(format!("{:?} has no integer value", f))),
+    }
+}
+
+fn float(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [x] = args(vm, argc)?;
+    Ok(Value::Float(to_float(&x)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{eval, value};
+
+    #[test]
+    fn keeps_integers_integers() {
+        assert_eq!(value("[math.abs(-3), math.abs(-2.5)]"), "[3, 2.5]");
+        assert_eq!(
+            value("[math.floor(7), math.floor(-1.5), math.ceil(1.2)]"),
+            "[7, -2.0, 2.0]"
+        );
+        assert_eq!(value("[math.round(2.5), math.round(-2.5)]"), "[3.0, -3.0]");
+        assert_eq!(value("[math.min(2, -1), math.max(2, 1.5)]"), "[-1, 2.0]");
+        assert_eq!(
+            value("[math.pow(2, 10), math.pow(2, -1), math.pow(4, 0.5)]"),
+            "[1024, 0.5, 2.0]"
+        );
+        assert_eq!(value("[math.int(-2.9), math.float(3)]"), "[-2, 3.0]");
+    }
+
+    #[test]
+    fn gives_floats_for_real_functions() {
+        assert_eq!(value("math.sqrt(16)"), "4.0");
+        assert_eq!(
+            value("[math.log(math.E), math.log2(8), math.log10(1000)]"),
+            "[1.0, 3.0, 3.0]"
+        );
+        assert_eq!(
+            value("[math.exp(0), math.cos(0), math.atan2(0, 1)]"),
+            "[1.0, 1.0, 0.0]"
+        );
+        assert_eq!(value("math.PI == math.TAU - math.PI"), "true");
+        assert_eq!(value("math.NAN == math.NAN"), "false");
+    }
+
+    #[test]
+    fn checks_integer_arithmetic() {
+        assert_eq!(value("math.checked_add(18446744073709551615, 1)"), "nil");
+        assert_eq!(value("math.checked_sub(0, 1)"), "-1");
+        assert_eq!(value("math.checked_mul(4294967296, 4294967296)"), "nil");
+        assert_eq!(
+            value("[math.checked_div(-7, 2), math.checked_rem(-7, 2)]"),
+            "[-3, -1]"
+        );
+        assert_eq!(value("math.checked_div(1, 0)"), "nil");
+        let e = eval("math.checked_add(1.5, 1)").unwrap_err();
+        assert_eq!(
+            e.kind().to_string(),
+            "Invalid argument: expected an integer"
+        );
+        let e = eval("math.pow(2, 200)").unwrap_err();
+        assert_eq!(e.kind().to_string(), "Invalid argument: integer overflow");
+    }
+}
@@ -0,0 +1,84 @@
+//! The `path` module, for building and taking apart file paths. None of its
+//! functions touch the filesystem.
+
+use std::path::{Path, PathBuf};
+
+use super::{args, string};
+use crate::{
+    error::Error,
+    eval::{Native, Value, Vm},
+};
+
+pub const NATIVES: &[Native] = &[
+    ("join", join, None),
+    ("parent", parent, None),
+    ("file_name", file_name, None),
+    ("extension", extension, None),
+];
+
+/// `path.join(a, b, ...)` joins any number of path parts. An absolute part
+/// replaces everything before it.
+fn join(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let parts = vm.pop_args(argc)?;
+    let mut path = PathBuf::new();
+    for part in &parts {
+        path.push(vm.text(part)?);
+    }
+    string(vm, path.to_string_lossy().into_owned())
+}
+
+/// Applies a function taking a part of a path, giving nil if there is no
+/// such part.
+fn part(vm: &mut Vm, argc: usize, f: impl FnOnce(&Path) -> Option<String>) -> Result<Value, Error> {
+    let [path] = args(vm, argc)?;
+    match f(Path::new(vm.text(&path)?)) {
+        Some(part) => string(vm, part),
+        None => Ok(Value::Nil),
+    }
+}
+
+/// `path.parent(p)` is the directory containing `p`, or nil for a root.
+fn parent(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    part(vm, argc, |path| {
+        path.parent()
+            .map(|parent| parent.to_string_lossy().into_owned())
+    })
+}
+
+fn file_name(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    part(vm, argc, |path| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+    })
+}
+
+/// `path.extension(p)` is the extension of the file name, without the dot.
+fn extension(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    part(vm, argc, |path| {
+        path.extension()
+            .map(|extension| extension.to_string_lossy().into_owned())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::value;
+
+    #[test]
+    fn joins_paths() {
+        assert_eq!(value("path.join(\"a\", \"b\", \"c.txt\")"), "\"a/b/c.txt\"");
+        assert_eq!(value("path.join(\"a\", \"/b\")"), "\"/b\"");
+        assert_eq!(value("path.join()"), "\"\"");
+    }
+
+    #[test]
+    fn takes_paths_apart() {
+        assert_eq!(value("path.parent(\"a/b/c.txt\")"), "\"a/b\"");
+        assert_eq!(value("path.parent(\"c.txt\")"), "\"\"");
+        assert_eq!(value("path.parent(\"/\")"), "nil");
+        assert_eq!(value("path.file_name(\"a/b/c.txt\")"), "\"c.txt\"");
+        assert_eq!(value("path.file_name(\"a/..\")"), "nil");
+        assert_eq!(value("path.extension(\"a/b/c.tar.gz\")"), "\"gz\"");
+        assert_eq!(value("path.extension(\"a/.profile\")"), "nil");
+    }
+}
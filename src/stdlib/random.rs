@@ -0,0 +1,168 @@
+//! The `random` module, a pseudorandom generator for reproducible programs.
+//!
+//! Every VM has its own generator, which starts from seed 0: a program gives
+//! the same numbers on every run until it calls `random.seed` with something
+//! that varies.
+
+use std::ops::RangeInclusive;
+
+use super::{args, invalid};
+use crate::{
+    error::Error,
+    eval::{int_value, Native, Value, Vm},
+};
+
+pub const NATIVES: &[Native] = &[
+    ("seed", seed, None),
+    ("int", int, None),
+    ("float", float, None),
+    ("choice", choice, None),
+];
+
+/// xoshiro256**, seeded through splitmix64.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: [u64; 4],
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        let mut x = seed;
+        let mut state = [0; 4];
+        for word in &mut state {
+            x = x.wrapping_add(0x9e3779b97f4a7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            *word = z ^ (z >> 31);
+        }
+        Self { state }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    /// A float in `0.0..1.0`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// An integer in `range`, without bias towards any part of it.
+    pub fn next_in(&mut self, range: RangeInclusive<i128>) -> i128 {
+        let span = (range.end() - range.start()) as u128 + 1;
+        // Draws above the last multiple of `span` would favour small results
+        let zone = u128::MAX - u128::MAX % span;
+        loop {
+            let draw = (self.next_u64() as u128) << 64 | self.next_u64() as u128;
+            if draw < zone {
+                return range.start() + (draw % span) as i128;
+            }
+        }
+    }
+}
+
+/// `random.seed(n)` restarts the generator from an integer.
+fn seed(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [n] = args(vm, argc)?;
+    let n = n
+        .as_int()
+        .ok_or_else(|| invalid("the seed must be an integer".to_owned()))?;
+    *vm.rng() = Rng::new(n as u64);
+    Ok(Value::Nil)
+}
+
+/// `random.int(lo, hi)` is an integer from `lo` to `hi`, both included.
+fn int(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [lo, hi] = args(vm, argc)?;
+    match (lo.as_int(), hi.as_int()) {
+        (Some(lo), Some(hi)) if lo <= hi => {
+            let n = vm.rng().next_in(lo..=hi);
+            int_value(n).ok_or_else(|| Error::eval(concat!(file!(), ":", line!())))
+        }
+        (Some(_), Some(_)) => Err(invalid("empty range".to_owned())),
+        _ => Err(invalid("expected integers".to_owned())),
+    }
+}
+
+/// `random.float()` is a float from 0 up to, but not including, 1.
+fn float(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [] = args(vm, argc)?;
+    Ok(Value::Float(vm.rng().next_f64()))
+}
+
+/// `random.choice(list)` is an item of a non-empty list.
+fn choice(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [list] = args(vm, argc)?;
+    let list = list.into_list()?;
+    let list = list.borrow();
+    if list.is_empty() {
+        return Err(invalid("choice from an empty list".to_owned()));
+    }
+    let i = vm.rng().next_in(0..=list.len() as i128 - 1);
+    Ok(list[i as usize].clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{eval, value};
+
+    #[test]
+    fn repeats_from_a_seed() {
+        let draws = |seed| {
+            let mut rng = Rng::new(seed);
+            (0..4).map(|_| rng.next_u64()).collect::<Vec<_>>()
+        };
+        assert_eq!(draws(7), draws(7));
+        assert_ne!(draws(7), draws(8));
+        assert_eq!(Rng::default().next_u64(), Rng::new(0).next_u64());
+    }
+
+    #[test]
+    fn stays_in_range() {
+        let mut rng = Rng::new(1);
+        let mut seen = [false; 3];
+        for _ in 0..1000 {
+            let n = rng.next_in(-1..=1);
+            seen[(n + 1) as usize] = true;
+            let f = rng.next_f64();
+            assert!((0.0..1.0).contains(&f));
+        }
+        assert_eq!(seen, [true; 3]);
+        assert_eq!(rng.next_in(5..=5), 5);
+        let full = rng.next_in(i128::from(i64::MIN)..=i128::from(u64::MAX));
+        assert!(full >= i128::from(i64::MIN) && full <= i128::from(u64::MAX));
+    }
+
+    #[test]
+    fn draws_the_same_in_every_program() {
+        let draws = "[random.int(1, 100), random.float(), random.choice([\"a\", \"b\", \"c\"])]";
+        let first = value(draws);
+        assert_eq!(value(draws), first);
+        let reseeded = format!("[random.seed(0), {}][1]", draws);
+        assert_eq!(value(&reseeded), first);
+        let e = eval("random.int(2, 1)").unwrap_err();
+        assert_eq!(e.kind().to_string(), "Invalid argument: empty range");
+        let e = eval("random.choice([])").unwrap_err();
+        assert_eq!(
+            e.kind().to_string(),
+            "Invalid argument: choice from an empty list"
+        );
+    }
+}
@@ -0,0 +1,278 @@
+//! The `string` module. Every function takes the string first, so they are
+//! also the methods of strings: `s.upper()` is `string.upper(s)`.
+//!
+//! Lengths and indices count characters rather than bytes.
+
+use std::{cell::RefCell, mem, rc::Rc};
+
+use super::{args, invalid, string};
+use crate::{
+    error::Error,
+    eval::{int_value, slice_range, Native, Value, Vm},
+};
+
+pub const NATIVES: &[Native] = &[
+    ("len", len, None),
+    ("concat", concat, None),
+    ("slice", slice, None),
+    ("chars", chars, None),
+    ("split", split, None),
+    ("join", join, None),
+    ("trim", trim, None),
+    ("trim_start", trim_start, None),
+    ("trim_end", trim_end, None),
+    ("upper", upper, None),
+    ("lower", lower, None),
+    ("find", find, None),
+    ("replace", replace, None),
+    ("contains", contains, None),
+    ("starts_with", starts_with, None),
+    ("ends_with", ends_with, None),
+    ("repeat", repeat, None),
+    ("parse_int", parse_int, None),
+    ("parse_float", parse_float, None),
+    ("from_number", from_number, None),
+];
+
+fn len(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [s] = args(vm, argc)?;
+    Ok(Value::Uint(vm.text(&s)?.chars().count() as u64))
+}
+
+/// `string.concat(a, b, ...)` joins any number of strings.
+fn concat(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let parts = vm.pop_args(argc)?;
+    let mut out = String::new();
+    for part in &parts {
+        out.push_str(vm.text(part)?);
+    }
+    string(vm, out)
+}
+
+/// The byte offset of the `index`th character, or the length for one past
+/// the end.
+fn offset(s: &str, index: usize) -> usize {
+    s.char_indices().nth(index).map_or(s.len(), |(i, _)| i)
+}
+
+/// `string.slice(s, from, to)` copies the characters in `from..to`.
+fn slice(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [s, from, to] = args(vm, argc)?;
+    let s = vm.text(&s)?;
+    let range = slice_range(&from, &to, s.chars().count())?;
+    let sliced = s[offset(s, range.start)..offset(s, range.end)].to_owned();
+    string(vm, sliced)
+}
+
+/// A list of new strings, counted against the heap limit.
+fn strings<'s>(vm: &mut Vm, parts: impl Iterator<Item = &'s str>) -> Result<Value, Error> {
+    let mut items = Vec::new();
+    for part in parts {
+        items.push(string(vm, part.to_owned())?);
+    }
+    vm.allocate(items.len() * mem::size_of::<Value>())?;
+    Ok(Value::List(Rc::new(RefCell::new(items))))
+}
+
+fn chars(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [s] = args(vm, argc)?;
+    let s = vm.text(&s)?.to_owned();
+    let chars = s.char_indices().map(|(i, c)| &s[i..i + c.len_utf8()]);
+    strings(vm, chars)
+}
+
+/// `string.split(s, sep)` splits around every `sep`, or into characters if
+/// `sep` is empty.
+fn split(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [s, sep] = args(vm, argc)?;
+    let (s, sep) = (vm.text(&s)?.to_owned(), vm.text(&sep)?.to_owned());
+    if sep.is_empty() {
+        let chars = s.char_indices().map(|(i, c)| &s[i..i + c.len_utf8()]);
+        return strings(vm, chars);
+    }
+    strings(vm, s.split(sep.as_str()))
+}
+
+/// `string.join(items, sep)` joins a list of strings, and is also a method of
+/// lists: `items.join(", ")`.
+pub fn join(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [items, sep] = args(vm, argc)?;
+    let items = items.into_list()?;
+    let items = items.borrow();
+    let sep = vm.text(&sep)?;
+    let mut out = String::new();
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            out.push_str(sep);
+        }
+        out.push_str(vm.text(item)?);
+    }
+    string(vm, out)
+}
+
+/// Applies a function from strings to strings.
+fn map(vm: &mut Vm, argc: usize, f: impl FnOnce(&str) -> String) -> Result<Value, Error> {
+    let [s] = args(vm, argc)?;
+    let mapped = f(vm.text(&s)?);
+    string(vm, mapped)
+}
+
+fn trim(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    map(vm, argc, |s| s.trim().to_owned())
+}
+
+fn trim_start(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    map(vm, argc, |s| s.trim_start().to_owned())
+}
+
+fn trim_end(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    map(vm, argc, |s| s.trim_end().to_owned())
+}
+
+fn upper(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    map(vm, argc, str::to_uppercase)
+}
+
+fn lower(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    map(vm, argc, str::to_lowercase)
+}
+
+/// `string.find(s, needle)` is the index of the first `needle` in `s`, or nil.
+fn find(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [s, needle] = args(vm, argc)?;
+    let s = vm.text(&s)?;
+    Ok(match s.find(vm.text(&needle)?) {
+        Some(i) => Value::Uint(s[..i].chars().count() as u64),
+        None => Value::Nil,
+    })
+}
+
+/// `string.replace(s, from, to)` replaces every `from`.
+fn replace(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [s, from, to] = args(vm, argc)?;
+    let replaced = vm.text(&s)?.replace(vm.text(&from)?, vm.text(&to)?);
+    string(vm, replaced)
+}
+
+/// Applies a test to a string and a pattern.
+fn test(vm: &mut Vm, argc: usize, f: impl FnOnce(&str, &str) -> bool) -> Result<Value, Error> {
+    let [s, pattern] = args(vm, argc)?;
+    Ok(Value::Bool(f(vm.text(&s)?, vm.text(&pattern)?)))
+}
+
+fn contains(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    test(vm, argc, |s, pattern| s.contains(pattern))
+}
+
+fn starts_with(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    test(vm, argc, |s, pattern| s.starts_with(pattern))
+}
+
+fn ends_with(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    test(vm, argc, |s, pattern| s.ends_with(pattern))
+}
+
+/// `string.repeat(s, n)` is `n` copies of `s`.
+fn repeat(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [s, n] = args(vm, argc)?;
+    let n = n
+        .as_int()
+        .and_then(|n| usize::try_from(n).ok())
+        .ok_or_else(|| invalid("repeat count must be a non-negative integer".to_owned()))?;
+    let s = vm.text(&s)?.to_owned();
+    vm.allocate(s.len().saturating_mul(n))?;
+    Ok(Value::String(s.repeat(n)))
+}
+
+/// `string.parse_int(s)` reads a decimal integer, raising an error if `s`
+/// isn't one.
+fn parse_int(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [s] = args(vm, argc)?;
+    let s = vm.text(&s)?;
+    s.parse::<i128>()
+        .ok()
+        .and_then(int_value)
+        .ok_or_else(|| invalid(format!("{:?} is not an integer", s)))
+}
+
+/// `string.parse_float(s)` reads a decimal number, raising an error if `s`
+/// isn't one.
+fn parse_float(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [s] = args(vm, argc)?;
+    let s = vm.text(&s)?;
+    s.parse::<f64>()
+        .map(Value::Float)
+        .map_err(|_| invalid(format!("{:?} is not a number", s)))
+}
+
+/// `string.from_number(n)` writes a number in decimal. Floats always have a
+/// fractional part or an exponent, so they read back as floats.
+fn from_number(vm: &mut Vm, argc: usize) -> Result<Value, Error> {
+    let [n] = args(vm, argc)?;
+    let s = match n {
+        Value::Int(i) => i.to_string(),
+        Value::Uint(u) => u.to_string(),
+        Value::Float(f) => format!("{:?}", f),
+        _ => return Err(invalid("expected a number".to_owned())),
+    };
+    string(vm, s)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::ErrorKind,
+        testing::{eval, value},
+    };
+
+    #[test]
+    fn counts_characters() {
+        assert_eq!(value(r#"string.len("héllo")"#), "5");
+        assert_eq!(value(r#""héllo".slice(1, 3)"#), r#""él""#);
+        assert_eq!(value(r#""héllo".slice(5, 5)"#), r#""""#);
+        assert_eq!(value(r#""né".chars()"#), r#"["n", "é"]"#);
+        assert_eq!(value(r#""héllo".find("l")"#), "2");
+        assert_eq!(value(r#""héllo".find("x")"#), "nil");
+        let e = eval(r#""abc".slice(2, 4)"#).unwrap_err();
+        assert!(matches!(
+            e.kind(),
+            ErrorKind::IndexOutOfBounds { index: 4, len: 3 }
+        ));
+    }
+
+    #[test]
+    fn splits_and_joins() {
+        assert_eq!(value(r#""a,b,,c".split(",")"#), r#"["a", "b", "", "c"]"#);
+        assert_eq!(value(r#""ab".split("")"#), r#"["a", "b"]"#);
+        assert_eq!(value(r#"["a", "b", "c"].join(", ")"#), r#""a, b, c""#);
+        assert_eq!(value(r#"string.concat("a", "b", "c")"#), r#""abc""#);
+    }
+
+    #[test]
+    fn transforms_and_tests() {
+        assert_eq!(value(r#""  hi  ".trim()"#), r#""hi""#);
+        assert_eq!(value(r#""  hi  ".trim_start()"#), r#""hi  ""#);
+        assert_eq!(value(r#""  hi  ".trim_end()"#), r#""  hi""#);
+        assert_eq!(value(r#""Straße".upper()"#), r#""STRASSE""#);
+        assert_eq!(value(r#""ABC".lower()"#), r#""abc""#);
+        assert_eq!(value(r#""a-b-c".replace("-", "+")"#), r#""a+b+c""#);
+        assert_eq!(value(r#""ab".repeat(3)"#), r#""ababab""#);
+        let tests = r#"["abc".contains("b"), "abc".starts_with("ab"), "abc".ends_with("b")]"#;
+        assert_eq!(value(tests), "[true, true, false]");
+    }
+
+    #[test]
+    fn converts_numbers() {
+        assert_eq!(value(r#"string.parse_int("-42")"#), "-42");
+        assert_eq!(value(r#"string.parse_float("2.5e1")"#), "25.0");
+        assert_eq!(value("string.from_number(1.0)"), r#""1.0""#);
+        assert_eq!(value("string.from_number(7)"), r#""7""#);
+        let e = eval(r#"string.parse_int("4x")"#).unwrap_err();
+        assert_eq!(
+            e.kind().to_string(),
+            r#"Invalid argument: "4x" is not an integer"#
+        );
+        let e = eval(r#""ab".repeat(-1)"#).unwrap_err();
+        assert!(matches!(e.kind(), ErrorKind::InvalidArgument { .. }));
+    }
+}
@@ -0,0 +1,100 @@
+//! Helpers for unit tests, which compile and run scripts given as strings.
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use chumsky::Span as _;
+use lasso::Rodeo;
+use logos::Logos;
+
+use crate::{
+    compiler::{Compiler, OptLevel, Program},
+    error::{Error, ErrorKind},
+    eval::Vm,
+    lexer::Token,
+    parser::{self, parse},
+    Span,
+};
+
+/// Compiles a script as if it were the file `test.oni`.
+pub fn compile(src: &str, interner: &mut Rodeo, opt_level: OptLevel) -> Result<Program, Error> {
+    compile_at(src, Path::new("test.oni"), interner, opt_level)
+}
+
+/// Compiles a script as if it were the file at `path`.
+pub fn compile_at(
+    src: &str,
+    path: &Path,
+    interner: &mut Rodeo,
+    opt_level: OptLevel,
+) -> Result<Program, Error> {
+    let tokens = Token::lexer(src)
+        .spanned()
+        .map(|(t, s)| (t, Span::new(path, s)))
+        .collect::<Vec<_>>();
+    let ast =
+        parse(tokens).map_err(|errors| parser::error(concat!(file!(), ":", line!()), errors))?;
+    Compiler::compile(ast, interner, Vec::new(), opt_level)
+}
+
+/// Compiles and runs a script.
+pub fn run(src: &str) -> Result<(), Error> {
+    let mut interner = Rodeo::new();
+    let program = compile(src, &mut interner, 0)?;
+    let mut vm = Vm::new(program, &mut interner)?;
+    vm.eval()
+}
+
+/// Evaluates an expression in `main`, giving its value as `inspect` writes
+/// it, or the error that stopped it.
+pub fn eval(expr: &str) -> Result<String, Error> {
+    match run(&format!("defn main() do\n    raise {}\nend\n", expr)) {
+        Err(e) => match e.kind() {
+            ErrorKind::Raised { value } => Ok(value.clone()),
+            _ => Err(e),
+        },
+        Ok(()) => unreachable!("the expression is raised"),
+    }
+}
+
+/// The value of an expression that must not fail, as `inspect` writes it.
+pub fn value(expr: &str) -> String {
+    eval(expr).unwrap()
+}
+
+/// The reason given by an error that has one, like a native's invalid
+/// argument or the verifier's invalid bytecode.
+pub fn reason<T: fmt::Debug>(res: Result<T, Error>) -> String {
+    match res.unwrap_err().kind() {
+        ErrorKind::Syntax { reason }
+        | ErrorKind::InvalidBytecode { reason }
+        | ErrorKind::Assembler { reason, .. }
+        | ErrorKind::Debugger { reason }
+        | ErrorKind::PermissionDenied { reason }
+        | ErrorKind::InvalidArgument { reason }
+        | ErrorKind::Io { reason } => reason.clone(),
+        kind => panic!("expected an error with a reason, got {}", kind),
+    }
+}
+
+/// The value a program raised and didn't rescue, as `inspect` writes it.
+/// Tests report their result this way, since `main` returns nothing.
+pub fn raised(res: Result<(), Error>) -> String {
+    match res {
+        Err(e) => match e.kind() {
+            ErrorKind::Raised { value } => value.clone(),
+            _ => panic!("expected a raised value, got {:?}", e),
+        },
+        Ok(()) => panic!("expected a raised value, but the program finished"),
+    }
+}
+
+/// An empty directory of its own for the test `name`, which the test removes.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("onilang-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,369 @@
+//! Checks that compiled code can't make the VM misbehave, whether it comes
+//! from the compiler, a bytecode file or was built by hand.
+
+use lasso::{Rodeo, Spur};
+
+use crate::{
+    compiler::{FuncProto, Program},
+    error::Error,
+    eval::{ConstValue, Flags, Opcode, RuntimeFunc},
+};
+
+/// Verifies every function of a program.
+pub fn program(program: &Program, interner: &Rodeo) -> Result<(), Error> {
+    let verifier = Verifier {
+        interner,
+        modules: program.modules.len(),
+    };
+    verifier.func(&program.main)?;
+    for module in &program.modules {
+        if module.index >= program.modules.len() {
+            return Err(invalid(format!(
+                "module index {} out of bounds",
+                module.index
+            )));
+        }
+        verifier.name(module.name)?;
+        for (&name, func) in &module.funcs {
+            verifier.name(name)?;
+            verifier.func(func)?;
+        }
+        for &global in &module.globals {
+            verifier.name(global)?;
+        }
+        verifier.func(&module.init)?;
+    }
+    for ty in program.types.values() {
+        verifier.name(ty.name)?;
+        for &(field, flags) in ty.fields.iter() {
+            verifier.name(field)?;
+            verifier.declaration(flags)?;
+        }
+        for (&name, method) in &ty.methods {
+            verifier.name(name)?;
+            if let RuntimeFunc::Virtual(func) = method {
+                verifier.func(func)?;
+            }
+        }
+        if let Some(ctor) = &ty.ctor {
+            verifier.func(ctor)?;
+        }
+    }
+    Ok(())
+}
+
+fn invalid(reason: String) -> Error {
+    Error::invalid_bytecode(concat!(file!(), ":", line!()), reason)
+}
+
+struct Verifier<'i> {
+    interner: &'i Rodeo,
+    modules: usize,
+}
+
+impl<'i> Verifier<'i> {
+    fn name(&self, name: Spur) -> Result<(), Error> {
+        match self.interner.try_resolve(&name) {
+            Some(_) => Ok(()),
+            None => Err(invalid(format!("unknown name {:?}", name))),
+        }
+    }
+
+    fn module(&self, module: usize) -> Result<(), Error> {
+        if module >= self.modules {
+            return Err(invalid(format!("module index {} out of bounds", module)));
+        }
+        Ok(())
+    }
+
+    /// Bindings are declared unassigned; `ASSIGNED` is only set by the VM.
+    fn declaration(&self, flags: Flags) -> Result<(), Error> {
+        if flags.contains(Flags::ASSIGNED) || !Flags::all().contains(flags) {
+            return Err(invalid(format!(
+                "invalid flags {:?} for a declaration",
+                flags
+            )));
+        }
+        Ok(())
+    }
+
+    fn func(&self, func: &FuncProto) -> Result<(), Error> {
+        self.module(func.module)?;
+        if func.spans.len() != func.code.len() {
+            return Err(invalid(format!(
+                "{} spans for {} instructions",
+                func.spans.len(),
+                func.code.len()
+            )));
+        }
+        for (ip, op) in func.code.iter().enumerate() {
+            self.operands(func, op)
+                .map_err(|e| e.with_span(func.span(ip).unwrap()))?;
+        }
+        stack_depths(func)
+    }
+
+    fn operands(&self, func: &FuncProto, op: &Opcode) -> Result<(), Error> {
+        match *op {
+            Opcode::Defslot(name, flags)
+            | Opcode::DefGlobal(name, flags)
+            | Opcode::DefField(name, flags) => {
+                self.name(name)?;
+                self.declaration(flags)
+            }
+            Opcode::Import(binding) => {
+                self.name(binding.name)?;
+                self.module(binding.module)?;
+                binding.item.map_or(Ok(()), |item| self.name(item))
+            }
+            Opcode::Dropslot(name)
+            | Opcode::Assign(name)
+            | Opcode::Read(name)
+            | Opcode::LoadField(name)
+            | Opcode::StoreField(name)
+            | Opcode::InitField(name)
+            | Opcode::Call(name, _)
+            | Opcode::CallMethod(name, _)
+            | Opcode::New(name, _) => self.name(name),
+            Opcode::Jump(target)
+            | Opcode::JumpIfFalse(target)
+            | Opcode::IterNext(target)
+            | Opcode::PushHandler(target) => {
+                if target >= func.code.len() {
+                    return Err(invalid(format!("jump target {} out of bounds", target)));
+                }
+                Ok(())
+            }
+            Opcode::Const(index) => {
+                if index >= func.consts.len() {
+                    return Err(invalid(format!("constant {} out of bounds", index)));
+                }
+                if let ConstValue::Str(s) = func.consts[index] {
+                    self.name(s)?;
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The number of values an instruction pops, and how many it pushes when it
+/// doesn't jump.
+fn stack_effect(op: &Opcode) -> (usize, usize) {
+    match *op {
+        Opcode::Defslot(..)
+        | Opcode::DefGlobal(..)
+        | Opcode::Import(_)
+        | Opcode::Dropslot(_)
+        | Opcode::Jump(_)
+        | Opcode::PushHandler(_)
+        | Opcode::PopHandler => (0, 0),
+        Opcode::Read(_) | Opcode::Bool(_) | Opcode::Const(_) | Opcode::Nil => (0, 1),
+        Opcode::Assign(_)
+        | Opcode::JumpIfFalse(_)
+        | Opcode::Pop
+        | Opcode::Return
+        | Opcode::Raise
+        | Opcode::NoMatch => (1, 0),
+        Opcode::LoadField(_) | Opcode::Seal | Opcode::Iter => (1, 1),
+        Opcode::Dup | Opcode::TestList(_) | Opcode::IterNext(_) => (1, 2),
+        Opcode::StoreField(_) | Opcode::DefField(..) | Opcode::InitField(_) => (2, 0),
+        Opcode::LoadIndex
+        | Opcode::TestKey
+        | Opcode::LoadKey
+        | Opcode::MakeRange(_)
+        | Opcode::Add
+        | Opcode::Sub
+        | Opcode::Eq
+        | Opcode::Ne
+        | Opcode::Lt
+        | Opcode::Le
+        | Opcode::Gt
+        | Opcode::Ge => (2, 1),
+        // The flag, above the value or error the `ensure` block ran with
+        Opcode::EndEnsure => (2, 1),
+        Opcode::StoreIndex => (3, 0),
+        Opcode::Call(_, argc) | Opcode::New(_, argc) | Opcode::MakeList(argc) => (argc, 1),
+        Opcode::CallValue(argc) | Opcode::CallMethod(_, argc) => (argc.saturating_add(1), 1),
+        Opcode::MakeMap(len) => (len.saturating_mul(2), 1),
+    }
+}
+
+/// Follows every path through the function, checking that the stack never
+/// underflows and has the same depth wherever paths meet.
+fn stack_depths(func: &FuncProto) -> Result<(), Error> {
+    let mut depths = vec![None; func.code.len()];
+    // The arguments are on the stack when the function starts
+    let mut pending = vec![(0, func.arity)];
+    while let Some((ip, depth)) = pending.pop() {
+        let op = match func.code.get(ip) {
+            Some(op) => op,
+            None => return Err(invalid("execution runs past the end of the code".into())),
+        };
+        let fail = |reason: String| invalid(reason).with_span(func.span(ip).unwrap());
+        match depths[ip] {
+            Some(known) if known == depth => continue,
+            Some(known) => {
+                return Err(fail(format!(
+                    "stack depth {} where another path has {}",
+                    depth, known
+                )))
+            }
+            None => depths[ip] = Some(depth),
+        }
+        let (pops, pushes) = stack_effect(op);
+        let rest = depth.checked_sub(pops).ok_or_else(|| {
+            fail(format!(
+                "stack underflow: {} values, {} needed",
+                depth, pops
+            ))
+        })?;
+        let next = rest + pushes;
+        match *op {
+            Opcode::Return if rest != 0 => {
+                return Err(fail(format!("{} values left on the stack at return", rest)))
+            }
+            Opcode::Return | Opcode::Raise | Opcode::NoMatch => {}
+            Opcode::Jump(target) => pending.push((target, next)),
+            Opcode::JumpIfFalse(target) => pending.extend([(ip + 1, next), (target, next)]),
+            // The iterator stays on the stack when it's exhausted
+            Opcode::IterNext(target) => pending.extend([(ip + 1, next), (target, depth)]),
+            // The handler starts with the error on top of the stack as it was
+            Opcode::PushHandler(target) => pending.extend([(ip + 1, next), (target, depth + 1)]),
+            _ => pending.push((ip + 1, next)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use lasso::Rodeo;
+
+    use super::*;
+    use crate::{
+        asm::assemble,
+        testing::{compile, reason},
+    };
+
+    /// Verifies a program whose `main` has `consts` and `code`.
+    fn verify(consts: &str, code: &str) -> Result<(), Error> {
+        let src = format!(
+            ".module 0 test\n\
+             .init arity 0 module 0 path \"test.oni\"\n    nil\n    return\n.end\n\
+             .func main arity 0 module 0 path \"test.oni\"\n{}{}.end\n",
+            consts, code
+        );
+        let mut interner = Rodeo::new();
+        let program = assemble(&src, &mut interner)?;
+        super::program(&program, &interner)
+    }
+
+    #[test]
+    fn accepts_compiled_programs() {
+        let src = r#"
+type Counter do
+    n := 0
+    defn bump() do
+        self.n = self.n + 1
+    end
+end
+
+defn main() do
+    c := new Counter
+    for i in 0..3 do
+        c.bump()
+    end
+    m := {a: [1, 2]}
+    m["b"] = match m do
+        {a: [x, y]} if x < y => try
+            raise x
+        rescue e do
+            e
+        ensure
+            nil
+        end,
+        _ => 0,
+    end
+end
+"#;
+        for level in 0..=2 {
+            let mut interner = Rodeo::new();
+            let program = compile(src, &mut interner, level).unwrap();
+            super::program(&program, &interner).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_stack_underflow() {
+        assert_eq!(
+            reason(verify("", "    nil\n    add\n    return\n")),
+            "stack underflow: 1 values, 2 needed"
+        );
+    }
+
+    #[test]
+    fn rejects_paths_meeting_at_different_depths() {
+        let code = "    bool true
+    jump_if_false skip
+    nil
+skip:
+    nil
+    return
+";
+        assert!(reason(verify("", code)).starts_with("stack depth"));
+    }
+
+    #[test]
+    fn rejects_values_left_at_return() {
+        assert_eq!(
+            reason(verify("", "    nil\n    nil\n    return\n")),
+            "1 values left on the stack at return"
+        );
+    }
+
+    #[test]
+    fn rejects_running_past_the_end() {
+        assert_eq!(
+            reason(verify("", "    nil\n    pop\n")),
+            "execution runs past the end of the code"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_operands() {
+        assert_eq!(
+            reason(verify("", "    const 0\n    return\n")),
+            "constant 0 out of bounds"
+        );
+        assert_eq!(
+            reason(verify(
+                "",
+                "    nil\n    defslot x mut+assigned\n    assign x\n    nil\n    return\n"
+            )),
+            "invalid flags BINDING_MODE_MUT | ASSIGNED for a declaration"
+        );
+        let mut interner = Rodeo::new();
+        let mut program = compile("defn main() do\n    nil\nend\n", &mut interner, 0).unwrap();
+        let mut code = program.main.code.to_vec();
+        code.insert(0, Opcode::Jump(100));
+        program.main.code = Rc::from(code);
+        let mut spans = program.main.spans.to_vec();
+        spans.push(0..0);
+        program.main.spans = Rc::from(spans);
+        assert_eq!(
+            reason(super::program(&program, &interner)),
+            "jump target 100 out of bounds"
+        );
+    }
+
+    #[test]
+    fn rejects_names_missing_from_the_interner() {
+        let mut interner = Rodeo::new();
+        let program = compile("defn main() do\n    nil\nend\n", &mut interner, 0).unwrap();
+        assert!(reason(super::program(&program, &Rodeo::new())).starts_with("unknown name"));
+    }
+}